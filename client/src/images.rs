@@ -480,6 +480,7 @@ impl From<Occupation> for Image {
             Occupation::Gathering => Image::Gathering,
             Occupation::Fighting => Image::Fighting,
             Occupation::Exploring => Image::Exploring,
+            Occupation::Crafting => Image::Manager,
         }
     }
 }
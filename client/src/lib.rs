@@ -8,7 +8,7 @@ use rand::RngCore;
 use rustrict::CensorStr;
 use seed::{prelude::*, *};
 use shared::{
-    Bundle, ClientEvent, Craftable, Dwarf, DwarfId, GameMode, Health, HireDwarfType, Item, ItemRarity, ItemType, LogMsg, Money, Occupation, Player, Popup, QuestId, QuestType, RewardMode, RewardType, SpecialDwarf, Stats, Territory, Time, TradeId, TradeType, TribeId, TutorialRequirement, TutorialReward, TutorialStep, UserId, WorldEvent, DISMANTLING_DIVIDER, JOIN_TRIBE_LEVEL, MAX_EFFECTIVENESS, MAX_HEALTH, MAX_NUM_TRADES, MIN_TRADE_VALUE, SPEED, TRADE_MONEY_MULTIPLIER, WINNER_NUM_PREMIUM_DAYS, WINNER_TRIBE_NUM_PREMIUM_DAYS
+    Banner, Building, Bundle, ChatChannel, ClientEvent, Craftable, Dwarf, DwarfId, GameMode, Health, HireDwarfType, Item, ItemRarity, ItemType, LogMsg, Money, Occupation, Player, Popup, QuestId, QuestType, RewardMode, RewardType, SpecialDwarf, Stats, Territory, Time, TradeId, TradeType, TribeId, TutorialRequirement, TutorialReward, TutorialStep, UserId, WorldEvent, DISMANTLING_DIVIDER, JOIN_TRIBE_LEVEL, MAX_EFFECTIVENESS, MAX_HEALTH, MAX_NUM_DIRECT_OFFERS, DIRECT_OFFER_DURATION, MAX_NUM_TRADES, MAX_BUY_ORDER_QTY, MIN_TRADE_VALUE, SPEED, TRADE_MONEY_MULTIPLIER, WINNER_NUM_PREMIUM_DAYS, WINNER_TRIBE_NUM_PREMIUM_DAYS, LEVELS_PER_BUILDING_SLOT, MAX_TITHE, MAX_TRIBE_NAME_LEN, MAX_TRIBE_UPGRADE_LEVEL, TRIBE_CHANGE_COOLDOWN, TRIBE_CHOICE_TIME, TribeUpgrade, Mastery, PresetTrigger, MAX_PRESET_NAME_LEN, Action, Rule, CraftTarget, RecipeTree
 };
 use std::str::FromStr;
 use strum::Display;
@@ -45,6 +45,7 @@ enum Icon {
     ChatUnread,
     Manager,
     Tribe,
+    Error,
 }
 
 impl Icon {
@@ -74,6 +75,7 @@ impl Icon {
             Icon::ChatUnread => "mark_chat_unread",
             Icon::Manager => "history_edu",
             Icon::Tribe => "handshake",
+            Icon::Error => "error",
         }
     }
 
@@ -123,6 +125,8 @@ pub enum Page {
     Ranking,
     Trading,
     Manager,
+    Automation,
+    Recipes,
     Tribe,
     Visit(Option<UserId>)
 }
@@ -145,6 +149,8 @@ impl Page {
             Some("ranking") => Page::Ranking,
             Some("trading") => Page::Trading,
             Some("manager") => Page::Manager,
+            Some("automation") => Page::Automation,
+            Some("recipes") => Page::Recipes,
             Some("tribe") => Page::Tribe,
             _ => Page::Base,
         };
@@ -193,6 +199,98 @@ pub struct TradeFilter {
     by_type: CustomMap<ItemType, bool>,
 }
 
+#[derive(Default)]
+pub struct BuyOrderForm {
+    item: Option<Item>,
+    qty: u64,
+    max_price: Money,
+}
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct DirectOfferForm {
+    to: Option<UserId>,
+    item: Option<Item>,
+    qty: u64,
+    money: Money,
+    requested_item: Option<Item>,
+    requested_qty: u64,
+    requested_money: Money,
+}
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct CraftingForm {
+    item: Option<Item>,
+    target: u64,
+    priority: u64,
+    reserve_item: Option<Item>,
+    reserve: u64,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct PresetForm {
+    name: String,
+    trigger: Option<PresetTrigger>,
+}
+
+const CONDITION_KINDS: [&str; 7] = [
+    "More than ... of an item",
+    "Less than ... of an item",
+    "More than ... food",
+    "Less than ... food",
+    "A dwarf below ...% health",
+    "During a world event",
+    "At certain hours",
+];
+
+const ACTION_KINDS: [&str; 5] = [
+    "Craft up to ...",
+    "Store food above ...",
+    "Dismantle above ...",
+    "Bid up to ... coins per item",
+    "Switch to a manager preset",
+];
+
+#[derive(Default, Clone, Debug)]
+pub struct RuleForm {
+    conditions: Vec<shared::Condition>,
+    condition_kind: usize,
+    condition_item: Option<Item>,
+    condition_value: u64,
+    condition_value2: u64,
+    condition_event: Option<WorldEvent>,
+    action_kind: usize,
+    action_item: Option<Item>,
+    action_value: u64,
+    action_preset: Option<String>,
+}
+
+impl RuleForm {
+    fn condition(&self) -> Option<shared::Condition> {
+        use shared::Condition;
+        match self.condition_kind {
+            0 => self.condition_item.map(|item| Condition::ItemAbove(item, self.condition_value)),
+            1 => self.condition_item.map(|item| Condition::ItemBelow(item, self.condition_value)),
+            2 => Some(Condition::FoodAbove(self.condition_value)),
+            3 => Some(Condition::FoodBelow(self.condition_value)),
+            4 => Some(Condition::HealthBelow(self.condition_value.min(100))),
+            5 => self.condition_event.map(Condition::EventActive),
+            6 => Some(Condition::Hours(self.condition_value.min(23), self.condition_value2.min(23))),
+            _ => None,
+        }
+    }
+
+    fn action(&self) -> Option<Action> {
+        match self.action_kind {
+            0 => self.action_item.map(|item| Action::CraftUpTo(item, self.action_value)),
+            1 => self.action_item.map(|item| Action::StoreAbove(item, self.action_value)),
+            2 => self.action_item.map(|item| Action::DismantleAbove(item, self.action_value)),
+            3 => self.action_item.map(|item| Action::BidUpTo(item, self.action_value)),
+            4 => self.action_preset.clone().map(Action::ApplyPreset),
+            _ => None,
+        }
+    }
+}
+
 impl Default for TradeFilter {
     fn default() -> Self {
         Self {
@@ -264,6 +362,7 @@ pub struct Model {
     page: Page,
     message: String,
     chat_visible: bool,
+    chat_channel: ChatChannel,
     history_visible: bool,
     inventory_filter: InventoryFilter,
     trade_filter: TradeFilter,
@@ -277,6 +376,14 @@ pub struct Model {
     confirm: Option<ClientEvent>,
     slider: CustomMap<(Item, SliderType), u64>,
     bid_max: CustomMap<TradeId, Money>,
+    buy_order: BuyOrderForm,
+    direct_offer: DirectOfferForm,
+    tribe_deposit: Money,
+    tribe_tithe: Option<u64>,
+    preset: PresetForm,
+    rule: RuleForm,
+    crafting: CraftingForm,
+    recipe_item: Option<Item>,
 }
 
 impl Model {
@@ -326,6 +433,7 @@ fn init(url: Url, orders: &mut impl Orders<Msg>) -> Model {
         page,
         message: String::new(),
         chat_visible: false,
+        chat_channel: ChatChannel::Global,
         history_visible: false,
         inventory_filter: InventoryFilter::default(),
         dwarfs_filter: DwarfsFilter::default(),
@@ -339,6 +447,14 @@ fn init(url: Url, orders: &mut impl Orders<Msg>) -> Model {
         confirm: None,
         slider: CustomMap::new(),
         bid_max: CustomMap::new(),
+        buy_order: BuyOrderForm::default(),
+        direct_offer: DirectOfferForm::default(),
+        tribe_deposit: 0,
+        tribe_tithe: None,
+        preset: PresetForm::default(),
+        rule: RuleForm::default(),
+        crafting: CraftingForm::default(),
+        recipe_item: None,
     }
 }
 
@@ -349,6 +465,7 @@ pub enum Msg {
     ChangeMessage(String),
     SubmitMessage,
     ToggleChat,
+    ChangeChatChannel(ChatChannel),
     ToggleHistory,
     ChangeEquipment(DwarfId, ItemType, Option<Item>),
     AssignToQuest(QuestId, usize, Option<DwarfId>),
@@ -381,6 +498,17 @@ pub enum Msg {
     ConfirmNo,
     SetSlider(Item, SliderType, u64),
     SetBidMax(TradeId, Money),
+    SetBuyOrderItem(Option<Item>),
+    SetBuyOrderQty(u64),
+    SetBuyOrderPrice(Money),
+    SetDirectOffer(DirectOfferForm),
+    SetTribeName(String),
+    SetTribeDeposit(Money),
+    SetTribeTithe(u64),
+    SetPresetForm(PresetForm),
+    SetRuleForm(RuleForm),
+    SetCraftingForm(CraftingForm),
+    SetRecipeItem(Option<Item>),
 }
 
 impl EngineMsg<shared::State> for Msg {}
@@ -467,7 +595,7 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         }
         Msg::SubmitMessage => {
             //send(ClientEvent::Message(model.message.clone()));
-            orders.send_msg(Msg::send_event(ClientEvent::Message(model.message.clone())));
+            orders.send_msg(Msg::send_event(ClientEvent::Message(model.chat_channel, model.message.clone())));
             model.message.clear();
         }
         Msg::ToggleChat => {
@@ -475,7 +603,11 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             if model.chat_visible {
                 model.history_visible = false;
             }
-            orders.send_msg(Msg::send_event(ClientEvent::ReadChat));
+            orders.send_msg(Msg::send_event(ClientEvent::ReadChat(model.chat_channel)));
+        }
+        Msg::ChangeChatChannel(channel) => {
+            model.chat_channel = channel;
+            orders.send_msg(Msg::send_event(ClientEvent::ReadChat(channel)));
         }
         Msg::ToggleHistory => {
             model.history_visible = !model.history_visible;
@@ -609,6 +741,40 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         Msg::SetBidMax(trade_id, money) => {
             model.bid_max.insert(trade_id, money);
         }
+        Msg::SetBuyOrderItem(item) => {
+            model.buy_order.item = item;
+        }
+        Msg::SetBuyOrderQty(qty) => {
+            model.buy_order.qty = qty;
+        }
+        Msg::SetBuyOrderPrice(max_price) => {
+            model.buy_order.max_price = max_price;
+        }
+        Msg::SetDirectOffer(direct_offer) => {
+            model.direct_offer = direct_offer;
+        }
+        Msg::SetTribeName(name) => {
+            orders.send_msg(Msg::send_event(ClientEvent::SetTribeName(name)));
+            model.custom_name = None;
+        }
+        Msg::SetTribeDeposit(money) => {
+            model.tribe_deposit = money;
+        }
+        Msg::SetTribeTithe(tithe) => {
+            model.tribe_tithe = Some(tithe);
+        }
+        Msg::SetPresetForm(preset) => {
+            model.preset = preset;
+        }
+        Msg::SetRuleForm(rule) => {
+            model.rule = rule;
+        }
+        Msg::SetCraftingForm(crafting) => {
+            model.crafting = crafting;
+        }
+        Msg::SetRecipeItem(item) => {
+            model.recipe_item = item;
+        }
     }
 }
 
@@ -656,12 +822,15 @@ fn view(model: &Model) -> Node<Msg> {
                     Page::Quest(quest_id) => quest(model, state, user_id, quest_id),
                     Page::Trading => trades(model, state, user_id),
                     Page::Manager => manager(model, state, user_id),
+                    Page::Automation => automation(model, state, user_id),
+                    Page::Recipes => recipes(model, state, user_id),
                     Page::Tribe => tribe(model, client_state, state, user_id),
 
                 }],
                 chat(model, state, user_id, client_state),
                 history(model, state, user_id, client_state),
                 last_received_items(model, state, user_id),
+                action_error(model, state, user_id),
             ]
         ]
     } else {
@@ -1063,6 +1232,8 @@ fn confirm(model: &Model, _state: &shared::State, _user_id: &shared::UserId) ->
                             p!["Do you really want to release this dwarf?"],
                         ClientEvent::Sell(..) =>
                             p!["Do you really want to sell this item on the market?"],
+                        ClientEvent::LeaveTribe =>
+                            p![format!("Do you really want to leave your tribe? The fame points you have spent stay with your tribe and the ones you have not spent are lost. After joining another tribe you can't leave it again for {}.", fmt_time(TRIBE_CHANGE_COOLDOWN, true))],
                         _ => p![],
                     },
                     button![ev(Ev::Click, move |_| Msg::ConfirmYes), "Yes"],
@@ -1158,11 +1329,33 @@ fn name(model: &Model, user_id: &shared::UserId, include_online_status: bool) ->
     }
 }
 
-fn tribe_name(tribe: TribeId, game_id: GameId) -> Node<Msg> {
+fn tribe_name(state: &shared::State, tribe: TribeId, game_id: GameId) -> Node<Msg> {
     let mut rng = Image::rng_from_str(&format!("{}-{}", tribe, game_id));
-    let tribe_name = format!("{} Tribe", Dwarf::name(&mut rng));
+    let generated_name = format!("{} Tribe", Dwarf::name(&mut rng));
     let tribe_color = ((rng.next_u32() % 192) as u8, (rng.next_u32() % 192) as u8, (rng.next_u32() % 192) as u8);
-    span![style![ St::Color => format!("rgb({}, {}, {})", tribe_color.0, tribe_color.1, tribe_color.2) ], tribe_name]
+    let tribe = state.tribes.get(&tribe);
+    span![
+        style![ St::Color => format!("rgb({}, {}, {})", tribe_color.0, tribe_color.1, tribe_color.2) ],
+        tribe.and_then(|tribe| tribe.banner).map(|banner| span![banner_icon(banner), " "]),
+        tribe.and_then(|tribe| tribe.name.clone()).map(|name| name.censor()).unwrap_or(generated_name)
+    ]
+}
+
+fn banner_icon(banner: Banner) -> Node<Msg> {
+    span![
+        attrs! {At::Alt => format!("{banner}")},
+        C!["material-symbols-outlined", "filled"],
+        match banner {
+            Banner::Axe => "carpenter",
+            Banner::Hammer => "hardware",
+            Banner::Crown => "crown",
+            Banner::Dragon => "pets",
+            Banner::Mountain => "landscape",
+            Banner::Tree => "park",
+            Banner::Fire => "local_fire_department",
+            Banner::Diamond => "diamond",
+        }
+    ]
 }
 
 fn ranking(
@@ -1229,8 +1422,8 @@ fn ranking(
 
         h2!["Ranking"],
         match state.settings.game_mode {
-            GameMode::Ranked => p![format!("To win this game, you need to meet two conditions. First, expand your settlement until you reach level 100. Second, become the king of this world. If both conditions are met, the game will be over and you will be the winner. As a reward, you get gifted a free premium account for {} days.", WINNER_NUM_PREMIUM_DAYS)],
-            GameMode::Speed => p![format!("To win this game, you need to meet two conditions. First, expand your settlement until you reach level 100. Second, become the king of this world. If both conditions are met, the game will be over and you will be the winner.")],
+            GameMode::Ranked => p![format!("To win this game, you need to meet two conditions. First, expand your settlement until you reach level {}. Second, become the king of this world. If both conditions are met, the game will be over and you will be the winner. As a reward, you get gifted a free premium account for {} days.", state.settings.win_level, WINNER_NUM_PREMIUM_DAYS)],
+            GameMode::Speed => p![format!("To win this game, you need to meet two conditions. First, expand your settlement until you reach level {}. Second, become the king of this world. If both conditions are met, the game will be over and you will be the winner.", state.settings.win_level)],
             GameMode::Infinite => p![format!("You are playing in the infinite world. There is no limit on the maximum level.")],
         },
         
@@ -1256,6 +1449,7 @@ fn ranking(
                         ],
                         if let Some(tribe) = player.tribe.as_ref() {
                             td![tribe_name(
+                                state,
                                 *tribe,
                                 model.game_id
                             )]
@@ -1379,6 +1573,27 @@ fn last_received_items(
     }
 }
 
+fn action_error(
+    model: &Model,
+    state: &shared::State,
+    user_id: &shared::UserId,
+) -> Node<Msg> {
+    if let Some((error, time)) = state.players.get(user_id).and_then(|player| player.last_error) {
+        if model.get_timestamp_millis_diff_now(time) > 3000 {
+            Node::Empty
+        } else {
+            div![
+                id!["action-error-toast"],
+                attrs! {At::Role => "alert"},
+                Icon::Error.draw(),
+                span![error.to_string()],
+            ]
+        }
+    } else {
+        Node::Empty
+    }
+}
+
 fn health_bar(curr: Health, max: Health) -> Node<Msg> {
     div![
         attrs! {At::Role => "progressbar", At::AriaValueMin => 0, At::AriaValueMax => max, At::AriaValueNow => curr, At::AriaLabel => "Health"},
@@ -1396,6 +1611,23 @@ fn health_bar(curr: Health, max: Health) -> Node<Msg> {
     ]
 }
 
+fn craft_bar(curr: Time, max: Time) -> Node<Msg> {
+    div![
+        attrs! {At::Role => "progressbar", At::AriaValueMin => 0, At::AriaValueMax => max, At::AriaValueNow => curr, At::AriaLabel => "Crafting"},
+        C!["health-bar-wrapper"],
+        div![
+            C!["craft-bar-curr"],
+            attrs! {
+                At::Style => format!("width: calc(100% / {max} * {curr});")
+            }
+        ],
+        div![
+            C!["health-bar-overlay"],
+            format!("{}%", (100 * curr / max).min(100))
+        ],
+    ]
+}
+
 fn score_bar(curr: u64, max: u64, rank: usize, max_rank: usize, markers: Vec<u64>) -> Node<Msg> {
     div![
         attrs! {At::Role => "progressbar", At::AriaValueMin => 0, At::AriaValueMax => max, At::AriaValueNow => curr, At::AriaLabel => "Score"},
@@ -1638,6 +1870,11 @@ fn dwarf_details(dwarf: Option<&Dwarf>, player: &Player, visit_mode: bool) -> Ve
                     vec![
                         br![],
                         dwarf_occupation(dwarf, player),
+                        if dwarf.occupation != Occupation::Idling {
+                            span![C!["short-info"], format!("{}", dwarf.mastery(dwarf.occupation))]
+                        } else {
+                            Node::Empty
+                        },
                         health_bar(dwarf.health, MAX_HEALTH),
                     ]
                 }
@@ -1941,6 +2178,105 @@ fn dwarfs(
     }
 }
 
+fn family_tree(model: &Model, player: &Player, dwarf_id: DwarfId, dwarf: &Dwarf) -> Node<Msg> {
+    let dwarf_link = |id: Option<DwarfId>| -> Node<Msg> {
+        match id.and_then(|id| player.dwarfs.get(&id).map(|relative| (id, relative))) {
+            Some((id, relative)) => a![
+                attrs! { At::Href => format!("{}/dwarfs/{}", model.base_path(), id) },
+                relative.actual_name()
+            ],
+            None if id.is_some() => span!["Deceased"],
+            None => span!["Unknown"],
+        }
+    };
+    let (father, mother) = dwarf.parents.unzip();
+    let grandparents = |parent: Option<DwarfId>| {
+        parent
+            .and_then(|parent| player.dwarfs.get(&parent))
+            .and_then(|parent| parent.parents)
+            .unzip()
+    };
+    let (fathers_father, fathers_mother) = grandparents(father);
+    let (mothers_father, mothers_mother) = grandparents(mother);
+    let siblings = player
+        .dwarfs
+        .iter()
+        .filter(|(id, other)| **id != dwarf_id && dwarf.parents.is_some() && other.parents == dwarf.parents)
+        .map(|(id, _)| dwarf_link(Some(*id)))
+        .collect::<Vec<_>>();
+    let children = player
+        .dwarfs
+        .iter()
+        .filter(|(_, other)| other.parents.map(|(f, m)| f == dwarf_id || m == dwarf_id).unwrap_or(false))
+        .map(|(id, _)| dwarf_link(Some(*id)))
+        .collect::<Vec<_>>();
+
+    let partners = player
+        .dwarfs
+        .iter()
+        .filter(|(_, other)| other.is_female != dwarf.is_female && other.is_adult())
+        .map(|(id, other)| (*id, other.actual_name().to_owned()))
+        .collect::<Vec<_>>();
+    let partner_ids = partners.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    let is_female = dwarf.is_female;
+    let pair = move |partner_id: DwarfId| {
+        if is_female {
+            (partner_id, dwarf_id)
+        } else {
+            (dwarf_id, partner_id)
+        }
+    };
+    let current_partner = player.pairing.and_then(|(f, m)| {
+        if f == dwarf_id {
+            Some(m)
+        } else if m == dwarf_id {
+            Some(f)
+        } else {
+            None
+        }
+    });
+
+    div![
+        h3!["Family"],
+        table![tbody![
+            tr![th!["Parents"], td![dwarf_link(father), " and ", dwarf_link(mother)]],
+            if father.is_some() {
+                tr![th!["Grandparents"], td![
+                    dwarf_link(fathers_father), ", ", dwarf_link(fathers_mother), ", ",
+                    dwarf_link(mothers_father), " and ", dwarf_link(mothers_mother)
+                ]]
+            } else {
+                Node::Empty
+            },
+            tr![th!["Siblings"], td![if siblings.is_empty() { vec![span!["None"]] } else { itertools::Itertools::intersperse(siblings.into_iter(), span![", "]).collect() }]],
+            tr![th!["Children"], td![if children.is_empty() { vec![span!["None"]] } else { itertools::Itertools::intersperse(children.into_iter(), span![", "]).collect() }]],
+        ]],
+        if dwarf.is_adult() {
+            div![
+                p!["Babies inherit each stat from one of their parents, with a small chance of mutation. Choose a partner so that the next baby of your settlement comes from this pair while both are idling."],
+                div![C!["button-row"],
+                    select![
+                        option![attrs! { At::Value => "", At::Selected => current_partner.is_none().as_at_value() }, "No Partner"],
+                        partners.iter().enumerate().map(|(idx, (id, name))| {
+                            option![
+                                attrs! { At::Value => format!("{}", idx), At::Selected => (current_partner == Some(*id)).as_at_value() },
+                                name.as_str()
+                            ]
+                        }),
+                        input_ev(Ev::Change, move |str| {
+                            Msg::send_event(ClientEvent::PairDwarfs(
+                                str.parse::<usize>().ok().and_then(|idx| partner_ids.get(idx).copied()).map(pair)
+                            ))
+                        })
+                    ],
+                ]
+            ]
+        } else {
+            Node::Empty
+        }
+    ]
+}
+
 fn dwarf(
     model: &Model,
     state: &shared::State,
@@ -2049,8 +2385,48 @@ fn dwarf(
                                 ],
                             ]]
                         ],
+                        div![
+                            h3!["Traits"],
+                            if dwarf.traits.is_empty() {
+                                p!["This dwarf has no special traits."]
+                            } else {
+                                ul![dwarf.traits.iter().map(|t| li![strong![format!("{}: ", t)], t.description()])]
+                            }
+                        ],
+                        div![
+                            h3!["Mastery"],
+                            table![
+                                tr![th!["Occupation"], th!["Mastery"], th!["Experience"], th!["Bonus"]],
+                                enum_iterator::all::<Occupation>()
+                                    .filter(|occupation| dwarf.experience(*occupation) > 0)
+                                    .map(|occupation| {
+                                        let mastery = dwarf.mastery(occupation);
+                                        tr![
+                                            td![format!("{occupation}")],
+                                            td![format!("{mastery}")],
+                                            td![
+                                                if let Some(next) = enum_iterator::next(&mastery) {
+                                                    format!(
+                                                        "{} / {}",
+                                                        fmt_time(dwarf.experience(occupation), false),
+                                                        fmt_time(next.required_experience(), false)
+                                                    )
+                                                } else {
+                                                    fmt_time(dwarf.experience(occupation), false)
+                                                }
+                                            ],
+                                            td![format!("+{}%", mastery.effectiveness_percent() - 100)],
+                                        ]
+                                    })
+                            ],
+                        ],
+                        div![
+                            h3!["Expected Production"],
+                            drops_per_hour(player.dwarf_drops_per_hour(dwarf_id, state).into_iter().collect()),
+                        ],
                     ]
                 ],
+                family_tree(model, player, dwarf_id, dwarf),
                 div![
                     h3!["Equipment"],
                     if let Some(consumable) = dwarf.equipment.get(&ItemType::Consumable) {
@@ -2224,7 +2600,9 @@ fn dwarf(
                                             h4![C!["title"], "Requires"],
                                             p![C!["subtitle"],stats_simple(&occupation.requires_stats())],
                                             h4![C!["title"], "Provides"],
-                                            p![C!["subtitle"], if all_items.is_empty() {
+                                            p![C!["subtitle"], if occupation == Occupation::Crafting {
+                                                "Speeds up the crafting queue.".to_owned()
+                                            } else if all_items.is_empty() {
                                                 "Lets your dwarf eat and restore health. Adds a possibility for child dwarfs for each male and female dwarf that is idling.".to_owned()
                                             } else {
                                                 all_items.into_iter().join(", ")
@@ -2828,6 +3206,7 @@ fn base(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<
                 tr![th!["Money"], td![format!("{} coins", player.money)]],
                 tr![th!["Food"], td![format!("{} food", player.base.food)]],
             ],
+            forecast(state, player),
             h3!["Upgrade Settlement"],
             div![
                 C!["image-aside"],
@@ -2878,6 +3257,52 @@ fn base(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<
                     Node::Empty
                 },
             ],
+            h3!["Buildings"],
+            p![format!(
+                "Every {} settlement levels you can add another building level. Choose wisely which buildings fit your settlement best, you have {} of {} building levels left.",
+                LEVELS_PER_BUILDING_SLOT,
+                player.base.free_building_slots(),
+                player.base.building_slots()
+            )],
+            if let Some((building, time_left)) = player.base.construction {
+                p![format!("Your dwarfs are constructing the {} ({} remaining).", building, fmt_time(time_left, true))]
+            } else {
+                Node::Empty
+            },
+            table![
+                tr![th!["Building"], th!["Level"], th!["Effect"], th!["Requires"], th![]],
+                enum_iterator::all::<Building>()
+                    .filter(|building| *building != Building::Headquarters)
+                    .map(|building| {
+                        let requires = player.base.building_upgrade_cost(building, &state.settings);
+                        tr![
+                            td![format!("{}", building)],
+                            td![format!("{}", player.base.building_level(building))],
+                            td![building.description()],
+                            td![if let Some(requires) = &requires {
+                                bundle(requires, player, true)
+                            } else {
+                                Node::Empty
+                            }],
+                            td![if let Some(requires) = requires {
+                                button![
+                                    if player.base.construction.is_none()
+                                        && player.base.free_building_slots() > 0
+                                        && player.inventory.items.check_remove(&requires)
+                                    {
+                                        attrs! {}
+                                    } else {
+                                        attrs! {At::Disabled => "true"}
+                                    },
+                                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::UpgradeBuilding(building))),
+                                    format!("Build ({})", fmt_time(player.base.building_time_ticks(building), true)),
+                                ]
+                            } else {
+                                span!["Maximum level reached"]
+                            }],
+                        ]
+                    }),
+            ],
             /*
             div![
                 h3!["Open Loot Crate"],
@@ -2919,6 +3344,85 @@ fn base(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<
     }
 }
 
+fn fmt_per_hour(qty: f64) -> String {
+    if qty >= 10.0 {
+        format!("{:.0}/h", qty)
+    } else {
+        format!("{:.2}/h", qty)
+    }
+}
+
+fn drops_per_hour(mut drops: Vec<(Item, f64)>) -> Node<Msg> {
+    drops.retain(|(_, qty)| *qty > 0.0);
+    drops.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    if drops.is_empty() {
+        p!["Nothing is produced in the current occupation."]
+    } else {
+        table![
+            tr![th!["Item"], th!["Expected"]],
+            drops.into_iter().map(|(item, qty)| tr![td![format!("{item}")], td![fmt_per_hour(qty)]])
+        ]
+    }
+}
+
+fn forecast(state: &shared::State, player: &Player) -> Node<Msg> {
+    let forecast = player.forecast(state);
+    let balance = forecast.food_balance();
+
+    div![
+        h3!["Production Forecast"],
+        p!["This is what your dwarfs are expected to produce on average with their current occupations, equipment and the current world event. Actual drops vary from hour to hour."],
+        table![
+            tr![th!["Food Income"], td![fmt_per_hour(forecast.food_income)]],
+            tr![th!["Food Burn"], td![fmt_per_hour(forecast.food_burn)]],
+            tr![
+                th!["Food Balance"],
+                td![
+                    if balance < 0.0 {
+                        format!("-{}", fmt_per_hour(-balance))
+                    } else {
+                        format!("+{}", fmt_per_hour(balance))
+                    }
+                ]
+            ],
+        ],
+        if balance < 0.0 {
+            p![format!(
+                "Put the produced food into the food storage to make it count. Without any food income, your food storage lasts for {}.",
+                fmt_time(player.remaining_time_until_starvation(state), true)
+            )]
+        } else {
+            Node::Empty
+        },
+        h4!["By Occupation"],
+        table![
+            tr![th!["Occupation"], th!["Dwarfs"], th!["Expected Items"]],
+            enum_iterator::all::<Occupation>()
+                .filter(|occupation| !matches!(occupation, Occupation::Idling | Occupation::Crafting))
+                .filter_map(|occupation| {
+                    let dwarfs = player.dwarfs.values().filter(|dwarf| dwarf.actual_occupation() == occupation).count();
+                    if dwarfs == 0 {
+                        return None;
+                    }
+                    let mut drops = player.occupation_drops_per_hour(occupation, state).into_iter().collect::<Vec<_>>();
+                    drops.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+                    Some(tr![
+                        td![format!("{occupation}")],
+                        td![format!("{dwarfs}")],
+                        td![drops
+                            .into_iter()
+                            .filter(|(_, qty)| *qty > 0.0)
+                            .map(|(item, qty)| format!("{item} {}", fmt_per_hour(qty)))
+                            .collect::<Vec<_>>()
+                            .join(", ")],
+                    ])
+                })
+        ],
+        h4!["By Item"],
+        drops_per_hour(forecast.items),
+    ]
+}
+
 fn manager(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<Msg> {
     if let Some(player) = state.players.get(user_id) {
         let is_premium = model
@@ -3065,6 +3569,14 @@ fn manager(model: &Model, state: &shared::State, user_id: &shared::UserId) -> No
                 },
             ],
 
+            manager_presets(model, player, is_premium),
+
+            div![
+                h3!["Automation"],
+                p!["Automation rules take care of crafting, storing food, dismantling, bidding and switching presets for you whenever their conditions are met."],
+                a![C!["button"], attrs! { At::Href => format!("{}/automation", model.base_path()) }, "Edit Automation Rules"],
+            ],
+
             div![
                 h3!["Hire Dwarf"],
                 p![
@@ -3156,7 +3668,7 @@ fn inventory_options(
                     true,
                 ),
                 if player.base.curr_level >= level {
-                    if player.auto_functions.auto_craft.contains(&item) && is_premium {
+                    if player.toggled(&Action::auto_craft(item)) && is_premium {
                         button![
                             ev(Ev::Click, move |_| Msg::send_event(
                                 ClientEvent::ToggleAutoCraft(item)
@@ -3217,7 +3729,7 @@ fn inventory_options(
             if matches!(
                 item.item_type(),
                 Some(ItemType::Tool | ItemType::Jewelry | ItemType::Clothing)
-            ) && (max > 0 || player.auto_functions.auto_dismantle.contains(&item))
+            ) && (max > 0 || player.toggled(&Action::auto_dismantle(item)))
             {
                 vec![
                     h4!["Dismantle Item"],
@@ -3236,7 +3748,7 @@ fn inventory_options(
                         player,
                         false,
                     ),
-                    if player.auto_functions.auto_dismantle.contains(&item) && is_premium {
+                    if player.toggled(&Action::auto_dismantle(item)) && is_premium {
                         button![
                             ev(Ev::Click, move |_| Msg::send_event(
                                 ClientEvent::ToggleAutoDismantle(item)
@@ -3279,7 +3791,7 @@ fn inventory_options(
         if item.nutritional_value().is_some() {
             vec![
                 h4!["Food Storage"],
-                if player.auto_functions.auto_store.contains(&item) && is_premium {
+                if player.toggled(&Action::auto_store(item)) && is_premium {
                     button![
                         ev(Ev::Click, move |_| Msg::send_event(
                             ClientEvent::ToggleAutoStore(item)
@@ -3465,10 +3977,10 @@ fn inventory(
                             && !model.inventory_filter.craftable))
                         && (
                             if model.inventory_filter.auto {
-                                player.auto_functions.auto_craft.contains(item)
+                                player.toggled(&Action::auto_craft(*item))
                                 || player.auto_functions.auto_sell.contains(item)
-                                || player.auto_functions.auto_store.contains(item)
-                                || player.auto_functions.auto_dismantle.contains(item)
+                                || player.toggled(&Action::auto_store(*item))
+                                || player.toggled(&Action::auto_dismantle(*item))
                             } else {
                                 true
                             }
@@ -3524,82 +4036,329 @@ fn inventory(
                         ]
                     }
                 ]})
-            ]
+            ],
+            if let InventoryMode::Overview = mode {
+                vec![crafting_queue(player), crafting_plan(model, player, is_premium)]
+            } else {
+                Vec::new()
+            },
         ]
     } else {
         Node::Empty
     }
 }
 
-fn trades(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<Msg> {
-    if let Some(player) = state.players.get(user_id) {
-        let mut trades = state.trade_deals.iter().map(|(trade_id, trade)| (*trade_id, trade)).collect::<Vec<_>>();
-        let is_premium = model
-            .state
-            .get_user_data(user_id)
-            .map(|user_data| user_data.premium > 0)
-            .unwrap_or(false);
+fn recipe_tree(tree: &RecipeTree, player: &Player) -> Node<Msg> {
+    let item = tree.item;
+    let stock = player.inventory.items.get(&item).copied().unwrap_or_default();
+    li![
+        span![
+            C!["clickable-item"],
+            if stock >= tree.qty { C![] } else { C!["unavailable"] },
+            format!("{}x {}", big_number(tree.qty), item),
+            ev(Ev::Click, move |_| Msg::SetRecipeItem(Some(item)))
+        ],
+        span![format!(" ({})", big_number(stock))],
+        if tree.ingredients.is_empty() {
+            Node::Empty
+        } else {
+            ul![tree.ingredients.iter().map(|ingredient| recipe_tree(ingredient, player))]
+        }
+    ]
+}
 
-        trades.sort_by_key(|(_, trade_deal)| trade_deal.time_left);
+fn recipes(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<Msg> {
+    if let Some(player) = state.players.get(user_id) {
+        let craftable = enum_iterator::all::<Item>()
+            .filter_map(|item| {
+                let (level, requires) = item.requires()?;
+                if level > player.base.curr_level {
+                    return None;
+                }
+                let now = player.inventory.items.can_remove_x_times(&requires).unwrap_or_default();
+                let from_raw = player
+                    .inventory
+                    .items
+                    .can_remove_x_times(&item.raw_materials())
+                    .unwrap_or_default();
+                if now > 0 || from_raw > 0 {
+                    Some((item, now, from_raw))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
 
-        div![
-            div![
-                C!["filter"],
-                div![
-                    C!["no-shrink"],
-                    div![
-                        input![
-                            id!["can-afford"],
-                            attrs! {At::Type => "checkbox", At::Checked => model.trade_filter.can_afford.as_at_value()},
-                            ev(Ev::Click, |_| Msg::TradeFilterCanAfford),
-                        ],
-                        label![attrs! {At::For => "can-afford"}, "Can Afford"]
-                    ],
-                    div![
-                        input![
-                            id!["my-trades"],
-                            attrs! {At::Type => "checkbox", At::Checked => model.trade_filter.my_bids.as_at_value()},
-                            ev(Ev::Click, |_| Msg::TradeFilterMyBids),
-                        ],
-                        label![attrs! {At::For => "my-trades"}, "My Trades"]
-                    ],
-                ],
+        div![C!["content"],
+            h2!["Recipe Explorer"],
+            p!["Browse the recipes of all items, what they are made of in the end and what they can be used for. Click on an item to explore it."],
+            item_select(enum_iterator::all::<Item>().collect(), model.recipe_item, Msg::SetRecipeItem),
+            if let Some(item) = model.recipe_item {
+                let raw_materials = item.raw_materials();
+                let used_in = item.used_in();
                 div![
-                    C!["no-shrink"],
-                    enum_iterator::all::<ItemType>()
-                        .map(|item_type| {
-                            div![
-                                input![
-                                    id![format!("{}", item_type)],
-                                    attrs! {At::Type => "checkbox", At::Checked => model.trade_filter.by_type.get(&item_type).copied().unwrap_or(false).as_at_value()},
-                                    ev(Ev::Click, move |_| Msg::TradeFilterByType(item_type)),
-                                ],
-                                label![attrs! {At::For => format!("{}", item_type)}, format!("{}", item_type)]
+                    h3![format!("{item}")],
+                    if let Some((level, _requires)) = item.requires() {
+                        vec![
+                            p![format!("Unlocked at level {}, takes {} to craft without help.", level, fmt_time(item.crafting_time(), false))],
+                            h4!["Recipe"],
+                            ul![recipe_tree(&item.recipe_tree(1), player)],
+                            h4!["Raw Materials"],
+                            bundle(&raw_materials, player, true),
+                            p![format!(
+                                "You have the raw materials for {} of this item.",
+                                big_number(player.inventory.items.can_remove_x_times(&raw_materials).unwrap_or_default())
+                            )],
+                        ]
+                    } else {
+                        vec![p!["This item cannot be crafted, your dwarfs have to find it."]]
+                    },
+                    h4!["Used In"],
+                    if used_in.is_empty() {
+                        p!["This item is not used in any recipe."]
+                    } else {
+                        ul![used_in.into_iter().map(|used_in| {
+                            li![
+                                C!["clickable-item"],
+                                span![
+                                    format!("{used_in}"),
+                                    ev(Ev::Click, move |_| Msg::SetRecipeItem(Some(used_in)))
+                                ]
                             ]
-                        })
-                ],
-                div![
-                    button![
-                        ev(Ev::Click, move |_| Msg::TradeFilterReset),
-                        "Reset Filter",
-                    ],
+                        })]
+                    },
                 ]
-            ],
-            table![
-                C!["items", "list"],
-                trades 
-                .into_iter()
-                .filter(|(_, trade_deal)| {
-                        trade_deal.user_trade_type == TradeType::Buy &&
-                        ((if model.trade_filter.can_afford {
-                            player.money >= trade_deal.next_bid
-                        } else {
-                            false
-                        }) || (if model.trade_filter.my_bids {
-                            if let Some((highest_bidder_user_id, _)) = trade_deal.highest_bidder {
-                                highest_bidder_user_id == *user_id
-                            } else {
-                                false
+            } else {
+                Node::Empty
+            },
+            h3!["What Can I Craft?"],
+            p!["The items you can craft right now with your inventory, and how many you could craft if your dwarfs also crafted all the intermediate items from raw materials."],
+            if craftable.is_empty() {
+                p!["You cannot craft anything with your current inventory."]
+            } else {
+                table![
+                    tr![th!["Item"], th!["Right Now"], th!["From Raw Materials"]],
+                    craftable.into_iter().map(|(item, now, from_raw)| {
+                        tr![
+                            td![
+                                C!["clickable-item"],
+                                span![format!("{item}"), ev(Ev::Click, move |_| Msg::SetRecipeItem(Some(item)))]
+                            ],
+                            td![big_number(now)],
+                            td![big_number(from_raw)],
+                        ]
+                    })
+                ]
+            },
+        ]
+    } else {
+        Node::Empty
+    }
+}
+
+fn crafting_queue(player: &Player) -> Node<Msg> {
+    let speed = player.crafting_speed();
+
+    div![
+        C!["content"],
+        h2!["Crafting Queue"],
+        p![format!(
+            "Your dwarfs craft one item after the other and take the ingredients when they start with an item. More complex items take longer. Dwarfs with the crafting occupation and a larger workshop speed up the crafting. Current crafting speed: {}%.",
+            speed
+        )],
+        if player.craft_queue.is_empty() {
+            p!["Nothing is being crafted right now."]
+        } else {
+            table![
+                C!["list"],
+                player.craft_queue.iter().enumerate().map(|(idx, order)| {
+                    let crafting_time = order.item.crafting_time();
+                    let time_left = (crafting_time * order.qty - order.progress) * 100 / speed.max(1);
+                    tr![
+                        C!["list-item-row"],
+                        td![img![
+                            C!["list-item-image"],
+                            attrs! {At::Src => Image::from(order.item).as_at_value()}
+                        ]],
+                        td![
+                            C!["list-item-content"],
+                            h3![C!["title"], format!("{} {}", big_number(order.qty), order.item)],
+                            p![
+                                C!["subtitle"],
+                                if order.auto { "Auto-crafting, " } else { "" },
+                                format!("done in {}", fmt_time(time_left, false))
+                            ],
+                            if idx == 0 {
+                                craft_bar(order.progress, crafting_time)
+                            } else {
+                                Node::Empty
+                            },
+                            button![
+                                ev(Ev::Click, move |_| Msg::send_event(ClientEvent::CancelCraft(idx))),
+                                "Cancel"
+                            ],
+                        ],
+                    ]
+                })
+            ]
+        },
+    ]
+}
+
+fn crafting_plan(model: &Model, player: &Player, is_premium: bool) -> Node<Msg> {
+    let form = model.crafting;
+    let craftable_items = enum_iterator::all::<Item>()
+        .filter(|item| item.requires().is_some())
+        .collect::<Vec<_>>();
+    let items = enum_iterator::all::<Item>().collect::<Vec<_>>();
+    let plan = player.plan_crafts();
+
+    div![
+        C!["content"],
+        h2!["Crafting Targets"],
+        p![a![C!["button"], attrs! { At::Href => format!("{}/recipes", model.base_path()) }, "Open Recipe Explorer"]],
+        p!["Auto-crafting keeps the items with a target at that stock and crafts the required intermediate items on the way. Targets with a higher priority get the ingredients first. Reserved items are never used for auto-crafting."],
+        if !is_premium {
+            p![C!["important"], "Auto-crafting is a premium feature."]
+        } else {
+            Node::Empty
+        },
+        table![
+            tr![th!["Item"], th!["Stock"], th!["Target"], th!["Priority"], th![]],
+            player.craft_targets().into_iter().map(|(item, target)| {
+                tr![
+                    td![format!("{item}")],
+                    td![big_number(player.inventory.items.get(&item).copied().unwrap_or_default())],
+                    td![big_number(target.target)],
+                    td![format!("{}", target.priority)],
+                    td![
+                        button![
+                            ev(Ev::Click, move |_| Msg::send_event(ClientEvent::SetCraftTarget(item, None))),
+                            "Remove"
+                        ]
+                    ],
+                ]
+            })
+        ],
+        div![C!["button-row"],
+            item_select(craftable_items, form.item, move |item| Msg::SetCraftingForm(CraftingForm { item, ..form })),
+            number_input(form.target, "Target", move |target| Msg::SetCraftingForm(CraftingForm { target, ..form })),
+            number_input(form.priority, "Priority", move |priority| Msg::SetCraftingForm(CraftingForm { priority, ..form })),
+            if let Some(item) = form.item {
+                button![
+                    attrs! { At::Disabled => (!is_premium || form.target == 0).as_at_value() },
+                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::SetCraftTarget(item, Some(CraftTarget { target: form.target, priority: form.priority })))),
+                    "Set Target"
+                ]
+            } else {
+                button![attrs! { At::Disabled => true.as_at_value() }, "Set Target"]
+            },
+        ],
+        h3!["Reserves"],
+        if player.auto_functions.reserves.is_empty() {
+            p!["You have not reserved any items."]
+        } else {
+            ul![player.auto_functions.reserves.iter().map(|(item, qty)| {
+                let item = *item;
+                li![
+                    format!("{} {} ", big_number(*qty), item),
+                    button![
+                        ev(Ev::Click, move |_| Msg::send_event(ClientEvent::SetReserve(item, 0))),
+                        "Remove"
+                    ]
+                ]
+            })]
+        },
+        div![C!["button-row"],
+            item_select(items, form.reserve_item, move |reserve_item| Msg::SetCraftingForm(CraftingForm { reserve_item, ..form })),
+            number_input(form.reserve, "Quantity", move |reserve| Msg::SetCraftingForm(CraftingForm { reserve, ..form })),
+            if let Some(item) = form.reserve_item {
+                button![
+                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::SetReserve(item, form.reserve))),
+                    "Set Reserve"
+                ]
+            } else {
+                button![attrs! { At::Disabled => true.as_at_value() }, "Set Reserve"]
+            },
+        ],
+        h3!["Planned Crafts"],
+        if plan.is_empty() {
+            p!["Nothing can be crafted right now."]
+        } else {
+            ol![plan.into_iter().flatten().map(|(item, qty)| li![format!("{} {}", big_number(qty), item)])]
+        },
+    ]
+}
+
+fn trades(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<Msg> {
+    if let Some(player) = state.players.get(user_id) {
+        let mut trades = state.trade_deals.iter().map(|(trade_id, trade)| (*trade_id, trade)).collect::<Vec<_>>();
+        let is_premium = model
+            .state
+            .get_user_data(user_id)
+            .map(|user_data| user_data.premium > 0)
+            .unwrap_or(false);
+
+        trades.sort_by_key(|(_, trade_deal)| trade_deal.time_left);
+
+        div![
+            div![
+                C!["filter"],
+                div![
+                    C!["no-shrink"],
+                    div![
+                        input![
+                            id!["can-afford"],
+                            attrs! {At::Type => "checkbox", At::Checked => model.trade_filter.can_afford.as_at_value()},
+                            ev(Ev::Click, |_| Msg::TradeFilterCanAfford),
+                        ],
+                        label![attrs! {At::For => "can-afford"}, "Can Afford"]
+                    ],
+                    div![
+                        input![
+                            id!["my-trades"],
+                            attrs! {At::Type => "checkbox", At::Checked => model.trade_filter.my_bids.as_at_value()},
+                            ev(Ev::Click, |_| Msg::TradeFilterMyBids),
+                        ],
+                        label![attrs! {At::For => "my-trades"}, "My Trades"]
+                    ],
+                ],
+                div![
+                    C!["no-shrink"],
+                    enum_iterator::all::<ItemType>()
+                        .map(|item_type| {
+                            div![
+                                input![
+                                    id![format!("{}", item_type)],
+                                    attrs! {At::Type => "checkbox", At::Checked => model.trade_filter.by_type.get(&item_type).copied().unwrap_or(false).as_at_value()},
+                                    ev(Ev::Click, move |_| Msg::TradeFilterByType(item_type)),
+                                ],
+                                label![attrs! {At::For => format!("{}", item_type)}, format!("{}", item_type)]
+                            ]
+                        })
+                ],
+                div![
+                    button![
+                        ev(Ev::Click, move |_| Msg::TradeFilterReset),
+                        "Reset Filter",
+                    ],
+                ]
+            ],
+            table![
+                C!["items", "list"],
+                trades 
+                .into_iter()
+                .filter(|(_, trade_deal)| {
+                        trade_deal.user_trade_type == TradeType::Buy &&
+                        ((if model.trade_filter.can_afford {
+                            player.money >= trade_deal.next_bid
+                        } else {
+                            false
+                        }) || (if model.trade_filter.my_bids {
+                            if let Some((highest_bidder_user_id, _)) = trade_deal.highest_bidder {
+                                highest_bidder_user_id == *user_id
+                            } else {
+                                false
                             }
                         } else {
                             false
@@ -3621,78 +4380,714 @@ fn trades(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Nod
                         highest_bidder_user_id == *user_id
                     } else {
                         false
-                    };
-                    let can_afford = player.money >= trade_deal.next_bid;
-                    let max_bid = model.bid_max.get(&trade_id).copied().unwrap_or_default();
+                    };
+                    let can_afford = player.money >= trade_deal.next_bid;
+                    let max_bid = model.bid_max.get(&trade_id).copied().unwrap_or_default();
+
+                    tr![
+
+                    C!["item"],
+                    C!["list-item-row"],
+                    match item.item_rarity() {
+                        ItemRarity::Common => C!["item-common"],
+                        ItemRarity::Uncommon => C!["item-uncommon"],
+                        ItemRarity::Rare => C!["item-rare"],
+                        ItemRarity::Epic => C!["item-epic"],
+                        ItemRarity::Legendary => C!["item-legendary"],
+                    },
+                    item_details(item, n),
+                    td![
+                        C!["list-item-content"],
+                        h4![C!["title"], "Cost" ],
+                        p![C!["subtitle"], format!("{} coins", trade_deal.next_bid)],
+                        p![format!("Deal ends in {}.", fmt_time(trade_deal.time_left, true))],
+                        if trade_deal.creator == Some(*user_id) {
+                            vec![p![format!("You created this deal.")]]
+                        } else {
+                            vec![
+                                if !can_afford && !highest_bidder_is_you {
+                                    p![format!("You can't afford this deal.")]
+                                } else {
+                                    Node::Empty
+                                },
+                                if let Some((highest_bidder_user_id, highest_bidder_money)) = trade_deal.highest_bidder {
+                                    if highest_bidder_user_id == *user_id {
+                                        p![format!("You are the highest bidder with {} coins.", highest_bidder_money)]
+                                    } else {
+                                        p![format!("Highest bidder has offered {} coins.", highest_bidder_money)]
+                                    }
+                                } else {
+                                    Node::Empty
+                                },                               
+                                button![
+                                    attrs! { At::Disabled => (highest_bidder_is_you || !can_afford).as_at_value() },
+                                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::Bid(trade_id))),
+                                    format!("Bid {} coins", trade_deal.next_bid)
+                                ],
+                                h4![C!["title"], "Auto Bidding"],
+                                div![C!["button-row"],
+                                    input![
+                                        attrs! { At::Type => "number", At::Min => "0", At::Value => format!("{}",max_bid) },
+                                        input_ev(Ev::Input, move |str| Msg::SetBidMax(trade_id, str.parse().unwrap_or(0)))
+                                    ],
+                                    if is_premium {
+                                        button![
+                                            attrs! { At::Disabled => (player.auto_bid(trade_id).unwrap_or_default() == model.bid_max.get(&trade_id).copied().unwrap_or_default()).as_at_value() },
+                                            ev(Ev::Click, move |_| Msg::send_event(ClientEvent::AutoBid(trade_id, max_bid))),
+                                            format!("Set Auto Bid")
+                                        ]
+                                    } else {
+                                        a![
+                                            C!["premium-feature", "button"],
+                                            format!("Set Auto Bid"),
+                                            attrs! { At::Href => format!("/store") },
+                                        ]
+                                    },
+                                    
+                                ]
+                            ]
+                            
+                        },
+ 
+                    ]
+                ]
+            }),
+            ],
+            buy_orders(model, state, user_id),
+            direct_offers(model, state, user_id),
+        ]
+    } else {
+        Node::Empty
+    }
+}
+
+fn manager_presets(model: &Model, player: &Player, is_premium: bool) -> Node<Msg> {
+    let form = model.preset.clone();
+
+    // One template per kind of trigger, the numbers are edited below the select.
+    let triggers = std::iter::once(PresetTrigger::FoodBelow(100))
+        .chain(enum_iterator::all::<WorldEvent>().map(PresetTrigger::WorldEvent))
+        .chain(std::iter::once(PresetTrigger::Hours(0, 6)))
+        .collect::<Vec<_>>();
+    let trigger_options = triggers
+        .iter()
+        .enumerate()
+        .map(|(idx, trigger)| {
+            let selected = match (form.trigger, trigger) {
+                (Some(PresetTrigger::FoodBelow(_)), PresetTrigger::FoodBelow(_)) => true,
+                (Some(PresetTrigger::Hours(..)), PresetTrigger::Hours(..)) => true,
+                (Some(current), trigger) => current == *trigger,
+                (None, _) => false,
+            };
+            option![
+                attrs! { At::Value => format!("{}", idx), At::Selected => selected.as_at_value() },
+                match trigger {
+                    PresetTrigger::FoodBelow(_) => "While food is low".to_owned(),
+                    PresetTrigger::Hours(..) => "At certain hours".to_owned(),
+                    trigger => format!("{trigger}"),
+                }
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    div![
+        h3!["Manager Presets"],
+        p!["Save the current numbers of dwarfs to assign as a preset to switch back to it later. Premium players can also let a preset switch on automatically while food is low, during a world event or at certain hours of the day. Once the condition is over, the manager goes back to the previous numbers."],
+        if player.manager_presets.is_empty() {
+            p!["You have not saved any presets yet."]
+        } else {
+            table![
+                tr![th!["Name"], th!["Dwarfs to Assign"], th!["Trigger"], th![]],
+                player.manager_presets.iter().map(|preset| {
+                    let apply_name = preset.name.clone();
+                    let delete_name = preset.name.clone();
+                    tr![
+                        td![
+                            preset.name.as_str(),
+                            if player.triggered_preset.as_ref().map(|triggered| triggered.name == preset.name).unwrap_or(false) {
+                                span![C!["short-info"], "Active"]
+                            } else {
+                                Node::Empty
+                            }
+                        ],
+                        td![
+                            preset
+                                .manager
+                                .iter()
+                                .filter(|(occupation, num)| **occupation != Occupation::Idling && **num > 0)
+                                .map(|(occupation, num)| format!("{num} {occupation}"))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ],
+                        td![preset.trigger.map(|trigger| format!("{trigger}")).unwrap_or("Manual".to_owned())],
+                        td![
+                            button![
+                                ev(Ev::Click, move |_| Msg::send_event(ClientEvent::ApplyManagerPreset(apply_name))),
+                                "Apply"
+                            ],
+                            button![
+                                ev(Ev::Click, move |_| Msg::send_event(ClientEvent::DeleteManagerPreset(delete_name))),
+                                "Delete"
+                            ],
+                        ],
+                    ]
+                })
+            ]
+        },
+        div![C!["button-row"],
+            input![
+                attrs! { At::Type => "text", At::Value => form.name, At::MaxLength => format!("{}", MAX_PRESET_NAME_LEN), At::Placeholder => "Preset Name" },
+                input_ev(Ev::Input, {
+                    let form = form.clone();
+                    move |name| Msg::SetPresetForm(PresetForm { name, ..form })
+                })
+            ],
+            select![
+                attrs! { At::Disabled => (!is_premium).as_at_value() },
+                option![attrs! { At::Value => "", At::Selected => form.trigger.is_none().as_at_value() }, "Manual only"],
+                trigger_options,
+                input_ev(Ev::Change, {
+                    let form = form.clone();
+                    move |str| Msg::SetPresetForm(PresetForm {
+                        trigger: str.parse::<usize>().ok().and_then(|idx| triggers.get(idx).copied()),
+                        ..form
+                    })
+                })
+            ],
+            match form.trigger {
+                Some(PresetTrigger::FoodBelow(food)) => vec![input![
+                    attrs! { At::Type => "number", At::Min => "0", At::Value => format!("{}", food), At::Placeholder => "Food" },
+                    input_ev(Ev::Input, {
+                        let form = form.clone();
+                        move |str| Msg::SetPresetForm(PresetForm {
+                            trigger: Some(PresetTrigger::FoodBelow(str.parse().unwrap_or(0))),
+                            ..form
+                        })
+                    })
+                ]],
+                Some(PresetTrigger::Hours(from, to)) => vec![
+                    input![
+                        attrs! { At::Type => "number", At::Min => "0", At::Max => "23", At::Value => format!("{}", from), At::Placeholder => "From" },
+                        input_ev(Ev::Input, {
+                            let form = form.clone();
+                            move |str| Msg::SetPresetForm(PresetForm {
+                                trigger: Some(PresetTrigger::Hours(str.parse::<u64>().unwrap_or(0).min(23), to)),
+                                ..form
+                            })
+                        })
+                    ],
+                    input![
+                        attrs! { At::Type => "number", At::Min => "0", At::Max => "24", At::Value => format!("{}", to), At::Placeholder => "To" },
+                        input_ev(Ev::Input, {
+                            let form = form.clone();
+                            move |str| Msg::SetPresetForm(PresetForm {
+                                trigger: Some(PresetTrigger::Hours(from, str.parse::<u64>().unwrap_or(0).min(23))),
+                                ..form
+                            })
+                        })
+                    ],
+                ],
+                _ => Vec::new(),
+            },
+            button![
+                attrs! { At::Disabled => form.name.trim().is_empty().as_at_value() },
+                ev(Ev::Click, {
+                    let form = form.clone();
+                    move |_| Msg::send_event(ClientEvent::SaveManagerPreset(form.name, form.trigger))
+                }),
+                "Save Current Numbers as Preset"
+            ],
+        ],
+    ]
+}
+
+fn index_select(options: &[&str], selected: usize, on_change: impl FnOnce(usize) -> Msg + Clone + 'static) -> Node<Msg> {
+    select![
+        options.iter().enumerate().map(|(idx, option)| {
+            option![
+                attrs! { At::Value => format!("{}", idx), At::Selected => (idx == selected).as_at_value() },
+                *option
+            ]
+        }),
+        input_ev(Ev::Change, move |str| on_change(str.parse().unwrap_or(0)))
+    ]
+}
+
+fn number_input(value: u64, placeholder: &str, on_change: impl FnOnce(u64) -> Msg + Clone + 'static) -> Node<Msg> {
+    input![
+        attrs! { At::Type => "number", At::Min => "0", At::Value => format!("{}", value), At::Placeholder => placeholder },
+        input_ev(Ev::Input, move |str| on_change(str.parse().unwrap_or(0)))
+    ]
+}
+
+fn automation(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<Msg> {
+    if let Some(player) = state.players.get(user_id) {
+        let is_premium = model
+            .state
+            .get_user_data(user_id)
+            .map(|user_data| user_data.premium > 0)
+            .unwrap_or(false);
+
+        let form = model.rule.clone();
+        let items = enum_iterator::all::<Item>().collect::<Vec<_>>();
+        let food_items = enum_iterator::all::<Item>()
+            .filter(|item| item.nutritional_value().is_some())
+            .collect::<Vec<_>>();
+        let craftable_items = enum_iterator::all::<Item>()
+            .filter(|item| item.requires().is_some())
+            .collect::<Vec<_>>();
+        let events = enum_iterator::all::<WorldEvent>().collect::<Vec<_>>();
+        let presets = player
+            .manager_presets
+            .iter()
+            .map(|preset| preset.name.clone())
+            .collect::<Vec<_>>();
+
+        div![C!["content"],
+            h2!["Automation"],
+            p!["Each rule carries out its action in every tick in which all of its conditions are met. A rule without conditions is always carried out. Switching to a manager preset only happens once when the conditions start to be met."],
+            if !is_premium {
+                p![C!["important"], "Automation rules are a premium feature. Your rules are kept, but they are not carried out without a premium account."]
+            } else {
+                Node::Empty
+            },
+            if player.rules.is_empty() {
+                p!["You have not set up any rules yet."]
+            } else {
+                table![
+                    tr![th!["Condition"], th!["Action"], th![]],
+                    player.rules.iter().enumerate().map(|(idx, rule)| {
+                        tr![
+                            td![if rule.conditions.is_empty() {
+                                "Always".to_owned()
+                            } else {
+                                rule.conditions.iter().map(|condition| format!("{condition}")).collect::<Vec<_>>().join(" and ")
+                            }],
+                            td![format!("{}", rule.action)],
+                            td![
+                                button![
+                                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::ToggleRule(idx))),
+                                    if rule.enabled { "Disable" } else { "Enable" }
+                                ],
+                                button![
+                                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::RemoveRule(idx))),
+                                    "Delete"
+                                ],
+                            ],
+                        ]
+                    })
+                ]
+            },
+            h3!["New Rule"],
+            if form.conditions.is_empty() {
+                p!["Always"]
+            } else {
+                ul![form.conditions.iter().map(|condition| li![format!("{condition}")])]
+            },
+            div![C!["button-row"],
+                index_select(&CONDITION_KINDS, form.condition_kind, {
+                    let form = form.clone();
+                    move |condition_kind| Msg::SetRuleForm(RuleForm { condition_kind, ..form })
+                }),
+                match form.condition_kind {
+                    0 | 1 => vec![
+                        item_select(items.clone(), form.condition_item, {
+                            let form = form.clone();
+                            move |condition_item| Msg::SetRuleForm(RuleForm { condition_item, ..form })
+                        }),
+                        number_input(form.condition_value, "Quantity", {
+                            let form = form.clone();
+                            move |condition_value| Msg::SetRuleForm(RuleForm { condition_value, ..form })
+                        }),
+                    ],
+                    2 | 3 | 4 => vec![number_input(form.condition_value, "Amount", {
+                        let form = form.clone();
+                        move |condition_value| Msg::SetRuleForm(RuleForm { condition_value, ..form })
+                    })],
+                    5 => {
+                        let options = events
+                            .iter()
+                            .enumerate()
+                            .map(|(idx, event)| {
+                                option![
+                                    attrs! { At::Value => format!("{}", idx), At::Selected => (form.condition_event == Some(*event)).as_at_value() },
+                                    format!("{}", event)
+                                ]
+                            })
+                            .collect::<Vec<_>>();
+                        vec![select![
+                            option![attrs! { At::Value => "", At::Selected => form.condition_event.is_none().as_at_value() }, "Select Event"],
+                            options,
+                            input_ev(Ev::Change, {
+                                let form = form.clone();
+                                move |str| Msg::SetRuleForm(RuleForm {
+                                    condition_event: str.parse::<usize>().ok().and_then(|idx| events.get(idx).copied()),
+                                    ..form
+                                })
+                            })
+                        ]]
+                    }
+                    _ => vec![
+                        number_input(form.condition_value, "From", {
+                            let form = form.clone();
+                            move |condition_value| Msg::SetRuleForm(RuleForm { condition_value, ..form })
+                        }),
+                        number_input(form.condition_value2, "To", {
+                            let form = form.clone();
+                            move |condition_value2| Msg::SetRuleForm(RuleForm { condition_value2, ..form })
+                        }),
+                    ],
+                },
+                if let Some(condition) = form.condition() {
+                    let mut conditions = form.conditions.clone();
+                    conditions.push(condition);
+                    let form = form.clone();
+                    button![
+                        ev(Ev::Click, move |_| Msg::SetRuleForm(RuleForm { conditions, ..form })),
+                        "Add Condition"
+                    ]
+                } else {
+                    button![attrs! { At::Disabled => true.as_at_value() }, "Add Condition"]
+                },
+            ],
+            div![C!["button-row"],
+                index_select(&ACTION_KINDS, form.action_kind, {
+                    let form = form.clone();
+                    move |action_kind| Msg::SetRuleForm(RuleForm { action_kind, ..form })
+                }),
+                match form.action_kind {
+                    0 | 1 | 2 | 3 => vec![
+                        item_select(
+                            match form.action_kind {
+                                0 | 2 => craftable_items,
+                                1 => food_items,
+                                _ => items,
+                            },
+                            form.action_item,
+                            {
+                                let form = form.clone();
+                                move |action_item| Msg::SetRuleForm(RuleForm { action_item, ..form })
+                            },
+                        ),
+                        number_input(form.action_value, if form.action_kind == 3 { "Coins" } else { "Quantity" }, {
+                            let form = form.clone();
+                            move |action_value| Msg::SetRuleForm(RuleForm { action_value, ..form })
+                        }),
+                    ],
+                    _ => {
+                        let options = presets
+                            .iter()
+                            .enumerate()
+                            .map(|(idx, name)| {
+                                option![
+                                    attrs! { At::Value => format!("{}", idx), At::Selected => (form.action_preset.as_ref() == Some(name)).as_at_value() },
+                                    name.as_str()
+                                ]
+                            })
+                            .collect::<Vec<_>>();
+                        vec![select![
+                            option![attrs! { At::Value => "", At::Selected => form.action_preset.is_none().as_at_value() }, "Select Preset"],
+                            options,
+                            input_ev(Ev::Change, {
+                                let form = form.clone();
+                                move |str| Msg::SetRuleForm(RuleForm {
+                                    action_preset: str.parse::<usize>().ok().and_then(|idx| presets.get(idx).cloned()),
+                                    ..form
+                                })
+                            })
+                        ]]
+                    }
+                },
+                if let Some(action) = form.action() {
+                    let conditions = form.conditions.clone();
+                    button![
+                        attrs! { At::Disabled => (!is_premium).as_at_value() },
+                        ev(Ev::Click, move |_| Msg::send_event(ClientEvent::AddRule(Rule::new(conditions, action)))),
+                        "Add Rule"
+                    ]
+                } else {
+                    button![attrs! { At::Disabled => true.as_at_value() }, "Add Rule"]
+                },
+                button![
+                    ev(Ev::Click, move |_| Msg::SetRuleForm(RuleForm::default())),
+                    "Reset"
+                ],
+            ],
+        ]
+    } else {
+        Node::Empty
+    }
+}
+
+fn item_select(items: Vec<Item>, selected: Option<Item>, on_change: impl FnOnce(Option<Item>) -> Msg + Clone + 'static) -> Node<Msg> {
+    let options = items
+        .iter()
+        .enumerate()
+        .map(|(idx, item)| {
+            option![
+                attrs! { At::Value => format!("{}", idx), At::Selected => (selected == Some(*item)).as_at_value() },
+                format!("{}", item)
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    select![
+        option![attrs! { At::Value => "", At::Selected => selected.is_none().as_at_value() }, "Select Item"],
+        options,
+        input_ev(Ev::Change, move |str| {
+            on_change(str.parse::<usize>().ok().and_then(|idx| items.get(idx).copied()))
+        })
+    ]
+}
+
+fn direct_offers(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<Msg> {
+    if let Some(player) = state.players.get(user_id) {
+        let form = model.direct_offer;
+        let mut players = state
+            .players
+            .keys()
+            .filter(|other| *other != user_id)
+            .map(|other| (*other, username(&model.state, other)))
+            .collect::<Vec<_>>();
+        players.sort_by(|(_, a), (_, b)| a.cmp(b));
+        let player_ids = players.iter().map(|(other, _)| *other).collect::<Vec<_>>();
+
+        let owned_items = player
+            .inventory
+            .items
+            .iter()
+            .filter(|(_, n)| **n > 0)
+            .map(|(item, _)| *item)
+            .sorted()
+            .collect::<Vec<_>>();
+
+        let items = form.item.map(|item| Bundle::new().add(item, form.qty)).unwrap_or_default();
+        let requested_items = form.requested_item.map(|item| Bundle::new().add(item, form.requested_qty)).unwrap_or_default();
+        let can_afford = player.money >= form.money && player.inventory.items.check_remove(&items);
+        let is_empty = items.iter().next().is_none() && form.money == 0;
+        let num_offers = state.direct_offers.values().filter(|offer| offer.from == *user_id).count();
+
+        div![
+            h2!["Direct Offers"],
+            p![format!(
+                "Offer items and coins to another player in exchange for something else. What you offer is held back until the offer is accepted, declined or withdrawn, or it expires after {}. {}/{} open offers.",
+                fmt_time(DIRECT_OFFER_DURATION, true),
+                num_offers,
+                MAX_NUM_DIRECT_OFFERS
+            )],
+            div![C!["button-row"],
+                select![
+                    option![attrs! { At::Value => "", At::Selected => form.to.is_none().as_at_value() }, "Select Player"],
+                    players.iter().enumerate().map(|(idx, (other, name))| {
+                        option![
+                            attrs! { At::Value => format!("{}", idx), At::Selected => (form.to == Some(*other)).as_at_value() },
+                            name.as_str()
+                        ]
+                    }),
+                    input_ev(Ev::Change, move |str| {
+                        Msg::SetDirectOffer(DirectOfferForm { to: str.parse::<usize>().ok().and_then(|idx| player_ids.get(idx).copied()), ..form })
+                    })
+                ],
+            ],
+            h4!["You Offer"],
+            div![C!["button-row"],
+                item_select(owned_items, form.item, move |item| Msg::SetDirectOffer(DirectOfferForm { item, ..form })),
+                input![
+                    attrs! { At::Type => "number", At::Min => "0", At::Value => format!("{}", form.qty), At::Placeholder => "Quantity" },
+                    input_ev(Ev::Input, move |str| Msg::SetDirectOffer(DirectOfferForm { qty: str.parse().unwrap_or(0), ..form }))
+                ],
+                input![
+                    attrs! { At::Type => "number", At::Min => "0", At::Value => format!("{}", form.money), At::Placeholder => "Coins" },
+                    input_ev(Ev::Input, move |str| Msg::SetDirectOffer(DirectOfferForm { money: str.parse().unwrap_or(0), ..form }))
+                ],
+            ],
+            h4!["You Request"],
+            div![C!["button-row"],
+                item_select(enum_iterator::all::<Item>().collect(), form.requested_item, move |requested_item| Msg::SetDirectOffer(DirectOfferForm { requested_item, ..form })),
+                input![
+                    attrs! { At::Type => "number", At::Min => "0", At::Value => format!("{}", form.requested_qty), At::Placeholder => "Quantity" },
+                    input_ev(Ev::Input, move |str| Msg::SetDirectOffer(DirectOfferForm { requested_qty: str.parse().unwrap_or(0), ..form }))
+                ],
+                input![
+                    attrs! { At::Type => "number", At::Min => "0", At::Value => format!("{}", form.requested_money), At::Placeholder => "Coins" },
+                    input_ev(Ev::Input, move |str| Msg::SetDirectOffer(DirectOfferForm { requested_money: str.parse().unwrap_or(0), ..form }))
+                ],
+            ],
+            if let Some(to) = form.to {
+                button![
+                    attrs! { At::Disabled => (is_empty || !can_afford || num_offers >= MAX_NUM_DIRECT_OFFERS).as_at_value() },
+                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::MakeOffer(to, items, form.money, requested_items, form.requested_money))),
+                    "Make Offer"
+                ]
+            } else {
+                button![attrs! { At::Disabled => true.as_at_value() }, "Make Offer"]
+            },
+            table![
+                C!["list"],
+                state.direct_offers.iter().filter(|(_, offer)| offer.from == *user_id || offer.to == *user_id).map(|(offer_id, offer)| {
+                    let offer_id = *offer_id;
+                    let incoming = offer.to == *user_id;
+                    tr![
+                        C!["list-item-row"],
+                        td![
+                            C!["list-item-content"],
+                            h3![C!["title"], if incoming {
+                                format!("Offer from {}", username(&model.state, &offer.from))
+                            } else {
+                                format!("Offer to {}", username(&model.state, &offer.to))
+                            }],
+                            p![C!["subtitle"], format!("Expires in {}.", fmt_time(offer.time_left, true))],
+                        ],
+                        td![
+                            C!["list-item-content"],
+                            h4![C!["title"], "Offered"],
+                            bundle(&offer.items, player, false),
+                            if offer.money > 0 { p![format!("{} coins", offer.money)] } else { Node::Empty },
+                        ],
+                        td![
+                            C!["list-item-content"],
+                            h4![C!["title"], "Requested"],
+                            bundle(&offer.requested_items, player, incoming),
+                            if offer.requested_money > 0 { p![format!("{} coins", offer.requested_money)] } else { Node::Empty },
+                        ],
+                        td![
+                            C!["list-item-content"],
+                            if incoming {
+                                div![C!["button-row"],
+                                    button![
+                                        attrs! { At::Disabled => (player.money < offer.requested_money || !player.inventory.items.check_remove(&offer.requested_items)).as_at_value() },
+                                        ev(Ev::Click, move |_| Msg::send_event(ClientEvent::AcceptOffer(offer_id))),
+                                        "Accept"
+                                    ],
+                                    button![
+                                        ev(Ev::Click, move |_| Msg::send_event(ClientEvent::DeclineOffer(offer_id))),
+                                        "Decline"
+                                    ],
+                                ]
+                            } else {
+                                button![
+                                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::CancelOffer(offer_id))),
+                                    "Withdraw"
+                                ]
+                            }
+                        ]
+                    ]
+                })
+            ]
+        ]
+    } else {
+        Node::Empty
+    }
+}
+
+fn buy_orders(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Node<Msg> {
+    if let Some(player) = state.players.get(user_id) {
+        let mut buy_orders = state
+            .trade_deals
+            .iter()
+            .filter(|(_, trade_deal)| trade_deal.user_trade_type == TradeType::Sell)
+            .filter(|(_, trade_deal)| {
+                model.trade_filter.by_type.values().all(|v| !v)
+                    || if let Some(item_type) = trade_deal.items.iter().next().and_then(|(item, _)| item.item_type()) {
+                        model.trade_filter.by_type.get(&item_type).copied().unwrap_or(false)
+                    } else {
+                        false
+                    }
+            })
+            .map(|(trade_id, trade_deal)| (*trade_id, trade_deal))
+            .collect::<Vec<_>>();
 
-                    tr![
+        buy_orders.sort_by_key(|(_, trade_deal)| trade_deal.time_left);
 
-                    C!["item"],
-                    C!["list-item-row"],
-                    match item.item_rarity() {
-                        ItemRarity::Common => C!["item-common"],
-                        ItemRarity::Uncommon => C!["item-uncommon"],
-                        ItemRarity::Rare => C!["item-rare"],
-                        ItemRarity::Epic => C!["item-epic"],
-                        ItemRarity::Legendary => C!["item-legendary"],
-                    },
-                    item_details(item, n),
-                    td![
-                        C!["list-item-content"],
-                        h4![C!["title"], "Cost" ],
-                        p![C!["subtitle"], format!("{} coins", trade_deal.next_bid)],
-                        p![format!("Deal ends in {}.", fmt_time(trade_deal.time_left, true))],
-                        if trade_deal.creator == Some(*user_id) {
-                            vec![p![format!("You created this deal.")]]
-                        } else {
-                            vec![
-                                if !can_afford && !highest_bidder_is_you {
-                                    p![format!("You can't afford this deal.")]
-                                } else {
-                                    Node::Empty
-                                },
-                                if let Some((highest_bidder_user_id, highest_bidder_money)) = trade_deal.highest_bidder {
-                                    if highest_bidder_user_id == *user_id {
-                                        p![format!("You are the highest bidder with {} coins.", highest_bidder_money)]
+        let sellable_items = enum_iterator::all::<Item>()
+            .filter(|item| item.item_type().is_some())
+            .collect::<Vec<_>>();
+        let form_item = model.buy_order.item;
+        let form_qty = model.buy_order.qty;
+        let form_price = model.buy_order.max_price;
+
+        div![
+            h2!["Buy Orders"],
+            p!["Post a buy order to let other players compete for delivering the items. The coins are held back until the deal ends, and you only pay the lowest offer."],
+            div![C!["button-row"],
+                item_select(sellable_items, form_item, Msg::SetBuyOrderItem),
+                input![
+                    attrs! { At::Type => "number", At::Min => "1", At::Max => format!("{}", MAX_BUY_ORDER_QTY), At::Value => format!("{}", form_qty), At::Placeholder => "Quantity" },
+                    input_ev(Ev::Input, |str| Msg::SetBuyOrderQty(str.parse().unwrap_or(0)))
+                ],
+                input![
+                    attrs! { At::Type => "number", At::Min => format!("{}", MIN_TRADE_VALUE), At::Value => format!("{}", form_price), At::Placeholder => "Max. Price" },
+                    input_ev(Ev::Input, |str| Msg::SetBuyOrderPrice(str.parse().unwrap_or(0)))
+                ],
+                if let Some(item) = form_item {
+                    button![
+                        attrs! { At::Disabled => (form_qty == 0 || form_qty > MAX_BUY_ORDER_QTY || form_price < MIN_TRADE_VALUE || player.money < form_price).as_at_value() },
+                        ev(Ev::Click, move |_| Msg::send_event(ClientEvent::BuyOrder(item, form_qty, form_price))),
+                        format!("Post Buy Order for {} coins", form_price)
+                    ]
+                } else {
+                    button![attrs! { At::Disabled => true.as_at_value() }, "Post Buy Order"]
+                },
+            ],
+            table![
+                C!["items", "list"],
+                buy_orders.into_iter().map(|(trade_id, trade_deal)| {
+                    let item = *trade_deal.items.iter().next().unwrap().0;
+                    let n = *trade_deal.items.iter().next().unwrap().1;
+                    let lowest_seller_is_you = trade_deal.highest_bidder.map(|(id, _)| id) == Some(*user_id);
+                    let has_items = player.inventory.items.check_remove(&trade_deal.items);
+
+                    tr![
+                        C!["item"],
+                        C!["list-item-row"],
+                        match item.item_rarity() {
+                            ItemRarity::Common => C!["item-common"],
+                            ItemRarity::Uncommon => C!["item-uncommon"],
+                            ItemRarity::Rare => C!["item-rare"],
+                            ItemRarity::Epic => C!["item-epic"],
+                            ItemRarity::Legendary => C!["item-legendary"],
+                        },
+                        item_details(item, n),
+                        td![
+                            C!["list-item-content"],
+                            h4![C!["title"], "Payment"],
+                            p![C!["subtitle"], format!("{} coins", trade_deal.next_bid)],
+                            p![format!("Deal ends in {}.", fmt_time(trade_deal.time_left, true))],
+                            if trade_deal.creator == Some(*user_id) {
+                                vec![
+                                    p![format!("You created this buy order and hold back {} coins.", trade_deal.escrow)],
+                                    if let Some((_, price)) = trade_deal.highest_bidder {
+                                        p![format!("The lowest seller asks for {} coins.", price)]
                                     } else {
-                                        p![format!("Highest bidder has offered {} coins.", highest_bidder_money)]
-                                    }
-                                } else {
-                                    Node::Empty
-                                },                               
-                                button![
-                                    attrs! { At::Disabled => (highest_bidder_is_you || !can_afford).as_at_value() },
-                                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::Bid(trade_id))),
-                                    format!("Bid {} coins", trade_deal.next_bid)
-                                ],
-                                h4![C!["title"], "Auto Bidding"],
-                                div![C!["button-row"],
-                                    input![
-                                        attrs! { At::Type => "number", At::Min => "0", At::Value => format!("{}",max_bid) },
-                                        input_ev(Ev::Input, move |str| Msg::SetBidMax(trade_id, str.parse().unwrap_or(0)))
-                                    ],
-                                    if is_premium {
-                                        button![
-                                            attrs! { At::Disabled => (player.auto_functions.auto_bid.get(&trade_id).copied().unwrap_or_default() == model.bid_max.get(&trade_id).copied().unwrap_or_default()).as_at_value() },
-                                            ev(Ev::Click, move |_| Msg::send_event(ClientEvent::AutoBid(trade_id, max_bid))),
-                                            format!("Set Auto Bid")
-                                        ]
+                                        p!["Nobody has offered to sell yet."]
+                                    },
+                                ]
+                            } else {
+                                vec![
+                                    if !has_items && !lowest_seller_is_you {
+                                        p![format!("You don't have the items for this deal.")]
                                     } else {
-                                        a![
-                                            C!["premium-feature", "button"],
-                                            format!("Set Auto Bid"),
-                                            attrs! { At::Href => format!("/store") },
-                                        ]
+                                        Node::Empty
                                     },
-                                    
+                                    if let Some((seller_user_id, price)) = trade_deal.highest_bidder {
+                                        if seller_user_id == *user_id {
+                                            p![format!("You are the lowest seller with {} coins.", price)]
+                                        } else {
+                                            p![format!("Lowest seller asks for {} coins.", price)]
+                                        }
+                                    } else {
+                                        Node::Empty
+                                    },
+                                    button![
+                                        attrs! { At::Disabled => (lowest_seller_is_you || !has_items || trade_deal.next_bid == 0).as_at_value() },
+                                        ev(Ev::Click, move |_| Msg::send_event(ClientEvent::Bid(trade_id))),
+                                        format!("Sell for {} coins", trade_deal.next_bid)
+                                    ],
                                 ]
-                            ]
-                            
-                        },
- 
+                            },
+                        ]
                     ]
-                ]
-            }),
+                })
             ]
         ]
     } else {
@@ -3702,8 +5097,7 @@ fn trades(model: &Model, state: &shared::State, user_id: &shared::UserId) -> Nod
 
 fn tribe(model: &Model, client_state: &ClientState<shared::State>, state: &shared::State, user_id: &shared::UserId) -> Node<Msg> {
     if let Some(player) = state.players.get(user_id) {
-        if let Some(tribe_id) = player.tribe {
-            //let tribe = state.tribes.get(&tribe_id).unwrap();
+        if let Some((tribe_id, tribe)) = player.tribe.and_then(|tribe_id| state.tribes.get(&tribe_id).map(|tribe| (tribe_id, tribe))) {
             let username = username(client_state, user_id);
 
 
@@ -3724,9 +5118,9 @@ fn tribe(model: &Model, client_state: &ClientState<shared::State>, state: &share
                 } else {
                     span![
                         "Your tribe is allied with the ",
-                        state.tribes.iter().filter(|(id, _)| **id != tribe_id && **id != king_tribe).map(|(id, _)| tribe_name(*id, model.game_id)),
+                        state.tribes.iter().filter(|(id, _)| **id != tribe_id && **id != king_tribe).map(|(id, _)| tribe_name(state, *id, model.game_id)),
                         " against ",
-                        tribe_name(king_tribe, model.game_id),
+                        tribe_name(state, king_tribe, model.game_id),
                         "."
                     ]
                 }
@@ -3817,11 +5211,23 @@ fn tribe(model: &Model, client_state: &ClientState<shared::State>, state: &share
                 
                 
                 p![strong!["You are member of the ", tribe_name(
+                    state,
                     tribe_id,
                     model.game_id
                 ), "."]],
                 p![strong![alliance_message]],
                 p![strong![format!("Your Fame Points: {} FP", player.tribe_points)]],
+                if player.can_leave_tribe(state.time) {
+                    button![
+                        ev(Ev::Click, move |_| Msg::Confirm(ClientEvent::LeaveTribe)),
+                        "Leave Tribe"
+                    ]
+                } else {
+                    p![format!(
+                        "You can leave your tribe in {}.",
+                        fmt_time(player.tribe_joined.map(|joined| (joined + TRIBE_CHANGE_COOLDOWN).saturating_sub(state.time)).unwrap_or_default(), true)
+                    )]
+                },
                 table![C!["list"],
 
                 enum_iterator::all::<Territory>()
@@ -3878,12 +5284,88 @@ fn tribe(model: &Model, client_state: &ClientState<shared::State>, state: &share
                             ],
                         ]
                     })
+                ],
+                tribe_council(model, client_state, state, user_id, tribe_id),
+                h2!["Tribe Members"],
+                table![C!["list"],
+                    state.tribe_members(tribe_id)
+                        .sorted_by_key(|(_, member)| std::cmp::Reverse(member.base.curr_level))
+                        .map(|(member_id, member)| {
+                            let member_id = *member_id;
+                            let votes = tribe.votes.values().filter(|candidate| **candidate == member_id).count();
+                            tr![C!["list-item-row"],
+                                td![C!["list-item-content"],
+                                    h3![C!["title"],
+                                        username(client_state, &member_id),
+                                        if tribe.is_chief(member_id) { " (Chief)" } else { "" },
+                                    ],
+                                    p![C!["subtitle"], format!("Level {}, {} dwarfs", member.base.curr_level, member.dwarfs.len())],
+                                    p![format!("{} FP", member.tribe_points)],
+                                    if member.is_online(state.time) {
+                                        p!["Online"]
+                                    } else {
+                                        p![format!("Last online {} ago", fmt_time(state.time - member.last_online, false))]
+                                    },
+                                    p![format!("{} votes for chief", votes)],
+                                    button![
+                                        attrs! { At::Disabled => (tribe.votes.get(user_id) == Some(&member_id)).as_at_value() },
+                                        ev(Ev::Click, move |_| Msg::send_event(ClientEvent::VoteChief(member_id))),
+                                        "Vote for Chief"
+                                    ],
+                                ]
+                            ]
+                        })
                 ]
             ]
         } else {
+            let member_counts = state.tribe_member_counts();
             div![C!["content"],
                 h2!["Your Tribe"],
-                p![format!("You are not a member of a tribe. You will be assigned a tribe at level {}.", JOIN_TRIBE_LEVEL)],
+                if player.base.curr_level < JOIN_TRIBE_LEVEL {
+                    p![format!("You are not a member of a tribe. You can join a tribe at level {}.", JOIN_TRIBE_LEVEL)]
+                } else if let Some(since) = player.tribe_choice_since {
+                    p![format!(
+                        "You are not a member of a tribe. Choose a tribe to join, otherwise you will be assigned to one in {}.",
+                        fmt_time((since + TRIBE_CHOICE_TIME).saturating_sub(state.time), true)
+                    )]
+                } else {
+                    p!["You are not a member of a tribe. Choose a tribe to join."]
+                },
+                if player.base.curr_level >= JOIN_TRIBE_LEVEL {
+                    table![C!["list"],
+                        state.tribes.iter().map(|(tribe_id, tribe)| {
+                            let tribe_id = *tribe_id;
+                            let is_full = state.tribe_is_full(tribe_id);
+                            tr![C!["list-item-row"],
+                                td![C!["list-item-content"],
+                                    h3![C!["title"], tribe_name(state, tribe_id, model.game_id)],
+                                    p![C!["subtitle"], format!(
+                                        "{} active members, {} territories conquered",
+                                        member_counts.get(&tribe_id).copied().unwrap_or_default(),
+                                        tribe.territories.values().sum::<u64>()
+                                    )],
+                                    if let Some(chief) = tribe.chief {
+                                        p![format!("Chief: {}", username(client_state, &chief))]
+                                    } else {
+                                        Node::Empty
+                                    },
+                                    if is_full {
+                                        p!["This tribe has too many members compared to the other tribes."]
+                                    } else {
+                                        Node::Empty
+                                    },
+                                    button![
+                                        attrs! { At::Disabled => is_full.as_at_value() },
+                                        ev(Ev::Click, move |_| Msg::send_event(ClientEvent::JoinTribe(tribe_id))),
+                                        "Join Tribe"
+                                    ],
+                                ]
+                            ]
+                        })
+                    ]
+                } else {
+                    Node::Empty
+                },
             ]
         }
     } else {
@@ -3891,6 +5373,108 @@ fn tribe(model: &Model, client_state: &ClientState<shared::State>, state: &share
     }
 }
 
+fn tribe_council(model: &Model, client_state: &ClientState<shared::State>, state: &shared::State, user_id: &shared::UserId, tribe_id: TribeId) -> Node<Msg> {
+    let (Some(player), Some(tribe)) = (state.players.get(user_id), state.tribes.get(&tribe_id)) else {
+        return Node::Empty;
+    };
+    let can_govern = tribe.can_govern(*user_id);
+    let is_chief = tribe.is_chief(*user_id);
+    let deposit = model.tribe_deposit;
+    let tithe = model.tribe_tithe.unwrap_or(tribe.tithe);
+
+    div![
+        h2!["Tribe Council"],
+        if let Some(chief) = tribe.chief {
+            p![format!("{} is the chief of your tribe. The chief decides on the name, banner and tithe, and spends the treasury on upgrades for the whole tribe. Members can change their vote at any time.", username(client_state, &chief))]
+        } else {
+            p!["Your tribe has no chief yet. Vote for one of the members below. Until a chief is elected, every member can choose the name and banner."]
+        },
+        h3!["Name and Banner"],
+        if can_govern {
+            if let Some(custom_name) = model.custom_name.clone() {
+                div![C!["button-row"],
+                    input![
+                        attrs! {At::Value => custom_name, At::MaxLength => format!("{}", MAX_TRIBE_NAME_LEN)},
+                        input_ev(Ev::Input, move |name| Msg::UpdateName(Some(name))),
+                    ],
+                    button![
+                        ev(Ev::Click, move |_| Msg::SetTribeName(custom_name)),
+                        "Save Name"
+                    ],
+                ]
+            } else {
+                let name = tribe.name.clone().unwrap_or_default();
+                button![
+                    ev(Ev::Click, move |_| Msg::UpdateName(Some(name))),
+                    "Edit Name"
+                ]
+            }
+        } else {
+            Node::Empty
+        },
+        div![C!["button-row"],
+            enum_iterator::all::<Banner>().map(|banner| {
+                button![
+                    attrs! { At::Disabled => (!can_govern || tribe.banner == Some(banner)).as_at_value() },
+                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::SetTribeBanner(banner))),
+                    banner_icon(banner),
+                    format!(" {}", banner)
+                ]
+            })
+        ],
+        h3!["Treasury"],
+        p![format!("The treasury contains {} coins. Members pay a tithe of {}% of their quest rewards into the treasury.", tribe.treasury, tribe.tithe)],
+        div![C!["button-row"],
+            input![
+                attrs! { At::Type => "number", At::Min => "0", At::Max => format!("{}", player.money), At::Value => format!("{}", deposit) },
+                input_ev(Ev::Input, |str| Msg::SetTribeDeposit(str.parse().unwrap_or(0)))
+            ],
+            button![
+                attrs! { At::Disabled => (deposit == 0 || deposit > player.money).as_at_value() },
+                ev(Ev::Click, move |_| Msg::send_event(ClientEvent::DepositToTreasury(deposit))),
+                format!("Deposit {} coins", deposit)
+            ],
+        ],
+        if is_chief {
+            div![C!["button-row"],
+                input![
+                    attrs! { At::Type => "number", At::Min => "0", At::Max => format!("{}", MAX_TITHE), At::Value => format!("{}", tithe) },
+                    input_ev(Ev::Input, |str| Msg::SetTribeTithe(str.parse().unwrap_or(0)))
+                ],
+                button![
+                    attrs! { At::Disabled => (tithe == tribe.tithe || tithe > MAX_TITHE).as_at_value() },
+                    ev(Ev::Click, move |_| Msg::send_event(ClientEvent::SetTithe(tithe))),
+                    format!("Set Tithe to {}%", tithe)
+                ],
+            ]
+        } else {
+            Node::Empty
+        },
+        h3!["Tribe Upgrades"],
+        table![C!["list"],
+            enum_iterator::all::<TribeUpgrade>().map(|upgrade| {
+                let cost = tribe.upgrade_cost(upgrade);
+                tr![C!["list-item-row"],
+                    td![C!["list-item-content"],
+                        h3![C!["title"], format!("{}", upgrade)],
+                        p![C!["subtitle"], format!("Level {}/{}", tribe.upgrade_level(upgrade), MAX_TRIBE_UPGRADE_LEVEL)],
+                        p![upgrade.description()],
+                        if let Some(cost) = cost {
+                            button![
+                                attrs! { At::Disabled => (!is_chief || tribe.treasury < cost).as_at_value() },
+                                ev(Ev::Click, move |_| Msg::send_event(ClientEvent::UpgradeTribe(upgrade))),
+                                format!("Upgrade for {} coins", cost)
+                            ]
+                        } else {
+                            p!["Maximum level reached."]
+                        }
+                    ]
+                ]
+            })
+        ],
+    ]
+}
+
 fn chat(
     model: &Model,
     state: &shared::State,
@@ -3910,9 +5494,29 @@ fn chat(
             if model.chat_visible {
                 div![
                     C!["togglable"],
+                    div![
+                        C!["chat-channels"],
+                        std::iter::once(ChatChannel::Global)
+                            .chain(player.tribe.map(ChatChannel::Tribe))
+                            .map(|channel| {
+                                button![
+                                    if channel == model.chat_channel { C!["active"] } else { C![] },
+                                    ev(Ev::Click, move |_| Msg::ChangeChatChannel(channel)),
+                                    if player.unread_chats.contains(&channel) && channel != model.chat_channel {
+                                        Icon::ChatUnread.draw()
+                                    } else {
+                                        Node::Empty
+                                    },
+                                    match channel {
+                                        ChatChannel::Global => span!["World"],
+                                        ChatChannel::Tribe(tribe_id) => span![tribe_name(state, tribe_id, model.game_id)],
+                                    }
+                                ]
+                            })
+                    ],
                     div![
                         C!["messages"],
-                        state.chat.messages.iter().map(|(user_id, message, time)| {
+                        state.chat.messages(model.chat_channel).into_iter().flatten().map(|(user_id, message, time)| {
                             let username = username(client_state, user_id);
                             p![
                                 C!["message"],
@@ -3950,7 +5554,7 @@ fn chat(
                     ev(Ev::Click, move |_| Msg::ToggleChat),
                     span![
                         attrs! {At::AriaHidden => "true"},
-                        if player.chat_unread {
+                        if !player.unread_chats.is_empty() {
                             Icon::ChatUnread.draw()
                         } else {
                             Icon::Chat.draw()
@@ -3988,6 +5592,8 @@ fn history(
                             span![C!["icon"], match msg {
                                 LogMsg::DwarfUpgrade(_,_) => Icon::Person,
                                 LogMsg::DwarfIsAdult(_) => Icon::Person,
+                                LogMsg::DwarfTrait(..) => Icon::Person,
+                                LogMsg::DwarfMastery(..) => Icon::Person,
                                 LogMsg::DwarfDied(_) => Icon::PersonRemove,
                                 LogMsg::NewPlayer(_) => Icon::WavingHand,
                                 LogMsg::NewDwarf(_) => Icon::PersonAdd,
@@ -4003,13 +5609,58 @@ fn history(
                                 LogMsg::BidWon(..) => Icon::Trade,
                                 LogMsg::ItemSold(..) => Icon::Trade,
                                 LogMsg::ItemNotSold(..) => Icon::Trade,
+                                LogMsg::BuildingUpgraded(..) => Icon::Settlement,
+                                LogMsg::BuyOrderFilled(..) => Icon::Trade,
+                                LogMsg::BuyOrderExpired(..) => Icon::Trade,
+                                LogMsg::OfferReceived(..) => Icon::Trade,
+                                LogMsg::OfferCompleted(..) => Icon::Trade,
+                                LogMsg::OfferDeclined(..) => Icon::Trade,
+                                LogMsg::OfferCancelled(..) => Icon::Trade,
+                                LogMsg::OfferExpired(..) => Icon::Trade,
+                                LogMsg::ChiefElected(..) => Icon::Tribe,
+                                LogMsg::TribeUpgraded(..) => Icon::Tribe,
+                                LogMsg::ChooseTribe => Icon::Tribe,
+                                LogMsg::JoinedTribe(..) => Icon::Tribe,
+                                LogMsg::MemberJoined(..) => Icon::Tribe,
+                                LogMsg::MemberLeft(..) => Icon::Tribe,
+                                LogMsg::ManagerPresetApplied(..) => Icon::Manager,
+                                LogMsg::ManagerPresetEnded(..) => Icon::Manager,
+                                LogMsg::CraftingStopped(..) => Icon::Inventory,
                             }.draw()],
                             span![" "],
                             span![C!["time"], format!("{} ago: ", fmt_time(state.time - time, false))],
                             match msg {
-                                LogMsg::Overbid(items, money, _) => {
+                                LogMsg::Overbid(items, money, trade_type) => {
+                                    let items = items
+                                        .clone()
+                                        .sorted_by_rarity()
+                                        .into_iter()
+                                        .map(|(item, n)| format!("{n}x {item}"))
+                                        .collect::<Vec<_>>()
+                                        .join(", ");
+                                    if *trade_type == TradeType::Sell {
+                                        span![format!("Another seller has offered {} for {} coins, your items have been returned.", items, money)]
+                                    } else {
+                                        span![format!("You have been overbid on {} for {} coins.", items, money)]
+                                    }
+                                }
+                                LogMsg::BidWon(items, money, trade_type) => {
+                                    let items = items
+                                        .clone()
+                                        .sorted_by_rarity()
+                                        .into_iter()
+                                        .map(|(item, n)| format!("{n}x {item}"))
+                                        .collect::<Vec<_>>()
+                                        .join(", ");
+                                    if *trade_type == TradeType::Sell {
+                                        span![format!("You have delivered {} for {} coins.", items, money)]
+                                    } else {
+                                        span![format!("You have successfully bought {} for {} coins.", items, money)]
+                                    }
+                                }
+                                LogMsg::ItemSold(items, money) => {
                                     span![format!(
-                                        "You have been overbid on {} for {} coins.",
+                                        "You sold {} for {} coins.",
                                         items
                                             .clone()
                                             .sorted_by_rarity()
@@ -4020,9 +5671,9 @@ fn history(
                                         money
                                     )]
                                 }
-                                LogMsg::BidWon(items, money, _) => {
+                                LogMsg::ItemNotSold(items, money) => {
                                     span![format!(
-                                        "You have successfully bought {} for {} coins.",
+                                        "You weren't able to sell {} for {} coins.",
                                         items
                                             .clone()
                                             .sorted_by_rarity()
@@ -4033,9 +5684,9 @@ fn history(
                                         money
                                     )]
                                 }
-                                LogMsg::ItemSold(items, money) => {
+                                LogMsg::BuyOrderFilled(items, money) => {
                                     span![format!(
-                                        "You sold {} for {} coins.",
+                                        "Your buy order for {} has been filled for {} coins.",
                                         items
                                             .clone()
                                             .sorted_by_rarity()
@@ -4046,9 +5697,9 @@ fn history(
                                         money
                                     )]
                                 }
-                                LogMsg::ItemNotSold(items, money) => {
+                                LogMsg::BuyOrderExpired(items, money) => {
                                     span![format!(
-                                        "You weren't able to sell {} for {} coins.",
+                                        "Nobody delivered {} for your buy order, {} coins have been returned.",
                                         items
                                             .clone()
                                             .sorted_by_rarity()
@@ -4059,12 +5710,128 @@ fn history(
                                         money
                                     )]
                                 }
+                                LogMsg::OfferReceived(from) => {
+                                    span![format!(
+                                        "{} has made you an offer, have a look at the trading page.",
+                                        username(client_state, from)
+                                    )]
+                                }
+                                LogMsg::OfferCompleted(other, items, money) => {
+                                    span![format!(
+                                        "You traded with {} and received {}{} coins.",
+                                        username(client_state, other),
+                                        items
+                                            .clone()
+                                            .sorted_by_rarity()
+                                            .into_iter()
+                                            .map(|(item, n)| format!("{n}x {item}, "))
+                                            .collect::<String>(),
+                                        money
+                                    )]
+                                }
+                                LogMsg::OfferDeclined(to) => {
+                                    span![format!(
+                                        "{} has declined your offer, your items and coins have been returned.",
+                                        username(client_state, to)
+                                    )]
+                                }
+                                LogMsg::OfferCancelled(from) => {
+                                    span![format!(
+                                        "{} has withdrawn their offer.",
+                                        username(client_state, from)
+                                    )]
+                                }
+                                LogMsg::OfferExpired(to) => {
+                                    span![format!(
+                                        "Your offer to {} has expired, your items and coins have been returned.",
+                                        username(client_state, to)
+                                    )]
+                                }
+                                LogMsg::ChiefElected(chief) => {
+                                    span![format!(
+                                        "{} has been elected as the chief of your tribe.",
+                                        username(client_state, chief)
+                                    )]
+                                }
+                                LogMsg::TribeUpgraded(upgrade, level) => {
+                                    span![format!(
+                                        "Your tribe has upgraded the {} to level {}.",
+                                        upgrade, level
+                                    )]
+                                }
+                                LogMsg::ChooseTribe => {
+                                    span![format!(
+                                        "You can now join a tribe, choose one on the tribe page within {}.",
+                                        fmt_time(TRIBE_CHOICE_TIME, true)
+                                    )]
+                                }
+                                LogMsg::JoinedTribe(tribe_id) => {
+                                    span!["You have joined the ", tribe_name(state, *tribe_id, model.game_id), "."]
+                                }
+                                LogMsg::MemberJoined(member) => {
+                                    span![format!(
+                                        "{} has joined your tribe.",
+                                        username(client_state, member)
+                                    )]
+                                }
+                                LogMsg::MemberLeft(member) => {
+                                    span![format!(
+                                        "{} has left your tribe.",
+                                        username(client_state, member)
+                                    )]
+                                }
+                                LogMsg::ManagerPresetApplied(name) => {
+                                    span![format!(
+                                        "The dwarfen manager has switched to the preset {}.",
+                                        name
+                                    )]
+                                }
+                                LogMsg::CraftingStopped(item, qty, missing) => {
+                                    let missing = missing
+                                        .clone()
+                                        .sorted_by_rarity()
+                                        .into_iter()
+                                        .map(|(item, n)| format!("{n}x {item}"))
+                                        .collect::<Vec<_>>()
+                                        .join(", ");
+                                    span![format!(
+                                        "Your dwarfs stopped crafting the remaining {}x {} because {} are missing.",
+                                        qty, item, missing
+                                    )]
+                                }
+                                LogMsg::ManagerPresetEnded(name) => {
+                                    span![format!(
+                                        "The condition of the preset {} is over, the dwarfen manager has switched back.",
+                                        name
+                                    )]
+                                }
                                 LogMsg::DwarfUpgrade(name, stat) => {
                                     span![format!(
                                         "Your dwarf {} has improved their {} stat while working.",
                                         name, stat
                                     )]
                                 }
+                                LogMsg::BuildingUpgraded(building, level) => {
+                                    span![format!(
+                                        "Your {} has been upgraded to level {}.",
+                                        building, level
+                                    )]
+                                }
+                                LogMsg::DwarfTrait(name, new_trait) => {
+                                    span![format!(
+                                        "Your dwarf {} has revealed the trait {}.",
+                                        name, new_trait
+                                    )]
+                                }
+                                LogMsg::DwarfMastery(name, occupation, mastery) => {
+                                    span![format!(
+                                        "Your dwarf {} is now {} {} in {}.",
+                                        name,
+                                        if matches!(mastery, Mastery::Apprentice | Mastery::Expert) { "an" } else { "a" },
+                                        mastery,
+                                        occupation
+                                    )]
+                                }
                                 LogMsg::DwarfIsAdult(name) => {
                                     span![format!(
                                         "Your dwarf {} is now an adult.",
@@ -4425,7 +6192,7 @@ fn nav(model: &Model) -> Node<Msg> {
             a![
                 C![
                     "button",
-                    if let Page::Manager = model.page {
+                    if let Page::Manager | Page::Automation = model.page {
                         "active disabled"
                     } else {
                         ""
@@ -1,15 +1,20 @@
 use std::str::FromStr;
 
-use crate::{game::GameState, ServerError};
+use crate::{
+    game::{run_world, GameState, GameStore, Snapshot},
+    ServerError,
+};
 use askama::Template;
 use askama_axum::Response;
 use axum::{
+    extract::Query,
     response::{IntoResponse, Redirect},
     Extension, Form,
 };
 use bcrypt::hash;
+use engine_shared::utils::custom_map::CustomMap;
 use serde::Deserialize;
-use shared::GameMode;
+use shared::{GameMode, Item, LedgerReason, WorldSettings, ONE_HOUR};
 use sqlx::SqlitePool;
 use tower_sessions::Session;
 
@@ -29,6 +34,37 @@ pub struct Settings {
 #[derive(Debug, Deserialize, Default)]
 pub struct CreateWorldSettings {
     game_mode: String,
+    world_speed: u64,
+    start_countdown_hours: u64,
+    num_tribes: u64,
+    win_level: u64,
+    quest_density: u64,
+    event_frequency: u64,
+}
+
+impl TryFrom<CreateWorldSettings> for WorldSettings {
+    type Error = ServerError;
+
+    fn try_from(form: CreateWorldSettings) -> Result<Self, Self::Error> {
+        let game_mode = GameMode::from_str(&form.game_mode)
+            .map_err(|_| ServerError::InvalidWorldSettings(format!("unknown game mode {}", form.game_mode)))?;
+
+        let settings = WorldSettings {
+            start_countdown: form.start_countdown_hours * ONE_HOUR,
+            world_speed: form.world_speed,
+            num_tribes: form.num_tribes,
+            win_level: form.win_level,
+            quest_density: form.quest_density,
+            event_frequency: form.event_frequency,
+            ..WorldSettings::from(game_mode)
+        };
+
+        settings
+            .validate()
+            .map_err(ServerError::InvalidWorldSettings)?;
+
+        Ok(settings)
+    }
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -38,10 +74,11 @@ struct User {
     premium: i64,
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Default)]
 struct Game {
     id: i64,
     winner: Option<i64>,
+    snapshots: Vec<Snapshot>,
 }
 
 #[derive(Template, Default)]
@@ -57,6 +94,11 @@ pub struct AddPremium {
     add_premium: i64,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RestoreSnapshot {
+    snapshot_id: i64,
+}
+
 pub async fn get_admin(
     session: Session,
     Extension(pool): Extension<SqlitePool>,
@@ -112,7 +154,7 @@ pub async fn get_admin(
     })
     .collect();
 
-    let games = sqlx::query_as(
+    let mut games: Vec<Game> = sqlx::query_as(
         r#"
                 SELECT id, winner
                 FROM games
@@ -122,9 +164,19 @@ pub async fn get_admin(
     .fetch_all(&pool)
     .await?
     .into_iter()
-    .map(|(id, winner)| Game { id, winner })
+    .map(|(id, winner)| Game {
+        id,
+        winner,
+        snapshots: Vec::new(),
+    })
     .collect();
 
+    for snapshot in GameStore::new(pool).snapshots().await? {
+        if let Some(game) = games.iter_mut().find(|game| game.id == snapshot.game_id) {
+            game.snapshots.push(snapshot);
+        }
+    }
+
     Ok(AdminTemplate {
         users,
         settings,
@@ -267,6 +319,7 @@ pub async fn post_create_world(
     session: Session,
     Extension(pool): Extension<SqlitePool>,
     Extension(game_state): Extension<GameState>,
+    Extension(store): Extension<GameStore>,
     Form(settings): Form<CreateWorldSettings>,
 ) -> Result<Response, ServerError> {
     let user_id = session
@@ -291,8 +344,55 @@ pub async fn post_create_world(
         return Err(ServerError::NoAdminPermissions);
     }
 
+    let settings = WorldSettings::try_from(settings)?;
 
-    game_state.create(GameMode::from_str(&settings.game_mode).unwrap()).await?;
+    let game_id = store.create_with_settings(&settings).await?;
+    run_world(&game_state, &store, game_id).await?;
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+pub async fn post_restore_snapshot(
+    session: Session,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(game_state): Extension<GameState>,
+    Extension(store): Extension<GameStore>,
+    Form(restore): Form<RestoreSnapshot>,
+) -> Result<Response, ServerError> {
+    let user_id = session
+        .get::<i64>(crate::USER_ID_KEY)
+        .await?
+        .ok_or(ServerError::InvalidSession)?;
+
+    let result: (i64,) = sqlx::query_as(
+        r#"
+                SELECT admin
+                FROM users
+                WHERE user_id = $1
+            "#,
+    )
+    .bind(user_id)
+    .fetch_one(&pool)
+    .await?;
+
+    let admin = result.0 == 1;
+
+    if !admin {
+        return Err(ServerError::NoAdminPermissions);
+    }
+
+    // The store has to be the one of the running worlds. The restore waits
+    // for the next save of the world, which then replaces the running one.
+    let (game_id, restored) = store.restore_snapshot(restore.snapshot_id).await?;
+    tokio::task::spawn(async move {
+        if restored.await.is_err() {
+            tracing::info!("game {} ended before it was restored", game_id);
+            return;
+        }
+        if let Err(err) = run_world(&game_state, &store, game_id).await {
+            tracing::error!("could not load restored game {}: {}", game_id, err);
+        }
+    });
 
     Ok(Redirect::to("/admin").into_response())
 }
@@ -336,3 +436,224 @@ pub async fn post_update_settings(
 
     Ok(Redirect::to("/admin").into_response())
 }
+
+/// The filters of the ledger page. Empty fields of the form are sent as
+/// empty strings, so they are parsed by hand.
+#[derive(Debug, Deserialize, Default)]
+pub struct LedgerQuery {
+    game_id: Option<String>,
+    user_id: Option<String>,
+    counterparty: Option<String>,
+    reason: Option<String>,
+}
+
+fn parse_filter(value: &Option<String>) -> Result<Option<i64>, ServerError> {
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => Ok(Some(value.parse()?)),
+    }
+}
+
+/// How many entries the ledger page shows at most.
+const LEDGER_LIMIT: i64 = 500;
+
+/// The reasons of the entries where something changed hands between two
+/// players.
+const SETTLED_TRADE_REASONS: &[LedgerReason] = &[
+    LedgerReason::TradeWon,
+    LedgerReason::TradeSold,
+    LedgerReason::BuyOrderFilled,
+    LedgerReason::OfferAccepted,
+];
+
+struct LedgerRow {
+    game_id: i64,
+    user_id: i64,
+    username: String,
+    time: i64,
+    reason: String,
+    money: i64,
+    items: String,
+    counterparty: Option<i64>,
+    counterparty_username: String,
+    created: time::PrimitiveDateTime,
+}
+
+/// Two players that traded with each other in a world.
+struct TradingPair {
+    game_id: i64,
+    user_id: i64,
+    username: String,
+    counterparty: i64,
+    counterparty_username: String,
+    trades: i64,
+    money: i64,
+}
+
+#[derive(Template)]
+#[template(path = "admin-ledger.html")]
+pub struct AdminLedgerTemplate {
+    game_id: Option<i64>,
+    user_id: Option<i64>,
+    counterparty: Option<i64>,
+    reason: String,
+    reasons: Vec<LedgerReason>,
+    entries: Vec<LedgerRow>,
+    limit: i64,
+    pairs: Vec<TradingPair>,
+}
+
+fn format_items(items: &str) -> String {
+    serde_json::from_str::<CustomMap<Item, i64>>(items)
+        .map(|items| {
+            items
+                .iter()
+                .filter(|(_, qty)| **qty != 0)
+                .map(|(item, qty)| format!("{:+} {}", qty, item))
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_else(|_| items.to_owned())
+}
+
+pub async fn get_ledger(
+    session: Session,
+    Extension(pool): Extension<SqlitePool>,
+    Query(query): Query<LedgerQuery>,
+) -> Result<Response, ServerError> {
+    let user_id = session
+        .get::<i64>(crate::USER_ID_KEY)
+        .await?
+        .ok_or(ServerError::InvalidSession)?;
+
+    let result: (i64,) = sqlx::query_as(
+        r#"
+                SELECT admin
+                FROM users
+                WHERE user_id = $1
+            "#,
+    )
+    .bind(user_id)
+    .fetch_one(&pool)
+    .await?;
+
+    let admin = result.0 == 1;
+
+    if !admin {
+        return Err(ServerError::NoAdminPermissions);
+    }
+
+    let game_id = parse_filter(&query.game_id)?;
+    let user_id = parse_filter(&query.user_id)?;
+    let counterparty = parse_filter(&query.counterparty)?;
+    let reason = query
+        .reason
+        .as_deref()
+        .and_then(|reason| LedgerReason::from_str(reason).ok());
+
+    #[allow(clippy::type_complexity)]
+    let entries: Vec<(i64, i64, Option<String>, i64, String, i64, String, Option<i64>, Option<String>, time::PrimitiveDateTime)> =
+        sqlx::query_as(
+            r#"
+                SELECT ledger.game_id, ledger.user_id, users.username, ledger.time, ledger.reason,
+                    ledger.money, ledger.items, ledger.counterparty, counterparties.username, ledger.created
+                FROM ledger
+                LEFT JOIN users ON users.user_id = ledger.user_id
+                LEFT JOIN users AS counterparties ON counterparties.user_id = ledger.counterparty
+                WHERE ($1 IS NULL OR ledger.game_id = $1)
+                AND ($2 IS NULL OR ledger.user_id = $2)
+                AND ($3 IS NULL OR ledger.counterparty = $3)
+                AND ($4 IS NULL OR ledger.reason = $4)
+                ORDER BY ledger.id DESC
+                LIMIT $5
+            "#,
+        )
+        .bind(game_id)
+        .bind(user_id)
+        .bind(counterparty)
+        .bind(reason.map(|reason| reason.to_string()))
+        .bind(LEDGER_LIMIT)
+        .fetch_all(&pool)
+        .await?;
+
+    let entries = entries
+        .into_iter()
+        .map(
+            |(game_id, user_id, username, time, reason, money, items, counterparty, counterparty_username, created)| {
+                LedgerRow {
+                    game_id,
+                    user_id,
+                    username: username.unwrap_or_else(|| String::from("[deleted]")),
+                    time,
+                    reason,
+                    money,
+                    items: format_items(&items),
+                    counterparty,
+                    counterparty_username: counterparty_username.unwrap_or_else(|| String::from("[deleted]")),
+                    created,
+                }
+            },
+        )
+        .collect();
+
+    // Many settled trades between the same two accounts are a hint for
+    // wash trading, so the pairs with the most trades come first.
+    let settled_trade_reasons = serde_json::to_string(
+        &SETTLED_TRADE_REASONS
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+
+    let pairs: Vec<(i64, i64, Option<String>, i64, Option<String>, i64, i64)> = sqlx::query_as(
+        r#"
+            SELECT ledger.game_id, ledger.user_id, users.username, ledger.counterparty,
+                counterparties.username, COUNT(*), SUM(ledger.money)
+            FROM ledger
+            LEFT JOIN users ON users.user_id = ledger.user_id
+            LEFT JOIN users AS counterparties ON counterparties.user_id = ledger.counterparty
+            WHERE ledger.counterparty IS NOT NULL
+            AND ledger.reason IN (SELECT value FROM json_each($1))
+            AND ($2 IS NULL OR ledger.game_id = $2)
+            AND ($3 IS NULL OR ledger.user_id = $3 OR ledger.counterparty = $3)
+            AND ($4 IS NULL OR ledger.user_id = $4 OR ledger.counterparty = $4)
+            GROUP BY ledger.game_id, ledger.user_id, ledger.counterparty
+            ORDER BY COUNT(*) DESC
+            LIMIT 50
+        "#,
+    )
+    .bind(settled_trade_reasons)
+    .bind(game_id)
+    .bind(user_id)
+    .bind(counterparty)
+    .fetch_all(&pool)
+    .await?;
+
+    let pairs = pairs
+        .into_iter()
+        .map(
+            |(game_id, user_id, username, counterparty, counterparty_username, trades, money)| TradingPair {
+                game_id,
+                user_id,
+                username: username.unwrap_or_else(|| String::from("[deleted]")),
+                counterparty,
+                counterparty_username: counterparty_username.unwrap_or_else(|| String::from("[deleted]")),
+                trades,
+                money,
+            },
+        )
+        .collect();
+
+    Ok(AdminLedgerTemplate {
+        game_id,
+        user_id,
+        counterparty,
+        reason: reason.map(|reason| reason.to_string()).unwrap_or_default(),
+        reasons: enum_iterator::all::<LedgerReason>().collect(),
+        entries,
+        limit: LEDGER_LIMIT,
+        pairs,
+    }
+    .into_response())
+}
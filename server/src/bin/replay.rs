@@ -0,0 +1,203 @@
+//! Rebuilds a world from a snapshot and its journal, to reproduce bugs in
+//! `State::update` and to check that the world still replays the same.
+//!
+//! Usage: `cargo run --release --bin replay -- --game 3 --until 86400`
+//!
+//! Options: `--game <id>`, `--snapshot <id>` to start from a snapshot other
+//! than the latest one before `--until <tick>`, `--journal <file>` if the
+//! journal is not in `JOURNAL_DIR`, `--out <file>` to write the replayed
+//! state as a save.
+//!
+//! Every checkpoint in the journal is compared with the replayed state, and
+//! the replay stops at the first one that differs. Without `--until` the
+//! world is replayed to the end of the journal and compared with the live
+//! state in `games.data`.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use engine_shared::{utils::custom_map::CustomMap, GameId};
+use shared::{save, JournalEntry, JournalReader, State, Time, UserData, UserId};
+use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
+
+/// Same as `game::journal_path` of the server.
+fn journal_path(game_id: GameId) -> PathBuf {
+    PathBuf::from(dotenv::var("JOURNAL_DIR").unwrap_or_else(|_| "journal".into()))
+        .join(format!("{}.journal", game_id))
+}
+
+/// Finds a snapshot of the world with the given time and checksum, for when
+/// the world was restored from one.
+async fn find_snapshot(pool: &SqlitePool, game_id: GameId, time: Time, checksum: u64) -> Option<State> {
+    let snapshots: Vec<(Vec<u8>,)> = sqlx::query_as(
+        r#"
+            SELECT data
+            FROM game_snapshots
+            WHERE game_id = $1
+            AND time = $2
+        "#,
+    )
+    .bind(game_id)
+    .bind(time as i64)
+    .fetch_all(pool)
+    .await
+    .expect("could not read the snapshots");
+
+    snapshots
+        .into_iter()
+        .filter_map(|(data,)| save::decode(&data).ok())
+        .find(|state| state.checksum() == checksum)
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args: HashMap<String, String> = HashMap::new();
+    let mut raw = std::env::args().skip(1);
+    while let Some(key) = raw.next() {
+        let key = key.trim_start_matches("--").to_owned();
+        args.insert(key, raw.next().expect("missing value for argument"));
+    }
+
+    let game_id: GameId = args.get("game").expect("missing --game").parse().unwrap();
+    let snapshot_id: Option<i64> = args.get("snapshot").map(|id| id.parse().unwrap());
+    let until: Option<Time> = args.get("until").map(|tick| tick.parse().unwrap());
+    let journal = args.get("journal").map(PathBuf::from).unwrap_or_else(|| journal_path(game_id));
+
+    let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", dotenv::var("DATABASE_FILE").unwrap()))
+        .unwrap()
+        .read_only(true);
+    let pool = SqlitePool::connect_with(options).await.expect("could not open the database");
+
+    let (snapshot_id, data): (i64, Vec<u8>) = sqlx::query_as(
+        r#"
+            SELECT id, data
+            FROM game_snapshots
+            WHERE game_id = $1
+            AND ($2 IS NULL OR id = $2)
+            AND time <= $3
+            ORDER BY time DESC, id DESC
+            LIMIT 1
+        "#,
+    )
+    .bind(game_id)
+    .bind(snapshot_id)
+    .bind(until.map(|until| until as i64).unwrap_or(i64::MAX))
+    .fetch_optional(&pool)
+    .await
+    .expect("could not read the snapshots")
+    .expect("no snapshot of the world before the given tick");
+
+    let mut state = save::decode(&data).expect("could not decode the snapshot");
+    let start = (state.time, state.checksum());
+    println!("starting from snapshot {} at time {}", snapshot_id, state.time);
+
+    let reader = JournalReader::new(BufReader::new(File::open(&journal).expect("could not open the journal")));
+    let mut user_data: CustomMap<UserId, UserData> = CustomMap::new();
+    let mut started = false;
+    let mut events = 0;
+    let mut checkpoints = 0;
+
+    for entry in reader {
+        let entry = entry.expect("could not read the journal");
+
+        // Skip to the entry the snapshot was taken at, but keep track of
+        // the user data on the way.
+        if !started {
+            match entry {
+                JournalEntry::UserData(data) => user_data = data,
+                JournalEntry::Checkpoint(time, checksum) | JournalEntry::Loaded(time, checksum) => {
+                    started = (time, checksum) == start;
+                }
+                JournalEntry::Event(..) => {}
+            }
+            continue;
+        }
+
+        match entry {
+            JournalEntry::UserData(data) => user_data = data,
+            JournalEntry::Event(seed, event) => {
+                let event = event.into_event();
+                if let (Some(until), engine_shared::Event::ServerEvent(_)) = (until, &event) {
+                    if state.time >= until {
+                        break;
+                    }
+                }
+                state.update_seeded(seed, event, &user_data);
+                events += 1;
+            }
+            JournalEntry::Checkpoint(time, checksum) => {
+                let replayed = state.checksum();
+                if (state.time, replayed) != (time, checksum) {
+                    println!(
+                        "diverged at the checkpoint at time {}: replayed time {} with checksum {:016x}, recorded checksum {:016x}",
+                        time, state.time, replayed, checksum
+                    );
+                    std::process::exit(1);
+                }
+                checkpoints += 1;
+            }
+            JournalEntry::Loaded(time, checksum) => {
+                // The world continues from a state other than the replayed
+                // one if it was restored from a snapshot.
+                if (state.time, state.checksum()) != (time, checksum) {
+                    state = find_snapshot(&pool, game_id, time, checksum).await.unwrap_or_else(|| {
+                        println!("the world was loaded from an unknown state at time {}", time);
+                        std::process::exit(1);
+                    });
+                    println!("the world was restored to time {}", time);
+                }
+            }
+        }
+    }
+
+    if !started {
+        println!("the journal has no checkpoint of the snapshot");
+        std::process::exit(1);
+    }
+
+    println!(
+        "replayed {} events and {} checkpoints to time {}, checksum {:016x}",
+        events,
+        checkpoints,
+        state.time,
+        state.checksum()
+    );
+
+    if let Some(out) = args.get("out") {
+        std::fs::write(out, save::encode(&state).expect("could not encode the state"))
+            .expect("could not write the state");
+        println!("wrote {}", out);
+    }
+
+    let live: Option<(Option<Vec<u8>>,)> = sqlx::query_as(
+        r#"
+            SELECT data
+            FROM games
+            WHERE id = $1
+        "#,
+    )
+    .bind(game_id)
+    .fetch_optional(&pool)
+    .await
+    .expect("could not read the world");
+
+    match live.and_then(|(data,)| data) {
+        Some(data) => {
+            let live = save::decode(&data).expect("could not decode the live world");
+            if live.time != state.time {
+                println!("the live world is at time {}, not compared", live.time);
+            } else if live.checksum() == state.checksum() {
+                println!("matches the live world");
+            } else {
+                println!("differs from the live world with checksum {:016x}", live.checksum());
+                std::process::exit(1);
+            }
+        }
+        None => println!("the world is closed, not compared"),
+    }
+}
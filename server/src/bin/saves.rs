@@ -0,0 +1,59 @@
+//! Writes the golden saves that make sure old worlds still load, the tests
+//! of `shared::save` decode them.
+//!
+//! Usage: `cargo run --release --bin saves` adds the golden saves of the
+//! current save version to `shared/fixtures/saves`, run it after adding a
+//! migration. It takes another directory as an optional argument.
+
+use std::path::PathBuf;
+
+use shared::{
+    save::{self, SAVE_VERSION},
+    sim::{Script, Simulation},
+    ClientEvent, GameMode, State, UserId, WorldSettings, ONE_HOUR, ONE_MINUTE,
+};
+
+/// The states that are saved in every version: a fresh world and one that
+/// has been played for a few hours.
+fn golden_states() -> Vec<(&'static str, State)> {
+    let mut simulation = Simulation::new(GameMode::Speed, 0);
+    for user_id in 1..=3 {
+        simulation.add_player(
+            UserId(user_id),
+            user_id == 1,
+            Script::new()
+                .every(ONE_MINUTE, ClientEvent::NextTutorialStep)
+                .every(ONE_MINUTE * 10, ClientEvent::UpgradeBase)
+                .every(ONE_MINUTE * 10, ClientEvent::Optimize(None)),
+        );
+    }
+    simulation.run(ONE_HOUR * 6, ONE_HOUR * 6);
+
+    vec![
+        ("new-ranked", State::with_settings(WorldSettings::from(GameMode::Ranked))),
+        ("played-speed", simulation.state),
+    ]
+}
+
+fn write(dir: PathBuf) {
+    std::fs::create_dir_all(&dir).expect("could not create the directory");
+    for (name, state) in golden_states() {
+        let path = dir.join(format!("v{}-{}.save", SAVE_VERSION, name));
+        if path.exists() {
+            eprintln!("skipping {}, golden saves are never overwritten", path.display());
+            continue;
+        }
+        std::fs::write(&path, save::encode(&state).expect("could not encode the state"))
+            .expect("could not write the save");
+        println!("wrote {}", path.display());
+    }
+}
+
+fn main() {
+    let dir = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/../shared/fixtures/saves")));
+
+    write(dir);
+}
@@ -0,0 +1,198 @@
+//! Runs a world offline with synthetic players and prints a CSV report.
+//!
+//! Usage: `cargo run --release --bin simulate -- --mode Speed --players 10 --days 14`
+//!
+//! Options: `--mode <Ranked|Speed|Infinite>`, `--players <n>`, `--days <n>`,
+//! `--seed <n>`, `--report-every <hours>`, `--premium`.
+
+use std::{collections::HashMap, str::FromStr};
+
+use shared::{
+    sim::{Simulation, Strategy},
+    ClientEvent, GameMode, Item, Occupation, State, UserId, MAX_LEVEL, ONE_DAY,
+    ONE_HOUR, ONE_MINUTE,
+};
+
+/// Plays like an attentive player that checks in every few minutes: it
+/// follows the tutorial, upgrades the settlement whenever possible, keeps a
+/// quarter of its dwarfs idling and spreads the others over the unlocked
+/// occupations, cooks all the food and equips the best tools.
+struct Attentive {
+    interval: u64,
+}
+
+const WORK_OCCUPATIONS: &[Occupation] = &[
+    Occupation::Logging,
+    Occupation::Mining,
+    Occupation::Hunting,
+    Occupation::Gathering,
+    Occupation::Fishing,
+    Occupation::Exploring,
+    Occupation::Farming,
+    Occupation::Rockhounding,
+];
+
+const FOOD_RESERVE: u64 = 1000;
+
+impl Strategy for Attentive {
+    fn events(&mut self, state: &State, user_id: UserId) -> Vec<ClientEvent> {
+        if !state.time.is_multiple_of(self.interval) {
+            return Vec::new();
+        }
+
+        let Some(player) = state.players.get(&user_id) else {
+            return Vec::new();
+        };
+
+        let mut events = vec![ClientEvent::NextTutorialStep, ClientEvent::UpgradeBase];
+
+        let count = |item: Item| player.inventory.items.get(&item).copied().unwrap_or_default();
+
+        // Burn just enough wood to cook all the raw food. Crafts are queued,
+        // so the next batch waits until the last one is done, and the cooked
+        // food is stored once it is in the inventory.
+        let cooking = [Item::Coal, Item::CookedMeat, Item::CookedFish]
+            .into_iter()
+            .any(|item| player.queued(item) > 0);
+        if !cooking {
+            let raw_food = count(Item::RawMeat) + count(Item::RawFish);
+            let coal = count(Item::Coal)
+                + raw_food
+                    .saturating_sub(count(Item::Coal))
+                    .min(count(Item::Wood) / 3);
+            if coal > count(Item::Coal) {
+                events.push(ClientEvent::Craft(Item::Coal, coal - count(Item::Coal)));
+            }
+            let meat = count(Item::RawMeat).min(coal);
+            let fish = count(Item::RawFish).min(coal - meat);
+            for (food, qty) in [(Item::CookedMeat, meat), (Item::CookedFish, fish)] {
+                if qty > 0 {
+                    events.push(ClientEvent::Craft(food, qty));
+                }
+            }
+        }
+        for (&item, &qty) in player.inventory.items.iter() {
+            if qty > 0
+                && item.nutritional_value().is_some()
+                && !matches!(item, Item::RawMeat | Item::RawFish)
+            {
+                events.push(ClientEvent::AddToFoodStorage(item, qty));
+            }
+        }
+
+        let managed = player
+            .dwarfs
+            .values()
+            .filter(|dwarf| dwarf.can_be_managed())
+            .count() as u64;
+        // Hunters count twice while the food storage runs low.
+        let mut shares = WORK_OCCUPATIONS
+            .iter()
+            .copied()
+            .filter(|occupation| occupation.unlocked_at_level() <= player.base.curr_level)
+            .collect::<Vec<_>>();
+        for occupation in &shares {
+            events.push(ClientEvent::SetManagerOccupation(*occupation, 0));
+        }
+        if player.base.food < FOOD_RESERVE {
+            shares.push(Occupation::Hunting);
+        }
+
+        let workers = managed - managed / 4;
+        let mut manager: HashMap<Occupation, u64> = HashMap::new();
+        for idx in 0..workers as usize {
+            *manager.entry(shares[idx % shares.len()]).or_default() += 1;
+        }
+        for (occupation, num) in manager {
+            events.push(ClientEvent::SetManagerOccupation(occupation, num));
+        }
+
+        for tool in [Item::Axe, Item::Pickaxe] {
+            if count(tool) + player.queued(tool) < managed / 4 {
+                events.push(ClientEvent::Craft(tool, 1));
+            }
+        }
+
+        events.push(ClientEvent::Optimize(None));
+
+        events
+    }
+}
+
+fn main() {
+    let mut args: HashMap<String, String> = HashMap::new();
+    let mut raw = std::env::args().skip(1);
+    while let Some(key) = raw.next() {
+        let key = key.trim_start_matches("--").to_owned();
+        if key == "premium" {
+            args.insert(key, "true".to_owned());
+        } else {
+            args.insert(key, raw.next().expect("missing value for argument"));
+        }
+    }
+
+    let game_mode = args
+        .get("mode")
+        .map(|mode| GameMode::from_str(mode).expect("unknown game mode"))
+        .unwrap_or(GameMode::Ranked);
+    let players: i64 = args.get("players").map(|n| n.parse().unwrap()).unwrap_or(10);
+    let days: u64 = args.get("days").map(|n| n.parse().unwrap()).unwrap_or(7);
+    let seed: u64 = args.get("seed").map(|n| n.parse().unwrap()).unwrap_or(0);
+    let report_every: u64 = args
+        .get("report-every")
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(24);
+    let premium = args.contains_key("premium");
+
+    let mut simulation = Simulation::new(game_mode, seed);
+    for user_id in 1..=players {
+        simulation.add_player(
+            UserId(user_id),
+            premium,
+            Attentive {
+                interval: ONE_MINUTE * 10,
+            },
+        );
+    }
+
+    println!("hours,user_id,level,money,dwarfs,adult_dwarfs,food,items");
+
+    let report_ticks = (report_every * ONE_HOUR).max(1);
+    let mut max_level_reached = None;
+    for tick in 1..=(days * ONE_DAY) / report_ticks * report_ticks {
+        simulation.step();
+
+        if max_level_reached.is_none()
+            && simulation
+                .state
+                .players
+                .values()
+                .any(|player| player.base.curr_level >= MAX_LEVEL)
+        {
+            max_level_reached = Some(simulation.state.time);
+        }
+
+        if tick.is_multiple_of(report_ticks) {
+            let report = simulation.report();
+            for player in report.players {
+                println!(
+                    "{},{},{},{},{},{},{},{}",
+                    report.time / ONE_HOUR,
+                    player.user_id.0,
+                    player.level,
+                    player.money,
+                    player.dwarfs,
+                    player.adult_dwarfs,
+                    player.food,
+                    player.items.values().sum::<u64>(),
+                );
+            }
+        }
+    }
+
+    if let Some(time) = max_level_reached {
+        eprintln!("level {} reached after {} hours", MAX_LEVEL, time / ONE_HOUR);
+    } else {
+        eprintln!("level {} not reached", MAX_LEVEL);
+    }
+}
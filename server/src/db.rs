@@ -39,6 +39,7 @@ pub async fn setup() -> Result<SqlitePool, Box<dyn std::error::Error>> {
             closed INTEGER NOT NULL DEFAULT 0,
             winner INTEGER,
             game_mode TEXT,
+            settings TEXT,
             FOREIGN KEY(winner) REFERENCES users(user_id) ON DELETE SET NULL
         )
     "#,
@@ -46,6 +47,104 @@ pub async fn setup() -> Result<SqlitePool, Box<dyn std::error::Error>> {
     .execute(&mut *transaction)
     .await?;
 
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS game_snapshots (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            game_id INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            data BLOB NOT NULL,
+            created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY(game_id) REFERENCES games(id) ON DELETE CASCADE
+        )
+    "#,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS game_snapshots_game_id ON game_snapshots (game_id, created)
+    "#,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS ledger (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            game_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            reason TEXT NOT NULL,
+            money INTEGER NOT NULL,
+            items TEXT NOT NULL,
+            counterparty INTEGER,
+            created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY(game_id) REFERENCES games(id)
+        )
+    "#,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS ledger_game_id_user_id ON ledger (game_id, user_id)
+    "#,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS ledger_user_id ON ledger (user_id)
+    "#,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let (has_settings_column,): (i64,) = sqlx::query_as(
+        r#"
+        SELECT COUNT(*) FROM pragma_table_info('games') WHERE name = 'settings'
+    "#,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    if has_settings_column == 0 {
+        tracing::info!("adding settings column to games");
+
+        sqlx::query(
+            r#"
+            ALTER TABLE games ADD COLUMN settings TEXT
+        "#,
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    // The compressed final state and the results of finished worlds.
+    for (column, column_type) in [("archive", "BLOB"), ("results", "TEXT")] {
+        let (has_column,): (i64,) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*) FROM pragma_table_info('games') WHERE name = $1
+        "#,
+        )
+        .bind(column)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        if has_column == 0 {
+            tracing::info!("adding {} column to games", column);
+
+            sqlx::query(&format!("ALTER TABLE games ADD COLUMN {column} {column_type}"))
+                .execute(&mut *transaction)
+                .await?;
+        }
+    }
+
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS settings (
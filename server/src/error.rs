@@ -1,4 +1,5 @@
 use askama_axum::IntoResponse;
+use engine_shared::GameId;
 use axum::{
     http::StatusCode,
     response::{Redirect, Response},
@@ -34,6 +35,22 @@ pub enum ServerError {
     GuestAccountError,
     #[error("encoding error: {0}")]
     EncodingError(#[from] rmp_serde::encode::Error),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("invalid world settings: {0}")]
+    InvalidWorldSettings(String),
+    #[error("save error: {0}")]
+    SaveError(#[from] shared::save::SaveError),
+    #[error("game {0} not found")]
+    GameNotFound(GameId),
+    #[error("could not load game {0}: {1}")]
+    LoadError(GameId, shared::save::SaveError),
+    #[error("snapshot {0} not found")]
+    SnapshotNotFound(i64),
+    #[error("game {0} is already being restored")]
+    RestorePending(GameId),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
 }
 
 impl IntoResponse for ServerError {
@@ -45,9 +62,15 @@ impl IntoResponse for ServerError {
             ServerError::NoAdminPermissions => {
                 (StatusCode::UNAUTHORIZED, format!("{self}")).into_response()
             }
-            ServerError::ValidationError(_) => {
+            ServerError::ValidationError(_) | ServerError::InvalidWorldSettings(_) => {
                 (StatusCode::BAD_REQUEST, format!("{self}")).into_response()
             }
+            ServerError::GameNotFound(_) | ServerError::SnapshotNotFound(_) => {
+                (StatusCode::NOT_FOUND, format!("{self}")).into_response()
+            }
+            ServerError::RestorePending(_) => {
+                (StatusCode::CONFLICT, format!("{self}")).into_response()
+            }
             _ => {
                 tracing::error!("an internal server error occurred: {self}");
                 (StatusCode::INTERNAL_SERVER_ERROR, format!("{self}")).into_response()
@@ -1,4 +1,10 @@
-use std::str::FromStr;
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
 use askama::Template;
 use askama_axum::{IntoResponse, Response};
@@ -14,10 +20,15 @@ use engine_server::BackendStore;
 use engine_shared::{utils::custom_map::CustomMap, GameId};
 use futures_util::{sink::SinkExt, stream::StreamExt};
 use serde::{Deserialize, Serialize};
-use shared::{ClientEvent, GameMode, UserData, UserId};
+use shared::{
+    save, ClientEvent, GameMode, Journal, JournalEntry, Ledger, LedgerEntry, Time, UserData, UserId,
+    WorldResults, WorldSettings, ONE_DAY, ONE_HOUR,
+};
 use sqlx::SqlitePool;
+use tokio::sync::oneshot;
 use tower_sessions::Session;
 use engine_shared::{State, Settings};
+use flate2::{write::GzEncoder, Compression};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PartialEventData {
@@ -29,20 +40,124 @@ use crate::ServerError;
 
 pub type GameState = engine_server::ServerState<shared::State, GameStore>;
 
+/// Loads a world and, once it is finished, the worlds that follow it. Used
+/// for every world that is started, so that all of them are restarted.
+pub async fn run_world(game_state: &GameState, store: &GameStore, game_id: GameId) -> Result<(), ServerError> {
+    let mut game_finished = game_state.load(game_id).await?;
+    let game_state = game_state.clone();
+    let store = store.clone();
+
+    tokio::task::spawn(async move {
+        let mut game_id = game_id;
+        loop {
+            game_finished.notified().await;
+
+            let next = match store.next_world(game_id).await {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::error!("could not restart game {}: {}", game_id, err);
+                    break;
+                }
+            };
+
+            match game_state.load(next).await {
+                Ok(next_finished) => {
+                    game_id = next;
+                    game_finished = next_finished;
+                }
+                Err(err) => {
+                    tracing::error!("could not load game {}: {}", next, err);
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Compresses the final state of a world for the archive.
+fn archive(state: &shared::State) -> Result<Vec<u8>, ServerError> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&save::encode(state)?)?;
+    Ok(encoder.finish()?)
+}
+
+/// The file with the journal of a world, see `shared::Journal`.
+fn journal_path(game_id: GameId) -> PathBuf {
+    PathBuf::from(dotenv::var("JOURNAL_DIR").unwrap_or_else(|_| "journal".into()))
+        .join(format!("{}.journal", game_id))
+}
+
+fn open_journal(game_id: GameId) -> Result<Journal, ServerError> {
+    let path = journal_path(game_id);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(Journal::recording(BufWriter::new(file)))
+}
+
+pub struct Snapshot {
+    pub id: i64,
+    pub game_id: GameId,
+    pub time: Time,
+    pub created: time::PrimitiveDateTime,
+}
+
+/// A snapshot waiting to be put back into its world, see
+/// `GameStore::restore_snapshot`.
+struct Restore {
+    snapshot_id: i64,
+    data: Vec<u8>,
+    restored: oneshot::Sender<()>,
+}
+
 #[derive(Clone)]
 pub struct GameStore {
     db: SqlitePool,
+    restores: Arc<Mutex<CustomMap<GameId, Restore>>>,
+    /// The journals of the loaded worlds, which record the user data
+    /// whenever it is loaded. Only the world a journal belongs to is saved,
+    /// so a world that was replaced by a restore can't overwrite it.
+    journals: Arc<Mutex<CustomMap<GameId, Journal>>>,
 }
 
 impl GameStore {
     pub fn new(db: SqlitePool) -> Self {
-        Self { db }
+        Self {
+            db,
+            restores: Arc::default(),
+            journals: Arc::default(),
+        }
+    }
+
+    /// Inserts a new world with the given settings, the settings are also stored
+    /// in their own column so that they survive the end of the world.
+    pub async fn create_with_settings(&self, settings: &WorldSettings) -> Result<GameId, ServerError> {
+        let data: Vec<u8> = save::encode(&shared::State::with_settings(settings.clone()))?;
+
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+                INSERT INTO games (data, winner, game_mode, settings)
+                VALUES ($1, NULL, $2, $3)
+                RETURNING id
+            "#,
+        )
+        .bind(data)
+        .bind(settings.game_mode.to_string())
+        .bind(serde_json::to_string(settings).unwrap())
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(id)
     }
 
     pub async fn load_all(self) -> Result<GameState, ServerError> {
-        let open_worlds: Vec<(GameId, String)> = sqlx::query_as(
+        let open_worlds: Vec<(GameId,)> = sqlx::query_as(
             r#"
-                    SELECT id, game_mode
+                    SELECT id
                     FROM games
                     WHERE closed = 0
                 "#,
@@ -50,82 +165,174 @@ impl GameStore {
         .fetch_all(&self.db)
         .await?;
 
-        let pool = self.db.clone();
+        let store = self.clone();
         let game_state = GameState::new(self);
 
-        for (id, game_mode) in open_worlds {
-            let game_finished = game_state.load(id).await?;
-            let game_state_clone = game_state.clone();
-            let pool_clone = pool.clone();
-
-            tokio::task::spawn(async move {
-                game_finished.notified().await;
-
-                let result: Result<(i64,), _> = sqlx::query_as(
-                    r#"
-                        SELECT auto_start_world
-                        FROM settings
-                        LIMIT 1
-                    "#,
-                )
-                .fetch_one(&pool_clone)
-                .await;
-            
-                if result.map(|result| result.0 != 0).unwrap_or(false) {
-                    game_state_clone.create(GameMode::from_str(&game_mode).unwrap_or(GameMode::Ranked)).await.unwrap();
-                }
-            });
+        for (id,) in open_worlds {
+            // A world that can't be loaded stops the server instead of
+            // silently missing from the game.
+            if let Err(err) = run_world(&game_state, &store, id).await {
+                tracing::error!("could not load game {}: {}", id, err);
+                return Err(err);
+            }
         }
 
         Ok(game_state)
     }
-}
 
-#[async_trait::async_trait]
-impl engine_server::BackendStore<shared::State> for GameStore {
-    type Error = ServerError;
+    /// The settings a world was created with, worlds from before custom
+    /// settings only have their game mode.
+    async fn world_settings(&self, game_id: GameId) -> Result<WorldSettings, ServerError> {
+        let (game_mode, settings): (String, Option<String>) = sqlx::query_as(
+            r#"
+                SELECT game_mode, settings
+                FROM games
+                WHERE id = $1
+            "#,
+        )
+        .bind(game_id)
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(ServerError::GameNotFound(game_id))?;
 
-    async fn create_game(&self, gamemode: GameMode) -> Result<GameId, Self::Error> {
-        let data: Vec<u8> = rmp_serde::to_vec(&shared::State::new(gamemode)).unwrap();
+        Ok(settings
+            .and_then(|settings| serde_json::from_str::<WorldSettings>(&settings).ok())
+            .unwrap_or_else(|| WorldSettings::from(GameMode::from_str(&game_mode).unwrap_or(GameMode::Ranked))))
+    }
 
-        let (id,): (i64,) = sqlx::query_as(
+    /// Creates the world that follows a finished one, if worlds are started
+    /// automatically. Custom worlds are restarted with the same settings.
+    async fn next_world(&self, game_id: GameId) -> Result<Option<GameId>, ServerError> {
+        let (auto_start_world,): (i64,) = sqlx::query_as(
             r#"
-                INSERT INTO games (data, winner, game_mode)
-                VALUES ($1, NULL, $2)
-                RETURNING id
+                SELECT auto_start_world
+                FROM settings
+                LIMIT 1
             "#,
         )
-        .bind(data)
-        .bind(gamemode.to_string())
         .fetch_one(&self.db)
         .await?;
 
-        Ok(id)
+        if auto_start_world == 0 {
+            return Ok(None);
+        }
+
+        let settings = self.world_settings(game_id).await?;
+        Ok(Some(self.create_with_settings(&settings).await?))
     }
 
-    async fn load_game(&self, game_id: GameId) -> Result<shared::State, Self::Error> {
-        let result: Option<(Option<Vec<u8>>,)> = sqlx::query_as(
+    /// Keeps a snapshot of a running world at most once per hour. Snapshots
+    /// older than a day are thinned out to the last one of each day.
+    async fn snapshot(&self, game_id: GameId, time: Time, data: &[u8]) -> Result<(), ServerError> {
+        let mut tx = self.db.begin().await?;
+
+        let (recent,): (i64,) = sqlx::query_as(
             r#"
-                    SELECT data
-                    FROM games
-                    WHERE id = $1
-                "#,
+                SELECT COUNT(*)
+                FROM game_snapshots
+                WHERE game_id = $1
+                AND created > DATETIME('now', '-1 hour')
+            "#,
         )
         .bind(game_id)
-        .fetch_optional(&self.db)
+        .fetch_one(&mut *tx)
         .await?;
 
-        let state: shared::State = result
-            .map(|(data,)| {
-                data.map(|data| rmp_serde::from_slice(&data[..]).unwrap())
-                    .unwrap()
-            })
-            .unwrap();
+        if recent == 0 {
+            sqlx::query(
+                r#"
+                    INSERT INTO game_snapshots (game_id, time, data)
+                    VALUES ($1, $2, $3)
+                "#,
+            )
+            .bind(game_id)
+            .bind(time as i64)
+            .bind(data)
+            .execute(&mut *tx)
+            .await?;
 
-        Ok(state)
+            sqlx::query(
+                r#"
+                    DELETE FROM game_snapshots
+                    WHERE game_id = $1
+                    AND created < DATETIME('now', '-1 day')
+                    AND id NOT IN (
+                        SELECT MAX(id)
+                        FROM game_snapshots
+                        WHERE game_id = $1
+                        AND created < DATETIME('now', '-1 day')
+                        GROUP BY DATE(created)
+                    )
+                "#,
+            )
+            .bind(game_id)
+            .execute(&mut *tx)
+            .await?;
+
+            tracing::info!("game {} snapshotted, ingame time {}", game_id, time);
+        }
+
+        tx.commit().await?;
+
+        Ok(())
     }
 
-    async fn load_user_data(&self) -> Result<CustomMap<UserId, UserData>, Self::Error> {
+    /// Writes the money and items that changed hands since the last save.
+    async fn write_ledger(&self, game_id: GameId, entries: Vec<LedgerEntry>) -> Result<(), ServerError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.db.begin().await?;
+
+        for entry in entries {
+            sqlx::query(
+                r#"
+                    INSERT INTO ledger (game_id, user_id, time, reason, money, items, counterparty)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7)
+                "#,
+            )
+            .bind(game_id)
+            .bind(entry.user_id.0)
+            .bind(entry.time as i64)
+            .bind(entry.reason.to_string())
+            .bind(entry.money)
+            .bind(serde_json::to_string(&entry.items).unwrap())
+            .bind(entry.counterparty.map(|user_id| user_id.0))
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// The snapshots of all worlds, newest first.
+    pub async fn snapshots(&self) -> Result<Vec<Snapshot>, ServerError> {
+        let snapshots: Vec<(i64, GameId, i64, time::PrimitiveDateTime)> = sqlx::query_as(
+            r#"
+                SELECT id, game_id, time, created
+                FROM game_snapshots
+                ORDER BY created DESC, id DESC
+            "#,
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(snapshots
+            .into_iter()
+            .map(|(id, game_id, time, created)| Snapshot {
+                id,
+                game_id,
+                time: time as Time,
+                created,
+            })
+            .collect())
+    }
+
+    /// The user data of all users, as it is passed to the worlds.
+    async fn user_data(&self) -> Result<CustomMap<UserId, UserData>, ServerError> {
         let users: Vec<(i64, String, i64, i64, i64, i64, time::PrimitiveDateTime, Option<i64>, String)> =
             sqlx::query_as(
                 r#"
@@ -164,19 +371,210 @@ impl engine_server::BackendStore<shared::State> for GameStore {
         Ok(users)
     }
 
+    /// Puts the state of a snapshot back into its world on the next save of
+    /// the running world, so that its live state can be snapshotted first
+    /// and the restore can be undone. Returns the world and a receiver that
+    /// fires once the world has to be loaded again, the running world isn't
+    /// saved anymore from then on.
+    pub async fn restore_snapshot(
+        &self,
+        snapshot_id: i64,
+    ) -> Result<(GameId, oneshot::Receiver<()>), ServerError> {
+        let (game_id, data): (GameId, Vec<u8>) = sqlx::query_as(
+            r#"
+                SELECT game_id, data
+                FROM game_snapshots
+                WHERE id = $1
+            "#,
+        )
+        .bind(snapshot_id)
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(ServerError::SnapshotNotFound(snapshot_id))?;
+
+        // Don't replace a world with a snapshot that can't be loaded.
+        save::decode(&data).map_err(|err| ServerError::LoadError(game_id, err))?;
+
+        // Only running worlds are saved again.
+        let open: Option<(i64,)> = sqlx::query_as(
+            r#"
+                SELECT id
+                FROM games
+                WHERE id = $1
+                AND closed = 0
+            "#,
+        )
+        .bind(game_id)
+        .fetch_optional(&self.db)
+        .await?;
+
+        if open.is_none() {
+            return Err(ServerError::GameNotFound(game_id));
+        }
+
+        let mut restores = self.restores.lock().unwrap();
+        if restores.contains_key(&game_id) {
+            return Err(ServerError::RestorePending(game_id));
+        }
+
+        // The restore is dropped if the world ends before its next save.
+        let (restored, done) = oneshot::channel();
+        restores.insert(
+            game_id,
+            Restore {
+                snapshot_id,
+                data,
+                restored,
+            },
+        );
+
+        Ok((game_id, done))
+    }
+
+    /// Replaces the live state of a world with a restored snapshot and keeps
+    /// the live state as a snapshot of its own.
+    async fn apply_restore(&self, game_id: GameId, live: &[u8], time: Time, restore: &Restore) -> Result<(), ServerError> {
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query(
+            r#"
+                INSERT INTO game_snapshots (game_id, time, data)
+                VALUES ($1, $2, $3)
+            "#,
+        )
+        .bind(game_id)
+        .bind(time as i64)
+        .bind(live)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+                UPDATE games
+                SET data = $2
+                WHERE id = $1
+            "#,
+        )
+        .bind(game_id)
+        .bind(&restore.data)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        tracing::info!("game {} restored to snapshot {}", game_id, restore.snapshot_id);
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl engine_server::BackendStore<shared::State> for GameStore {
+    type Error = ServerError;
+
+    async fn create_game(&self, gamemode: GameMode) -> Result<GameId, Self::Error> {
+        self.create_with_settings(&WorldSettings::from(gamemode)).await
+    }
+
+    async fn load_game(&self, game_id: GameId) -> Result<shared::State, Self::Error> {
+        let result: Option<(Option<Vec<u8>>,)> = sqlx::query_as(
+            r#"
+                    SELECT data
+                    FROM games
+                    WHERE id = $1
+                "#,
+        )
+        .bind(game_id)
+        .fetch_optional(&self.db)
+        .await?;
+
+        let data = result
+            .and_then(|(data,)| data)
+            .ok_or(ServerError::GameNotFound(game_id))?;
+
+        let (version, _) = save::version(&data).map_err(|err| ServerError::LoadError(game_id, err))?;
+        if version < save::SAVE_VERSION {
+            tracing::info!("migrating game {} from save version {} to {}", game_id, version, save::SAVE_VERSION);
+        }
+
+        let mut state = save::decode(&data).map_err(|err| ServerError::LoadError(game_id, err))?;
+
+        state.ledger = Ledger::recording();
+
+        // A broken journal must not keep the world from running, the world
+        // still needs a journal of its own to be saved.
+        let journal = open_journal(game_id).unwrap_or_else(|err| {
+            tracing::error!("could not open the journal of game {}: {}", game_id, err);
+            Journal::recording(std::io::sink())
+        });
+        let user_data = self.user_data().await?;
+        journal.record(|| JournalEntry::Loaded(state.time, state.checksum()));
+        journal.record(|| JournalEntry::UserData(user_data));
+        self.journals.lock().unwrap().insert(game_id, journal.clone());
+        state.journal = journal;
+
+        Ok(state)
+    }
+
+    async fn load_user_data(&self) -> Result<CustomMap<UserId, UserData>, Self::Error> {
+        let users = self.user_data().await?;
+
+        // The worlds are updated with the user data from now on.
+        for journal in self.journals.lock().unwrap().values() {
+            journal.record(|| JournalEntry::UserData(users.clone()));
+        }
+
+        Ok(users)
+    }
+
     async fn save_game(&self, game_id: GameId, state: &shared::State) -> Result<(), Self::Error> {
+        let loaded = self
+            .journals
+            .lock()
+            .unwrap()
+            .get(&game_id)
+            .is_some_and(|journal| journal.same_as(&state.journal));
+        if !loaded {
+            tracing::info!("game {} was replaced, not saved", game_id);
+            return Ok(());
+        }
+
+        state
+            .journal
+            .record(|| JournalEntry::Checkpoint(state.time, state.checksum()));
+
+        self.write_ledger(game_id, state.ledger.drain()).await?;
+
         if let Some(winner) = state.winner() {
+            self.restores.lock().unwrap().swap_remove(&game_id);
+            self.journals.lock().unwrap().swap_remove(&game_id);
+
             sqlx::query(
                 r#"
                         UPDATE games
                         SET data = NULL,
                         winner = $2,
-                        closed = 1
+                        closed = 1,
+                        archive = $3,
+                        results = $4
                         WHERE id = $1
                     "#,
             )
             .bind(game_id)
             .bind(winner.0)
+            .bind(archive(state)?)
+            .bind(serde_json::to_string(&state.results())?)
+            .execute(&self.db)
+            .await?;
+
+            // Snapshots are only kept for the lifetime of a world.
+            sqlx::query(
+                r#"
+                        DELETE FROM game_snapshots
+                        WHERE game_id = $1
+                    "#,
+            )
+            .bind(game_id)
             .execute(&self.db)
             .await?;
 
@@ -199,6 +597,16 @@ impl engine_server::BackendStore<shared::State> for GameStore {
             }
             
         } else {
+            let data = save::encode(state)?;
+
+            let restore = self.restores.lock().unwrap().swap_remove(&game_id);
+            if let Some(restore) = restore {
+                self.apply_restore(game_id, &data, state.time, &restore).await?;
+                self.journals.lock().unwrap().swap_remove(&game_id);
+                let _ = restore.restored.send(());
+                return Ok(());
+            }
+
             sqlx::query(
                 r#"
                         UPDATE games
@@ -207,11 +615,13 @@ impl engine_server::BackendStore<shared::State> for GameStore {
                     "#,
             )
             .bind(game_id)
-            .bind(rmp_serde::to_vec(&state)?)
+            .bind(&data)
             .execute(&self.db)
             .await?;
 
             tracing::info!("game {} saved, ingame time {}", game_id, state.time);
+
+            self.snapshot(game_id, state.time, &data).await?;
         }
 
         Ok(())
@@ -294,6 +704,40 @@ pub async fn get_game(
 #[template(path = "game-select.html")]
 pub struct GameSelectTemplate {
     current_worlds: Vec<(GameId, String)>,
+    finished_worlds: Vec<FinishedWorld>,
+}
+
+pub struct FinishedWorld {
+    id: GameId,
+    game_mode: String,
+    winner: Option<String>,
+}
+
+/// The finished worlds that have results, the most recent first.
+async fn finished_worlds(pool: &SqlitePool, limit: i64) -> Result<Vec<FinishedWorld>, ServerError> {
+    let result: Vec<(GameId, Option<String>, Option<String>)> = sqlx::query_as(
+        r#"
+                SELECT id, game_mode, username
+                FROM games
+                LEFT JOIN users ON winner = user_id
+                WHERE closed = 1
+                AND results IS NOT NULL
+                ORDER BY id DESC
+                LIMIT $1
+            "#,
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(result
+        .into_iter()
+        .map(|(id, game_mode, winner)| FinishedWorld {
+            id,
+            game_mode: game_mode.unwrap_or_default(),
+            winner,
+        })
+        .collect())
 }
 
 pub async fn get_game_select(
@@ -320,7 +764,8 @@ pub async fn get_game_select(
     if current_worlds.len() == 1 {
         Ok(Redirect::temporary(&format!("/game/{}", current_worlds[0].0)).into_response())
     } else {
-        Ok(GameSelectTemplate { current_worlds }.into_response())
+        let finished_worlds = finished_worlds(&pool, 5).await?;
+        Ok(GameSelectTemplate { current_worlds, finished_worlds }.into_response())
     }
 }
 
@@ -328,6 +773,7 @@ pub async fn get_game_select(
 #[template(path = "valhalla.html")]
 pub struct ValhallaTemplate {
     users: Vec<UserData>,
+    finished_worlds: Vec<FinishedWorld>,
 }
 
 pub async fn get_valhalla(Extension(pool): Extension<SqlitePool>) -> Result<Response, ServerError> {
@@ -347,5 +793,139 @@ pub async fn get_valhalla(Extension(pool): Extension<SqlitePool>) -> Result<Resp
         )
     });
 
-    Ok(ValhallaTemplate { users }.into_response())
+    let finished_worlds = finished_worlds(&pool, 100).await?;
+
+    Ok(ValhallaTemplate { users, finished_worlds }.into_response())
+}
+
+pub struct PlayerRow {
+    username: String,
+    level: u64,
+    dwarfs: usize,
+    tribe: String,
+    items_produced: u64,
+    quests_won: u64,
+}
+
+pub struct EldestDwarfRow {
+    name: String,
+    owner: String,
+    age_years: u64,
+}
+
+pub struct TribeRow {
+    name: String,
+    members: usize,
+    territories: String,
+}
+
+#[derive(Template)]
+#[template(path = "results.html")]
+pub struct ResultsTemplate {
+    game_id: GameId,
+    game_mode: GameMode,
+    duration: String,
+    winner: Option<String>,
+    players: Vec<PlayerRow>,
+    tribes: Vec<TribeRow>,
+    top_producers: Vec<(String, u64)>,
+    eldest_dwarf: Option<EldestDwarfRow>,
+}
+
+pub async fn get_results(
+    Path(game_id): Path<GameId>,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Response, ServerError> {
+    let result: Option<(Option<String>,)> = sqlx::query_as(
+        r#"
+                SELECT results
+                FROM games
+                WHERE id = $1
+                AND closed = 1
+            "#,
+    )
+    .bind(game_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let results: WorldResults = result
+        .and_then(|(results,)| results)
+        .and_then(|results| serde_json::from_str(&results).ok())
+        .ok_or(ServerError::GameNotFound(game_id))?;
+
+    let user_data = GameStore::new(pool).load_user_data().await?;
+    let username = |user_id: UserId| {
+        user_data
+            .get(&user_id)
+            .map(|user_data| user_data.username.clone())
+            .unwrap_or_else(|| "Deleted User".to_owned())
+    };
+    let tribe_name = |tribe_id: shared::TribeId| {
+        results
+            .tribes
+            .iter()
+            .find(|tribe| tribe.tribe_id == tribe_id)
+            .and_then(|tribe| tribe.name.clone())
+            .unwrap_or_else(|| format!("Tribe {}", tribe_id))
+    };
+
+    let players = results
+        .players
+        .iter()
+        .map(|player| PlayerRow {
+            username: username(player.user_id),
+            level: player.level,
+            dwarfs: player.dwarfs,
+            tribe: player.tribe.map(tribe_name).unwrap_or_default(),
+            items_produced: player.items_produced,
+            quests_won: player.quests_won,
+        })
+        .collect();
+
+    let tribes = results
+        .tribes
+        .iter()
+        .map(|tribe| TribeRow {
+            name: tribe_name(tribe.tribe_id),
+            members: tribe.members,
+            territories: tribe
+                .territories
+                .iter()
+                .map(|territory| territory.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        })
+        .collect();
+
+    let top_producers = results
+        .top_producers
+        .iter()
+        .filter_map(|user_id| {
+            let player = results.players.iter().find(|player| player.user_id == *user_id)?;
+            Some((username(*user_id), player.items_produced))
+        })
+        .collect();
+
+    Ok(ResultsTemplate {
+        game_id,
+        game_mode: results.game_mode,
+        duration: format!(
+            "{} days and {} hours",
+            results.duration / ONE_DAY,
+            results.duration % ONE_DAY / ONE_HOUR
+        ),
+        winner: results.winner.map(username),
+        players,
+        tribes,
+        top_producers,
+        eldest_dwarf: results
+            .eldest_dwarf
+            .as_ref()
+            .map(|dwarf| EldestDwarfRow {
+                name: dwarf.name.clone(),
+                owner: username(dwarf.user_id),
+                age_years: dwarf.age_years,
+            }),
+    }
+    .into_response())
 }
@@ -117,7 +117,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_same_site(SameSite::Lax)
         .with_expiry(Expiry::OnInactivity(time::Duration::days(30)));
 
-    let game_state = GameStore::new(pool.clone()).load_all().await?;
+    let store = GameStore::new(pool.clone());
+    let game_state = store.clone().load_all().await?;
 
     // Manage the number of hours for premium accounts.
     let pool_clone = pool.clone();
@@ -190,6 +191,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/store", get(store::get_store))
         .route("/about", get(about::get_about))
         .route("/valhalla", get(game::get_valhalla))
+        .route("/results/:game_id", get(game::get_results))
         .nest(
             "/game",
             Router::new()
@@ -237,8 +239,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/admin/create-world", post(admin::post_create_world))
         .route("/admin/update-settings", post(admin::post_update_settings))
         .route("/admin/add-premium", post(admin::post_add_premium))
+        .route("/admin/restore-snapshot", post(admin::post_restore_snapshot))
+        .route("/admin/ledger", get(admin::get_ledger))
         .route("/stripe-webhooks", post(store::handle_webhook))
         .layer(Extension(game_state))
+        .layer(Extension(store))
         .layer(Extension(pool.clone()))
         .layer(session_layer)
         .layer(
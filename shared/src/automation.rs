@@ -0,0 +1,276 @@
+use crate::{
+    in_hours, Account, ActionError, Craftable, Item, LogMsg, Money, Player, State, Time, TradeDeal, TradeId,
+    TradeType, WorldEvent, MAX_HEALTH,
+};
+use serde::{Deserialize, Serialize};
+
+/// The toggles of the inventory are rules as well.
+pub const MAX_RULES: usize = 100;
+
+/// An automation rule of a player. The action is carried out every tick in
+/// which all of the conditions hold.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Rule {
+    pub conditions: Vec<Condition>,
+    pub action: Action,
+    pub enabled: bool,
+    /// Whether the conditions held in the last tick, so that switching
+    /// presets only happens once when they start to hold.
+    #[serde(default)]
+    pub triggered: bool,
+}
+
+impl Rule {
+    pub fn new(conditions: Vec<Condition>, action: Action) -> Self {
+        Rule {
+            conditions,
+            action,
+            enabled: true,
+            triggered: false,
+        }
+    }
+
+    pub fn holds(&self, player: &Player, event: Option<WorldEvent>, time: Time) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.holds(player, event, time))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Condition {
+    ItemAbove(Item, u64),
+    ItemBelow(Item, u64),
+    FoodAbove(u64),
+    FoodBelow(u64),
+    /// Any of the dwarfs has less than this percentage of health.
+    HealthBelow(u64),
+    EventActive(WorldEvent),
+    /// From the first to the second hour of each day in the world.
+    Hours(u64, u64),
+}
+
+impl Condition {
+    pub fn holds(self, player: &Player, event: Option<WorldEvent>, time: Time) -> bool {
+        let stock = |item: Item| player.inventory.items.get(&item).copied().unwrap_or_default();
+        match self {
+            Condition::ItemAbove(item, qty) => stock(item) > qty,
+            Condition::ItemBelow(item, qty) => stock(item) < qty,
+            Condition::FoodAbove(food) => player.base.food > food,
+            Condition::FoodBelow(food) => player.base.food < food,
+            Condition::HealthBelow(percent) => player
+                .dwarfs
+                .values()
+                .any(|dwarf| !dwarf.dead() && dwarf.health * 100 < percent * MAX_HEALTH),
+            Condition::EventActive(world_event) => event == Some(world_event),
+            Condition::Hours(from, to) => in_hours(time, from, to),
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::ItemAbove(item, qty) => write!(f, "more than {qty} {item}"),
+            Condition::ItemBelow(item, qty) => write!(f, "less than {qty} {item}"),
+            Condition::FoodAbove(food) => write!(f, "more than {food} food"),
+            Condition::FoodBelow(food) => write!(f, "less than {food} food"),
+            Condition::HealthBelow(percent) => write!(f, "a dwarf below {percent}% health"),
+            Condition::EventActive(world_event) => write!(f, "during {world_event}"),
+            Condition::Hours(from, to) => write!(f, "from {from}:00 to {to}:00"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Action {
+    /// Crafts the item until the stock reaches the amount.
+    CraftUpTo(Item, u64),
+    /// Stores the food that exceeds the amount.
+    StoreAbove(Item, u64),
+    /// Dismantles the items that exceed the amount.
+    DismantleAbove(Item, u64),
+    /// Bids on the trade deals of the item while the price per item is at
+    /// most the given amount.
+    BidUpTo(Item, Money),
+    ApplyPreset(String),
+    /// Bids on the trade deal until the total reaches the amount. Removed
+    /// once the trade deal is over.
+    BidOnTrade(TradeId, Money),
+}
+
+impl Action {
+    /// The action of the auto-craft toggle of the item.
+    pub fn auto_craft(item: Item) -> Self {
+        Action::CraftUpTo(item, u64::MAX)
+    }
+
+    /// The action of the auto-store toggle of the item.
+    pub fn auto_store(item: Item) -> Self {
+        Action::StoreAbove(item, 0)
+    }
+
+    /// The action of the auto-dismantle toggle of the item.
+    pub fn auto_dismantle(item: Item) -> Self {
+        Action::DismantleAbove(item, 0)
+    }
+
+    /// The highest total this action bids on the trade deal.
+    fn max_bid(&self, trade: &TradeDeal) -> Option<Money> {
+        match self {
+            Action::BidOnTrade(trade_id, max_bid) => (*trade_id == trade.trade_id).then_some(*max_bid),
+            Action::BidUpTo(item, max_price) => {
+                if trade.user_trade_type != TradeType::Buy || trade.items.len() != 1 {
+                    return None;
+                }
+                trade.items.get(item).map(|qty| max_price * qty)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::CraftUpTo(item, u64::MAX) => write!(f, "Craft {item}"),
+            Action::CraftUpTo(item, qty) => write!(f, "Craft {item} up to {qty}"),
+            Action::StoreAbove(item, qty) => write!(f, "Store {item} above {qty}"),
+            Action::DismantleAbove(item, qty) => write!(f, "Dismantle {item} above {qty}"),
+            Action::BidUpTo(item, money) => write!(f, "Bid on {item} up to {money} coins each"),
+            Action::ApplyPreset(name) => write!(f, "Switch to the preset {name}"),
+            Action::BidOnTrade(trade_id, money) => write!(f, "Bid on trade #{trade_id} up to {money} coins"),
+        }
+    }
+}
+
+impl Player {
+    /// Adds a rule after checking that it can be carried out.
+    pub fn add_rule(&mut self, rule: Rule) -> Result<(), ActionError> {
+        if self.rules.len() >= MAX_RULES {
+            return Err(ActionError::TooManyRules);
+        }
+        for condition in &rule.conditions {
+            if let Condition::Hours(from, to) = condition {
+                if *from >= 24 || *to >= 24 {
+                    return Err(ActionError::InvalidHours);
+                }
+            }
+        }
+        if let Action::ApplyPreset(name) = &rule.action {
+            if !self.manager_presets.iter().any(|preset| &preset.name == name) {
+                return Err(ActionError::PresetNotFound);
+            }
+        }
+        self.rules.push(Rule::new(rule.conditions, rule.action));
+        Ok(())
+    }
+
+    /// Whether the toggle of the action is on. Toggles are enabled rules
+    /// without any conditions.
+    pub fn toggled(&self, action: &Action) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.enabled && rule.conditions.is_empty() && &rule.action == action)
+    }
+
+    /// Switches the toggle of the action and returns whether it is on now.
+    pub fn toggle(&mut self, action: Action) -> Result<bool, ActionError> {
+        let on = !self.toggled(&action);
+        self.remove_toggle(&action);
+        if on {
+            self.add_rule(Rule::new(Vec::new(), action))?;
+        }
+        Ok(on)
+    }
+
+    pub fn remove_toggle(&mut self, action: &Action) {
+        self.rules
+            .retain(|rule| !(rule.conditions.is_empty() && &rule.action == action));
+    }
+
+    /// The highest total the player bids automatically on the trade deal.
+    pub fn auto_bid(&self, trade_id: TradeId) -> Option<Money> {
+        self.rules.iter().find_map(|rule| match rule.action {
+            Action::BidOnTrade(id, max_bid) if id == trade_id => Some(max_bid),
+            _ => None,
+        })
+    }
+
+    pub fn set_auto_bid(&mut self, trade_id: TradeId, max_bid: Money) -> Result<(), ActionError> {
+        self.remove_auto_bid(trade_id);
+        if max_bid > 0 {
+            self.add_rule(Rule::new(Vec::new(), Action::BidOnTrade(trade_id, max_bid)))?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_auto_bid(&mut self, trade_id: TradeId) {
+        self.rules
+            .retain(|rule| !matches!(rule.action, Action::BidOnTrade(id, _) if id == trade_id));
+    }
+
+    /// The highest total any of the bidding rules offers for the trade deal.
+    /// Rules with conditions only count if these held in the last tick.
+    pub fn max_auto_bid(&self, trade: &TradeDeal) -> Option<Money> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.enabled && (rule.conditions.is_empty() || rule.triggered))
+            .filter_map(|rule| rule.action.max_bid(trade))
+            .max()
+    }
+
+    /// Carries out the rules whose conditions hold. Bids need the trade
+    /// deals of the world, so they are placed by the caller through
+    /// `max_auto_bid`.
+    pub fn run_rules(&mut self, event: Option<WorldEvent>, time: Time, account: Account) {
+        for idx in 0..self.rules.len() {
+            let rule = &self.rules[idx];
+            let holds = rule.enabled && rule.holds(self, event, time);
+            let triggered = std::mem::replace(&mut self.rules[idx].triggered, holds);
+            if !holds {
+                continue;
+            }
+
+            let stock = |player: &Player, item: Item| {
+                player.inventory.items.get(&item).copied().unwrap_or_default()
+            };
+            match self.rules[idx].action.clone() {
+                Action::CraftUpTo(item, qty) => {
+                    if let Some((_, requires)) = item.requires() {
+                        let missing = qty.saturating_sub(stock(self, item) + self.queued(item));
+                        let possible = self.inventory.items.can_remove_x_times(&requires).unwrap_or_default();
+                        if missing.min(possible) > 0 {
+                            let _ = State::craft(self, item, missing.min(possible));
+                        }
+                    }
+                }
+                Action::StoreAbove(item, qty) => {
+                    let surplus = stock(self, item).saturating_sub(qty);
+                    if surplus > 0 {
+                        let _ = State::add_to_food_storage(self, item, surplus);
+                    }
+                }
+                Action::DismantleAbove(item, qty) => {
+                    let surplus = stock(self, item).saturating_sub(qty);
+                    if surplus > 0 {
+                        let _ = State::dismantle(self, item, surplus, time, account);
+                    }
+                }
+                Action::BidUpTo(..) | Action::BidOnTrade(..) => {}
+                Action::ApplyPreset(name) => {
+                    if !triggered {
+                        if let Some(preset) = self.manager_presets.iter().find(|preset| preset.name == name) {
+                            // The dwarfs are only reassigned if the preset
+                            // changes the manager.
+                            let manager = self.fit_manager(&preset.manager);
+                            if !self.same_manager(&manager) && self.apply_manager(&manager).is_ok() {
+                                self.log.add(time, LogMsg::ManagerPresetApplied(name));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
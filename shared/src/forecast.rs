@@ -0,0 +1,185 @@
+//! Expected production of the dwarfs, computed from the same ratios that
+//! `ProductionSystem` rolls every tick, and the food that the dwarfs need to
+//! make up for the health they lose while working.
+
+use crate::{
+    Dwarf, DwarfId, Item, ItemProbability, ItemType, Occupation, Player, State, WorldEvent,
+    MAX_HEALTH, ONE_HOUR,
+};
+use engine_shared::utils::custom_map::CustomMap;
+
+/// What a player can expect per hour with the current occupations.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Forecast {
+    /// The expected drops per hour, most frequent first.
+    pub items: Vec<(Item, f64)>,
+    /// The food the produced items are worth if they are put into the
+    /// food storage, per hour.
+    pub food_income: f64,
+    /// The food needed per hour to restore the health lost by the dwarfs.
+    pub food_burn: f64,
+}
+
+impl Forecast {
+    pub fn food_balance(&self) -> f64 {
+        self.food_income - self.food_burn
+    }
+}
+
+fn probability((numerator, denominator): (u32, u32)) -> f64 {
+    if numerator == 0 {
+        0.0
+    } else if denominator == 0 || numerator > denominator {
+        1.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+fn add_drops(drops: &mut CustomMap<Item, f64>, other: CustomMap<Item, f64>) {
+    for (item, qty) in other {
+        *drops.entry(item).or_default() += qty;
+    }
+}
+
+fn sorted(drops: CustomMap<Item, f64>) -> Vec<(Item, f64)> {
+    let mut drops = drops.into_iter().collect::<Vec<_>>();
+    drops.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    drops
+}
+
+impl Player {
+    /// The expected drops per hour of a dwarf in its current occupation, at
+    /// the current time of day.
+    pub fn dwarf_drops_per_hour(&self, dwarf_id: DwarfId, state: &State) -> CustomMap<Item, f64> {
+        let mut drops = CustomMap::new();
+        let Some(dwarf) = self.dwarfs.get(&dwarf_id) else {
+            return drops;
+        };
+        if dwarf.dead() || !dwarf.is_adult() {
+            return drops;
+        }
+
+        let occupation = dwarf.actual_occupation();
+        for item in enum_iterator::all::<Item>() {
+            if let Some(ItemProbability {
+                expected_ticks_per_drop,
+            }) = item.item_probability(occupation)
+            {
+                let ratio = dwarf.ratio_effectiveness(
+                    &state.settings,
+                    &self.dwarfs,
+                    self.drop_denominator_mul(occupation, expected_ticks_per_drop, state.event, &state.tribes),
+                    state.time,
+                );
+                drops.insert(item, probability(ratio) * ONE_HOUR as f64);
+            }
+        }
+        drops
+    }
+
+    /// The expected drops per hour of all dwarfs in an occupation.
+    pub fn occupation_drops_per_hour(&self, occupation: Occupation, state: &State) -> CustomMap<Item, f64> {
+        let mut drops = CustomMap::new();
+        for (dwarf_id, dwarf) in self.dwarfs.iter() {
+            if dwarf.actual_occupation() == occupation {
+                add_drops(&mut drops, self.dwarf_drops_per_hour(*dwarf_id, state));
+            }
+        }
+        drops
+    }
+
+    /// The food per hour that makes up for the health the dwarf loses.
+    pub fn dwarf_food_burn_per_hour(&self, dwarf: &Dwarf, event: Option<WorldEvent>, world_speed: u64) -> f64 {
+        if dwarf.dead() || dwarf.equipment.get(&ItemType::Consumable) == Some(&Item::BearClawPowder) {
+            return 0.0;
+        }
+        let health_cost_multiplier = match event {
+            Some(WorldEvent::Plague) => (1 + self.dwarfs.len() as u64 / 15).min(3),
+            _ => 1,
+        };
+        let health_cost_per_hour = (dwarf.actual_occupation().health_cost_per_tick()
+            * dwarf.health_cost_percent()
+            / 100
+            * health_cost_multiplier
+            * world_speed
+            * ONE_HOUR) as f64;
+        health_cost_per_hour / (MAX_HEALTH / 1000) as f64 * dwarf.food_multiplier() as f64
+    }
+
+    /// The expected production and food consumption of the whole
+    /// settlement, assuming the dwarfs keep their current occupations.
+    pub fn forecast(&self, state: &State) -> Forecast {
+        let mut drops = CustomMap::new();
+        let mut food_burn = 0.0;
+        for (dwarf_id, dwarf) in self.dwarfs.iter() {
+            add_drops(&mut drops, self.dwarf_drops_per_hour(*dwarf_id, state));
+            food_burn += self.dwarf_food_burn_per_hour(dwarf, state.event, state.settings.world_speed);
+        }
+
+        let food_income = drops
+            .iter()
+            .filter_map(|(item, qty)| Some(item.nutritional_value()? as f64 * qty))
+            .sum();
+
+        Forecast {
+            items: sorted(drops),
+            food_income,
+            food_burn,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GameMode, ProductionSystem, TickSystem, UserId};
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    #[test]
+    fn drops_match_the_production_system() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut state = State::new(GameMode::Ranked);
+        state.start_countdown = 0;
+        state.time = ONE_HOUR * 12;
+        let mut player = Player::new(state.time, &mut rng, &mut state.next_dwarf_id);
+        player.inventory.items = Default::default();
+        let occupations = [
+            Occupation::Mining,
+            Occupation::Logging,
+            Occupation::Hunting,
+            Occupation::Gathering,
+            Occupation::Fishing,
+        ];
+        for (dwarf, occupation) in player.dwarfs.values_mut().zip(occupations) {
+            dwarf.occupation = occupation;
+        }
+        state.players.insert(UserId(1), player);
+
+        let hours = 5;
+        let forecast = state.players.get(&UserId(1)).unwrap().forecast(&state);
+        for _ in 0..ONE_HOUR * hours {
+            ProductionSystem.tick(&mut state, &mut rng, &CustomMap::new()).unwrap();
+        }
+
+        let produced = &state.players.get(&UserId(1)).unwrap().inventory.items;
+        for item in produced.keys() {
+            assert!(forecast.items.iter().any(|(forecast_item, _)| forecast_item == item), "{item}");
+        }
+        let mut checked = 0;
+        for (item, per_hour) in forecast.items {
+            let expected = per_hour * hours as f64;
+            let actual = produced.get(&item).copied().unwrap_or_default() as f64;
+            // Drops are binomial, so they stay within a few standard
+            // deviations of the expected number.
+            assert!(
+                (actual - expected).abs() <= 5.0 * expected.sqrt() + 1.0,
+                "{item}: expected {expected}, got {actual}"
+            );
+            if expected > 50.0 {
+                checked += 1;
+            }
+        }
+        assert!(checked > 0);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{
-    Bundle, BundleType, Craftable, Food, Money, Occupation, Stats, ONE_DAY, ONE_HOUR, ONE_MINUTE,
+    Bundle, BundleType, Craftable, Food, Money, Occupation, Stats, Time, CRAFTING_TIME_PER_DEPTH,
+    ONE_DAY, ONE_HOUR, ONE_MINUTE,
 };
 use enum_iterator::Sequence;
 use serde::{Deserialize, Serialize};
@@ -162,6 +163,14 @@ pub enum Item {
     DivingSuit,
 }
 
+/// An item together with the recipe trees of its ingredients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecipeTree {
+    pub item: Item,
+    pub qty: u64,
+    pub ingredients: Vec<RecipeTree>,
+}
+
 impl Craftable for Item {
     fn requires(self) -> Option<(u64, Bundle<Item>)> {
         match self {
@@ -712,7 +721,6 @@ impl Item {
                 agility: 1,
                 intelligence: 1,
                 perception: 1,
-                ..Default::default()
             },
             Item::RingOfIntelligence => Stats {
                 intelligence: 6,
@@ -862,6 +870,10 @@ impl Item {
             (Item::Wheelbarrow, Occupation::Gathering) => 8,
             (Item::Plough, Occupation::Farming) => 10,
             (Item::Lantern, Occupation::Mining | Occupation::Rockhounding) => 4,
+            (Item::Gloves, Occupation::Crafting) => 6,
+            (Item::BearClawGloves, Occupation::Crafting) => 8,
+            (Item::Lantern | Item::Dagger, Occupation::Crafting) => 3,
+            (Item::Headlamp | Item::Overall, Occupation::Crafting) => 5,
             (Item::Cat, Occupation::Fishing) => 6,
             (Item::Dolphin, Occupation::Fishing) => 10,
             (Item::Boat, Occupation::Fishing) => 10,
@@ -1069,7 +1081,7 @@ impl Item {
                 }),
                 _ => None,
             },
-            Occupation::Idling => None,
+            Occupation::Idling | Occupation::Crafting => None,
         }
     }
 
@@ -1105,6 +1117,54 @@ impl Item {
         rarity.unwrap_or(160000)
     }
 
+    /// The items without a recipe that one of this item is made of in
+    /// the end. Items without a recipe are their own raw material.
+    pub fn raw_materials(self) -> Bundle<Item> {
+        let mut raw_materials = Bundle::new();
+        if let Some((_level, requires)) = self.requires() {
+            for (ingredient, qty) in requires.iter() {
+                raw_materials.add_checked(ingredient.raw_materials().mul(*qty));
+            }
+        } else {
+            raw_materials = raw_materials.add(self, 1);
+        }
+        raw_materials
+    }
+
+    /// The complete recipe of the given number of this item.
+    pub fn recipe_tree(self, qty: u64) -> RecipeTree {
+        RecipeTree {
+            item: self,
+            qty,
+            ingredients: self
+                .requires()
+                .map(|(_level, requires)| {
+                    requires
+                        .sorted_by_rarity()
+                        .into_iter()
+                        .map(|(ingredient, n)| ingredient.recipe_tree(n * qty))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The items that have this item as a direct ingredient.
+    pub fn used_in(self) -> Vec<Item> {
+        enum_iterator::all::<Item>()
+            .filter(|item| {
+                item.requires()
+                    .map(|(_level, requires)| requires.get(&self).is_some())
+                    .unwrap_or(false)
+            })
+            .collect()
+    }
+
+    /// Time it takes to craft one of this item without any help.
+    pub fn crafting_time(self) -> Time {
+        CRAFTING_TIME_PER_DEPTH * self.crafting_depth().max(1)
+    }
+
     pub fn crafting_depth(self) -> u64 {
         let mut depth = 0;
 
@@ -1162,3 +1222,32 @@ pub struct ItemProbability {
 }
 
 impl BundleType for Item {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(tree: &RecipeTree, raw_materials: &mut Bundle<Item>) {
+        if tree.ingredients.is_empty() {
+            raw_materials.add_checked(Bundle::new().add(tree.item, tree.qty));
+        }
+        for ingredient in &tree.ingredients {
+            leaves(ingredient, raw_materials);
+        }
+    }
+
+    #[test]
+    fn raw_materials_are_the_leaves_of_the_recipe_tree() {
+        for item in [Item::Iron, Item::Nail, Item::ChainMail, Item::PoisonedBow, Item::Crossbow, Item::Wood] {
+            for qty in [1, 3] {
+                let mut expected = Bundle::new();
+                leaves(&item.recipe_tree(qty), &mut expected);
+                let raw_materials = item.raw_materials().mul(qty);
+                assert_eq!(raw_materials.len(), expected.len(), "{item}");
+                for (raw, n) in expected.iter() {
+                    assert_eq!(raw_materials.get(raw), Some(n), "{item}");
+                }
+            }
+        }
+    }
+}
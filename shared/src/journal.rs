@@ -0,0 +1,211 @@
+//! A record of every event applied to a world, so that bugs in
+//! `State::update` can be reproduced by replaying the world from a snapshot.
+//!
+//! Every event is applied with an rng seeded from a fresh seed, which is
+//! recorded together with the event once it was applied, also if it was
+//! rejected. The server turns recording on
+//! when it loads a world, with a file per world that every entry is written
+//! to right away, and records the user data whenever it changes and a
+//! checkpoint with the checksum of the state whenever the world is saved.
+//! The `replay` binary of the server reads them back.
+
+use crate::{ClientEvent, ServerEvent, State, Time, UserData, UserId};
+use engine_shared::{utils::custom_map::CustomMap, Event};
+use serde::{Deserialize, Serialize};
+use std::{
+    hash::{Hash, Hasher},
+    io::{BufRead, Write},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum JournalEvent {
+    Client(ClientEvent, UserId),
+    Server(ServerEvent),
+}
+
+impl JournalEvent {
+    pub(crate) fn new(event: &Event<State>) -> Self {
+        match event {
+            Event::ClientEvent(event, user_id) => JournalEvent::Client(event.clone(), *user_id),
+            Event::ServerEvent(event) => JournalEvent::Server(event.clone()),
+        }
+    }
+
+    pub fn into_event(self) -> Event<State> {
+        match self {
+            JournalEvent::Client(event, user_id) => Event::ClientEvent(event, user_id),
+            JournalEvent::Server(event) => Event::ServerEvent(event),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum JournalEntry {
+    /// The user data that the following events are applied with.
+    UserData(CustomMap<UserId, UserData>),
+    /// An event and the seed of the rng it was applied with.
+    Event(u64, JournalEvent),
+    /// The world was saved with the given time and checksum.
+    Checkpoint(Time, u64),
+    /// The world was loaded from a save with the given time and checksum,
+    /// the entries after it continue from that state.
+    Loaded(Time, u64),
+}
+
+/// The writer of a journal, which is dropped once writing to it failed.
+type Recording = Option<Box<dyn Write + Send>>;
+
+/// The journal a world records into. The journal is not part of the save,
+/// and clones of a state share the journal.
+#[derive(Clone, Default)]
+pub struct Journal(Option<Arc<Mutex<Recording>>>);
+
+impl Journal {
+    /// Records into the given writer, every entry is flushed as soon as it
+    /// is recorded.
+    pub fn recording(writer: impl Write + Send + 'static) -> Self {
+        Journal(Some(Arc::new(Mutex::new(Some(Box::new(writer))))))
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Whether both are the journal of the same loaded world.
+    pub fn same_as(&self, other: &Journal) -> bool {
+        match (&self.0, &other.0) {
+            (Some(this), Some(other)) => Arc::ptr_eq(this, other),
+            _ => false,
+        }
+    }
+
+    /// Writes an entry to the journal. A journal that can't be written to
+    /// stops recording, but must not keep the world from running.
+    pub fn record(&self, entry: impl FnOnce() -> JournalEntry) {
+        let Some(recording) = &self.0 else {
+            return;
+        };
+        let mut recording = recording.lock().unwrap();
+        let Some(writer) = recording.as_mut() else {
+            return;
+        };
+
+        let written = write_entries(&mut *writer, &[entry()])
+            .map_err(|err| err.to_string())
+            .and_then(|()| writer.flush().map_err(|err| err.to_string()));
+        if let Err(err) = written {
+            println!("could not write the journal, recording stopped: {}", err);
+            *recording = None;
+        }
+    }
+}
+
+impl Hash for Journal {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+impl std::fmt::Debug for Journal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Journal {{ recording: {} }}", self.is_recording())
+    }
+}
+
+impl State {
+    /// A hash of the whole state, to compare a replayed world with the
+    /// original.
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = fxhash::FxHasher64::default();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+pub fn write_entries(mut writer: impl Write, entries: &[JournalEntry]) -> Result<(), rmp_serde::encode::Error> {
+    for entry in entries {
+        rmp_serde::encode::write(&mut writer, entry)?;
+    }
+    Ok(())
+}
+
+/// Reads the entries of a journal one after the other.
+pub struct JournalReader<R> {
+    reader: R,
+}
+
+impl<R: BufRead> JournalReader<R> {
+    pub fn new(reader: R) -> Self {
+        JournalReader { reader }
+    }
+}
+
+impl<R: BufRead> Iterator for JournalReader<R> {
+    type Item = Result<JournalEntry, rmp_serde::decode::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.fill_buf() {
+            Ok([]) => None,
+            Ok(_) => Some(rmp_serde::from_read(&mut self.reader)),
+            Err(err) => Some(Err(rmp_serde::decode::Error::InvalidDataRead(err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActionError, GameMode, Occupation};
+
+    /// Keeps what is written to it, to read the journal back.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rejected_events_replay_the_same() {
+        let mut state = State::new(GameMode::Ranked);
+        state.start_countdown = 0;
+        let start = state.clone();
+
+        let buffer = Buffer::default();
+        state.journal = Journal::recording(buffer.clone());
+        let user_data = CustomMap::new();
+        let user_id = UserId(1);
+
+        // The manager is set up before the event is rejected, so the event
+        // has to be replayed as it is.
+        let rejected = ClientEvent::SetManagerOccupation(Occupation::Mining, 1000);
+        state.update_seeded(1, Event::ClientEvent(ClientEvent::Init, user_id), &user_data);
+        state.update_seeded(2, Event::ServerEvent(ServerEvent::Tick), &user_data);
+        state.update_seeded(3, Event::ClientEvent(rejected, user_id), &user_data);
+        let player = state.players.get(&user_id).unwrap();
+        assert_eq!(player.last_error.map(|(error, _)| error), Some(ActionError::NotEnoughIdleDwarfs));
+
+        let entries = JournalReader::new(&buffer.0.lock().unwrap()[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(matches!(
+            entries[..],
+            [JournalEntry::Event(1, _), JournalEntry::Event(2, _), JournalEntry::Event(3, _)]
+        ));
+
+        let mut replayed = start;
+        for entry in entries {
+            match entry {
+                JournalEntry::Event(seed, event) => replayed.update_seeded(seed, event.into_event(), &user_data),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(replayed.checksum(), state.checksum());
+    }
+}
@@ -0,0 +1,175 @@
+//! A record of the money and items that change hands, so that admins can
+//! follow where the wealth of a player came from.
+//!
+//! The server turns recording on when it loads a world and writes the
+//! recorded entries to the database every time the world is saved. The
+//! items collected by working dwarfs and other random finds are not
+//! recorded, they would drown out everything else.
+
+use crate::{Bundle, Item, Time, UserId};
+use engine_shared::utils::custom_map::CustomMap;
+use enum_iterator::Sequence;
+use serde::{Deserialize, Serialize};
+use std::{
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+use strum::{Display, EnumString};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, Sequence, Display, EnumString)]
+pub enum LedgerReason {
+    TutorialReward,
+    HireDwarf,
+    /// The ingredients of an item were taken from the inventory.
+    CraftStarted,
+    CraftFinished,
+    CraftCancelled,
+    Dismantle,
+    AutoSell,
+    LootCrate,
+    Construction,
+    TreasuryDeposit,
+    QuestReward,
+    /// The share of the quest money that goes to the king.
+    KingsCut,
+    /// The items of a player were put up for auction.
+    TradeListed,
+    /// Money held back for the highest bid on an auction.
+    TradeBid,
+    /// The bid was returned after someone else bid more.
+    TradeOutbid,
+    TradeWon,
+    TradeSold,
+    /// Money held back for a buy order.
+    BuyOrderPlaced,
+    /// Items held back for the lowest offer on a buy order.
+    BuyOrderOffer,
+    /// The items were returned after someone else offered them for less.
+    BuyOrderUnderbid,
+    BuyOrderFilled,
+    BuyOrderExpired,
+    /// Money and items held back for a direct offer.
+    OfferMade,
+    OfferAccepted,
+    /// What was held back for a direct offer came back because it was
+    /// declined, cancelled or expired.
+    OfferReturned,
+}
+
+/// A change of the money and items of a player.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LedgerEntry {
+    pub time: Time,
+    pub user_id: UserId,
+    pub reason: LedgerReason,
+    /// Positive if the player received money.
+    pub money: i64,
+    /// Positive quantities were received, negative ones given away.
+    pub items: CustomMap<Item, i64>,
+    /// The other player of a trade.
+    pub counterparty: Option<UserId>,
+}
+
+impl LedgerEntry {
+    pub fn new(time: Time, user_id: UserId, reason: LedgerReason) -> Self {
+        LedgerEntry {
+            time,
+            user_id,
+            reason,
+            money: 0,
+            items: CustomMap::new(),
+            counterparty: None,
+        }
+    }
+
+    pub fn received_money(mut self, money: u64) -> Self {
+        self.money += money as i64;
+        self
+    }
+
+    pub fn paid_money(mut self, money: u64) -> Self {
+        self.money -= money as i64;
+        self
+    }
+
+    pub fn received(mut self, items: &Bundle<Item>) -> Self {
+        for (item, qty) in items.iter() {
+            *self.items.entry(*item).or_default() += *qty as i64;
+        }
+        self
+    }
+
+    pub fn gave(mut self, items: &Bundle<Item>) -> Self {
+        for (item, qty) in items.iter() {
+            *self.items.entry(*item).or_default() -= *qty as i64;
+        }
+        self
+    }
+
+    pub fn with(mut self, counterparty: Option<UserId>) -> Self {
+        self.counterparty = counterparty;
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        self.money == 0 && self.items.values().all(|qty| *qty == 0)
+    }
+}
+
+/// The entries recorded since the world was last saved. Like the journal,
+/// the ledger is not part of the save and clones of a state share it.
+#[derive(Clone, Default)]
+pub struct Ledger(Option<Arc<Mutex<Vec<LedgerEntry>>>>);
+
+impl Ledger {
+    pub fn recording() -> Self {
+        Ledger(Some(Arc::new(Mutex::new(Vec::new()))))
+    }
+
+    /// The entry is only built if the ledger is recording.
+    pub fn record(&self, entry: impl FnOnce() -> LedgerEntry) {
+        if let Some(entries) = &self.0 {
+            let entry = entry();
+            if !entry.is_empty() {
+                entries.lock().unwrap().push(entry);
+            }
+        }
+    }
+
+    pub fn account(&self, user_id: UserId) -> Account<'_> {
+        Account { ledger: self, user_id }
+    }
+
+    /// Takes the entries recorded so far.
+    pub fn drain(&self) -> Vec<LedgerEntry> {
+        self.0
+            .as_ref()
+            .map(|entries| std::mem::take(&mut *entries.lock().unwrap()))
+            .unwrap_or_default()
+    }
+}
+
+impl Hash for Ledger {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+impl std::fmt::Debug for Ledger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ledger {{ recording: {} }}", self.0.is_some())
+    }
+}
+
+/// The ledger of a single player, for the methods of `Player` that don't
+/// know whose player they are.
+#[derive(Clone, Copy)]
+pub struct Account<'a> {
+    ledger: &'a Ledger,
+    user_id: UserId,
+}
+
+impl Account<'_> {
+    pub fn record(&self, time: Time, reason: LedgerReason, entry: impl FnOnce(LedgerEntry) -> LedgerEntry) {
+        self.ledger
+            .record(|| entry(LedgerEntry::new(time, self.user_id, reason)));
+    }
+}
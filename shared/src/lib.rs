@@ -1,6 +1,25 @@
+mod automation;
+mod forecast;
 mod items;
-
+mod journal;
+mod ledger;
+pub mod optimizer;
+mod planner;
+mod results;
+pub mod save;
+pub mod sim;
+mod systems;
+#[cfg(test)]
+mod testing;
+
+pub use automation::*;
+pub use forecast::*;
 pub use items::*;
+pub use journal::*;
+pub use ledger::*;
+pub use planner::*;
+pub use results::*;
+pub use systems::*;
 
 use engine_shared::{
     utils::custom_map::{CustomMap, CustomSet},
@@ -9,8 +28,9 @@ use engine_shared::{
 use enum_iterator::Sequence;
 use rand::{
     seq::{IteratorRandom, SliceRandom},
-    Rng,
+    Rng, SeedableRng,
 };
+use rand_chacha::ChaCha8Rng;
 use serde::{Deserialize, Serialize};
 use std::{collections::VecDeque, hash::Hash, ops::Deref};
 use strum::{Display, EnumString};
@@ -28,6 +48,10 @@ pub const FREE_LOOT_CRATE: u64 = ONE_DAY;
 pub const WINNER_NUM_PREMIUM_DAYS: i64 = 30;
 pub const WINNER_TRIBE_NUM_PREMIUM_DAYS: i64 = 7;
 pub const FEMALE_PROBABILITY: f64 = 0.5;
+pub const MUTATION_PROBABILITY: f64 = 0.2;
+pub const TRAIT_PROBABILITY: f64 = 0.3;
+pub const TRAIT_INHERIT_PROBABILITY: f64 = 0.5;
+pub const MAX_TRAITS: usize = 3;
 pub const MAX_LEVEL: u64 = 100;
 pub const AGE_SECONDS_PER_TICK: u64 = 365 * 24;
 pub const ADULT_AGE: u64 = 20;
@@ -42,7 +66,28 @@ pub const NEW_PLAYER_DIVIDER: u64 = 8;
 pub const JOIN_TRIBE_LEVEL: u64 = 20;
 pub const MIN_TRADE_VALUE: u64 = 100;
 pub const MAX_NUM_TRADES: usize = 5;
+pub const MAX_BUY_ORDER_QTY: u64 = 100_000;
+pub const MAX_NUM_DIRECT_OFFERS: usize = 5;
+pub const DIRECT_OFFER_DURATION: u64 = ONE_DAY;
 pub const QUEST_PREPARATION_TIME: u64 = ONE_HOUR;
+pub const NUM_TRIBES: u64 = 3;
+pub const MAX_NUM_TRIBES: u64 = 10;
+pub const MAX_WORLD_SPEED: u64 = 100;
+pub const MAX_BUILDING_LEVEL: u64 = 10;
+pub const LEVELS_PER_BUILDING_SLOT: u64 = 5;
+pub const MAX_TRIBE_NAME_LEN: usize = 24;
+pub const MAX_TITHE: u64 = 25;
+pub const MAX_TRIBE_UPGRADE_LEVEL: u64 = 5;
+pub const TRIBE_CHOICE_TIME: u64 = ONE_DAY;
+pub const MAX_MANAGER_PRESETS: usize = 10;
+pub const CRAFTING_TIME_PER_DEPTH: Time = ONE_MINUTE;
+pub const MAX_CRAFT_ORDERS: usize = 20;
+pub const MAX_PRESET_NAME_LEN: usize = 24;
+pub const PRESET_FOOD_MARGIN_PERCENT: u64 = 20;
+pub const TRIBE_CHANGE_COOLDOWN: u64 = ONE_DAY * 3;
+pub const NIGHT_START_HOUR: u64 = 20;
+pub const NIGHT_END_HOUR: u64 = 6;
+pub const MAX_TRIBE_SIZE_DIFFERENCE: usize = 3;
 
 pub type Money = u64;
 pub type Food = u64;
@@ -53,6 +98,7 @@ pub type Time = u64;
 pub type QuestId = u64;
 pub type TribeId = u64;
 pub type TradeId = u64;
+pub type DirectOfferId = u64;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, strum::EnumString, strum::Display)]
 pub enum GameMode {
@@ -74,6 +120,55 @@ pub struct WorldSettings {
     pub ranked: bool,
     pub infinite: bool,
     pub game_mode: GameMode,
+    #[serde(default)]
+    pub disabled_phases: CustomSet<TickPhase>,
+    #[serde(default = "WorldSettings::default_num_tribes")]
+    pub num_tribes: u64,
+    #[serde(default = "WorldSettings::default_win_level")]
+    pub win_level: u64,
+    /// Number of open quests in percent of the usual amount.
+    #[serde(default = "WorldSettings::default_percent")]
+    pub quest_density: u64,
+    /// Chance for a world event to start in percent of the usual chance.
+    #[serde(default = "WorldSettings::default_percent")]
+    pub event_frequency: u64,
+}
+
+impl WorldSettings {
+    fn default_num_tribes() -> u64 {
+        NUM_TRIBES
+    }
+
+    fn default_win_level() -> u64 {
+        MAX_LEVEL
+    }
+
+    fn default_percent() -> u64 {
+        100
+    }
+
+    /// Checks that the settings describe a playable world.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start_countdown > ONE_DAY * 7 {
+            return Err("start countdown must be at most 7 days".to_string());
+        }
+        if self.world_speed == 0 || self.world_speed > MAX_WORLD_SPEED {
+            return Err(format!("world speed must be between 1 and {MAX_WORLD_SPEED}"));
+        }
+        if self.num_tribes == 0 || self.num_tribes > MAX_NUM_TRIBES {
+            return Err(format!("number of tribes must be between 1 and {MAX_NUM_TRIBES}"));
+        }
+        if self.win_level < JOIN_TRIBE_LEVEL || self.win_level > MAX_LEVEL {
+            return Err(format!("win level must be between {JOIN_TRIBE_LEVEL} and {MAX_LEVEL}"));
+        }
+        if self.quest_density == 0 || self.quest_density > 1000 {
+            return Err("quest density must be between 1 and 1000 percent".to_string());
+        }
+        if self.event_frequency > 1000 {
+            return Err("event frequency must be at most 1000 percent".to_string());
+        }
+        Ok(())
+    }
 }
 
 impl From<GameMode> for WorldSettings {
@@ -85,6 +180,11 @@ impl From<GameMode> for WorldSettings {
                 ranked: true,
                 infinite: false,
                 game_mode: value,
+                disabled_phases: CustomSet::new(),
+                num_tribes: NUM_TRIBES,
+                win_level: MAX_LEVEL,
+                quest_density: 100,
+                event_frequency: 100,
             },
             GameMode::Speed => WorldSettings {
                 start_countdown: if cfg!(debug_assertions) { 0 } else { ONE_HOUR * 24 },
@@ -92,6 +192,11 @@ impl From<GameMode> for WorldSettings {
                 ranked: false,
                 infinite: false,
                 game_mode: value,
+                disabled_phases: CustomSet::new(),
+                num_tribes: NUM_TRIBES,
+                win_level: MAX_LEVEL,
+                quest_density: 100,
+                event_frequency: 100,
             },
             GameMode::Infinite => WorldSettings {
                 start_countdown: if cfg!(debug_assertions) { 0 } else { ONE_HOUR * 24 },
@@ -99,6 +204,11 @@ impl From<GameMode> for WorldSettings {
                 ranked: false,
                 infinite: true,
                 game_mode: value,
+                disabled_phases: CustomSet::new(),
+                num_tribes: NUM_TRIBES,
+                win_level: MAX_LEVEL,
+                quest_density: 100,
+                event_frequency: 100,
             },
         }
     }
@@ -110,6 +220,130 @@ pub enum Popup {
     NewItems(Bundle<Item>),
 }
 
+/// The reason why a client event of a player was rejected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum ActionError {
+    DwarfNotFound,
+    QuestNotFound,
+    TradeNotFound,
+    PremiumRequired,
+    NotEnoughMoney,
+    NotEnoughItems,
+    NotEnoughIdleDwarfs,
+    NotEnoughSpace,
+    SpecialDwarfAlreadyHired,
+    LevelTooLow(u64),
+    QuestLevelMismatch,
+    DwarfIsChild,
+    DwarfIsAdult,
+    DwarfOnQuest,
+    NotCraftable,
+    NotDismantlable,
+    NotFood,
+    NotEquippable,
+    NotSellable,
+    TradeValueTooLow,
+    TooManyTrades,
+    OwnTrade,
+    AlreadyHighestBidder,
+    NoTribe,
+    NoTribePoints,
+    TutorialStepIncomplete,
+    DwarfsLeft,
+    MaxLevelReached,
+    AlreadyConstructing,
+    NoBuildingSlots,
+    AutoBidUnavailable,
+    OfferNotFound,
+    PlayerNotFound,
+    EmptyOffer,
+    OwnOffer,
+    TooManyOffers,
+    NotChief,
+    InvalidTribeName,
+    NotTribeMember,
+    TitheTooHigh,
+    TribeNotFound,
+    TribeFull,
+    AlreadyInTribe,
+    TribeChangeCooldown,
+    InvalidPairing,
+    InvalidPresetName,
+    TooManyPresets,
+    PresetNotFound,
+    TooManyRules,
+    RuleNotFound,
+    CraftQueueFull,
+    CraftOrderNotFound,
+    InventoryFull,
+    ZeroAmount,
+    AmountTooHigh,
+    InvalidHours,
+}
+
+impl std::fmt::Display for ActionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActionError::DwarfNotFound => write!(f, "This dwarf is not part of your settlement anymore."),
+            ActionError::QuestNotFound => write!(f, "This quest is already over."),
+            ActionError::TradeNotFound => write!(f, "This trade deal is already over."),
+            ActionError::PremiumRequired => write!(f, "This feature requires a premium account."),
+            ActionError::NotEnoughMoney => write!(f, "You do not have enough money."),
+            ActionError::NotEnoughItems => write!(f, "You do not have the required items."),
+            ActionError::NotEnoughIdleDwarfs => write!(f, "There are not enough idling dwarfs left to assign."),
+            ActionError::NotEnoughSpace => write!(f, "Your settlement has no space for more dwarfs."),
+            ActionError::SpecialDwarfAlreadyHired => write!(f, "This dwarf already lives in your settlement."),
+            ActionError::LevelTooLow(level) => write!(f, "Your settlement needs to be at least level {level}."),
+            ActionError::QuestLevelMismatch => write!(f, "Your settlement level does not fit this quest."),
+            ActionError::DwarfIsChild => write!(f, "Children cannot do this."),
+            ActionError::DwarfIsAdult => write!(f, "Only children can become apprentices."),
+            ActionError::DwarfOnQuest => write!(f, "This dwarf is busy with a quest."),
+            ActionError::NotCraftable => write!(f, "This item cannot be crafted."),
+            ActionError::NotDismantlable => write!(f, "This item cannot be dismantled."),
+            ActionError::NotFood => write!(f, "This item cannot be stored as food."),
+            ActionError::NotEquippable => write!(f, "This item cannot be equipped here."),
+            ActionError::NotSellable => write!(f, "This item cannot be sold."),
+            ActionError::TradeValueTooLow => write!(f, "These items are not valuable enough to be sold."),
+            ActionError::TooManyTrades => write!(f, "You cannot have more than {MAX_NUM_TRADES} open trade deals."),
+            ActionError::OwnTrade => write!(f, "You cannot bid on your own trade deal."),
+            ActionError::AlreadyHighestBidder => write!(f, "You are already the highest bidder."),
+            ActionError::NoTribe => write!(f, "You are not a member of a tribe."),
+            ActionError::NoTribePoints => write!(f, "You have no tribe points left."),
+            ActionError::TutorialStepIncomplete => write!(f, "Complete the current tutorial step first."),
+            ActionError::DwarfsLeft => write!(f, "You can only restart once all your dwarfs are gone."),
+            ActionError::MaxLevelReached => write!(f, "Your settlement cannot be upgraded any further."),
+            ActionError::AlreadyConstructing => write!(f, "Your dwarfs are already constructing."),
+            ActionError::NoBuildingSlots => write!(f, "Your settlement has no space for another building level."),
+            ActionError::AutoBidUnavailable => write!(f, "Auto bidding is not available for buy orders."),
+            ActionError::OfferNotFound => write!(f, "This offer does not exist anymore."),
+            ActionError::PlayerNotFound => write!(f, "This player is not part of this world."),
+            ActionError::EmptyOffer => write!(f, "An offer needs to contain some items or coins."),
+            ActionError::OwnOffer => write!(f, "You cannot make an offer to yourself."),
+            ActionError::TooManyOffers => write!(f, "You have reached the maximum number of open offers."),
+            ActionError::NotChief => write!(f, "Only the chief of your tribe can do this."),
+            ActionError::InvalidTribeName => write!(f, "The tribe name must not be empty or longer than {} characters.", MAX_TRIBE_NAME_LEN),
+            ActionError::NotTribeMember => write!(f, "This player is not a member of your tribe."),
+            ActionError::TitheTooHigh => write!(f, "The tithe can be at most {}%.", MAX_TITHE),
+            ActionError::TribeNotFound => write!(f, "This tribe does not exist."),
+            ActionError::TribeFull => write!(f, "This tribe has too many members compared to the other tribes."),
+            ActionError::AlreadyInTribe => write!(f, "You need to leave your current tribe first."),
+            ActionError::InvalidPairing => write!(f, "A pair needs an adult male and an adult female dwarf."),
+            ActionError::TribeChangeCooldown => write!(f, "You have joined your tribe recently, please wait a bit longer before leaving."),
+            ActionError::InvalidPresetName => write!(f, "The preset name must not be empty or longer than {} characters.", MAX_PRESET_NAME_LEN),
+            ActionError::TooManyPresets => write!(f, "You cannot have more than {} manager presets.", MAX_MANAGER_PRESETS),
+            ActionError::PresetNotFound => write!(f, "This manager preset does not exist."),
+            ActionError::TooManyRules => write!(f, "You cannot have more than {} automation rules.", MAX_RULES),
+            ActionError::RuleNotFound => write!(f, "This automation rule does not exist."),
+            ActionError::CraftQueueFull => write!(f, "Your crafting queue cannot hold more than {} orders.", MAX_CRAFT_ORDERS),
+            ActionError::CraftOrderNotFound => write!(f, "This crafting order is already done."),
+            ActionError::InventoryFull => write!(f, "Your inventory has no room for these items."),
+            ActionError::ZeroAmount => write!(f, "The amount needs to be at least one."),
+            ActionError::AmountTooHigh => write!(f, "You cannot order more than {MAX_BUY_ORDER_QTY} items at once."),
+            ActionError::InvalidHours => write!(f, "Hours need to be between 0 and 23."),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, Sequence, Copy)]
 pub enum WorldEvent {
     Drought,
@@ -349,6 +583,14 @@ pub struct State {
     pub start_countdown: u64,
     #[serde(default)]
     pub eldest: Option<(UserId, DwarfId)>,
+    #[serde(default)]
+    pub direct_offers: CustomMap<DirectOfferId, DirectOffer>,
+    #[serde(default)]
+    pub next_direct_offer_id: DirectOfferId,
+    #[serde(skip)]
+    pub journal: Journal,
+    #[serde(skip)]
+    pub ledger: Ledger,
 }
 /*
 impl Default for State {
@@ -383,13 +625,13 @@ impl Default for State {
 
 impl State {
     pub fn new(gamemode: GameMode) -> Self {
-        let mut tribes = CustomMap::default();
-        tribes.insert(0, Tribe::default());
-        tribes.insert(1, Tribe::default());
-        tribes.insert(2, Tribe::default());
-
-        let settings = WorldSettings::from(gamemode);   
+        Self::with_settings(WorldSettings::from(gamemode))
+    }
 
+    pub fn with_settings(settings: WorldSettings) -> Self {
+        let tribes = (0..settings.num_tribes)
+            .map(|tribe_id| (tribe_id, Tribe::default()))
+            .collect();
 
         Self {
             players: CustomMap::default(),
@@ -406,19 +648,28 @@ impl State {
             start_countdown: settings.start_countdown,
             settings,
             eldest: None,
+            direct_offers: CustomMap::default(),
+            next_direct_offer_id: 0,
+            journal: Journal::default(),
+            ledger: Ledger::default(),
         }
     }
 
-    fn add_to_food_storage(player: &mut Player, item: Item, qty: u64) {
-        if let Some(food) = item.nutritional_value() {
-            if player
-                .inventory
-                .items
-                .remove_checked(Bundle::new().add(item, qty))
-            {
-                player.base.food += food * qty;
-            }
+    fn add_to_food_storage(player: &mut Player, item: Item, qty: u64) -> Result<(), ActionError> {
+        if qty == 0 {
+            return Err(ActionError::ZeroAmount);
+        }
+        let food = item.nutritional_value().ok_or(ActionError::NotFood)?;
+        if !player
+            .inventory
+            .items
+            .remove_checked(Bundle::new().add(item, qty))
+        {
+            return Err(ActionError::NotEnoughItems);
         }
+        player.base.food += food * qty;
+
+        Ok(())
     }
 
     /*
@@ -435,1559 +686,1006 @@ impl State {
     }
     */
 
-    fn craft(player: &mut Player, item: Item, qty: u64) {
-        if let Some((level, requires)) = item.requires() {
-            if player.base.curr_level >= level && player.inventory.items.remove_checked(requires.mul(qty)) {
-                player
-                    .inventory
-                    .items
-                    .add_checked(Bundle::new().add(item, qty));
-            }
+    /// Puts the item into the crafting queue. The ingredients are taken once
+    /// the dwarfs start working on each item.
+    fn craft(player: &mut Player, item: Item, qty: u64) -> Result<(), ActionError> {
+        if qty == 0 {
+            return Err(ActionError::ZeroAmount);
         }
-    }
-
-    fn dismantle(player: &mut Player, item: Item, qty: u64) {
-        if let Some((_level, requires)) = item.requires() {
-            if matches!(
-                item.item_type(),
-                Some(ItemType::Tool | ItemType::Jewelry | ItemType::Clothing)
-            ) && player
-                    .inventory
-                    .items
-                    .remove_checked(Bundle::new().add(item, qty)) {
-                player
-                    .inventory
-                    .items
-                    .add_checked(requires.mul(qty).div(DISMANTLING_DIVIDER));
-            }
+        let (level, requires) = item.requires().ok_or(ActionError::NotCraftable)?;
+        if player.base.curr_level < level {
+            return Err(ActionError::LevelTooLow(level));
         }
+        if !player.inventory.items.check_remove(&requires.mul(qty)) {
+            return Err(ActionError::NotEnoughItems);
+        }
+        player.queue_craft(item, qty, false)
     }
 
-    pub fn winner(&self) -> Option<UserId> {
-        if self.settings.infinite {
-            return None;
+    fn dismantle(player: &mut Player, item: Item, qty: u64, time: Time, account: Account) -> Result<(), ActionError> {
+        if qty == 0 {
+            return Err(ActionError::ZeroAmount);
         }
-
-        for (user_id, player) in &self.players {
-            if player.base.curr_level == 100 && self.king == Some(*user_id) {
-                return Some(*user_id);
-            }
+        let (_level, requires) = item.requires().ok_or(ActionError::NotDismantlable)?;
+        if !matches!(
+            item.item_type(),
+            Some(ItemType::Tool | ItemType::Jewelry | ItemType::Clothing)
+        ) {
+            return Err(ActionError::NotDismantlable);
         }
-        None
+        if !player
+            .inventory
+            .items
+            .remove_checked(Bundle::new().add(item, qty))
+        {
+            return Err(ActionError::NotEnoughItems);
+        }
+        let materials = requires.mul(qty).div(DISMANTLING_DIVIDER);
+        account.record(time, LedgerReason::Dismantle, |entry| {
+            entry.gave(&Bundle::new().add(item, qty)).received(&materials)
+        });
+        player.inventory.items.add_checked(materials);
+
+        Ok(())
     }
 
-    pub fn rewarded_premium_days(&self) -> Vec<(UserId, i64)> {
-        let winner_id = self.winner().unwrap();
-        let winner_tribe = self.players.get(&winner_id).and_then(|p|p.tribe);
+    fn client_event(
+        &mut self,
+        rng: &mut impl Rng,
+        event: ClientEvent,
+        user_id: UserId,
+        is_premium: bool,
+    ) -> Result<(), ActionError> {
+        let Some(player) = self.players.get_mut(&user_id) else {
+            return Ok(());
+        };
 
-        self.players
-            .iter()
-            .flat_map(|(&user_id, player)| {
-                if user_id == winner_id {
-                    return Some((user_id, WINNER_NUM_PREMIUM_DAYS));
-                } else if player.tribe == winner_tribe {
-                    return Some((user_id, WINNER_TRIBE_NUM_PREMIUM_DAYS));
+        match event {
+            ClientEvent::Init => {}
+            ClientEvent::SpendTribePoint(territory) => {
+                let tribe_id = player.tribe.ok_or(ActionError::NoTribe)?;
+                if player.tribe_points == 0 {
+                    return Err(ActionError::NoTribePoints);
+                }
+                let tribe = self.tribes.get_mut(&tribe_id).ok_or(ActionError::NoTribe)?;
+                player.tribe_points -= 1;
+                *tribe.territories.entry(territory).or_default() += 1;
+            }
+            ClientEvent::Bid(trade_id) => {
+                let trade = self.trade_deals.get_mut(&trade_id).ok_or(ActionError::TradeNotFound)?;
+                trade.check_bid(player, user_id)?;
+                trade.bid(&mut self.players, user_id, self.time, &self.ledger).ok_or(ActionError::TradeNotFound)?;
+            }
+            ClientEvent::AutoBid(trade_id, bid) => {
+                if !is_premium {
+                    return Err(ActionError::PremiumRequired);
+                }
+                let trade = self.trade_deals.get_mut(&trade_id).ok_or(ActionError::TradeNotFound)?;
+                if trade.user_trade_type == TradeType::Sell {
+                    return Err(ActionError::AutoBidUnavailable);
+                }
+                player.set_auto_bid(trade_id, bid)?;
+                if bid >= trade.next_bid && trade.check_bid(player, user_id).is_ok() {
+                    trade.bid(&mut self.players, user_id, self.time, &self.ledger).ok_or(ActionError::TradeNotFound)?;
+                }
+            }
+            ClientEvent::SetMentor(apprentice_id, mentor_id) => {
+                if let Some(mentor_id) = mentor_id {
+                    let apprentice = player.dwarfs.get(&apprentice_id).ok_or(ActionError::DwarfNotFound)?;
+                    if apprentice.is_adult() {
+                        return Err(ActionError::DwarfIsAdult);
+                    }
+                    let mentor = player.dwarfs.get(&mentor_id).ok_or(ActionError::DwarfNotFound)?;
+                    if !mentor.is_adult() {
+                        return Err(ActionError::DwarfIsChild);
+                    }
+                }
+                player.set_mentor(apprentice_id, mentor_id).ok_or(ActionError::DwarfNotFound)?;
+            }
+            ClientEvent::ReleaseDwarf(dwarf_id) => {
+                let dwarf = player.dwarfs.get_mut(&dwarf_id).ok_or(ActionError::DwarfNotFound)?;
+                dwarf.released = true;
+            }
+            ClientEvent::ToggleManualManagement(dwarf_id) => {
+                let dwarf = player.dwarfs.get_mut(&dwarf_id).ok_or(ActionError::DwarfNotFound)?;
+                dwarf.manual_management = !dwarf.manual_management;
+            }
+            ClientEvent::SetDwarfName(dwarf_id, name) => {
+                let dwarf = player.dwarfs.get_mut(&dwarf_id).ok_or(ActionError::DwarfNotFound)?;
+                let name = name.trim();
+                if name.is_empty() {
+                    dwarf.custom_name = None;
                 } else {
-                    return None;
+                    dwarf.custom_name = Some(name.to_string());
                 }
-            })
-            .collect()
-    }
-}
+            }
+            ClientEvent::Optimize(to_optimize_dwarf_id) => {
+                optimizer::optimize(player, to_optimize_dwarf_id)?;
+            }
+            ClientEvent::SetManagerOccupation(occupation, num) => {
+                player.set_manager();
+
+                let curr = player.manager.get(&occupation).copied().unwrap_or_default();
+                if curr < num {
+                    let diff = num - curr;
+                    if player
+                        .manager
+                        .get(&Occupation::Idling)
+                        .copied()
+                        .unwrap_or_default()
+                        < diff
+                    {
+                        return Err(ActionError::NotEnoughIdleDwarfs);
+                    }
+                    player.manager.insert(occupation, num);
+                    *player.manager.entry(Occupation::Idling).or_default() -= diff;
+                } else {
+                    let diff = curr - num;
+                    player.manager.insert(occupation, num);
+                    *player.manager.entry(Occupation::Idling).or_default() += diff;
+                }
+            }
+            ClientEvent::ConfirmPopup => {
+                player.popups.pop_front();
+            }
+            ClientEvent::SkipAllPopups => {
+                player.popups.clear();
+            }
+            ClientEvent::NextTutorialStep => {
+                if let Some(step) = player.tutorial_step {
+                    if !step.requires().complete(player) {
+                        return Err(ActionError::TutorialStepIncomplete);
+                    }
+                    match step.reward() {
+                        TutorialReward::Money(money) => {
+                            player.money += money;
+                            self.ledger.record(|| {
+                                LedgerEntry::new(self.time, user_id, LedgerReason::TutorialReward).received_money(money)
+                            });
+                        }
+                        TutorialReward::Items(bundle) => {
+                            if !player.inventory.add(bundle.clone(), self.time) {
+                                return Err(ActionError::InventoryFull);
+                            }
+                            self.ledger.record(|| {
+                                LedgerEntry::new(self.time, user_id, LedgerReason::TutorialReward).received(&bundle)
+                            });
+                        }
+                        TutorialReward::Dwarfs(num) => {
+                            for _ in 0..num {
+                                player.new_dwarf(
+                                    rng,
+                                    &mut self.next_dwarf_id,
+                                    self.time,
+                                    Some(Stats::default()),
+                                );
+                            }
+                        }
+                    }
+                    player.tutorial_step = step.next();
+                }
+            }
+            ClientEvent::HireDwarf(dwarf_type) => {
+                if player.money < dwarf_type.cost() {
+                    return Err(ActionError::NotEnoughMoney);
+                }
+                if player.dwarfs.len() >= player.base.max_dwarfs() {
+                    return Err(ActionError::NotEnoughSpace);
+                }
+                player.money -= dwarf_type.cost();
+                self.ledger.record(|| {
+                    LedgerEntry::new(self.time, user_id, LedgerReason::HireDwarf).paid_money(dwarf_type.cost())
+                });
+                player.new_dwarf(rng, &mut self.next_dwarf_id, self.time, Some(Stats::default()));
+            }
+            ClientEvent::HireSpecialDwarf(special_dwarf) => {
+                if player.money < special_dwarf.cost() {
+                    return Err(ActionError::NotEnoughMoney);
+                }
+                if player.dwarfs.len() >= player.base.max_dwarfs() {
+                    return Err(ActionError::NotEnoughSpace);
+                }
+                if player.dwarfs.values().any(|dwarf| dwarf.special_skin == Some(special_dwarf)) {
+                    return Err(ActionError::SpecialDwarfAlreadyHired);
+                }
+                player.money -= special_dwarf.cost();
+                self.ledger.record(|| {
+                    LedgerEntry::new(self.time, user_id, LedgerReason::HireDwarf).paid_money(special_dwarf.cost())
+                });
+                player.new_special_dwarf(
+                    &mut self.next_dwarf_id,
+                    self.time,
+                    special_dwarf,
+                );
+            }
+            ClientEvent::ToggleAutoCraft(item) => {
+                if !is_premium {
+                    return Err(ActionError::PremiumRequired);
+                }
+                if player.toggle(Action::auto_craft(item))? {
+                    player.remove_toggle(&Action::auto_dismantle(item));
+                }
+            }
+            ClientEvent::SetCraftTarget(item, target) => {
+                if !is_premium {
+                    return Err(ActionError::PremiumRequired);
+                }
+                if let Some(target) = target {
+                    if item.requires().is_none() {
+                        return Err(ActionError::NotCraftable);
+                    }
+                    player.auto_functions.craft_targets.insert(item, target);
+                    player.remove_toggle(&Action::auto_dismantle(item));
+                    player.auto_craft(self.time, is_premium);
+                } else {
+                    player.auto_functions.craft_targets.swap_remove(&item);
+                }
+            }
+            ClientEvent::SetReserve(item, qty) => {
+                if qty > 0 {
+                    player.auto_functions.reserves.insert(item, qty);
+                } else {
+                    player.auto_functions.reserves.swap_remove(&item);
+                }
+            }
+            ClientEvent::ToggleAutoDismantle(item) => {
+                if !is_premium {
+                    return Err(ActionError::PremiumRequired);
+                }
+                if player.toggle(Action::auto_dismantle(item))? {
+                    player.remove_toggle(&Action::auto_craft(item));
+                    player.auto_functions.craft_targets.swap_remove(&item);
+                }
+            }
+            ClientEvent::ToggleAutoStore(item) => {
+                if !is_premium {
+                    return Err(ActionError::PremiumRequired);
+                }
+                player.toggle(Action::auto_store(item))?;
+            }
+            ClientEvent::ToggleAutoSell(_item) => {
+                /*if is_premium {
+                    if player.auto_functions.auto_sell.contains(&item) {
+                        player.auto_functions.auto_sell.swap_remove(&item);
+                    } else {
+                        player.auto_functions.auto_sell.insert(item);
+                    }
+                }*/
+            }
+            ClientEvent::ToggleAutoIdle => {
+                //if is_premium {
+                player.auto_functions.auto_idle = !player.auto_functions.auto_idle;
+                //}
+            }
+            ClientEvent::Restart => {
+                if !player.dwarfs.is_empty() {
+                    return Err(ActionError::DwarfsLeft);
+                }
+                let player = Player::new(self.time, rng, &mut self.next_dwarf_id);
+                self.players.insert(user_id, player);
+            }
+            ClientEvent::Message(channel, message) => {
+                if !player.can_read(channel) {
+                    return Err(ActionError::NoTribe);
+                }
+                self.chat
+                    .add_message(&mut self.players, user_id, channel, message, self.time);
+            }
+            ClientEvent::ChangeOccupation(dwarf_id, occupation) => {
+                let dwarf = player.dwarfs.get_mut(&dwarf_id).ok_or(ActionError::DwarfNotFound)?;
 
-impl engine_shared::State for State {
-    type ServerEvent = ServerEvent;
-    type ClientEvent = ClientEvent;
-    type UserId = UserId;
-    type UserData = UserData;
-    type Settings = GameMode;
+                if dwarf.participates_in_quest.is_some() {
+                    return Err(ActionError::DwarfOnQuest);
+                }
+                if player.base.curr_level < occupation.unlocked_at_level() {
+                    return Err(ActionError::LevelTooLow(occupation.unlocked_at_level()));
+                }
+                if !dwarf.is_adult() {
+                    return Err(ActionError::DwarfIsChild);
+                }
+                dwarf.change_occupation(occupation);
+            }
+            ClientEvent::Craft(item, qty) => {
+                Self::craft(player, item, qty)?;
+            }
+            ClientEvent::CancelCraft(idx) => {
+                player.cancel_craft(idx, self.time, self.ledger.account(user_id))?;
+            }
+            ClientEvent::Dismantle(item, qty) => {
+                Self::dismantle(player, item, qty, self.time, self.ledger.account(user_id))?;
+            }
+            ClientEvent::UpgradeBase => {
+                let requires = player
+                    .base
+                    .upgrade_cost(&self.settings)
+                    .ok_or(ActionError::MaxLevelReached)?;
+                if player.base.build_time > 0 {
+                    return Err(ActionError::AlreadyConstructing);
+                }
+                if !player.inventory.items.remove_checked(requires.clone()) {
+                    return Err(ActionError::NotEnoughItems);
+                }
+                self.ledger.record(|| {
+                    LedgerEntry::new(self.time, user_id, LedgerReason::Construction).gave(&requires)
+                });
+                player.base.upgrade(&self.settings);
+            }
+            ClientEvent::UpgradeBuilding(building) => {
+                let requires = player
+                    .base
+                    .building_upgrade_cost(building, &self.settings)
+                    .ok_or(ActionError::MaxLevelReached)?;
+                if building == Building::Headquarters {
+                    if player.base.build_time > 0 {
+                        return Err(ActionError::AlreadyConstructing);
+                    }
+                } else {
+                    if player.base.construction.is_some() {
+                        return Err(ActionError::AlreadyConstructing);
+                    }
+                    if player.base.free_building_slots() == 0 {
+                        return Err(ActionError::NoBuildingSlots);
+                    }
+                }
+                if !player.inventory.items.remove_checked(requires.clone()) {
+                    return Err(ActionError::NotEnoughItems);
+                }
+                self.ledger.record(|| {
+                    LedgerEntry::new(self.time, user_id, LedgerReason::Construction).gave(&requires)
+                });
+                player.base.upgrade_building(building, &self.settings);
+            }
+            ClientEvent::ChangeEquipment(dwarf_id, item_type, item) => {
+                let dwarf = player.dwarfs.get_mut(&dwarf_id).ok_or(ActionError::DwarfNotFound)?;
+                let equipment = &mut dwarf.equipment;
+
+                let old_item = if let Some(item) = item {
+                    if !item
+                        .item_type()
+                        .as_ref()
+                        .map(ItemType::equippable)
+                        .unwrap_or(false)
+                        || item.item_type().unwrap() != item_type
+                    {
+                        return Err(ActionError::NotEquippable);
+                    }
+                    if !player
+                        .inventory
+                        .items
+                        .remove_checked(Bundle::new().add(item, 1))
+                    {
+                        return Err(ActionError::NotEnoughItems);
+                    }
+                    if item_type == ItemType::Consumable {
+                        dwarf.consumable_timer = item.consumable_duration().unwrap_or_default();
+                    }
+                    equipment.insert(item_type, item)
+                } else {
+                    equipment.swap_remove(&item_type)
+                };
 
-    const DURATION_PER_TICK: std::time::Duration = std::time::Duration::from_millis(1000 / SPEED);
+                if let Some(old_item) = old_item {
+                    if old_item.item_type() != Some(ItemType::Consumable) {
+                        player
+                            .inventory
+                            .items
+                            .add_checked(Bundle::new().add(old_item, 1));
+                    }
+                }
+            }
+            ClientEvent::OpenLootCrate => {
+                /*if player.money >= LOOT_CRATE_COST {
+                    player.money -= LOOT_CRATE_COST;
+                    player.open_loot_crate(rng, self.time);
+                }*/
+            }
+            ClientEvent::OpenDailyReward => {
+                /*if player.reward_time <= self.time {
+                    player.reward_time = self.time + FREE_LOOT_CRATE;
+                    player.open_loot_crate(rng, self.time);
+                }*/
+            }
+            ClientEvent::AssignToQuest(quest_id, dwarf_idx, dwarf_id) => {
+                if let Some(dwarf_id) = dwarf_id {
+                    let quest = self.quests.get(&quest_id).ok_or(ActionError::QuestNotFound)?;
+                    if player.base.curr_level < quest.quest_type.occupation().unlocked_at_level() {
+                        return Err(ActionError::LevelTooLow(quest.quest_type.occupation().unlocked_at_level()));
+                    }
+                    if player.base.curr_level > quest.max_level
+                        || player.base.curr_level < quest.min_level
+                    {
+                        return Err(ActionError::QuestLevelMismatch);
+                    }
 
-    fn settings(&self) -> Self::Settings {
-        self.settings.game_mode
-    }
+                    let dwarf = player.dwarfs.get_mut(&dwarf_id).ok_or(ActionError::DwarfNotFound)?;
 
-    fn closed(&self) -> bool {
-        self.winner().is_some()
-    }
+                    if !dwarf.is_adult() {
+                        return Err(ActionError::DwarfIsChild);
+                    }
 
-    fn update(
-        &mut self,
-        rng: &mut impl Rng,
-        event: Event<Self>,
-        user_data: &CustomMap<UserId, UserData>,
-    ) {
-        let update_result = move || -> Option<()> {
-            match event {
-                Event::ClientEvent(event, user_id) if self.start_countdown == 0 => {
-                    if !self.players.contains_key(&user_id) {
-                        self.players.insert(
-                            user_id,
-                            Player::new(self.time, rng, &mut self.next_dwarf_id),
-                        );
+                    if let Some((_, old_quest_id, old_dwarf_idx)) =
+                        dwarf.participates_in_quest
+                    {
+                        let old_quest = self.quests.get_mut(&old_quest_id).ok_or(ActionError::QuestNotFound)?;
+                        let old_contestant =
+                            old_quest.contestants.entry(user_id).or_default();
+                        old_contestant.dwarfs.swap_remove(&old_dwarf_idx);
                     }
-                    let player = self.players.get_mut(&user_id)?;
-                    player.last_online = self.time;
 
-                    let is_premium = user_data
-                        .get(&user_id)
-                        .map(|user_data| user_data.premium > 0)
-                        .unwrap_or(false);
+                    let quest = self.quests.get_mut(&quest_id).ok_or(ActionError::QuestNotFound)?;
+                    let contestant = quest.contestants.entry(user_id).or_default();
 
-                    match event {
-                        ClientEvent::Init => {}
-                        ClientEvent::SpendTribePoint(territory) => {
-                            if let Some(tribe_id) = player.tribe {
-                                if player.tribe_points > 0 {
-                                    player.tribe_points -= 1;
-                                    let tribe = self.tribes.get_mut(&tribe_id)?;
-                                    *tribe.territories.entry(territory).or_default() += 1;
-                                }
-                            }
+                    dwarf.participates_in_quest =
+                        Some((quest.quest_type, quest_id, dwarf_idx));
 
+                    if dwarf_idx < quest.quest_type.max_dwarfs() {
+                        let old_dwarf_id =
+                            contestant.dwarfs.insert(dwarf_idx, dwarf_id);
+                        if let Some(old_dwarf_id) = old_dwarf_id {
+                            let dwarf = player.dwarfs.get_mut(&old_dwarf_id).ok_or(ActionError::DwarfNotFound)?;
+                            dwarf.participates_in_quest = None;
                         }
-                        ClientEvent::Bid(trade_id) => {
-                            if let Some(trade) = self.trade_deals.get_mut(&trade_id) {
-                                trade.bid(&mut self.players, user_id, self.time)?;
-                            }
-                        }
-                        ClientEvent::AutoBid(trade_id, bid) => {
-                            if is_premium {
-                                if let Some(trade) = self.trade_deals.get_mut(&trade_id) {
-                                    player.auto_functions.auto_bid.insert(trade_id, bid);
-                                    if bid >= trade.next_bid {
-                                        trade.bid(&mut self.players, user_id, self.time)?;
-                                    }
-                                }
-                            }
-                        }
-                        ClientEvent::SetMentor(apprentice_id, mentor_id) => {
-                            if let Some(mentor_id) = mentor_id {
-                                let apprentice = player.dwarfs.get(&apprentice_id)?;
-                                if apprentice.is_adult() {
-                                    return None;
-                                }
-                                let mentor = player.dwarfs.get(&mentor_id)?;
-                                if !mentor.is_adult() {
-                                    return None;
-                                }
-                            }
-                            player.set_mentor(apprentice_id, mentor_id)?;
-                        }
-                        ClientEvent::ReleaseDwarf(dwarf_id) => {
-                            let dwarf = player.dwarfs.get_mut(&dwarf_id)?;
-                            dwarf.released = true;
-                        }
-                        ClientEvent::ToggleManualManagement(dwarf_id) => {
-                            let dwarf = player.dwarfs.get_mut(&dwarf_id)?;
-                            dwarf.manual_management = !dwarf.manual_management;
-                        }
-                        ClientEvent::SetDwarfName(dwarf_id, name) => {
-                            let dwarf = player.dwarfs.get_mut(&dwarf_id)?;
-                            let name = name.trim();
-                            if name.is_empty() {
-                                dwarf.custom_name = None;
-                            } else {
-                                dwarf.custom_name = Some(name.to_string());
-                            }
-                        }
-                        ClientEvent::Optimize(to_optimize_dwarf_id) => {
-                            // Reassign occupations.
+                    }
+                } else {
+                    let quest = self.quests.get_mut(&quest_id).ok_or(ActionError::QuestNotFound)?;
+                    let contestant = quest.contestants.entry(user_id).or_default();
 
-                            if to_optimize_dwarf_id.is_none() {
-                                player.set_manager();
+                    let old_dwarf_id = contestant.dwarfs.swap_remove(&dwarf_idx);
 
-                                let mut occupations_to_fill = player.manager.clone();
-                                occupations_to_fill.swap_remove(&Occupation::Idling);
+                    if let Some(old_dwarf_id) = old_dwarf_id {
+                        let dwarf = player.dwarfs.get_mut(&old_dwarf_id).ok_or(ActionError::DwarfNotFound)?;
+                        dwarf.participates_in_quest = None;
+                    }
+                }
+            }
+            ClientEvent::AddToFoodStorage(item, qty) => {
+                Self::add_to_food_storage(player, item, qty)?;
+            }
+            ClientEvent::Sell(item, qty) => {
+                if qty > 0 {
 
-                                for dwarf in player.dwarfs.values_mut() {
-                                    if dwarf.can_be_managed() {
-                                        dwarf.occupation = Occupation::Idling;
-                                    }
-                                }
+                    if item.item_type().is_none() {
+                        return Err(ActionError::NotSellable);
+                    }
 
-                                loop {
-                                    occupations_to_fill.retain(|_, num| *num > 0);
-
-                                    if occupations_to_fill.is_empty() {
-                                        break;
-                                    }
-
-                                    let mut best_dwarf_effectiveness = 0;
-                                    let mut best_dwarf_occupation = None;
-                                    let mut best_dwarf_id = None;
-                                    for (dwarf_id, dwarf) in &player.dwarfs {
-                                        if dwarf.occupation == Occupation::Idling
-                                            && dwarf.can_be_managed()
-                                        {
-                                            for (occupation, _num) in &occupations_to_fill {
-                                                let effectiveness =
-                                                    dwarf.stats.cross(occupation.requires_stats());
-                                                if effectiveness >= best_dwarf_effectiveness {
-                                                    best_dwarf_effectiveness = effectiveness;
-                                                    best_dwarf_id = Some(*dwarf_id);
-                                                    best_dwarf_occupation = Some(*occupation);
-                                                }
-                                            }
-                                        }
-                                    }
-
-                                    if let Some(best_dwarf_id) = best_dwarf_id {
-                                        let best_dwarf = player.dwarfs.get_mut(&best_dwarf_id)?;
-                                        let best_dwarf_occupation = best_dwarf_occupation
-                                            .expect("occupation known if id is known");
-                                        best_dwarf.change_occupation(best_dwarf_occupation);
-                                        *occupations_to_fill
-                                            .get_mut(&best_dwarf_occupation)
-                                            .expect("occupation is always one that is to fill") -=
-                                            1;
-                                    } else {
-                                        break;
-                                    }
-                                }
+                    if self.trade_deals.iter().filter(|(_, trade)| trade.creator == Some(user_id)).count() >= MAX_NUM_TRADES {
+                        return Err(ActionError::TooManyTrades);
+                    }
 
-                                debug_assert!(occupations_to_fill.is_empty());
-                            }
+                    let trade_deal =
+                        TradeDeal::from_player(user_id, player, item, qty, self.next_trade_id)?;
+                    self.ledger.record(|| {
+                        LedgerEntry::new(self.time, user_id, LedgerReason::TradeListed).gave(&trade_deal.items)
+                    });
+                    self.trade_deals.insert(self.next_trade_id, trade_deal);
+                    self.next_trade_id += 1;
 
-                            // Reassign equipment.
-
-                            let dwarf_ids = if let Some(dwarf_id) = to_optimize_dwarf_id {
-                                vec![dwarf_id]
-                            } else {
-                                player.dwarfs.keys().cloned().collect()
-                            };
-
-                            for dwarf_id in &dwarf_ids {
-                                let dwarf = player.dwarfs.get_mut(dwarf_id)?;
-                                if dwarf.can_be_managed() || to_optimize_dwarf_id.is_some() {
-                                    for item_type in enum_iterator::all::<ItemType>() {
-                                        if item_type.equippable() && item_type != ItemType::Consumable {
-                                            if let Some(item) = dwarf.equipment.swap_remove(&item_type) {
-                                                player
-                                                    .inventory
-                                                    .items
-                                                    .add_checked(Bundle::new().add(item, 1));
-                                            }
-                                        }
-   
-
-                                    }
-                                    /*for (_, item) in dwarf.equipment.drain(..) {
-                                        player
-                                            .inventory
-                                            .items
-                                            .add_checked(Bundle::new().add(item, 1));
-                                    }*/
-                                }
-                            }
+                    /*
+                    let qty = qty.min(player.inventory.items.get(&item).copied().unwrap_or(0));
+                    let items = Bundle::new().add(item, qty);
+                    let next_bid = item.money_value(qty) * TRADE_MONEY_MULTIPLIER;
 
-                            loop {
-                                let mut best_dwarf_effectiveness = 0;
-                                let mut best_dwarf_id = None;
-                                let mut best_dwarf_item = None;
-                                for dwarf_id in &dwarf_ids {
-                                    let dwarf = player.dwarfs.get(dwarf_id)?;
-                                    if dwarf.can_be_managed() || to_optimize_dwarf_id.is_some() {
-                                        let occupation_to_optimize =
-                                            if to_optimize_dwarf_id.is_some() {
-                                                dwarf.actual_occupation()
-                                            } else {
-                                                dwarf.occupation
-                                            };
-
-                                        for (item, _) in
-                                            player.inventory.items.iter().filter(|(item, num)| {
-                                                **num > 0
-                                                    && item
-                                                        .item_type()
-                                                        .map(|item_type| item_type.equippable() && item_type != ItemType::Consumable)
-                                                        .unwrap_or(false)
-                                                    && dwarf
-                                                        .equipment
-                                                        .get(&item.item_type().expect(
-                                                            "equippables always have item types",
-                                                        ))
-                                                        .is_none()
-                                            })
-                                        {
-                                            let mut dwarf_clone = dwarf.clone();
-
-                                            let effectiveness_before = dwarf_clone
-                                                .effectiveness_not_normalized(
-                                                    occupation_to_optimize,
-                                                );
-
-                                            dwarf_clone.equipment.insert(
-                                                item.item_type()
-                                                    .expect("equippables always have item types"),
-                                                *item,
-                                            );
-
-                                            let effectiveness_after = dwarf_clone
-                                                .effectiveness_not_normalized(
-                                                    occupation_to_optimize,
-                                                );
-
-                                            let effectiveness_diff = effectiveness_after as i64
-                                                - effectiveness_before as i64;
-
-                                            if effectiveness_diff > best_dwarf_effectiveness {
-                                                best_dwarf_effectiveness = effectiveness_diff;
-                                                best_dwarf_item = Some(*item);
-                                                best_dwarf_id = Some(*dwarf_id);
-                                            }
-                                        }
-                                    }
-                                }
+                    if qty == 0 {
+                        return None;
+                    }
 
-                                if let Some(best_dwarf_id) = best_dwarf_id {
-                                    let best_dwarf = player.dwarfs.get_mut(&best_dwarf_id)?;
-                                    let best_dwarf_item =
-                                        best_dwarf_item.expect("item known if id is known");
-
-                                    if player
-                                        .inventory
-                                        .items
-                                        .remove_checked(Bundle::new().add(best_dwarf_item, 1))
-                                    {
-                                        best_dwarf.equipment.insert(
-                                            best_dwarf_item
-                                                .item_type()
-                                                .expect("equippables always have item types"),
-                                            best_dwarf_item,
-                                        );
-                                    } else if cfg!(debug_assertions) {
-                                        panic!("something went wrong!");
-                                    }
-                                } else {
-                                    break;
-                                }
-                            }
-                        }
-                        ClientEvent::SetManagerOccupation(occupation, num) => {
-                            player.set_manager();
-
-                            let curr = player.manager.get(&occupation).copied().unwrap_or_default();
-                            if curr < num {
-                                let diff = num - curr;
-                                if player
-                                    .manager
-                                    .get(&Occupation::Idling)
-                                    .copied()
-                                    .unwrap_or_default()
-                                    >= diff
-                                {
-                                    player.manager.insert(occupation, num);
-                                    *player.manager.entry(Occupation::Idling).or_default() -= diff;
-                                }
-                            } else {
-                                let diff = curr - num;
-                                player.manager.insert(occupation, num);
-                                *player.manager.entry(Occupation::Idling).or_default() += diff;
-                            }
-                        }
-                        ClientEvent::ConfirmPopup => {
-                            player.popups.pop_front();
-                        }
-                        ClientEvent::SkipAllPopups => {
-                            player.popups.clear();
-                        }
-                        ClientEvent::NextTutorialStep => {
-                            if let Some(step) = player.tutorial_step {
-                                if step.requires().complete(player) {
-                                    match step.reward() {
-                                        TutorialReward::Money(money) => {
-                                            player.money += money;
-                                        }
-                                        TutorialReward::Items(bundle) => {
-                                            player.inventory.add(bundle, self.time);
-                                        }
-                                        TutorialReward::Dwarfs(num) => {
-                                            for _ in 0..num {
-                                                player.new_dwarf(
-                                                    rng,
-                                                    &mut self.next_dwarf_id,
-                                                    self.time,
-                                                    Some(Stats::default()),
-                                                );
-                                            }
-                                        }
-                                    }
-                                    player.tutorial_step = step.next();
-                                }
-                            }
-                        }
-                        ClientEvent::HireDwarf(dwarf_type) => {
-                            if player.money >= dwarf_type.cost()
-                                && player.dwarfs.len() < player.base.max_dwarfs()
-                            {
-                                player.money -= dwarf_type.cost();
-                                player.new_dwarf(rng, &mut self.next_dwarf_id, self.time, Some(Stats::default()));
-                            }
-                        }
-                        ClientEvent::HireSpecialDwarf(special_dwarf) => {
-                            if player.money >= special_dwarf.cost()
-                                && player.dwarfs.len() < player.base.max_dwarfs()
-                                && player.dwarfs.values().all(|dwarf| dwarf.special_skin != Some(special_dwarf))
-                            {
-                                player.money -= special_dwarf.cost();
-                                player.new_special_dwarf(
-                                    &mut self.next_dwarf_id,
-                                    self.time,
-                                    special_dwarf,
-                                );
-                            }
-                        }
-                        ClientEvent::ToggleAutoCraft(item) => {
-                            if is_premium {
-                                if player.auto_functions.auto_craft.contains(&item) {
-                                    player.auto_functions.auto_craft.swap_remove(&item);
-                                } else {
-                                    player.auto_functions.auto_craft.insert(item);
-                                    player.auto_functions.auto_dismantle.swap_remove(&item);
-                                    player.auto_craft(self.time, is_premium);
-                                }
-                            }
-                        }
-                        ClientEvent::ToggleAutoDismantle(item) => {
-                            if is_premium {
-                                if player.auto_functions.auto_dismantle.contains(&item) {
-                                    player.auto_functions.auto_dismantle.swap_remove(&item);
-                                } else {
-                                    player.auto_functions.auto_dismantle.insert(item);
-                                    player.auto_functions.auto_craft.swap_remove(&item);
-                                    player.auto_dismantle(self.time, is_premium);
-                                }
-                            }
-                        }
-                        ClientEvent::ToggleAutoStore(item) => {
-                            if is_premium {
-                                if player.auto_functions.auto_store.contains(&item) {
-                                    player.auto_functions.auto_store.swap_remove(&item);
-                                } else {
-                                    player.auto_functions.auto_store.insert(item);
-                                    player.auto_store(is_premium);
-                                }
-                            }
-                        }
-                        ClientEvent::ToggleAutoSell(_item) => {
-                            /*if is_premium {
-                                if player.auto_functions.auto_sell.contains(&item) {
-                                    player.auto_functions.auto_sell.swap_remove(&item);
-                                } else {
-                                    player.auto_functions.auto_sell.insert(item);
-                                }
-                            }*/
-                        }
-                        ClientEvent::ToggleAutoIdle => {
-                            //if is_premium {
-                            player.auto_functions.auto_idle = !player.auto_functions.auto_idle;
-                            //}
-                        }
-                        ClientEvent::Restart => {
-                            if player.dwarfs.len() == 0 {
-                                let player = Player::new(self.time, rng, &mut self.next_dwarf_id);
-                                self.players.insert(user_id, player);
-                            }
-                        }
-                        ClientEvent::Message(message) => {
-                            self.chat
-                                .add_message(&mut self.players, user_id, message, self.time);
-                        }
-                        ClientEvent::ChangeOccupation(dwarf_id, occupation) => {
-                            let dwarf = player.dwarfs.get_mut(&dwarf_id)?;
-
-                            if dwarf.participates_in_quest.is_none()
-                                && player.base.curr_level >= occupation.unlocked_at_level()
-                                && dwarf.is_adult()
-                            {
-                                dwarf.change_occupation(occupation);
-                            }
-                        }
-                        ClientEvent::Craft(item, qty) => {
-                            Self::craft(player, item, qty);
-                        }
-                        ClientEvent::Dismantle(item, qty) => {
-                            Self::dismantle(player, item, qty);
-                        }
-                        ClientEvent::UpgradeBase => {
-                            if let Some(requires) = player.base.upgrade_cost(&self.settings) {
-                                if player.inventory.items.remove_checked(requires) {
-                                    player.base.upgrade(&self.settings);
-                                }
-                            }
-                        }
-                        ClientEvent::ChangeEquipment(dwarf_id, item_type, item) => {
-                            let dwarf = player.dwarfs.get_mut(&dwarf_id)?;
-                            let equipment = &mut dwarf.equipment;
-
-                            let old_item = if let Some(item) = item {
-                                if item
-                                    .item_type()
-                                    .as_ref()
-                                    .map(ItemType::equippable)
-                                    .unwrap_or(false)
-                                    && item.item_type().unwrap() == item_type
-                                    && player
-                                        .inventory
-                                        .items
-                                        .remove_checked(Bundle::new().add(item, 1))
-                                {
-                                    if item_type == ItemType::Consumable {
-                                        dwarf.consumable_timer = item.consumable_duration().unwrap_or_default();
-                                    }
-                                    equipment.insert(item_type, item)
-                                } else {
-                                    None
-                                }
-                            } else {
-                                equipment.swap_remove(&item_type)
-                            };
-
-                            if let Some(old_item) = old_item {
-                                if old_item.item_type() != Some(ItemType::Consumable) {
-                                    player
-                                        .inventory
-                                        .items
-                                        .add_checked(Bundle::new().add(old_item, 1));
-                                }
-                            }
-                        }
-                        ClientEvent::OpenLootCrate => {
-                            /*if player.money >= LOOT_CRATE_COST {
-                                player.money -= LOOT_CRATE_COST;
-                                player.open_loot_crate(rng, self.time);
-                            }*/
-                        }
-                        ClientEvent::OpenDailyReward => {
-                            /*if player.reward_time <= self.time {
-                                player.reward_time = self.time + FREE_LOOT_CRATE;
-                                player.open_loot_crate(rng, self.time);
-                            }*/
-                        }
-                        ClientEvent::AssignToQuest(quest_id, dwarf_idx, dwarf_id) => {
-                            if let Some(dwarf_id) = dwarf_id {
-                                let quest = self.quests.get(&quest_id)?;
-                                if player.base.curr_level > quest.max_level
-                                    || player.base.curr_level < quest.min_level
-                                    || player.base.curr_level < quest.quest_type.occupation().unlocked_at_level()
-                                {
-                                    return None;
-                                }
-
-                                let dwarf = player.dwarfs.get_mut(&dwarf_id)?;
-
-                                if dwarf.is_adult() {
-                                    if let Some((_, old_quest_id, old_dwarf_idx)) =
-                                        dwarf.participates_in_quest
-                                    {
-                                        let old_quest = self.quests.get_mut(&old_quest_id)?;
-                                        let old_contestant =
-                                            old_quest.contestants.entry(user_id).or_default();
-                                        old_contestant.dwarfs.swap_remove(&old_dwarf_idx);
-                                    }
-
-                                    let quest = self.quests.get_mut(&quest_id)?;
-                                    let contestant = quest.contestants.entry(user_id).or_default();
-
-                                    dwarf.participates_in_quest =
-                                        Some((quest.quest_type, quest_id, dwarf_idx));
-
-                                    if dwarf_idx < quest.quest_type.max_dwarfs() {
-                                        let old_dwarf_id =
-                                            contestant.dwarfs.insert(dwarf_idx, dwarf_id);
-                                        if let Some(old_dwarf_id) = old_dwarf_id {
-                                            let dwarf = player.dwarfs.get_mut(&old_dwarf_id)?;
-                                            dwarf.participates_in_quest = None;
-                                        }
-                                    }
-                                }
-                            } else {
-                                let quest = self.quests.get_mut(&quest_id)?;
-                                let contestant = quest.contestants.entry(user_id).or_default();
-
-                                let old_dwarf_id = contestant.dwarfs.swap_remove(&dwarf_idx);
-
-                                if let Some(old_dwarf_id) = old_dwarf_id {
-                                    let dwarf = player.dwarfs.get_mut(&old_dwarf_id)?;
-                                    dwarf.participates_in_quest = None;
-                                }
-                            }
-                        }
-                        ClientEvent::AddToFoodStorage(item, qty) => {
-                            Self::add_to_food_storage(player, item, qty);
-                        }
-                        ClientEvent::Sell(item, qty) => {
-                            if qty > 0 {
+                    if next_bid == 0 {
+                        return None;
+                    }
 
-                                if item.item_type().is_none() {
-                                    return None;
-                                }
+                    if !player.inventory.items.remove_checked(items.clone()) {
+                        return None;
+                    }
 
-                                if self.trade_deals.iter().filter(|(_, trade)| trade.creator == Some(user_id)).count() >= MAX_NUM_TRADES {
-                                    return None;
-                                }
-                                
-                                if let Some(trade_deal) =
-                                    TradeDeal::from_player(user_id, player, item, qty, self.next_trade_id)
-                                {
-                                    self.trade_deals.insert(self.next_trade_id, trade_deal);
-                                    self.next_trade_id += 1;
-                                }
-                                
-                                /*
-                                let qty = qty.min(player.inventory.items.get(&item).copied().unwrap_or(0));
-                                let items = Bundle::new().add(item, qty);
-                                let next_bid = item.money_value(qty) * TRADE_MONEY_MULTIPLIER;
-
-                                if qty == 0 {
-                                    return None;
-                                }
+                    player.money += next_bid;
+                    */
+                } else {
+                    return Err(ActionError::ZeroAmount);
+                }
+            }
+            ClientEvent::BuyOrder(item, qty, max_price) => {
+                if self.trade_deals.iter().filter(|(_, trade)| trade.creator == Some(user_id)).count() >= MAX_NUM_TRADES {
+                    return Err(ActionError::TooManyTrades);
+                }
 
-                                if next_bid == 0 {
-                                    return None;
-                                }
+                let trade_deal =
+                    TradeDeal::buy_order(user_id, player, item, qty, max_price, self.next_trade_id)?;
+                self.ledger.record(|| {
+                    LedgerEntry::new(self.time, user_id, LedgerReason::BuyOrderPlaced).paid_money(trade_deal.escrow)
+                });
+                self.trade_deals.insert(self.next_trade_id, trade_deal);
+                self.next_trade_id += 1;
+            }
+            ClientEvent::MakeOffer(to, items, money, requested_items, requested_money) => {
+                if self.direct_offers.values().filter(|offer| offer.from == user_id).count() >= MAX_NUM_DIRECT_OFFERS {
+                    return Err(ActionError::TooManyOffers);
+                }
 
-                                if !player.inventory.items.remove_checked(items.clone()) {
-                                    return None;
-                                }
+                if !self.players.contains_key(&to) {
+                    return Err(ActionError::PlayerNotFound);
+                }
 
-                                player.money += next_bid;
-                                */
-                            }
-                        }
-                        ClientEvent::ReadLog => {
-                            player.log.unread = false;
-                        }
-                        ClientEvent::ReadChat => {
-                            player.chat_unread = false;
+                let player = self.players.get_mut(&user_id).ok_or(ActionError::PlayerNotFound)?;
+                let offer = DirectOffer::new(user_id, player, to, items, money, requested_items, requested_money)?;
+                self.ledger.record(|| {
+                    LedgerEntry::new(self.time, user_id, LedgerReason::OfferMade)
+                        .gave(&offer.items)
+                        .paid_money(offer.money)
+                        .with(Some(to))
+                });
+                self.direct_offers.insert(self.next_direct_offer_id, offer);
+                self.next_direct_offer_id += 1;
+
+                let receiver = self.players.get_mut(&to).ok_or(ActionError::PlayerNotFound)?;
+                receiver.log.add(self.time, LogMsg::OfferReceived(user_id));
+            }
+            ClientEvent::AcceptOffer(offer_id) => {
+                let offer = self.direct_offers.get(&offer_id).filter(|offer| offer.to == user_id).ok_or(ActionError::OfferNotFound)?;
+                offer.accept(&mut self.players, self.time, &self.ledger)?;
+                self.direct_offers.swap_remove(&offer_id);
+            }
+            ClientEvent::DeclineOffer(offer_id) => {
+                let offer = self.direct_offers.get(&offer_id).filter(|offer| offer.to == user_id).ok_or(ActionError::OfferNotFound)?;
+                if let Some(sender) = offer.refund(&mut self.players, self.time, &self.ledger) {
+                    sender.log.add(self.time, LogMsg::OfferDeclined(user_id));
+                }
+                self.direct_offers.swap_remove(&offer_id);
+            }
+            ClientEvent::CancelOffer(offer_id) => {
+                let offer = self.direct_offers.get(&offer_id).filter(|offer| offer.from == user_id).ok_or(ActionError::OfferNotFound)?;
+                offer.refund(&mut self.players, self.time, &self.ledger);
+                if let Some(receiver) = self.players.get_mut(&offer.to) {
+                    receiver.log.add(self.time, LogMsg::OfferCancelled(user_id));
+                }
+                self.direct_offers.swap_remove(&offer_id);
+            }
+            ClientEvent::SetTribeName(name) => {
+                let tribe_id = player.tribe.ok_or(ActionError::NoTribe)?;
+                let tribe = self.tribes.get_mut(&tribe_id).ok_or(ActionError::NoTribe)?;
+                if !tribe.can_govern(user_id) {
+                    return Err(ActionError::NotChief);
+                }
+                let name = name.trim();
+                if name.is_empty() || name.chars().count() > MAX_TRIBE_NAME_LEN {
+                    return Err(ActionError::InvalidTribeName);
+                }
+                tribe.name = Some(name.to_string());
+            }
+            ClientEvent::SetTribeBanner(banner) => {
+                let tribe_id = player.tribe.ok_or(ActionError::NoTribe)?;
+                let tribe = self.tribes.get_mut(&tribe_id).ok_or(ActionError::NoTribe)?;
+                if !tribe.can_govern(user_id) {
+                    return Err(ActionError::NotChief);
+                }
+                tribe.banner = Some(banner);
+            }
+            ClientEvent::VoteChief(candidate) => {
+                let tribe_id = player.tribe.ok_or(ActionError::NoTribe)?;
+                let members = self
+                    .tribe_members(tribe_id)
+                    .map(|(member_id, _)| *member_id)
+                    .collect::<CustomSet<_>>();
+                if !members.contains(&candidate) {
+                    return Err(ActionError::NotTribeMember);
+                }
+                let tribe = self.tribes.get_mut(&tribe_id).ok_or(ActionError::NoTribe)?;
+                let previous_chief = tribe.chief;
+                tribe.votes.insert(user_id, candidate);
+                tribe.elect_chief(&members);
+                if let Some(chief) = tribe.chief.filter(|chief| Some(*chief) != previous_chief) {
+                    for member_id in &members {
+                        if let Some(member) = self.players.get_mut(member_id) {
+                            member.log.add(self.time, LogMsg::ChiefElected(chief));
                         }
                     }
                 }
-                Event::ClientEvent(_, _) => {}
-                Event::ServerEvent(event) => {
-                    match event {
-                        ServerEvent::Tick => {
-                            self.time += 1;
+            }
+            ClientEvent::DepositToTreasury(money) => {
+                if money == 0 {
+                    return Err(ActionError::ZeroAmount);
+                }
+                let tribe_id = player.tribe.ok_or(ActionError::NoTribe)?;
+                let tribe = self.tribes.get_mut(&tribe_id).ok_or(ActionError::NoTribe)?;
+                if player.money < money {
+                    return Err(ActionError::NotEnoughMoney);
+                }
+                player.money -= money;
+                tribe.treasury += money;
+                self.ledger.record(|| {
+                    LedgerEntry::new(self.time, user_id, LedgerReason::TreasuryDeposit).paid_money(money)
+                });
+            }
+            ClientEvent::SetTithe(tithe) => {
+                let tribe_id = player.tribe.ok_or(ActionError::NoTribe)?;
+                let tribe = self.tribes.get_mut(&tribe_id).ok_or(ActionError::NoTribe)?;
+                if !tribe.is_chief(user_id) {
+                    return Err(ActionError::NotChief);
+                }
+                if tithe > MAX_TITHE {
+                    return Err(ActionError::TitheTooHigh);
+                }
+                tribe.tithe = tithe;
+            }
+            ClientEvent::UpgradeTribe(upgrade) => {
+                let tribe_id = player.tribe.ok_or(ActionError::NoTribe)?;
+                let tribe = self.tribes.get_mut(&tribe_id).ok_or(ActionError::NoTribe)?;
+                if !tribe.is_chief(user_id) {
+                    return Err(ActionError::NotChief);
+                }
+                let cost = tribe.upgrade_cost(upgrade).ok_or(ActionError::MaxLevelReached)?;
+                if tribe.treasury < cost {
+                    return Err(ActionError::NotEnoughMoney);
+                }
+                tribe.treasury -= cost;
+                *tribe.upgrades.entry(upgrade).or_default() += 1;
+                let level = tribe.upgrade_level(upgrade);
+                for member in self.players.values_mut().filter(|member| member.tribe == Some(tribe_id)) {
+                    member.log.add(self.time, LogMsg::TribeUpgraded(upgrade, level));
+                }
+            }
+            ClientEvent::JoinTribe(tribe_id) => {
+                if player.tribe.is_some() {
+                    return Err(ActionError::AlreadyInTribe);
+                }
+                if player.base.curr_level < JOIN_TRIBE_LEVEL {
+                    return Err(ActionError::LevelTooLow(JOIN_TRIBE_LEVEL));
+                }
+                if !self.tribes.contains_key(&tribe_id) {
+                    return Err(ActionError::TribeNotFound);
+                }
+                if self.tribe_is_full(tribe_id) {
+                    return Err(ActionError::TribeFull);
+                }
+                self.join_tribe(user_id, tribe_id);
+            }
+            ClientEvent::LeaveTribe => {
+                let tribe_id = player.tribe.ok_or(ActionError::NoTribe)?;
+                if !player.can_leave_tribe(self.time) {
+                    return Err(ActionError::TribeChangeCooldown);
+                }
+                player.tribe = None;
+                player.tribe_choice_since = Some(self.time);
+                // Points earned for one tribe can't be spent for another.
+                player.tribe_points = 0;
+                player.unread_chats.swap_remove(&ChatChannel::Tribe(tribe_id));
+
+                let members = self
+                    .tribe_members(tribe_id)
+                    .map(|(member_id, _)| *member_id)
+                    .collect::<CustomSet<_>>();
+                if let Some(tribe) = self.tribes.get_mut(&tribe_id) {
+                    tribe.elect_chief(&members);
+                }
+                for member_id in &members {
+                    if let Some(member) = self.players.get_mut(member_id) {
+                        member.log.add(self.time, LogMsg::MemberLeft(user_id));
+                    }
+                }
+            }
+            ClientEvent::PairDwarfs(pairing) => {
+                if let Some((father_id, mother_id)) = pairing {
+                    let father = player.dwarfs.get(&father_id).ok_or(ActionError::DwarfNotFound)?;
+                    let mother = player.dwarfs.get(&mother_id).ok_or(ActionError::DwarfNotFound)?;
+                    if father.is_female || !mother.is_female {
+                        return Err(ActionError::InvalidPairing);
+                    }
+                    if !father.is_adult() || !mother.is_adult() {
+                        return Err(ActionError::DwarfIsChild);
+                    }
+                }
+                player.pairing = pairing;
+            }
+            ClientEvent::SaveManagerPreset(name, trigger) => {
+                let name = name.trim().to_owned();
+                if name.is_empty() || name.chars().count() > MAX_PRESET_NAME_LEN {
+                    return Err(ActionError::InvalidPresetName);
+                }
+                if trigger.is_some() && !is_premium {
+                    return Err(ActionError::PremiumRequired);
+                }
+                if let Some(PresetTrigger::Hours(from, to)) = trigger {
+                    if from >= 24 || to >= 24 {
+                        return Err(ActionError::InvalidHours);
+                    }
+                }
 
-                            if self.start_countdown > 0 {
-                                self.start_countdown -= 1;
-                            }
+                player.set_manager();
+                let preset = ManagerPreset {
+                    name,
+                    manager: player.manager.clone(),
+                    trigger,
+                };
+                if let Some(existing) = player
+                    .manager_presets
+                    .iter_mut()
+                    .find(|existing| existing.name == preset.name)
+                {
+                    *existing = preset;
+                } else if player.manager_presets.len() >= MAX_MANAGER_PRESETS {
+                    return Err(ActionError::TooManyPresets);
+                } else {
+                    player.manager_presets.push(preset);
+                }
+            }
+            ClientEvent::DeleteManagerPreset(name) => {
+                let idx = player
+                    .manager_presets
+                    .iter()
+                    .position(|preset| preset.name == name)
+                    .ok_or(ActionError::PresetNotFound)?;
+                player.manager_presets.remove(idx);
+            }
+            ClientEvent::ApplyManagerPreset(name) => {
+                let manager = player
+                    .manager_presets
+                    .iter()
+                    .find(|preset| preset.name == name)
+                    .ok_or(ActionError::PresetNotFound)?
+                    .manager
+                    .clone();
+                player.apply_manager(&manager)?;
+            }
+            ClientEvent::AddRule(rule) => {
+                if !is_premium {
+                    return Err(ActionError::PremiumRequired);
+                }
+                player.add_rule(rule)?;
+            }
+            ClientEvent::RemoveRule(idx) => {
+                if idx >= player.rules.len() {
+                    return Err(ActionError::RuleNotFound);
+                }
+                player.rules.remove(idx);
+            }
+            ClientEvent::ToggleRule(idx) => {
+                let rule = player.rules.get_mut(idx).ok_or(ActionError::RuleNotFound)?;
+                rule.enabled = !rule.enabled;
+                rule.triggered = false;
+            }
+            ClientEvent::ReadLog => {
+                player.log.unread = false;
+            }
+            ClientEvent::ReadChat(channel) => {
+                player.unread_chats.swap_remove(&channel);
+            }
+        }
 
-                            // Find the dwarfen eldest.
-                            let mut eldest = None;
-                            let mut age = 0;
-                            for (user_id, player) in &self.players {
-                                for (dwarf_id, dwarf) in &player.dwarfs {
-                                    if dwarf.age_seconds >= age {
-                                        age = dwarf.age_seconds;
-                                        eldest = Some((*user_id, *dwarf_id));
-                                    }
-                                }
-                            }
-                            self.eldest = eldest;
-                            if let Some((user_id, dwarf_id)) = self.eldest {
-                                if let Some(player) = self.players.get_mut(&user_id) {
-                                    if let Some(_dwarf) = player.dwarfs.get_mut(&dwarf_id) {
-                                        if gen_ratio_valid(rng, self.settings.world_speed as u32, ONE_DAY as u32) {
-                                            player.inventory.add(Bundle::new().add(Item::KnowledgeOfTheEldest, 1), self.time);
-                                        }
-                                    }
-                                }
-                            }
+        Ok(())
+    }
 
-                            if matches!(self.event, Some(WorldEvent::Revolution)) {
-                                self.king = None;
-                            };
+    pub fn winner(&self) -> Option<UserId> {
+        if self.settings.infinite {
+            return None;
+        }
 
-                            if self.event.is_some() {
-                                if gen_ratio_valid(rng, self.settings.world_speed as u32, ONE_DAY as u32 / 4) {
-                                    self.event = None;
-                                }
-                            } else if gen_ratio_valid(rng, self.settings.world_speed as u32, ONE_DAY as u32 / 2) {
-                                self.event = Some(enum_iterator::all().choose(rng).unwrap());
-                            }
+        for (user_id, player) in &self.players {
+            if player.base.curr_level >= self.settings.win_level && self.king == Some(*user_id) {
+                return Some(*user_id);
+            }
+        }
+        None
+    }
 
-                            let fewest_members_tribe_id = *self
-                                        .tribes
-                                        .keys()
-                                        .map(|tribe_id| (tribe_id, self.players.values().filter(|player| player.is_active(self.time) && player.tribe == Some(*tribe_id)).count()))
-                                        .min_by_key(|(_, count)| *count)
-                                        .unwrap().0;
-
-                            let tribes_map = self.players.iter()
-                                .filter_map(|(user_id, player)| player.tribe.map(|tribe_id| (*user_id, tribe_id)))
-                                .collect::<CustomMap<_, _>>();
-
-
-                            /*
-                            let king_tribe = if let Some(king) = self.king {
-                                self.players.get(&king).and_then(|player| player.tribe)
-                            } else {
-                                None
-                            };
-                            */
-                            let king_tribe = self.tribes.iter()
-                                .max_by_key(|(_, t)| t.territories.values().sum::<u64>())
-                                .map(|(t_id, _)| *t_id);
-
-                            let territory_scores =  enum_iterator::all::<Territory>()
-                                .flat_map(|territory| {
-                                    self.tribes.iter().map(|(tribe_id, _)| {
-                                        let score = if king_tribe.is_none() || Some(*tribe_id) == king_tribe {
-                                            self.tribes.get(tribe_id).unwrap().territories.get(&territory).copied().unwrap_or(0)
-                                        } else {
-                                            self.tribes.iter()
-                                                .filter(|(t_id, _)| **t_id != king_tribe.unwrap())
-                                                .map(|(_, t)| t.territories.get(&territory).copied().unwrap_or(0))
-                                                .sum()
-                                        };
-                                    
-                                        ((territory, *tribe_id), score)
-                                    }).collect::<Vec<_>>()
-                                })
-                                .collect::<CustomMap<_, _>>();
-
-                            for (user_id, player) in self.players.iter_mut() {
-                                let is_premium = user_data
-                                    .get(user_id)
-                                    .map(|user_data| user_data.premium > 0)
-                                    .unwrap_or(false);
-
-                                // Build the base.
-                                if let Some(JOIN_TRIBE_LEVEL) = player.base.build(&self.settings) {
-                                    let referrer_tribe = user_data.get(user_id).and_then(|user_data| {
-                                        user_data.referrer
-                                    }).and_then(|referrer_id| {
-                                        tribes_map.get(&referrer_id)
-                                    });
-
-                                    if let Some(referrer_tribe) = referrer_tribe {
-                                        player.tribe = Some(*referrer_tribe);
-                                    } else {
-                                        player.tribe = Some(fewest_members_tribe_id);
-                                    }
-                                }
-                                
-                                // Revolutionary spirit drops.
-                                if Some(*user_id) != self.king {
-                                    if gen_ratio_valid(rng, 
-                                        self.settings.world_speed as u32,
-                                        ONE_DAY as u32 * 3,
-                                    ) {
-                                        player.inventory.add(Bundle::new().add(Item::RevolutionarySpirit, 1), self.time);
-                                    }
-                                }
+    /// Adds the player to the tribe. The player keeps the tribe points
+    /// (fame points in the client) earned before joining, the ones left
+    /// when leaving a tribe are forfeited by `ClientEvent::LeaveTribe`.
+    pub fn join_tribe(&mut self, user_id: UserId, tribe_id: TribeId) {
+        for member in self.players.values_mut() {
+            if member.tribe == Some(tribe_id) {
+                member.log.add(self.time, LogMsg::MemberJoined(user_id));
+            }
+        }
+        if let Some(player) = self.players.get_mut(&user_id) {
+            player.tribe = Some(tribe_id);
+            player.tribe_choice_since = None;
+            player.tribe_joined = Some(self.time);
+            player.log.add(self.time, LogMsg::JoinedTribe(tribe_id));
+        }
+    }
 
-                                // Chance for a new dwarf!
-                                let controlled_territories = enum_iterator::all::<Territory>()
-                                    .filter(|territory| {
-                                        if let Some(tribe_id) = player.tribe {
-                                            let score = territory_scores.get(&(*territory, tribe_id)).copied().unwrap_or_default();
-                                            let scores = territory_scores
-                                                .iter()
-                                                .filter(|((t,t_id), _)| *t == *territory && (king_tribe.is_none() || Some(*t_id) == king_tribe || *t_id == tribe_id))
-                                                .map(|(_, s)| *s)
-                                                .collect::<Vec<_>>();
-
-                                            let under_control = scores
-                                                .iter()
-                                                .filter(|s| **s >= score)
-                                                .count() == 1;
-                                            
-                                            under_control
-                                        } else {
-                                            false
-                                        }
-                                    })
-                                    .map(|territory| territory)
-                                    .collect::<Vec<_>>();
-                                    
-                                // Drops for territories.
-                                if !controlled_territories.is_empty() {
-                                    if gen_ratio_valid(rng, 
-                                        self.settings.world_speed as u32,
-                                            ONE_DAY as u32 * 3 / (controlled_territories.len() as u32 + 1)
-                                    ) {
-                                        let t = controlled_territories
-                                            .choose(rng)
-                                            .unwrap();
-    
-                                        let item = t.drop();
+    pub fn tribe_members(&self, tribe_id: TribeId) -> impl Iterator<Item = (&UserId, &Player)> {
+        self.players
+            .iter()
+            .filter(move |(_, player)| player.tribe == Some(tribe_id))
+    }
 
-                                        player.inventory.add(Bundle::new().add(item, 1), self.time);
-                                    }
-                                }
+    pub fn active_players(&self) -> usize {
+        self.players
+            .values()
+            .filter(|player| player.is_active(self.time))
+            .count()
+    }
 
-                                if gen_ratio_valid(rng, 
-                                    self.event
-                                        .map(|event| event.new_dwarfs_multiplier())
-                                        .unwrap_or(1) * self.settings.world_speed as u32,
-                                        ONE_DAY as u32 * 5 / (controlled_territories.len() as u32 + 1)
-                                ) {
-                                    let added_stats = controlled_territories.iter()
-                                        .flat_map(|t| t.best_for_occupation())
-                                        .collect::<Vec<_>>()
-                                        .choose(rng)
-                                        .map(|o| o.requires_stats())
-                                        .unwrap_or(Stats::default());
-
-                                    player.new_dwarf(
-                                        rng,
-                                        &mut self.next_dwarf_id,
-                                        self.time,
-                                        Some(added_stats),
-                                    );
-                                }
+    pub fn active_not_new_players(&self) -> usize {
+        self.players
+            .values()
+            .filter(|player| player.is_active(self.time) && !player.is_new(self.time))
+            .count()
+    }
 
-                                let male_idle_dwarfs = player
-                                    .dwarfs
-                                    .values()
-                                    .filter(|dwarf| {
-                                        dwarf.occupation == Occupation::Idling
-                                            && dwarf.is_adult()
-                                            && !dwarf.is_female
-                                    })
-                                    .count();
-
-                                let female_idle_dwarfs = player
-                                    .dwarfs
-                                    .values()
-                                    .filter(|dwarf| {
-                                        dwarf.occupation == Occupation::Idling
-                                            && dwarf.is_adult()
-                                            && dwarf.is_female
-                                    })
-                                    .count();
-
-                                let pairs = male_idle_dwarfs.min(female_idle_dwarfs);
-
-                                // Chance for a new baby dwarf!
-                                let baby_dwarf_multiplier_event =
-                                    if matches!(self.event, Some(WorldEvent::FullMoon)) {
-                                        3
-                                    } else {
-                                        1
-                                    };
-
-                                let baby_dwarf_multiplier_consumable = player
-                                    .dwarfs
-                                    .values()
-                                    .filter(|dwarf| {
-                                        dwarf.occupation == Occupation::Idling
-                                            && dwarf.is_adult()
-                                    })
-                                    .fold(1, |acc, d| acc * match d.equipment.get(&ItemType::Consumable) {
-                                        Some(&Item::RhinoHornPowder) => 2,
-                                        _ => 1,
-                                    });
-
-                                if gen_ratio_valid(rng, 
-                                    pairs as u32
-                                        * baby_dwarf_multiplier_event * baby_dwarf_multiplier_consumable * self.settings.world_speed as u32,
-                                    ONE_HOUR as u32 * 4,
-                                ) {
-                                    player.new_dwarf(rng, &mut self.next_dwarf_id, self.time, None);
-                                }
+    pub fn max_player_level(&self) -> u64 {
+        self.players
+            .values()
+            .map(|player| player.base.curr_level)
+            .max()
+            .unwrap_or(1)
+    }
 
-                                let mut became_adult = CustomSet::new();
-
-                                // Let the dwarfs eat!
-                                let health_cost_multiplier = match self.event {
-                                    Some(WorldEvent::Plague) => {
-                                        (1 + player.dwarfs.len() as u64 / 15).min(3)
-                                    }
-                                    _ => 1,
-                                };
-                                let mut sorted_by_health =
-                                    player.dwarfs.iter_mut().collect::<Vec<_>>();
-                                sorted_by_health.sort_by_key(|(_, dwarf)| dwarf.health);
-                                for (dwarf_id, dwarf) in sorted_by_health {
-                                    if dwarf.consumable_timer > 0 {
-                                        dwarf.consumable_timer = dwarf.consumable_timer.saturating_sub(self.settings.world_speed);
-                                    } else {
-                                        dwarf.equipment.swap_remove(&ItemType::Consumable);
-                                    }
-
-                                    match dwarf.equipment.get(&ItemType::Consumable) {
-                                        Some(&Item::BearClawPowder) => {},
-                                        _ => {
-                                            dwarf.decr_health(
-                                                dwarf.actual_occupation().health_cost_per_tick()
-                                                    * health_cost_multiplier * self.settings.world_speed,
-                                            );
-                                        },
-                                    }
-                                    
-                                    if dwarf.actual_occupation() == Occupation::Idling {
-                                        let substract_food = self.settings.world_speed.min(player.base.food);
-                                        if substract_food > 0 {
-                                            if dwarf.health <= MAX_HEALTH - (MAX_HEALTH / 1000) * substract_food {
-                                                player.base.food = player.base.food.saturating_sub(substract_food);
-                                                dwarf.incr_health((MAX_HEALTH / 1000) * substract_food);
-                                            } else if player.auto_functions.auto_idle {
-                                                dwarf.auto_idle = false;
-                                            }
-                                        } else if dwarf.auto_idle {
-                                            dwarf.auto_idle = false;
-                                        }
-                                    } else if player.auto_functions.auto_idle
-                                        && dwarf.health <= MAX_HEALTH / 5
-                                        && dwarf.occupation != Occupation::Idling
-                                        && player.base.food > 0
-                                    {
-                                        dwarf.auto_idle = true;
-                                    }
-
-                                    if !dwarf.dead() {
-                                        let is_adult_before = dwarf.is_adult();
-
-                                        match dwarf.equipment.get(&ItemType::Consumable) {
-                                            Some(&Item::TigerFangPowder) => {},
-                                            _ => {
-                                                dwarf.age_seconds += AGE_SECONDS_PER_TICK * self.settings.world_speed;
-                                            },
-                                        }
-
-                                        if dwarf.age_years() > 200 && gen_ratio_valid(rng, self.settings.world_speed as u32, ONE_DAY as u32 * 5) {
-                                            dwarf.health = 0;
-                                        }
-                                        if !is_adult_before && dwarf.is_adult() {
-                                            became_adult.insert(*dwarf_id);
-                                        }
-                                    }
-                                }
+    pub fn tribe_member_counts(&self) -> CustomMap<TribeId, usize> {
+        self.tribes
+            .keys()
+            .map(|tribe_id| (*tribe_id, self.players.values().filter(|player| player.is_active(self.time) && player.tribe == Some(*tribe_id)).count()))
+            .collect()
+    }
 
-                                // Let the dwarfs improve and handle deaths.
-                                let ids = player.dwarfs.keys().cloned().collect::<Vec<_>>();
-                                for dwarf_id in ids {
-                                    let dwarf = player.dwarfs.get(&dwarf_id)?;
-                                    let (improvement_occupation, improvement_multiplier) = if dwarf
-                                        .is_adult()
-                                    {
-                                        (dwarf.actual_occupation(), 1)
-                                    } else if let Some(mentor_id) = dwarf.mentor {
-                                        if let Some(mentor) = player.dwarfs.get(&mentor_id) {
-                                            if mentor.is_adult() {
-                                                (
-                                                    mentor.actual_occupation(),
-                                                    APPRENTICE_EFFECTIVENESS_DIVIDER,
-                                                )
-                                            } else {
-                                                (
-                                                    Occupation::Idling,
-                                                    APPRENTICE_EFFECTIVENESS_DIVIDER,
-                                                )
-                                            }
-                                        } else {
-                                            (
-                                                Occupation::Idling,
-                                                APPRENTICE_EFFECTIVENESS_DIVIDER,
-                                            )
-                                        }
-                                    } else {
-                                        (Occupation::Idling, APPRENTICE_EFFECTIVENESS_DIVIDER)
-                                    };
-
-                                    let dwarf = player.dwarfs.get_mut(&dwarf_id)?;
-
-                                    if !dwarf.dead() {
-                                        if gen_ratio_valid(rng, 
-                                            improvement_occupation.requires_stats().agility as u32
-                                                * improvement_multiplier as u32
-                                                * self.settings.world_speed as u32,
-                                            IMPROVEMENT_DURATION,
-                                        ) && dwarf.stats.agility < 10
-                                        {
-                                            dwarf.stats.agility += 1;
-                                            player.log.add(
-                                                self.time,
-                                                LogMsg::DwarfUpgrade(
-                                                    dwarf.actual_name().to_owned(),
-                                                    "agility".to_string(),
-                                                ),
-                                            );
-                                        }
-                                        if gen_ratio_valid(rng, 
-                                            improvement_occupation.requires_stats().endurance
-                                                as u32 * self.settings.world_speed as u32
-                                                * improvement_multiplier as u32,
-                                            IMPROVEMENT_DURATION,
-                                        ) && dwarf.stats.endurance < 10
-                                        {
-                                            dwarf.stats.endurance += 1;
-                                            player.log.add(
-                                                self.time,
-                                                LogMsg::DwarfUpgrade(
-                                                    dwarf.actual_name().to_owned(),
-                                                    "endurance".to_string(),
-                                                ),
-                                            );
-                                        }
-                                        if gen_ratio_valid(rng, 
-                                            improvement_occupation.requires_stats().strength as u32
-                                                * improvement_multiplier as u32 * self.settings.world_speed as u32,
-                                            IMPROVEMENT_DURATION,
-                                        ) && dwarf.stats.strength < 10
-                                        {
-                                            dwarf.stats.strength += 1;
-                                            player.log.add(
-                                                self.time,
-                                                LogMsg::DwarfUpgrade(
-                                                    dwarf.actual_name().to_owned(),
-                                                    "strength".to_string(),
-                                                ),
-                                            );
-                                        }
-                                        if gen_ratio_valid(rng, 
-                                            improvement_occupation.requires_stats().intelligence
-                                                as u32
-                                                * improvement_multiplier as u32,
-                                            IMPROVEMENT_DURATION,
-                                        ) && dwarf.stats.intelligence < 10
-                                        {
-                                            dwarf.stats.intelligence += 1;
-                                            player.log.add(
-                                                self.time,
-                                                LogMsg::DwarfUpgrade(
-                                                    dwarf.actual_name().to_owned(),
-                                                    "intelligence".to_string(),
-                                                ),
-                                            );
-                                        }
-                                        if gen_ratio_valid(rng, 
-                                            improvement_occupation.requires_stats().perception
-                                                as u32
-                                                * improvement_multiplier as u32,
-                                            IMPROVEMENT_DURATION,
-                                        ) && dwarf.stats.perception < 10
-                                        {
-                                            dwarf.stats.perception += 1;
-                                            player.log.add(
-                                                self.time,
-                                                LogMsg::DwarfUpgrade(
-                                                    dwarf.actual_name().to_owned(),
-                                                    "perception".to_string(),
-                                                ),
-                                            );
-                                        }
-                                    }
-                                }
+    /// A tribe can't be joined once it has too many active members compared
+    /// to the smallest tribe.
+    pub fn tribe_is_full(&self, tribe_id: TribeId) -> bool {
+        let counts = self.tribe_member_counts();
+        let smallest = counts.values().min().copied().unwrap_or_default();
+        counts.get(&tribe_id).copied().unwrap_or_default() >= smallest + MAX_TRIBE_SIZE_DIFFERENCE
+    }
 
-                                // Let the dwarfs work!
-                                let mut added_items = Bundle::new();
-                                for dwarf in player.dwarfs.values() {
-                                    if !dwarf.dead() && dwarf.is_adult() {
-                                        for item in enum_iterator::all::<Item>() {
-                                            if let Some(ItemProbability {
-                                                expected_ticks_per_drop,
-                                            }) =
-                                                item.item_probability(dwarf.actual_occupation())
-                                            {
-                                                if dwarf.gen_ratio_effectiveness(
-                                                    &self.settings,
-                                                    &player.dwarfs,
-                                                    rng,
-                                                    expected_ticks_per_drop
-                                                        * self
-                                                            .event
-                                                            .as_ref()
-                                                            .map(|f| {
-                                                                f.occupation_divider(
-                                                                    dwarf.actual_occupation(),
-                                                                )
-                                                            })
-                                                            .unwrap_or(1),
-                                                ) {
-                                                    added_items = added_items.add(item, 1);
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                player.add_items(added_items, self.time, is_premium);
+    pub fn fewest_members_tribe(&self) -> Option<TribeId> {
+        self.tribes
+            .keys()
+            .map(|tribe_id| (tribe_id, self.players.values().filter(|player| player.is_active(self.time) && player.tribe == Some(*tribe_id)).count()))
+            .min_by_key(|(_, count)| *count)
+            .map(|(tribe_id, _)| *tribe_id)
+    }
 
-                                // Handle dwarfs that became adult
-                                for dwarf_id in became_adult {
-                                    player.set_mentor(dwarf_id, None);
+    pub fn king_tribe(&self) -> Option<TribeId> {
+        self.tribes.iter()
+            .max_by_key(|(_, t)| t.territories.values().sum::<u64>())
+            .map(|(t_id, _)| *t_id)
+    }
 
-                                    let dwarf: &Dwarf = player.dwarfs.get(&dwarf_id)?;
-                                    player.log.add(
-                                        self.time,
-                                        LogMsg::DwarfIsAdult(dwarf.actual_name().to_owned()),
-                                    );
-                                }
+    pub fn territory_scores(&self) -> CustomMap<(Territory, TribeId), u64> {
+        let king_tribe = self.king_tribe();
 
-                                // Handle removed dwarfs
-                                let removed_dwarfs = player
-                                    .dwarfs
-                                    .iter()
-                                    .filter(|(_, dwarf)| dwarf.dead() || dwarf.released)
-                                    .map(|(id, _)| id)
-                                    .copied()
-                                    .collect::<CustomSet<DwarfId>>();
-                                for dwarf_id in removed_dwarfs {
-                                    let apprentice_id: Option<DwarfId> =
-                                        player.dwarfs.get(&dwarf_id)?.apprentice;
-
-                                    if let Some(apprentice_id) = apprentice_id {
-                                        player.set_mentor(apprentice_id, None);
-                                    }
-                                    player.set_mentor(dwarf_id, None);
-
-                                    let dwarf: &Dwarf = player.dwarfs.get(&dwarf_id)?;
-                                    // Send log message that dwarf died.
-                                    player.log.add(
-                                        self.time,
-                                        LogMsg::DwarfDied(dwarf.actual_name().to_owned()),
-                                    );
-                                    // Add the equipment to the inventory.
-                                    for (_, item) in &dwarf.equipment {
-                                        player
-                                            .inventory
-                                            .items
-                                            .add_checked(Bundle::new().add(*item, 1));
-                                    }
-                                }
-                                // Remove dead dwarfs from quests.
-                                for quest in self.quests.values_mut() {
-                                    if let Some(contestant) = quest.contestants.get_mut(user_id) {
-                                        contestant.dwarfs.retain(|_, dwarf_id| {
-                                            !player
-                                                .dwarfs
-                                                .get(&*dwarf_id)
-                                                .map(|d| d.dead() || d.released)
-                                                .unwrap_or(true)
-                                        });
-                                    }
-                                }
+        enum_iterator::all::<Territory>()
+            .flat_map(|territory| {
+                self.tribes.iter().map(|(tribe_id, _)| {
+                    let score = if king_tribe.is_none() || Some(*tribe_id) == king_tribe {
+                        self.tribes.get(tribe_id).unwrap().territories.get(&territory).copied().unwrap_or(0)
+                    } else {
+                        self.tribes.iter()
+                            .filter(|(t_id, _)| **t_id != king_tribe.unwrap())
+                            .map(|(_, t)| t.territories.get(&territory).copied().unwrap_or(0))
+                            .sum()
+                    };
+
+                    ((territory, *tribe_id), score)
+                }).collect::<Vec<_>>()
+            })
+            .collect()
+    }
 
-                                // Remove dead dwarfs from the base.
-                                player
-                                    .dwarfs
-                                    .retain(|_, dwarf| !(dwarf.dead() || dwarf.released));
-                            }
+    pub fn controlled_territories(&self, tribe_id: TribeId) -> Vec<Territory> {
+        let king_tribe = self.king_tribe();
+        let territory_scores = self.territory_scores();
 
-                            // Continue the active quests.
-                            for quest in self.quests.values_mut() {
-                                quest.run(&self.settings, &self.players)?;
-
-                                if quest.done() {
-                                    match quest.quest_type.reward_mode() {
-                                        RewardMode::BestGetsAll(money) => {
-                                            if let Some(user_id) = quest.best() {
-                                                if let Some(player) = self.players.get_mut(&user_id)
-                                                {
-                                                    player.tribe_points += 1;
-
-                                                    if self.king.is_some() {
-                                                        player.money += money * 9 / 10;
-                                                    } else {
-                                                        player.money += money;
-                                                    }
-                                                    player.log.add(
-                                                        self.time,
-                                                        LogMsg::QuestCompletedMoney(
-                                                            quest.quest_type,
-                                                            money,
-                                                        ),
-                                                    );
-                                                }
-                                                if let Some(king) = self.king {
-                                                    if let Some(player) =
-                                                        self.players.get_mut(&king)
-                                                    {
-                                                        player.money += money / 10;
-                                                        player.log.add(
-                                                            self.time,
-                                                            LogMsg::MoneyForKing(money / 10),
-                                                        );
-                                                    }
-                                                }
-                                                for contestant_id in quest.contestants.keys() {
-                                                    if *contestant_id != user_id {
-                                                        let player =
-                                                            self.players.get_mut(contestant_id)?;
-                                                        player.log.add(
-                                                            self.time,
-                                                            LogMsg::QuestCompletedMoney(
-                                                                quest.quest_type,
-                                                                0,
-                                                            ),
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        RewardMode::BecomeKing => {
-                                            if let Some(user_id) = quest.best() {
-                                                if let Some(player) = self.players.get_mut(&user_id)
-                                                {
-                                                    player.tribe_points += 1;
-
-                                                    if !matches!(
-                                                        self.event,
-                                                        Some(WorldEvent::Revolution)
-                                                    ) {
-                                                        self.king = Some(user_id);
-                                                        player.log.add(
-                                                            self.time,
-                                                            LogMsg::QuestCompletedKing(
-                                                                quest.quest_type,
-                                                                true,
-                                                            ),
-                                                        );
-                                                    }
-                                                }
-                                                for contestant_id in quest.contestants.keys() {
-                                                    if Some(*contestant_id) != self.king {
-                                                        let player =
-                                                            self.players.get_mut(contestant_id)?;
-                                                        player.log.add(
-                                                            self.time,
-                                                            LogMsg::QuestCompletedKing(
-                                                                quest.quest_type,
-                                                                false,
-                                                            ),
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        RewardMode::SplitFairly(money) => {
-                                            for (user_id, money) in
-                                                quest.split_by_score(if self.king.is_some() {
-                                                    money * 9 / 10
-                                                } else {
-                                                    money
-                                                })
-                                            {
-                                                if let Some(player) = self.players.get_mut(&user_id)
-                                                {
-                                                    player.money += money;
-                                                    player.log.add(
-                                                        self.time,
-                                                        LogMsg::QuestCompletedMoney(
-                                                            quest.quest_type,
-                                                            money,
-                                                        ),
-                                                    );
-                                                }
-                                            }
-                                            if let Some(king) = self.king {
-                                                if let Some(player) = self.players.get_mut(&king) {
-                                                    player.money += money / 10;
-                                                    player.log.add(
-                                                        self.time,
-                                                        LogMsg::MoneyForKing(money / 10),
-                                                    );
-                                                }
-                                            }
-                                        }
-                                        RewardMode::BestGetsItems(items) => {
-                                            if let Some(user_id) = quest.best() {
-                                                if let Some(player) = self.players.get_mut(&user_id)
-                                                {
-                                                    player.tribe_points += 1;
-
-                                                    let is_premium = user_data
-                                                        .get(&user_id)
-                                                        .map(|user_data| user_data.premium > 0)
-                                                        .unwrap_or(false);
-
-                                                    player.add_items(
-                                                        items.clone(),
-                                                        self.time,
-                                                        is_premium,
-                                                    );
-                                                    player.log.add(
-                                                        self.time,
-                                                        LogMsg::QuestCompletedItems(
-                                                            quest.quest_type,
-                                                            Some(items),
-                                                        ),
-                                                    );
-                                                }
-                                                for contestant_id in quest.contestants.keys() {
-                                                    if *contestant_id != user_id {
-                                                        let player =
-                                                            self.players.get_mut(contestant_id)?;
-                                                        player.log.add(
-                                                            self.time,
-                                                            LogMsg::QuestCompletedItems(
-                                                                quest.quest_type,
-                                                                None,
-                                                            ),
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        RewardMode::ItemsByChance(items) => {
-                                            if let Some(user_id) = quest.chance_by_score(rng) {
-                                                if let Some(player) = self.players.get_mut(&user_id)
-                                                {
-                                                    let is_premium = user_data
-                                                        .get(&user_id)
-                                                        .map(|user_data| user_data.premium > 0)
-                                                        .unwrap_or(false);
-
-                                                    player.add_items(
-                                                        items.clone(),
-                                                        self.time,
-                                                        is_premium,
-                                                    );
-                                                    player.log.add(
-                                                        self.time,
-                                                        LogMsg::QuestCompletedItems(
-                                                            quest.quest_type,
-                                                            Some(items),
-                                                        ),
-                                                    );
-                                                }
-                                                for contestant_id in quest.contestants.keys() {
-                                                    if *contestant_id != user_id {
-                                                        let player =
-                                                            self.players.get_mut(contestant_id)?;
-                                                        player.log.add(
-                                                            self.time,
-                                                            LogMsg::QuestCompletedItems(
-                                                                quest.quest_type,
-                                                                None,
-                                                            ),
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        RewardMode::NewDwarfByChance(num_dwarfs) => {
-                                            let mut reward = CustomMap::new();
-                                            for _ in 0..num_dwarfs {
-                                                if let Some(user_id) = quest.chance_by_score(rng) {
-                                                    *reward.entry(user_id).or_default() += 1;
-                                                }
-                                            }
-                                            for (user_id, num_dwarfs) in reward.iter() {
-                                                if let Some(player) =
-                                                    self.players.get_mut(user_id)
-                                                {
-                                                    player.log.add(
-                                                        self.time,
-                                                        LogMsg::QuestCompletedDwarfs(
-                                                            quest.quest_type,
-                                                            Some(*num_dwarfs),
-                                                        ),
-                                                    );
-                                                    for _ in 0..*num_dwarfs {
-                                                        player.new_dwarf(
-                                                            rng,
-                                                            &mut self.next_dwarf_id,
-                                                            self.time,
-                                                            Some(Stats::default()),
-                                                        );
-                                                    }
-       
-                                                }
-                                            }
-                                            for contestant_id in quest.contestants.keys() {
-                                                if !reward.contains_key(contestant_id) {
-                                                    let player = self
-                                                        .players
-                                                        .get_mut(contestant_id)?;
-                                                    player.log.add(
-                                                        self.time,
-                                                        LogMsg::QuestCompletedDwarfs(
-                                                            quest.quest_type,
-                                                            None,
-                                                        ),
-                                                    );
-                                                }
-                                            }
-                                        }
-                                        RewardMode::NewDwarf(num_dwarfs) => {
-                                            if let Some(user_id) = quest.best() {
-                                                if let Some(player) = self.players.get_mut(&user_id)
-                                                {
-                                                    player.tribe_points += 1;
-                                                    player.log.add(
-                                                        self.time,
-                                                        LogMsg::QuestCompletedDwarfs(
-                                                            quest.quest_type,
-                                                            Some(num_dwarfs),
-                                                        ),
-                                                    );
-                                                    for _ in 0..num_dwarfs {
-                                                        player.new_dwarf(
-                                                            rng,
-                                                            &mut self.next_dwarf_id,
-                                                            self.time,
-                                                            Some(Stats::default()),
-                                                        );
-                                                    }
-                                                }
-                                                for contestant_id in quest.contestants.keys() {
-                                                    if *contestant_id != user_id {
-                                                        let player =
-                                                            self.players.get_mut(contestant_id)?;
-                                                        player.log.add(
-                                                            self.time,
-                                                            LogMsg::QuestCompletedDwarfs(
-                                                                quest.quest_type,
-                                                                None,
-                                                            ),
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-
-                                    for (contestant_id, contestant) in quest.contestants.iter() {
-                                        let player = self.players.get_mut(contestant_id)?;
-                                        for dwarf_id in contestant.dwarfs.values() {
-                                            let dwarf = player.dwarfs.get_mut(dwarf_id)?;
-                                            dwarf.participates_in_quest = None;
-                                        }
-                                    }
-                                }
-                            }
+        enum_iterator::all::<Territory>()
+            .filter(|territory| {
+                let score = territory_scores.get(&(*territory, tribe_id)).copied().unwrap_or_default();
+                territory_scores
+                    .iter()
+                    .filter(|((t, t_id), _)| *t == *territory && (king_tribe.is_none() || Some(*t_id) == king_tribe || *t_id == tribe_id))
+                    .filter(|(_, s)| **s >= score)
+                    .count() == 1
+            })
+            .collect()
+    }
 
-                            self.quests.retain(|_, quest| !quest.done());
-
-                            // Add quests.
-                            let active_players = self
-                                .players
-                                .iter()
-                                .filter(|(_, player)| player.is_active(self.time))
-                                .count();
-
-                            let active_not_new_players = self
-                                .players
-                                .iter()
-                                .filter(|(_, player)| {
-                                    player.is_active(self.time) && !player.is_new(self.time)
-                                })
-                                .count();
-
-                            let num_quests = if cfg!(debug_assertions) {
-                                30
-                            } else {
-                                (active_players / (2 * NEW_PLAYER_DIVIDER as usize) + active_not_new_players * 2)
-                                    .max(10)
-                            };
-
-                            let max_player_level = self
-                                .players
-                                .iter()
-                                .map(|(_, player)| player.base.curr_level)
-                                .max()
-                                .unwrap_or(1);
-
-                            let mut potential_quests = enum_iterator::all::<QuestType>()
-                                .filter(|quest_type| {
-                                    (if let Some(level) = quest_type.max_level() {
-                                        max_player_level > level - 10 && max_player_level <= level
-                                    } else {
-                                        true
-                                    }) && (!quest_type.one_at_a_time()
-                                        || (quest_type.one_at_a_time()
-                                            && !self
-                                                .quests
-                                                .values()
-                                                .any(|quest| quest.quest_type == *quest_type)))
-                                })
-                                .collect::<CustomSet<_>>();
-
-                            while self.quests.len() < num_quests {
-                                if potential_quests.is_empty() {
-                                    break;
-                                }
+    pub fn rewarded_premium_days(&self) -> Vec<(UserId, i64)> {
+        let winner_id = self.winner().unwrap();
+        let winner_tribe = self.players.get(&winner_id).and_then(|p|p.tribe);
 
-                                let selected_quest = *potential_quests
-                                    .iter()
-                                    .copied()
-                                    .collect::<Vec<_>>()
-                                    .choose(rng)
-                                    .expect("potential quests is empty");
+        self.players
+            .iter()
+            .flat_map(|(&user_id, player)| {
+                if user_id == winner_id {
+                    return Some((user_id, WINNER_NUM_PREMIUM_DAYS));
+                } else if player.tribe == winner_tribe {
+                    return Some((user_id, WINNER_TRIBE_NUM_PREMIUM_DAYS));
+                } else {
+                    return None;
+                }
+            })
+            .collect()
+    }
+}
 
-                                if selected_quest.one_at_a_time() {
-                                    potential_quests.swap_remove(&selected_quest);
-                                }
+impl engine_shared::State for State {
+    type ServerEvent = ServerEvent;
+    type ClientEvent = ClientEvent;
+    type UserId = UserId;
+    type UserData = UserData;
+    type Settings = GameMode;
 
-                                let (min_level, max_level) =
-                                    if let Some(level) = selected_quest.max_level() {
-                                        (1, level)
-                                    } else if selected_quest.one_at_a_time() {
-                                        (1, u64::MAX)
-                                    } else {
-                                        let selected_level = self
-                                            .players
-                                            .iter()
-                                            .collect::<Vec<_>>()
-                                            .choose_weighted(rng, |(_, player)| {
-                                                if player.is_active(self.time) {
-                                                    if player.is_new(self.time) {
-                                                        1
-                                                    } else {
-                                                        NEW_PLAYER_DIVIDER
-                                                    }
-                                                } else {
-                                                    0
-                                                }
-                                            })
-                                            .map(|(_, player)| player.base.curr_level)
-                                            .unwrap_or(1);
-                                        
-                                        let bracket_low = selected_level / 10 + 5;
-                                        let bracket_high = selected_level / 5 + 10;
-                                        let mut min_level = (selected_level.saturating_sub(rng.gen_range(bracket_low..=bracket_high))).max(1);
-                                        let mut max_level = selected_level + rng.gen_range(bracket_low..=bracket_high);
-                                        
-                                        if min_level < selected_quest.occupation().unlocked_at_level() {
-                                            let diff = selected_quest.occupation().unlocked_at_level() - min_level;
-                                            min_level += diff;
-                                            max_level += diff;
-                                        }
-
-                                        (min_level, max_level)
-                                    };
-
-                                if max_level < selected_quest.occupation().unlocked_at_level() {
-                                    continue;
-                                }
+    const DURATION_PER_TICK: std::time::Duration = std::time::Duration::from_millis(1000 / SPEED);
 
-                                let quest = Quest::new(selected_quest, min_level, max_level);
+    fn settings(&self) -> Self::Settings {
+        self.settings.game_mode
+    }
 
-                                self.quests.insert(self.next_quest_id, quest);
+    fn closed(&self) -> bool {
+        self.winner().is_some()
+    }
 
-                                self.next_quest_id += 1;
-                            }
+    fn update(
+        &mut self,
+        rng: &mut impl Rng,
+        event: Event<Self>,
+        user_data: &CustomMap<UserId, UserData>,
+    ) {
+        self.update_seeded(rng.gen(), event, user_data);
+    }
+}
+
+impl State {
+    /// Applies an event with an rng seeded from the given seed, so that the
+    /// event can be replayed from the journal.
+    pub fn update_seeded(
+        &mut self,
+        seed: u64,
+        event: Event<Self>,
+        user_data: &CustomMap<UserId, UserData>,
+    ) {
+        // ChaCha is reproducible across platforms and versions of rand, unlike
+        // the standard and the small rng, so replays apply the same events.
+        let rng = &mut ChaCha8Rng::seed_from_u64(seed);
+
+        // The journal records the event once it is applied. Rejected events
+        // are recorded as well, since a handler may have changed the state
+        // before it failed, only ignored events are not recorded at all.
+        let journal = self.journal.clone();
+        let mut journaled = journal
+            .is_recording()
+            .then(|| JournalEntry::Event(seed, JournalEvent::new(&event)));
+        let journaled_ref = &mut journaled;
+
+        let update_result = move || -> Option<()> {
+            match event {
+                Event::ClientEvent(event, user_id) if self.start_countdown == 0 => {
+                    if !self.players.contains_key(&user_id) {
+                        self.players.insert(
+                            user_id,
+                            Player::new(self.time, rng, &mut self.next_dwarf_id),
+                        );
+                    }
+                    let player = self.players.get_mut(&user_id)?;
+                    player.last_online = self.time;
+
+                    let is_premium = user_data
+                        .get(&user_id)
+                        .map(|user_data| user_data.premium > 0)
+                        .unwrap_or(false);
+
+                    if let Err(error) = self.client_event(rng, event, user_id, is_premium) {
+                        let player = self.players.get_mut(&user_id)?;
+                        player.last_error = Some((error, self.time));
+                    }
+                }
+                Event::ClientEvent(_, _) => {
+                    *journaled_ref = None;
+                }
+                Event::ServerEvent(event) => {
+                    match event {
+                        ServerEvent::Tick => {
+                            self.time += 1;
 
-                            // Add trades.
-                            for trade in self.trade_deals.values_mut() {
-                                trade.update(&self.settings, &mut self.players, self.time)?;
+                            if self.start_countdown > 0 {
+                                self.start_countdown -= 1;
                             }
 
-                            self.trade_deals.retain(|_, trade| !trade.done());
-
-                            let num_trades = if cfg!(debug_assertions) {
-                                15
-                            } else {
-                                (active_players / (5 * NEW_PLAYER_DIVIDER as usize) + active_not_new_players / 5)
-                                    .max(3)
-                                    .min(15)
-                            };
-
-                            while self
-                                .trade_deals
-                                .values()
-                                .filter(|trade_deal| trade_deal.creator.is_none())
-                                .count()
-                                < num_trades
-                            {
-                                self.trade_deals.insert(self.next_trade_id, TradeDeal::new(rng, max_player_level, self.next_trade_id));
-                                self.next_trade_id += 1;
+                            for phase in enum_iterator::all::<TickPhase>() {
+                                // A failing phase must not keep the others from running.
+                                if !self.settings.disabled_phases.contains(&phase)
+                                    && phase.run(self, rng, user_data).is_none()
+                                {
+                                    log::error!("tick phase {} failed", phase);
+                                }
                             }
 
                             // Only keep players that were recently active or have any dwarfs left.
@@ -2003,6 +1701,10 @@ impl engine_shared::State for State {
         if update_result.is_none() {
             println!("state update failed");
         }
+
+        if let Some(entry) = journaled {
+            journal.record(|| entry);
+        }
     }
 }
 
@@ -2197,10 +1899,31 @@ pub enum LogMsg {
     NotEnoughSpaceForDwarf,
     DwarfUpgrade(String, String),
     DwarfIsAdult(String),
+    DwarfTrait(String, Trait),
+    DwarfMastery(String, Occupation, Mastery),
     Overbid(Bundle<Item>, Money, TradeType),
     BidWon(Bundle<Item>, Money, TradeType),
     ItemSold(Bundle<Item>, Money),
     ItemNotSold(Bundle<Item>, Money),
+    BuildingUpgraded(Building, u64),
+    BuyOrderFilled(Bundle<Item>, Money),
+    BuyOrderExpired(Bundle<Item>, Money),
+    OfferReceived(UserId),
+    OfferCompleted(UserId, Bundle<Item>, Money),
+    OfferDeclined(UserId),
+    OfferCancelled(UserId),
+    OfferExpired(UserId),
+    ChiefElected(UserId),
+    TribeUpgraded(TribeUpgrade, u64),
+    ChooseTribe,
+    JoinedTribe(TribeId),
+    MemberJoined(UserId),
+    MemberLeft(UserId),
+    ManagerPresetApplied(String),
+    ManagerPresetEnded(String),
+    /// The item, how many were still to be crafted and the missing
+    /// ingredients.
+    CraftingStopped(Item, u64, Bundle<Item>),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash)]
@@ -2219,33 +1942,121 @@ pub struct Player {
     pub start_time: Time,
     pub popups: VecDeque<Popup>,
     pub manager: CustomMap<Occupation, u64>,
-    #[serde(default)]
-    pub chat_unread: bool,
     pub tribe: Option<TribeId>,
     pub tribe_points: u64,
+    #[serde(default)]
+    pub last_error: Option<(ActionError, Time)>,
+    /// When the player reached the level to join a tribe. Players that
+    /// don't choose a tribe in time are assigned to one.
+    #[serde(default)]
+    pub tribe_choice_since: Option<Time>,
+    #[serde(default)]
+    pub tribe_joined: Option<Time>,
+    /// Father and mother the player wants the next baby from.
+    #[serde(default)]
+    pub pairing: Option<(DwarfId, DwarfId)>,
+    #[serde(default)]
+    pub manager_presets: Vec<ManagerPreset>,
+    #[serde(default)]
+    pub triggered_preset: Option<TriggeredPreset>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub craft_queue: VecDeque<CraftOrder>,
+    /// Items found by the dwarfs over the whole world, for the results.
+    #[serde(default)]
+    pub items_produced: u64,
+    #[serde(default)]
+    pub quests_won: u64,
+    #[serde(default)]
+    pub unread_chats: CustomSet<ChatChannel>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
+pub struct CraftOrder {
+    pub item: Item,
+    /// Items left to craft, including the one in progress.
+    pub qty: u64,
+    pub progress: Time,
+    /// Whether the ingredients of the item in progress were taken already.
+    pub started: bool,
+    /// Whether the order was planned by auto-crafting.
+    pub auto: bool,
+}
+
+/// A preset that was applied by its trigger.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
+pub struct TriggeredPreset {
+    pub name: String,
+    /// The manager to go back to once the trigger ends.
+    pub previous: CustomMap<Occupation, u64>,
+    /// The manager right after the preset was applied. If the player changes
+    /// the manager in the meantime, it is not replaced when the trigger ends.
+    pub applied: CustomMap<Occupation, u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
+pub struct ManagerPreset {
+    pub name: String,
+    pub manager: CustomMap<Occupation, u64>,
+    pub trigger: Option<PresetTrigger>,
+}
+
+/// A condition under which a manager preset is applied automatically.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum PresetTrigger {
+    /// While the food storage holds less than this.
+    FoodBelow(u64),
+    /// While the world event is going on.
+    WorldEvent(WorldEvent),
+    /// From the first to the second hour of each day in the world.
+    Hours(u64, u64),
+}
+
+impl PresetTrigger {
+    /// Whether the preset should be applied. A preset that is applied
+    /// already stays until the food is `PRESET_FOOD_MARGIN_PERCENT` above the
+    /// threshold, so that it doesn't switch back and forth every tick.
+    pub fn active(self, player: &Player, event: Option<WorldEvent>, time: Time, applied: bool) -> bool {
+        match self {
+            PresetTrigger::FoodBelow(food) if applied => {
+                player.base.food < food * (100 + PRESET_FOOD_MARGIN_PERCENT) / 100
+            }
+            PresetTrigger::FoodBelow(food) => player.base.food < food,
+            PresetTrigger::WorldEvent(world_event) => event == Some(world_event),
+            PresetTrigger::Hours(from, to) => in_hours(time, from, to),
+        }
+    }
+}
+
+impl std::fmt::Display for PresetTrigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresetTrigger::FoodBelow(food) => write!(f, "While food is below {food}"),
+            PresetTrigger::WorldEvent(world_event) => write!(f, "During {world_event}"),
+            PresetTrigger::Hours(from, to) => write!(f, "From {from}:00 to {to}:00"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash)]
 pub struct AutoFunctions {
     pub auto_idle: bool,
-    pub auto_craft: CustomSet<Item>,
-    pub auto_store: CustomSet<Item>,
     pub auto_sell: CustomSet<Item>,
-    #[serde(default = "CustomSet::new")]
-    pub auto_dismantle: CustomSet<Item>,
     #[serde(default = "CustomMap::new")]
-    pub auto_bid: CustomMap<TradeId, Money>,
+    pub craft_targets: CustomMap<Item, CraftTarget>,
+    /// Items that auto-crafting leaves untouched.
+    #[serde(default = "CustomMap::new")]
+    pub reserves: CustomMap<Item, u64>,
 }
 
 impl Default for AutoFunctions {
     fn default() -> Self {
         Self {
             auto_idle: true,
-            auto_craft: CustomSet::new(),
-            auto_store: CustomSet::new(),
             auto_sell: CustomSet::new(),
-            auto_dismantle: CustomSet::new(),
-            auto_bid: CustomMap::new(),
+            craft_targets: CustomMap::new(),
+            reserves: CustomMap::new(),
         }
     }
 }
@@ -2265,9 +2076,19 @@ impl Player {
             start_time: time,
             popups: VecDeque::new(),
             manager: CustomMap::new(),
-            chat_unread: false,
             tribe: None,
             tribe_points: 0,
+            tribe_choice_since: None,
+            tribe_joined: None,
+            pairing: None,
+            last_error: None,
+            manager_presets: Vec::new(),
+            triggered_preset: None,
+            rules: Vec::new(),
+            craft_queue: VecDeque::new(),
+            items_produced: 0,
+            quests_won: 0,
+            unread_chats: CustomSet::new(),
         };
 
         player.new_dwarf(rng, next_dwarf_id, time, Some(Stats::default()));
@@ -2285,12 +2106,33 @@ impl Player {
                     .add(Item::Stone, 10000)
                     .add(Item::Coal, 10000),
                 time,
-            )
+            );
         }
 
         player
     }
 
+    /// Bonus in percent from the upgrades of the tribe of this player.
+    pub fn tribe_bonus(&self, tribes: &CustomMap<TribeId, Tribe>, upgrade: TribeUpgrade) -> u64 {
+        self.tribe
+            .and_then(|tribe_id| tribes.get(&tribe_id))
+            .map(|tribe| tribe.upgrade_bonus(upgrade))
+            .unwrap_or_default()
+    }
+
+    pub fn can_leave_tribe(&self, time: Time) -> bool {
+        self.tribe_joined
+            .map(|joined| time >= joined + TRIBE_CHANGE_COOLDOWN)
+            .unwrap_or(true)
+    }
+
+    pub fn can_read(&self, channel: ChatChannel) -> bool {
+        match channel {
+            ChatChannel::Global => true,
+            ChatChannel::Tribe(tribe_id) => self.tribe == Some(tribe_id),
+        }
+    }
+
     pub fn remaining_time_until_starvation(&self, state: &State) -> Time {
         let mut health_available = self.base.food * (MAX_HEALTH / 1000);
         let mut health_cost_per_tick = 0;
@@ -2314,6 +2156,21 @@ impl Player {
         health_available / health_cost_per_tick
     }
 
+    /// The `denominator_mul` of `Dwarf::gen_ratio_effectiveness` for an item
+    /// that drops in the occupation.
+    pub fn drop_denominator_mul(
+        &self,
+        occupation: Occupation,
+        expected_ticks_per_drop: u64,
+        event: Option<WorldEvent>,
+        tribes: &CustomMap<TribeId, Tribe>,
+    ) -> u64 {
+        expected_ticks_per_drop
+            * event.map(|f| f.occupation_divider(occupation)).unwrap_or(1)
+            * 100
+            / (100 + self.base.occupation_bonus(occupation) + self.tribe_bonus(tribes, TribeUpgrade::Guildhall))
+    }
+
     pub fn average_efficiency(&self) -> Option<u64> {
         self.dwarfs
             .values()
@@ -2322,26 +2179,105 @@ impl Player {
             .checked_div(self.dwarfs.len() as u64)
     }
 
-    pub fn set_manager(&mut self) {
-        let manager_num = self.manager.values().copied().sum::<u64>();
-        let dwarfs_num = self
-            .dwarfs
-            .values()
-            .filter(|dwarf| dwarf.can_be_managed())
-            .count() as u64;
+    pub fn set_manager(&mut self) {
+        let manager_num = self.manager.values().copied().sum::<u64>();
+        let dwarfs_num = self
+            .dwarfs
+            .values()
+            .filter(|dwarf| dwarf.can_be_managed())
+            .count() as u64;
+
+        if manager_num > dwarfs_num {
+            self.manager.clear();
+            for dwarf in self.dwarfs.values() {
+                if dwarf.can_be_managed() {
+                    *self.manager.entry(dwarf.occupation).or_default() += 1;
+                }
+            }
+        } else if dwarfs_num > manager_num {
+            self.manager
+                .entry(Occupation::Idling)
+                .and_modify(|v| *v += dwarfs_num - manager_num)
+                .or_insert(dwarfs_num - manager_num);
+        }
+    }
+
+    /// The manager fitted to the number of dwarfs that can be managed.
+    fn fit_manager(&self, manager: &CustomMap<Occupation, u64>) -> CustomMap<Occupation, u64> {
+        let mut remaining = self
+            .dwarfs
+            .values()
+            .filter(|dwarf| dwarf.can_be_managed())
+            .count() as u64;
+
+        let mut fitted = CustomMap::new();
+        for (occupation, num) in manager.iter() {
+            if *occupation != Occupation::Idling {
+                let num = (*num).min(remaining);
+                remaining -= num;
+                fitted.insert(*occupation, num);
+            }
+        }
+        fitted.insert(Occupation::Idling, remaining);
+        fitted
+    }
+
+    /// Replaces the manager, fitting it to the number of dwarfs that can be
+    /// managed, and reassigns the dwarfs.
+    pub fn apply_manager(&mut self, manager: &CustomMap<Occupation, u64>) -> Result<(), ActionError> {
+        self.manager = self.fit_manager(manager);
+        optimizer::optimize(self, None)
+    }
+
+    /// Whether the manager assigns the same number of dwarfs to every
+    /// occupation as the other one.
+    fn same_manager(&self, other: &CustomMap<Occupation, u64>) -> bool {
+        enum_iterator::all::<Occupation>().all(|occupation| {
+            self.manager.get(&occupation).copied().unwrap_or_default()
+                == other.get(&occupation).copied().unwrap_or_default()
+        })
+    }
+
+    /// Applies the first preset whose trigger is active, or goes back to the
+    /// previous manager once no trigger is active anymore.
+    pub fn update_manager_presets(&mut self, event: Option<WorldEvent>, time: Time) {
+        let applied = self.triggered_preset.as_ref().map(|triggered| triggered.name.as_str());
+        let triggered = self
+            .manager_presets
+            .iter()
+            .find(|preset| {
+                preset
+                    .trigger
+                    .map(|trigger| trigger.active(self, event, time, applied == Some(preset.name.as_str())))
+                    .unwrap_or(false)
+            })
+            .map(|preset| (preset.name.clone(), preset.manager.clone()));
 
-        if manager_num > dwarfs_num {
-            self.manager.clear();
-            for dwarf in self.dwarfs.values() {
-                if dwarf.can_be_managed() {
-                    *self.manager.entry(dwarf.occupation).or_default() += 1;
+        match (self.triggered_preset.take(), triggered) {
+            (Some(current), Some((name, _))) if current.name == name => {
+                self.triggered_preset = Some(current);
+            }
+            (current, Some((name, manager))) => {
+                let previous = current
+                    .map(|current| current.previous)
+                    .unwrap_or_else(|| self.manager.clone());
+                if self.apply_manager(&manager).is_ok() {
+                    self.log.add(time, LogMsg::ManagerPresetApplied(name.clone()));
+                    self.triggered_preset = Some(TriggeredPreset {
+                        name,
+                        previous,
+                        applied: self.manager.clone(),
+                    });
                 }
             }
-        } else if dwarfs_num > manager_num {
-            self.manager
-                .entry(Occupation::Idling)
-                .and_modify(|v| *v += dwarfs_num - manager_num)
-                .or_insert(dwarfs_num - manager_num);
+            (Some(current), None) => {
+                // Changes the player made in the meantime are kept.
+                if self.same_manager(&current.applied) {
+                    let _ = self.apply_manager(&current.previous);
+                }
+                self.log.add(time, LogMsg::ManagerPresetEnded(current.name));
+            }
+            (None, None) => {}
         }
     }
 
@@ -2416,6 +2352,62 @@ impl Player {
         }
     }
 
+    /// A baby of two dwarfs of the settlement that inherits their stats.
+    pub fn new_child(
+        &mut self,
+        rng: &mut impl Rng,
+        next_dwarf_id: &mut DwarfId,
+        time: Time,
+        father_id: DwarfId,
+        mother_id: DwarfId,
+    ) {
+        if self.dwarfs.len() < self.base.max_dwarfs() {
+            let (Some(father), Some(mother)) = (self.dwarfs.get(&father_id), self.dwarfs.get(&mother_id)) else {
+                return;
+            };
+            let dwarf = Dwarf::new_child(rng, (father_id, father), (mother_id, mother));
+            self.log
+                .add(time, LogMsg::NewDwarf(dwarf.actual_name().to_owned()));
+            self.add_popup(Popup::NewDwarf(dwarf.clone()));
+            self.dwarfs.insert(*next_dwarf_id, dwarf);
+            *next_dwarf_id += 1;
+        } else {
+            self.log.add(time, LogMsg::NotEnoughSpaceForDwarf);
+        }
+    }
+
+    /// The pair chosen by the player if both are idling, otherwise a random
+    /// idle pair.
+    pub fn parents_for_birth(&self, rng: &mut impl Rng) -> Option<(DwarfId, DwarfId)> {
+        let is_idle = |dwarf_id: &DwarfId| {
+            self.dwarfs
+                .get(dwarf_id)
+                .map(|dwarf| dwarf.occupation == Occupation::Idling && dwarf.is_adult())
+                .unwrap_or(false)
+        };
+
+        if let Some((father_id, mother_id)) = self.pairing {
+            if is_idle(&father_id) && is_idle(&mother_id) {
+                return Some((father_id, mother_id));
+            }
+        }
+
+        let father_id = self
+            .dwarfs
+            .iter()
+            .filter(|(dwarf_id, dwarf)| !dwarf.is_female && is_idle(dwarf_id))
+            .map(|(dwarf_id, _)| *dwarf_id)
+            .choose(rng)?;
+        let mother_id = self
+            .dwarfs
+            .iter()
+            .filter(|(dwarf_id, dwarf)| dwarf.is_female && is_idle(dwarf_id))
+            .map(|(dwarf_id, _)| *dwarf_id)
+            .choose(rng)?;
+
+        Some((father_id, mother_id))
+    }
+
     pub fn new_special_dwarf(
         &mut self,
         next_dwarf_id: &mut DwarfId,
@@ -2435,7 +2427,7 @@ impl Player {
     }
 
 
-    pub fn open_loot_crate(&mut self, rng: &mut impl Rng, time: Time) {
+    pub fn open_loot_crate(&mut self, rng: &mut impl Rng, time: Time, account: Account) {
         let possible_items: Vec<Item> = enum_iterator::all::<Item>()
             /*.filter(|item| {
                 matches!(item.item_rarity(), ItemRarity::Epic | ItemRarity::Legendary)
@@ -2445,86 +2437,162 @@ impl Player {
         let bundle = Bundle::new().add(item, (10000 / item.item_rarity_num()).max(1).min(100));
         self.log.add(time, LogMsg::OpenedLootCrate(bundle.clone()));
         self.add_popup(Popup::NewItems(bundle.clone()));
-        self.add_items(bundle, time, true);
+        if self.add_items(bundle.clone(), time, true, account) {
+            account.record(time, LedgerReason::LootCrate, |entry| entry.received(&bundle));
+        }
+    }
+
+    /// Returns whether the items were added, the auto functions only run if they were.
+    pub fn add_items(&mut self, bundle: Bundle<Item>, time: Time, is_premium: bool, account: Account) -> bool {
+        if !self.inventory.add(bundle, time) {
+            return false;
+        }
+        self.run_auto_functions(time, is_premium, account);
+        true
     }
 
-    pub fn add_items(&mut self, bundle: Bundle<Item>, time: Time, is_premium: bool) {
-        self.inventory.add(bundle, time);
-        self.auto_dismantle(time, is_premium);
+    fn run_auto_functions(&mut self, time: Time, is_premium: bool, account: Account) {
         self.auto_craft(time, is_premium);
-        self.auto_store(is_premium);
-        self.auto_sell(is_premium);
+        self.auto_sell(time, is_premium, account);
     }
 
-    pub fn auto_craft(&mut self, time: Time, is_premium: bool) {
-        if is_premium {
-            let mut items_added = false;
-            // Auto-craft!
-            for &item in &self.auto_functions.auto_craft {
-                if let Some((level, requires)) = item.requires() {
-                    if self.base.curr_level >= level {
-                        if let Some(qty) = self.inventory.items.can_remove_x_times(&requires) {
-                            if qty > 0 && self.inventory.items.remove_checked(requires.mul(qty)) {
-                                self.inventory.add(Bundle::new().add(item, qty), time);
-
-                                items_added = true;
-                            }
-                        }
-                    }
+    pub fn auto_craft(&mut self, _time: Time, is_premium: bool) {
+        // Auto-craft once the previous plan is done!
+        if is_premium && self.craft_queue.iter().all(|order| !order.auto) {
+            for steps in self.plan_crafts() {
+                // Only whole plans are queued, so that no intermediate items
+                // are crafted without the item they are meant for.
+                if self.craft_queue.len() + steps.len() > MAX_CRAFT_ORDERS {
+                    continue;
+                }
+                for (item, qty) in steps {
+                    let _ = self.queue_craft(item, qty, true);
                 }
             }
+        }
+    }
+
+    pub fn queue_craft(&mut self, item: Item, qty: u64, auto: bool) -> Result<(), ActionError> {
+        if qty == 0 {
+            return Ok(());
+        }
+        if self.craft_queue.len() >= MAX_CRAFT_ORDERS {
+            return Err(ActionError::CraftQueueFull);
+        }
+        self.craft_queue.push_back(CraftOrder {
+            item,
+            qty,
+            progress: 0,
+            started: false,
+            auto,
+        });
+        Ok(())
+    }
 
-            if items_added {
-                self.auto_craft(time, is_premium);
+    /// Number of items that are still waiting in the crafting queue.
+    pub fn queued(&self, item: Item) -> u64 {
+        self.craft_queue
+            .iter()
+            .filter(|order| order.item == item)
+            .map(|order| order.qty)
+            .sum()
+    }
+
+    pub fn cancel_craft(&mut self, idx: usize, time: Time, account: Account) -> Result<(), ActionError> {
+        let order = self.craft_queue.get(idx).ok_or(ActionError::CraftOrderNotFound)?;
+        if order.started {
+            if let Some((_level, requires)) = order.item.requires() {
+                // The order stays queued if the ingredients cannot be given back.
+                if !self.inventory.items.add_checked(requires.clone()) {
+                    return Err(ActionError::InventoryFull);
+                }
+                account.record(time, LedgerReason::CraftCancelled, |entry| entry.received(&requires));
             }
         }
+        self.craft_queue.remove(idx);
+        Ok(())
     }
 
-    pub fn auto_dismantle(&mut self, time: Time, is_premium: bool) {
-        if is_premium {
-            let mut items_added = false;
-            // Auto-craft!
-            for &item in &self.auto_functions.auto_dismantle {
-                if let Some((_level, requires)) = item.requires() {
-                    let qty = self.inventory.items.get(&item).copied().unwrap_or_default();
-                    if qty > 0 && self
-                            .inventory
-                            .items
-                            .remove_checked(Bundle::new().add(item, qty)) {
-                        self.inventory
-                            .add(requires.mul(qty).div(DISMANTLING_DIVIDER), time);
+    /// Crafting speed in percent. Every crafting dwarf helps, and better
+    /// equipped dwarfs help more.
+    pub fn crafting_speed(&self) -> u64 {
+        100 + self.base.crafting_bonus()
+            + self
+                .dwarfs
+                .values()
+                .filter(|dwarf| {
+                    !dwarf.dead() && dwarf.is_adult() && dwarf.actual_occupation() == Occupation::Crafting
+                })
+                .map(|dwarf| 20 + dwarf.effectiveness_percent(Occupation::Crafting))
+                .sum::<u64>()
+    }
 
-                        items_added = true;
-                    }
+    /// Works on the crafting queue for one tick.
+    pub fn work_on_crafts(&mut self, settings: &WorldSettings, time: Time, is_premium: bool, account: Account) {
+        let mut budget = settings.world_speed * self.crafting_speed() / 100;
+        let mut finished = false;
+
+        while budget > 0 {
+            let Some(order) = self.craft_queue.front_mut() else {
+                break;
+            };
+            let Some((_level, requires)) = order.item.requires() else {
+                self.craft_queue.pop_front();
+                continue;
+            };
+
+            if !order.started {
+                if self.inventory.items.remove_checked(requires.clone()) {
+                    account.record(time, LedgerReason::CraftStarted, |entry| entry.gave(&requires));
+                    order.started = true;
+                } else {
+                    // The ingredients are only taken for one item at a time,
+                    // so the player may have used them up in the meantime.
+                    let missing = requires
+                        .iter()
+                        .filter_map(|(ingredient, qty)| {
+                            let stock = self.inventory.items.get(ingredient).copied().unwrap_or_default();
+                            (stock < *qty).then_some((*ingredient, qty - stock))
+                        })
+                        .collect();
+                    let (item, qty) = (order.item, order.qty);
+                    self.craft_queue.pop_front();
+                    self.log.add(time, LogMsg::CraftingStopped(item, qty, missing));
+                    continue;
                 }
             }
 
-            if items_added {
-                self.auto_dismantle(time, is_premium);
+            let remaining = order.item.crafting_time().saturating_sub(order.progress);
+            if budget >= remaining {
+                let crafted = Bundle::new().add(order.item, 1);
+                if !self.inventory.items.check_add(&crafted) {
+                    // The finished item waits in the queue until there is room for it.
+                    order.progress += remaining;
+                    break;
+                }
+                budget -= remaining;
+                order.qty -= 1;
+                order.progress = 0;
+                order.started = false;
+                // Crafted items can be used by the next order right away.
+                if order.qty == 0 {
+                    self.craft_queue.pop_front();
+                }
+                account.record(time, LedgerReason::CraftFinished, |entry| entry.received(&crafted));
+                self.inventory.add(crafted, time);
+                finished = true;
+            } else {
+                order.progress += budget;
+                budget = 0;
             }
         }
-    }
 
-    pub fn auto_store(&mut self, is_premium: bool) {
-        if is_premium {
-            // Auto-store!
-            for &item in &self.auto_functions.auto_store {
-                if let Some(&qty) = self.inventory.items.get(&item) {
-                    if let Some(food) = item.nutritional_value() {
-                        if self
-                            .inventory
-                            .items
-                            .remove_checked(Bundle::new().add(item, qty))
-                        {
-                            self.base.food += food * qty;
-                        }
-                    }
-                }
-            }
+        if finished {
+            self.run_auto_functions(time, is_premium, account);
         }
     }
 
-    pub fn auto_sell(&mut self, is_premium: bool) {
+    pub fn auto_sell(&mut self, time: Time, is_premium: bool, account: Account) {
         if is_premium {
             // Auto-sell!
             for &item in &self.auto_functions.auto_sell {
@@ -2534,6 +2602,11 @@ impl Player {
                             .items
                             .remove_checked(Bundle::new().add(item, qty)) {
                         self.money += item.money_value(1) * qty;
+                        account.record(time, LedgerReason::AutoSell, |entry| {
+                            entry
+                                .gave(&Bundle::new().add(item, qty))
+                                .received_money(item.money_value(1) * qty)
+                        });
                     }
                 }
             }
@@ -2555,7 +2628,11 @@ impl Inventory {
         }
     }
 
-    pub fn add(&mut self, bundle: Bundle<Item>, time: Time) {
+    /// Adds the items, or nothing at all if they don't fit.
+    pub fn add(&mut self, bundle: Bundle<Item>, time: Time) -> bool {
+        if !self.items.check_add(&bundle) {
+            return false;
+        }
         for (item, qty) in bundle.iter() {
             if let Some((back_item, back_qty, back_time)) = self.last_received.back_mut() {
                 if back_item == item {
@@ -2569,7 +2646,7 @@ impl Inventory {
                 self.last_received.pop_front();
             }
         }
-        self.items.add_checked(bundle);
+        self.items.add_checked(bundle)
     }
 
     pub fn by_type(&self, item_type: Option<ItemType>) -> Vec<Item> {
@@ -2602,6 +2679,28 @@ impl Stats {
         }
     }
 
+    /// Each stat comes from one of the parents and mutates by one point with
+    /// a chance of `MUTATION_PROBABILITY`.
+    pub fn inherit(rng: &mut impl Rng, father: Self, mother: Self) -> Self {
+        let mut gene = |a: i8, b: i8| {
+            let stat = if rng.gen_bool(0.5) { a } else { b };
+            let mutation = if rng.gen_bool(MUTATION_PROBABILITY) {
+                if rng.gen_bool(0.5) { 1 } else { -1 }
+            } else {
+                0
+            };
+            (stat + mutation).clamp(1, 10)
+        };
+
+        Stats {
+            strength: gene(father.strength, mother.strength),
+            endurance: gene(father.endurance, mother.endurance),
+            agility: gene(father.agility, mother.agility),
+            intelligence: gene(father.intelligence, mother.intelligence),
+            perception: gene(father.perception, mother.perception),
+        }
+    }
+
     pub fn sum(self, other: Self) -> Self {
         Stats {
             strength: (self.strength + other.strength).min(10).max(1),
@@ -2652,6 +2751,14 @@ pub struct Dwarf {
     pub consumable_timer: u64,
     #[serde(default)]
     pub special_skin: Option<SpecialDwarf>,
+    /// Father and mother of dwarfs born in the settlement.
+    #[serde(default)]
+    pub parents: Option<(DwarfId, DwarfId)>,
+    #[serde(default)]
+    pub traits: CustomSet<Trait>,
+    /// Time spent working in each occupation.
+    #[serde(default)]
+    pub experience: CustomMap<Occupation, u64>,
 }
 
 impl Dwarf {
@@ -2757,10 +2864,28 @@ impl Dwarf {
             released: false,
             consumable_timer: 0,
             special_skin: None,
+            parents: None,
+            traits: Trait::roll(rng),
+            experience: CustomMap::new(),
         }
     }
 
 
+    fn new_child(rng: &mut impl Rng, father: (DwarfId, &Dwarf), mother: (DwarfId, &Dwarf)) -> Self {
+        let mut dwarf = Dwarf::new_baby(rng);
+        dwarf.stats = Stats::inherit(rng, father.1.stats, mother.1.stats);
+        dwarf.parents = Some((father.0, mother.0));
+        for inherited in father.1.traits.iter().chain(mother.1.traits.iter()) {
+            if dwarf.traits.len() < MAX_TRAITS
+                && rng.gen_bool(TRAIT_INHERIT_PROBABILITY)
+                && inherited.fits(&dwarf.traits)
+            {
+                dwarf.traits.insert(*inherited);
+            }
+        }
+        dwarf
+    }
+
     fn new_baby(rng: &mut impl Rng) -> Self {
         let name = Dwarf::name(rng);
 
@@ -2781,6 +2906,9 @@ impl Dwarf {
             released: false,
             consumable_timer: 0,
             special_skin: None,
+            parents: None,
+            traits: Trait::roll(rng),
+            experience: CustomMap::new(),
         }
     }
 
@@ -2802,6 +2930,9 @@ impl Dwarf {
             released: false,
             consumable_timer: 0,
             special_skin: Some(special_dwarf),
+            parents: None,
+            traits: CustomSet::new(),
+            experience: CustomMap::new(),
         }
     }
 
@@ -2835,6 +2966,81 @@ impl Dwarf {
         }
     }
 
+    /// Health cost of the current occupation in percent of the usual cost.
+    pub fn health_cost_percent(&self) -> u64 {
+        self.traits
+            .iter()
+            .fold(100, |percent, t| percent * t.health_cost_percent() / 100)
+    }
+
+    pub fn food_multiplier(&self) -> u64 {
+        if self.traits.contains(&Trait::Glutton) {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Quest score in percent of the usual score.
+    pub fn quest_score_percent(&self, occupation: Occupation) -> u64 {
+        if self.traits.contains(&Trait::Brawler) && occupation == Occupation::Fighting {
+            125
+        } else {
+            100
+        }
+    }
+
+    pub fn experience(&self, occupation: Occupation) -> u64 {
+        self.experience.get(&occupation).copied().unwrap_or_default()
+    }
+
+    pub fn mastery(&self, occupation: Occupation) -> Mastery {
+        Mastery::from_experience(self.experience(occupation))
+    }
+
+    /// Adds experience and returns the new mastery if the dwarf reached the
+    /// next tier.
+    pub fn gain_experience(&mut self, occupation: Occupation, experience: u64) -> Option<Mastery> {
+        if occupation == Occupation::Idling {
+            return None;
+        }
+        let before = self.mastery(occupation);
+        *self.experience.entry(occupation).or_default() += experience;
+        let after = self.mastery(occupation);
+        if after != before {
+            Some(after)
+        } else {
+            None
+        }
+    }
+
+    /// The bonus of night owls that hunt, fish or explore at night.
+    pub fn night_percent(&self, time: Time) -> u64 {
+        if self.traits.contains(&Trait::NightOwl)
+            && is_night(time)
+            && matches!(
+                self.actual_occupation(),
+                Occupation::Hunting | Occupation::Fishing | Occupation::Exploring
+            )
+        {
+            125
+        } else {
+            100
+        }
+    }
+
+    /// Rolls a new trait that the dwarf does not have yet.
+    pub fn unlock_trait(&mut self, rng: &mut impl Rng) -> Option<Trait> {
+        if self.traits.len() >= MAX_TRAITS {
+            return None;
+        }
+        let new_trait = enum_iterator::all::<Trait>()
+            .filter(|t| t.fits(&self.traits))
+            .choose(rng)?;
+        self.traits.insert(new_trait);
+        Some(new_trait)
+    }
+
     pub fn change_occupation(&mut self, occupation: Occupation) {
         self.occupation = occupation;
     }
@@ -2858,10 +3064,24 @@ impl Dwarf {
         dwarfs: &CustomMap<DwarfId, Dwarf>,
         rng: &mut impl Rng,
         denominator_mul: u64,
+        time: Time,
     ) -> bool {
+        let (numerator, denominator) = self.ratio_effectiveness(settings, dwarfs, denominator_mul, time);
+        gen_ratio_valid(rng, numerator, denominator)
+    }
+
+    /// The ratio with which `gen_ratio_effectiveness` succeeds.
+    pub fn ratio_effectiveness(
+        &self,
+        settings: &WorldSettings,
+        dwarfs: &CustomMap<DwarfId, Dwarf>,
+        denominator_mul: u64,
+        time: Time,
+    ) -> (u32, u32) {
         let denominator = (MAX_EFFECTIVENESS / (MIN_MAX_DWARF_DIFFERENCE - 1)) * denominator_mul;
-        gen_ratio_valid(rng, 
-            (self.numerator_effectiveness(dwarfs) / 100) as u32 * settings.world_speed as u32,
+        (
+            (self.numerator_effectiveness(dwarfs) * self.night_percent(time) / 100 / 100) as u32
+                * settings.world_speed as u32,
             (denominator / 100) as u32,
         )
     }
@@ -2899,12 +3119,133 @@ impl Dwarf {
 
         usefulness = usefulness.min(30);
 
-        let effectiveness = usefulness * self.effective_stats().cross(occupation.requires_stats());
+        let effectiveness = usefulness * self.effective_stats().cross(occupation.requires_stats())
+            * self.traits.iter().map(|t| t.effectiveness_percent(occupation)).product::<u64>()
+            / 100u64.pow(self.traits.len() as u32)
+            * self.mastery(occupation).effectiveness_percent()
+            / 100;
 
         effectiveness.min(MAX_EFFECTIVENESS)
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, Sequence, PartialEq, Eq, PartialOrd, Ord, Display, Hash)]
+pub enum Mastery {
+    Novice,
+    Apprentice,
+    Journeyman,
+    Expert,
+    Master,
+}
+
+impl Mastery {
+    /// Experience needed for this tier, one experience point per second of work.
+    pub fn required_experience(self) -> u64 {
+        match self {
+            Mastery::Novice => 0,
+            Mastery::Apprentice => ONE_DAY,
+            Mastery::Journeyman => ONE_DAY * 3,
+            Mastery::Expert => ONE_DAY * 7,
+            Mastery::Master => ONE_DAY * 14,
+        }
+    }
+
+    pub fn from_experience(experience: u64) -> Self {
+        enum_iterator::all::<Mastery>()
+            .filter(|mastery| mastery.required_experience() <= experience)
+            .last()
+            .unwrap_or(Mastery::Novice)
+    }
+
+    pub fn effectiveness_percent(self) -> u64 {
+        match self {
+            Mastery::Novice => 100,
+            Mastery::Apprentice => 105,
+            Mastery::Journeyman => 110,
+            Mastery::Expert => 120,
+            Mastery::Master => 130,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, Sequence, PartialEq, Eq, Display, Hash)]
+#[strum(serialize_all = "title_case")]
+pub enum Trait {
+    Glutton,
+    NightOwl,
+    Tireless,
+    Lucky,
+    Diligent,
+    Clumsy,
+    Brawler,
+    Sickly,
+}
+
+impl Trait {
+    /// Every trait is rolled independently with a chance of `TRAIT_PROBABILITY`
+    /// divided by the number of traits, skipping the ones that conflict with
+    /// a trait rolled before.
+    pub fn roll(rng: &mut impl Rng) -> CustomSet<Trait> {
+        let num_traits = enum_iterator::cardinality::<Trait>() as f64;
+        let mut traits = CustomSet::new();
+        for new_trait in enum_iterator::all::<Trait>() {
+            if traits.len() < MAX_TRAITS
+                && rng.gen_bool(TRAIT_PROBABILITY / num_traits)
+                && new_trait.fits(&traits)
+            {
+                traits.insert(new_trait);
+            }
+        }
+        traits
+    }
+
+    /// Traits with opposite effects, a dwarf never has both.
+    pub fn conflicts_with(self, other: Trait) -> bool {
+        matches!(
+            (self, other),
+            (Trait::Diligent, Trait::Clumsy)
+                | (Trait::Clumsy, Trait::Diligent)
+                | (Trait::Tireless, Trait::Sickly)
+                | (Trait::Sickly, Trait::Tireless)
+        )
+    }
+
+    /// Whether a dwarf with the traits can get this trait.
+    pub fn fits(self, traits: &CustomSet<Trait>) -> bool {
+        !traits.contains(&self) && !traits.iter().any(|other| self.conflicts_with(*other))
+    }
+
+    /// Night owls only work better at night, see `Dwarf::night_percent`.
+    pub fn effectiveness_percent(self, _occupation: Occupation) -> u64 {
+        match self {
+            Trait::Diligent => 110,
+            Trait::Clumsy => 90,
+            _ => 100,
+        }
+    }
+
+    pub fn health_cost_percent(self) -> u64 {
+        match self {
+            Trait::Tireless => 50,
+            Trait::Sickly => 150,
+            _ => 100,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Trait::Glutton => "Eats twice as much food while idling.",
+            Trait::NightOwl => "Works 25% better at night as a hunter, fisher or explorer.",
+            Trait::Tireless => "Loses health half as fast while working.",
+            Trait::Lucky => "Has a better chance to win quests that are decided by luck.",
+            Trait::Diligent => "Works 10% better in every occupation.",
+            Trait::Clumsy => "Works 10% worse in every occupation.",
+            Trait::Brawler => "Scores 25% more in fighting quests.",
+            Trait::Sickly => "Loses health 50% faster while working.",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Copy, Sequence, PartialEq, Eq, Display, Hash)]
 #[strum(serialize_all = "title_case")]
 pub enum Occupation {
@@ -2918,6 +3259,7 @@ pub enum Occupation {
     Exploring,
     Farming,
     Rockhounding,
+    Crafting,
 }
 
 impl Occupation {
@@ -2933,6 +3275,7 @@ impl Occupation {
             Occupation::Exploring => 5,
             Occupation::Farming => 3,
             Occupation::Rockhounding => 5,
+            Occupation::Crafting => 2,
         }
     }
 
@@ -2948,6 +3291,7 @@ impl Occupation {
             Occupation::Fighting => 30,
             Occupation::Farming => 40,
             Occupation::Rockhounding => 50,
+            Occupation::Crafting => 1,
         }
     }
 
@@ -3002,38 +3346,142 @@ impl Occupation {
                 strength: 10,
                 ..Default::default()
             },
+            Occupation::Crafting => Stats {
+                intelligence: 10,
+                agility: 10,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
+pub struct Base {
+    pub curr_level: u64,
+    pub build_time: Time,
+    pub food: Food,
+    #[serde(default)]
+    pub buildings: CustomMap<Building, u64>,
+    #[serde(default)]
+    pub construction: Option<(Building, Time)>,
+}
+
+impl Default for Base {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Base {
+    pub fn new() -> Base {
+        Base {
+            curr_level: 1,
+            build_time: 0,
+            food: 0,
+            buildings: CustomMap::new(),
+            construction: None,
+        }
+    }
+
+    pub fn max_dwarfs(&self) -> usize {
+        self.max_dwarfs_at(self.curr_level)
+    }
+
+    pub fn max_dwarfs_at(&self, level: u64) -> usize {
+        (level as usize).div_ceil(2) + self.building_level(Building::Huts) as usize
+    }
+
+    pub fn building_level(&self, building: Building) -> u64 {
+        if building == Building::Headquarters {
+            self.curr_level
+        } else {
+            self.buildings.get(&building).copied().unwrap_or_default()
+        }
+    }
+
+    /// Every few settlement levels unlock a slot for a building level, so
+    /// players have to choose which buildings they want to specialize in.
+    pub fn building_slots(&self) -> u64 {
+        self.curr_level / LEVELS_PER_BUILDING_SLOT
+    }
+
+    pub fn free_building_slots(&self) -> u64 {
+        let used = self.buildings.values().sum::<u64>() + self.construction.is_some() as u64;
+        self.building_slots().saturating_sub(used)
+    }
+
+    /// Effectiveness bonus in percent for the given occupation.
+    pub fn occupation_bonus(&self, occupation: Occupation) -> u64 {
+        enum_iterator::all::<Building>()
+            .filter(|building| building.occupation() == Some(occupation))
+            .map(|building| self.building_level(building) * 10)
+            .sum()
+    }
+
+    /// Bonus for the crafting speed in percent.
+    pub fn crafting_bonus(&self) -> u64 {
+        self.building_level(Building::Workshop) * 10
+    }
+
+    /// Bonus for the chance of new children in percent.
+    pub fn birth_bonus(&self) -> u64 {
+        self.building_level(Building::Tavern) * 10
+    }
+
+    pub fn building_upgrade_cost(&self, building: Building, settings: &WorldSettings) -> Option<Bundle<Item>> {
+        if building == Building::Headquarters {
+            return self.upgrade_cost(settings);
         }
+
+        let next_level = self.building_level(building) + 1;
+        if next_level > MAX_BUILDING_LEVEL {
+            return None;
+        }
+
+        let multiplier = next_level * next_level;
+        Some(
+            Bundle::new()
+                .add(Item::Wood, 200 * multiplier)
+                .add(Item::Stone, 200 * multiplier)
+                .add(Item::Nail, 20 * multiplier)
+                .add(Item::Gold, if next_level > 5 { 5 * multiplier } else { 0 }),
+        )
     }
-}
 
-#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
-pub struct Base {
-    pub curr_level: u64,
-    pub build_time: Time,
-    pub food: Food,
-}
+    pub fn building_time_ticks(&self, building: Building) -> u64 {
+        if building == Building::Headquarters {
+            return self.build_time_ticks();
+        }
 
-impl Default for Base {
-    fn default() -> Self {
-        Self::new()
+        let next_level = self.building_level(building) + 1;
+        self.with_workshop(next_level * (next_level + 1) * ONE_MINUTE * 5)
     }
-}
 
-impl Base {
-    pub fn new() -> Base {
-        Base {
-            curr_level: 1,
-            build_time: 0,
-            food: 0,
-        }
+    fn with_workshop(&self, ticks: u64) -> u64 {
+        ticks * (20 - self.building_level(Building::Workshop).min(MAX_BUILDING_LEVEL)) / 20
     }
 
-    pub fn max_dwarfs(&self) -> usize {
-        self.max_dwarfs_at(self.curr_level)
+    pub fn upgrade_building(&mut self, building: Building, settings: &WorldSettings) {
+        if building == Building::Headquarters {
+            self.upgrade(settings);
+        } else if self.construction.is_none() && self.building_level(building) < MAX_BUILDING_LEVEL {
+            self.construction = Some((building, self.building_time_ticks(building)));
+        }
     }
 
-    pub fn max_dwarfs_at(&self, level: u64) -> usize {
-        (level as usize + 1) / 2
+    /// Continues the construction of a building and returns the building
+    /// and its new level once it is finished.
+    pub fn construct(&mut self, settings: &WorldSettings) -> Option<(Building, u64)> {
+        let (building, time_left) = self.construction.as_mut()?;
+        *time_left = time_left.saturating_sub(settings.world_speed);
+        if *time_left == 0 {
+            let building = *building;
+            self.construction = None;
+            let level = self.buildings.entry(building).or_default();
+            *level += 1;
+            return Some((building, *level));
+        }
+        None
     }
 
     pub fn upgrade_cost(&self, settings: &WorldSettings) -> Option<Bundle<Item>> {
@@ -3057,7 +3505,7 @@ impl Base {
     }
 
     pub fn build_time_ticks(&self) -> u64 {
-        self.curr_level * (self.curr_level / 10 + 1) * 15
+        self.with_workshop(self.curr_level * (self.curr_level / 10 + 1) * 15)
     }
 
     pub fn build(&mut self, settings: &WorldSettings) -> Option<u64> {
@@ -3113,17 +3561,20 @@ pub type DwarfId = u64;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientEvent {
     Init,
-    Message(String),
+    Message(ChatChannel, String),
     ChangeOccupation(DwarfId, Occupation),
     Craft(Item, u64),
+    CancelCraft(usize),
     Dismantle(Item, u64),
     UpgradeBase,
+    UpgradeBuilding(Building),
     ChangeEquipment(DwarfId, ItemType, Option<Item>),
     OpenLootCrate,
     OpenDailyReward,
     AssignToQuest(QuestId, usize, Option<DwarfId>),
     AddToFoodStorage(Item, u64),
     Sell(Item, u64),
+    BuyOrder(Item, u64, Money),
     Restart,
     ToggleAutoCraft(Item),
     ToggleAutoStore(Item),
@@ -3143,9 +3594,30 @@ pub enum ClientEvent {
     AutoBid(TradeId, Money),
     ReleaseDwarf(DwarfId),
     ReadLog,
-    ReadChat,
+    ReadChat(ChatChannel),
     SpendTribePoint(Territory),
     SkipAllPopups,
+    MakeOffer(UserId, Bundle<Item>, Money, Bundle<Item>, Money),
+    AcceptOffer(DirectOfferId),
+    DeclineOffer(DirectOfferId),
+    CancelOffer(DirectOfferId),
+    SetTribeName(String),
+    SetTribeBanner(Banner),
+    VoteChief(UserId),
+    DepositToTreasury(Money),
+    SetTithe(u64),
+    UpgradeTribe(TribeUpgrade),
+    JoinTribe(TribeId),
+    LeaveTribe,
+    PairDwarfs(Option<(DwarfId, DwarfId)>),
+    SaveManagerPreset(String, Option<PresetTrigger>),
+    DeleteManagerPreset(String),
+    ApplyManagerPreset(String),
+    SetCraftTarget(Item, Option<CraftTarget>),
+    SetReserve(Item, u64),
+    AddRule(Rule),
+    RemoveRule(usize),
+    ToggleRule(usize),
 }
 
 impl engine_shared::ClientEvent for ClientEvent {
@@ -3165,25 +3637,50 @@ impl engine_shared::ServerEvent<State> for ServerEvent {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+pub enum ChatChannel {
+    #[default]
+    Global,
+    Tribe(TribeId),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default, Hash)]
 pub struct Chat {
+    /// The messages of the global channel.
     pub messages: VecDeque<(UserId, String, Time)>,
+    #[serde(default)]
+    pub tribe_messages: CustomMap<TribeId, VecDeque<(UserId, String, Time)>>,
 }
 
 impl Chat {
+    pub fn messages(&self, channel: ChatChannel) -> Option<&VecDeque<(UserId, String, Time)>> {
+        match channel {
+            ChatChannel::Global => Some(&self.messages),
+            ChatChannel::Tribe(tribe_id) => self.tribe_messages.get(&tribe_id),
+        }
+    }
+
     pub fn add_message(
         &mut self,
         players: &mut CustomMap<UserId, Player>,
         user_id: UserId,
+        channel: ChatChannel,
         message: String,
         time: Time,
     ) {
+        let messages = match channel {
+            ChatChannel::Global => &mut self.messages,
+            ChatChannel::Tribe(tribe_id) => self.tribe_messages.entry(tribe_id).or_default(),
+        };
+
         for player in players.values_mut() {
-            player.chat_unread = true;
+            if player.can_read(channel) {
+                player.unread_chats.insert(channel);
+            }
         }
-        self.messages.push_back((user_id, message, time));
-        if self.messages.len() > 100 {
-            self.messages.pop_front();
+        messages.push_back((user_id, message, time));
+        if messages.len() > 100 {
+            messages.pop_front();
         }
     }
 }
@@ -3234,10 +3731,10 @@ impl Quest {
     }
 
     pub fn chance_by_score(&self, rng: &mut impl Rng) -> Option<UserId> {
-        let total_score: u64 = self.contestants.values().map(|c| c.achieved_score).sum();
+        let total_score: u64 = self.contestants.values().map(|c| c.achieved_score + c.luck).sum();
         self.contestants
             .iter()
-            .map(|(user_id, c)| (*user_id, c.achieved_score as f64 / total_score as f64))
+            .map(|(user_id, c)| (*user_id, (c.achieved_score + c.luck) as f64 / total_score as f64))
             .collect::<Vec<_>>()
             .choose_weighted(rng, |elem| elem.1)
             .ok()
@@ -3250,11 +3747,12 @@ impl Quest {
             Contestant {
                 dwarfs: CustomMap::new(),
                 achieved_score: 0,
+                luck: 0,
             },
         );
     }
 
-    pub fn run(&mut self, settings: &WorldSettings, players: &CustomMap<UserId, Player>) -> Option<()> {
+    pub fn run(&mut self, settings: &WorldSettings, players: &CustomMap<UserId, Player>, tribes: &CustomMap<TribeId, Tribe>) -> Option<()> {
         if self.preparation_time > 0 {
             self.preparation_time = self.preparation_time.saturating_sub(settings.world_speed);
             return Some(());
@@ -3263,17 +3761,31 @@ impl Quest {
         if self.time_left > 0 {
             self.time_left = self.time_left.saturating_sub(settings.world_speed);
             for (user_id, contestant) in self.contestants.iter_mut() {
+                // Contestants that are gone since don't score anymore.
+                let Some(player) = players.get(user_id) else {
+                    continue;
+                };
                 for dwarf_id in contestant.dwarfs.values() {
-                    let player = players.get(user_id)?;
-                    let dwarf = player.dwarfs.get(dwarf_id)?;
+                    let Some(dwarf) = player.dwarfs.get(dwarf_id) else {
+                        continue;
+                    };
 
                     if dwarf.actual_occupation() == self.quest_type.occupation() {
-                        if matches!(dwarf.equipment.get(&ItemType::Consumable), Some(&Item::KnowledgeOfTheEldest | &Item::BlessingOfTheGods)) {
-                            contestant.achieved_score +=
-                                dwarf.numerator_effectiveness(&player.dwarfs) / 50;
+                        let score = if matches!(dwarf.equipment.get(&ItemType::Consumable), Some(&Item::KnowledgeOfTheEldest | &Item::BlessingOfTheGods)) {
+                            dwarf.numerator_effectiveness(&player.dwarfs) / 50
                         } else {
-                            contestant.achieved_score +=
-                                dwarf.numerator_effectiveness(&player.dwarfs) / 100;
+                            dwarf.numerator_effectiveness(&player.dwarfs) / 100
+                        };
+                        let score = score
+                            * dwarf.quest_score_percent(self.quest_type.occupation())
+                            / 100
+                            * (100
+                                + player.base.occupation_bonus(self.quest_type.occupation())
+                                + player.tribe_bonus(tribes, TribeUpgrade::WarCamp))
+                            / 100;
+                        contestant.achieved_score += score;
+                        if dwarf.traits.contains(&Trait::Lucky) {
+                            contestant.luck += score;
                         }
                     }
                 }
@@ -3323,6 +3835,9 @@ impl RewardMode {
 pub struct Contestant {
     pub dwarfs: CustomMap<usize, DwarfId>,
     pub achieved_score: u64,
+    /// Additional weight from lucky dwarfs for quests that are decided by chance.
+    #[serde(default)]
+    pub luck: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Sequence, Hash)]
@@ -3638,6 +4153,9 @@ pub struct TradeDeal {
     #[serde(default)]
     pub creator: Option<UserId>,
     pub trade_id: TradeId,
+    /// Money the creator of a buy order has put aside to pay the seller.
+    #[serde(default)]
+    pub escrow: Money,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
@@ -3646,6 +4164,132 @@ pub enum TradeType {
     Sell,
 }
 
+/// Items and coins offered to a specific player in exchange for other items
+/// and coins. What is offered is held back until the offer is accepted,
+/// declined, cancelled or expires.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
+pub struct DirectOffer {
+    pub from: UserId,
+    pub to: UserId,
+    pub items: Bundle<Item>,
+    pub money: Money,
+    pub requested_items: Bundle<Item>,
+    pub requested_money: Money,
+    pub time_left: Time,
+}
+
+impl DirectOffer {
+    pub fn new(
+        from: UserId,
+        player: &mut Player,
+        to: UserId,
+        items: Bundle<Item>,
+        money: Money,
+        requested_items: Bundle<Item>,
+        requested_money: Money,
+    ) -> Result<Self, ActionError> {
+        if from == to {
+            return Err(ActionError::OwnOffer);
+        }
+
+        if items.values().all(|n| *n == 0) && money == 0 {
+            return Err(ActionError::EmptyOffer);
+        }
+
+        if player.money < money {
+            return Err(ActionError::NotEnoughMoney);
+        }
+
+        if !player.inventory.items.remove_checked(items.clone()) {
+            return Err(ActionError::NotEnoughItems);
+        }
+
+        player.money -= money;
+
+        Ok(DirectOffer {
+            from,
+            to,
+            items,
+            money,
+            requested_items,
+            requested_money,
+            time_left: DIRECT_OFFER_DURATION,
+        })
+    }
+
+    /// Exchanges both sides of the offer. Nothing changes hands if either
+    /// player can't complete the exchange.
+    pub fn accept(&self, players: &mut CustomMap<UserId, Player>, time: Time, ledger: &Ledger) -> Result<(), ActionError> {
+        let receiver = players.get(&self.to).ok_or(ActionError::PlayerNotFound)?;
+        if receiver.money < self.requested_money {
+            return Err(ActionError::NotEnoughMoney);
+        }
+        if !receiver.inventory.items.check_remove(&self.requested_items) {
+            return Err(ActionError::NotEnoughItems);
+        }
+        if !receiver.inventory.items.check_add(&self.items) {
+            return Err(ActionError::NotEnoughSpace);
+        }
+        let sender = players.get(&self.from).ok_or(ActionError::PlayerNotFound)?;
+        if !sender.inventory.items.check_add(&self.requested_items) {
+            return Err(ActionError::NotEnoughSpace);
+        }
+
+        let receiver = players.get_mut(&self.to).ok_or(ActionError::PlayerNotFound)?;
+        receiver.inventory.items.remove_checked(self.requested_items.clone());
+        receiver.inventory.add(self.items.clone(), time);
+        receiver.money -= self.requested_money;
+        receiver.money += self.money;
+        receiver.log.add(
+            time,
+            LogMsg::OfferCompleted(self.from, self.items.clone(), self.money),
+        );
+        ledger.record(|| {
+            LedgerEntry::new(time, self.to, LedgerReason::OfferAccepted)
+                .gave(&self.requested_items)
+                .paid_money(self.requested_money)
+                .received(&self.items)
+                .received_money(self.money)
+                .with(Some(self.from))
+        });
+
+        let sender = players.get_mut(&self.from).ok_or(ActionError::PlayerNotFound)?;
+        sender.inventory.add(self.requested_items.clone(), time);
+        sender.money += self.requested_money;
+        sender.log.add(
+            time,
+            LogMsg::OfferCompleted(self.to, self.requested_items.clone(), self.requested_money),
+        );
+        ledger.record(|| {
+            LedgerEntry::new(time, self.from, LedgerReason::OfferAccepted)
+                .received(&self.requested_items)
+                .received_money(self.requested_money)
+                .with(Some(self.to))
+        });
+
+        Ok(())
+    }
+
+    /// Returns what was offered to the player who made the offer.
+    pub fn refund<'a>(
+        &self,
+        players: &'a mut CustomMap<UserId, Player>,
+        time: Time,
+        ledger: &Ledger,
+    ) -> Option<&'a mut Player> {
+        let sender = players.get_mut(&self.from)?;
+        sender.inventory.add(self.items.clone(), time);
+        sender.money += self.money;
+        ledger.record(|| {
+            LedgerEntry::new(time, self.from, LedgerReason::OfferReturned)
+                .received(&self.items)
+                .received_money(self.money)
+                .with(Some(self.to))
+        });
+        Some(sender)
+    }
+}
+
 impl TradeDeal {
     pub fn new(rng: &mut impl Rng, max_player_level: u64, trade_id: TradeId) -> Self {
         let item = enum_iterator::all::<Item>()
@@ -3677,10 +4321,11 @@ impl TradeDeal {
             creator: None,
             user_trade_type: TradeType::Buy,
             trade_id,
+            escrow: 0,
         }
     }
 
-    pub fn from_player(user_id: UserId, player: &mut Player, item: Item, qty: u64, trade_id: TradeId) -> Option<Self> {
+    pub fn from_player(user_id: UserId, player: &mut Player, item: Item, qty: u64, trade_id: TradeId) -> Result<Self, ActionError> {
         let qty = qty.min(player.inventory.items.get(&item).copied().unwrap_or(0));
         let time_left = ((qty * item.item_rarity_num()) / 10)
             .max(ONE_MINUTE * 20)
@@ -3689,18 +4334,18 @@ impl TradeDeal {
         let next_bid = item.money_value(qty) * TRADE_MONEY_MULTIPLIER;
 
         if qty == 0 {
-            return None;
+            return Err(ActionError::NotEnoughItems);
         }
 
         if next_bid < MIN_TRADE_VALUE {
-            return None;
+            return Err(ActionError::TradeValueTooLow);
         }
 
         if !player.inventory.items.remove_checked(items.clone()) {
-            return None;
+            return Err(ActionError::NotEnoughItems);
         }
 
-        Some(TradeDeal {
+        Ok(TradeDeal {
             items,
             next_bid,
             time_left,
@@ -3708,15 +4353,73 @@ impl TradeDeal {
             creator: Some(user_id),
             user_trade_type: TradeType::Buy,
             trade_id,
+            escrow: 0,
+        })
+    }
+
+    /// Creates a buy order where the player pays at most `max_price` for the
+    /// items. The money is held back until the deal ends, other players can
+    /// underbid each other to be the one who delivers the items.
+    pub fn buy_order(user_id: UserId, player: &mut Player, item: Item, qty: u64, max_price: Money, trade_id: TradeId) -> Result<Self, ActionError> {
+        if qty == 0 {
+            return Err(ActionError::ZeroAmount);
+        }
+
+        if qty > MAX_BUY_ORDER_QTY {
+            return Err(ActionError::AmountTooHigh);
+        }
+
+        if item.item_type().is_none() {
+            return Err(ActionError::NotSellable);
+        }
+
+        let time_left = (qty
+            .checked_mul(item.item_rarity_num())
+            .ok_or(ActionError::AmountTooHigh)?
+            / 10)
+            .clamp(ONE_MINUTE * 20, ONE_HOUR * 4);
+
+        if max_price < MIN_TRADE_VALUE {
+            return Err(ActionError::TradeValueTooLow);
+        }
+
+        if player.money < max_price {
+            return Err(ActionError::NotEnoughMoney);
+        }
+
+        player.money -= max_price;
+
+        Ok(TradeDeal {
+            items: Bundle::new().add(item, qty),
+            next_bid: max_price,
+            time_left,
+            highest_bidder: None,
+            creator: Some(user_id),
+            user_trade_type: TradeType::Sell,
+            trade_id,
+            escrow: max_price,
         })
     }
 
-    pub fn update(&mut self, settings: &WorldSettings, players: &mut CustomMap<UserId, Player>, time: Time) -> Option<()> {
+    pub fn update(
+        &mut self,
+        settings: &WorldSettings,
+        players: &mut CustomMap<UserId, Player>,
+        time: Time,
+        ledger: &Ledger,
+    ) -> Option<()> {
         if self.time_left > 0 {
             self.time_left = self.time_left.saturating_sub(settings.world_speed);
-            if self.time_left == 0 || self.next_bid <= 1 {
+            // A buy order can't get any cheaper once the offers are down to
+            // a single coin.
+            let underbid = self.user_trade_type == TradeType::Sell && self.next_bid <= 1;
+            if self.time_left == 0 || underbid {
                 for player in players.values_mut() {
-                    player.auto_functions.auto_bid.swap_remove(&self.trade_id);
+                    player.remove_auto_bid(self.trade_id);
+                }
+
+                if self.user_trade_type == TradeType::Sell {
+                    return self.settle_buy_order(players, time, ledger);
                 }
 
                 if let Some((best_bidder_user_id, best_bidder_money)) = self.highest_bidder {
@@ -3726,10 +4429,20 @@ impl TradeDeal {
                         time,
                         LogMsg::BidWon(self.items.clone(), best_bidder_money, self.user_trade_type),
                     );
+                    ledger.record(|| {
+                        LedgerEntry::new(time, best_bidder_user_id, LedgerReason::TradeWon)
+                            .received(&self.items)
+                            .with(self.creator)
+                    });
 
                     if let Some(creator) = self.creator {
                         let c = players.get_mut(&creator)?;
                         c.money += best_bidder_money;
+                        ledger.record(|| {
+                            LedgerEntry::new(time, creator, LedgerReason::TradeSold)
+                                .received_money(best_bidder_money)
+                                .with(Some(best_bidder_user_id))
+                        });
                         c.log.add(
                             time,
                             LogMsg::ItemSold(self.items.clone(), best_bidder_money),
@@ -3744,6 +4457,9 @@ impl TradeDeal {
                     */
                     let c = players.get_mut(&creator)?;
                     c.money += self.next_bid;
+                    ledger.record(|| {
+                        LedgerEntry::new(time, creator, LedgerReason::TradeSold).received_money(self.next_bid)
+                    });
                     c.log.add(
                         time,
                         LogMsg::ItemSold(self.items.clone(), self.next_bid),
@@ -3754,16 +4470,94 @@ impl TradeDeal {
         Some(())
     }
 
+    /// The seller with the lowest offer gets paid and the creator of the
+    /// buy order gets the items and the rest of the held back money.
+    fn settle_buy_order(&mut self, players: &mut CustomMap<UserId, Player>, time: Time, ledger: &Ledger) -> Option<()> {
+        // The order is also settled early once the price reached one coin,
+        // as nobody can offer less.
+        self.time_left = 0;
+
+        if let Some((seller_user_id, price)) = self.highest_bidder {
+            let s = players.get_mut(&seller_user_id)?;
+            s.money += price;
+            ledger.record(|| {
+                LedgerEntry::new(time, seller_user_id, LedgerReason::BuyOrderFilled)
+                    .received_money(price)
+                    .with(self.creator)
+            });
+            s.log.add(
+                time,
+                LogMsg::BidWon(self.items.clone(), price, self.user_trade_type),
+            );
+
+            if let Some(creator) = self.creator {
+                let c = players.get_mut(&creator)?;
+                c.inventory.add(self.items.clone(), time);
+                c.money += self.escrow.saturating_sub(price);
+                ledger.record(|| {
+                    LedgerEntry::new(time, creator, LedgerReason::BuyOrderFilled)
+                        .received(&self.items)
+                        .received_money(self.escrow.saturating_sub(price))
+                        .with(Some(seller_user_id))
+                });
+                c.log.add(
+                    time,
+                    LogMsg::BuyOrderFilled(self.items.clone(), price),
+                );
+            }
+        } else if let Some(creator) = self.creator {
+            let c = players.get_mut(&creator)?;
+            c.money += self.escrow;
+            ledger.record(|| {
+                LedgerEntry::new(time, creator, LedgerReason::BuyOrderExpired).received_money(self.escrow)
+            });
+            c.log.add(
+                time,
+                LogMsg::BuyOrderExpired(self.items.clone(), self.escrow),
+            );
+        }
+
+        Some(())
+    }
+
     pub fn done(&self) -> bool {
         self.time_left == 0
     }
 
+    /// Checks whether the player could place the next bid on this trade deal.
+    pub fn check_bid(&self, player: &Player, user_id: UserId) -> Result<(), ActionError> {
+        if self.creator == Some(user_id) {
+            return Err(ActionError::OwnTrade);
+        }
+        if self.highest_bidder.map(|(id, _)| id) == Some(user_id) {
+            return Err(ActionError::AlreadyHighestBidder);
+        }
+        match self.user_trade_type {
+            TradeType::Buy => {
+                if player.money < self.next_bid {
+                    return Err(ActionError::NotEnoughMoney);
+                }
+            }
+            TradeType::Sell => {
+                if !player.inventory.items.check_remove(&self.items) {
+                    return Err(ActionError::NotEnoughItems);
+                }
+                if self.next_bid == 0 {
+                    return Err(ActionError::TradeValueTooLow);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn bid_to(
         &mut self,
         players: &mut CustomMap<UserId, Player>,
         user_id: UserId,
         time: Time,
         money: Money,
+        ledger: &Ledger,
     ) -> Option<()> {
         if self.user_trade_type == TradeType::Buy {
             if self.creator == Some(user_id) || self.highest_bidder.map(|(id, _)| id) == Some(user_id) {
@@ -3777,14 +4571,55 @@ impl TradeDeal {
                         time,
                         LogMsg::Overbid(self.items.clone(), money, self.user_trade_type),
                     );
+                    ledger.record(|| {
+                        LedgerEntry::new(time, best_bidder_user_id, LedgerReason::TradeOutbid)
+                            .received_money(best_bidder_money)
+                            .with(Some(user_id))
+                    });
                 }
                 players.get_mut(&user_id)?.money -= money;
+                ledger.record(|| {
+                    LedgerEntry::new(time, user_id, LedgerReason::TradeBid)
+                        .paid_money(money)
+                        .with(self.creator)
+                });
                 self.highest_bidder = Some((user_id, money));
                 self.next_bid = money + (money / 10).max(1);
                 if self.time_left < ONE_MINUTE * SPEED {
                     self.time_left += ONE_MINUTE * SPEED;
                 }
             }
+        } else {
+            // Reverse auction, the seller hands over the items until someone
+            // else offers them for less.
+            if self.creator == Some(user_id) || self.highest_bidder.map(|(id, _)| id) == Some(user_id) {
+                return None;
+            }
+            if players.get_mut(&user_id)?.inventory.items.remove_checked(self.items.clone()) {
+                if let Some((best_bidder_user_id, _)) = self.highest_bidder {
+                    let p = players.get_mut(&best_bidder_user_id)?;
+                    p.inventory.add(self.items.clone(), time);
+                    p.log.add(
+                        time,
+                        LogMsg::Overbid(self.items.clone(), money, self.user_trade_type),
+                    );
+                    ledger.record(|| {
+                        LedgerEntry::new(time, best_bidder_user_id, LedgerReason::BuyOrderUnderbid)
+                            .received(&self.items)
+                            .with(Some(user_id))
+                    });
+                }
+                ledger.record(|| {
+                    LedgerEntry::new(time, user_id, LedgerReason::BuyOrderOffer)
+                        .gave(&self.items)
+                        .with(self.creator)
+                });
+                self.highest_bidder = Some((user_id, money));
+                self.next_bid = money.saturating_sub((money / 10).max(1));
+                if self.time_left < ONE_MINUTE * SPEED {
+                    self.time_left += ONE_MINUTE * SPEED;
+                }
+            }
         }
 
         Some(())
@@ -3795,8 +4630,13 @@ impl TradeDeal {
         players: &mut CustomMap<UserId, Player>,
         user_id: UserId,
         time: Time,
+        ledger: &Ledger,
     ) -> Option<()> {
-        self.bid_to(players, user_id, time, self.next_bid)?;
+        self.bid_to(players, user_id, time, self.next_bid, ledger)?;
+
+        if self.user_trade_type == TradeType::Sell {
+            return Some(());
+        }
         
         // Auto bidding.
         let mut max_bid_user_id = None;
@@ -3804,15 +4644,15 @@ impl TradeDeal {
             if player_user_id == &user_id {
                 continue;
             }
-            if let Some(max_bid) = player.auto_functions.auto_bid.get(&self.trade_id) {
-                if *max_bid >= self.next_bid {
+            if let Some(max_bid) = player.max_auto_bid(self) {
+                if max_bid >= self.next_bid {
                     max_bid_user_id = Some(*player_user_id);
                 }
             }
         }
         
         if let Some(max_bid_user_id) = max_bid_user_id {
-            self.bid(players, max_bid_user_id, time)?;
+            self.bid(players, max_bid_user_id, time, ledger)?;
         }
         
         Some(())
@@ -3822,6 +4662,126 @@ impl TradeDeal {
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Default)]
 pub struct Tribe {
     pub territories: CustomMap<Territory, u64>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub banner: Option<Banner>,
+    #[serde(default)]
+    pub chief: Option<UserId>,
+    /// The candidate each member has voted for.
+    #[serde(default)]
+    pub votes: CustomMap<UserId, UserId>,
+    #[serde(default)]
+    pub treasury: Money,
+    /// Percentage of the quest money of the members that goes to the treasury.
+    #[serde(default)]
+    pub tithe: u64,
+    #[serde(default)]
+    pub upgrades: CustomMap<TribeUpgrade, u64>,
+}
+
+impl Tribe {
+    pub fn is_chief(&self, user_id: UserId) -> bool {
+        self.chief == Some(user_id)
+    }
+
+    /// Members can change the name and banner as long as no chief has been elected.
+    pub fn can_govern(&self, user_id: UserId) -> bool {
+        self.chief.is_none() || self.is_chief(user_id)
+    }
+
+    /// The candidate with the most votes among the members becomes chief.
+    /// On a tie the current chief stays in office.
+    pub fn elect_chief(&mut self, members: &CustomSet<UserId>) {
+        self.votes
+            .retain(|voter, candidate| members.contains(voter) && members.contains(candidate));
+
+        let mut counts: CustomMap<UserId, u64> = CustomMap::new();
+        for candidate in self.votes.values() {
+            *counts.entry(*candidate).or_default() += 1;
+        }
+
+        let current = self
+            .chief
+            .filter(|chief| members.contains(chief))
+            .map(|chief| counts.get(&chief).copied().unwrap_or_default())
+            .unwrap_or_default();
+
+        if let Some((candidate, votes)) = counts
+            .into_iter()
+            .max_by_key(|(candidate, votes)| (*votes, std::cmp::Reverse(candidate.0)))
+        {
+            if votes > current {
+                self.chief = Some(candidate);
+            }
+        }
+
+        if !self.chief.map(|chief| members.contains(&chief)).unwrap_or(true) {
+            self.chief = None;
+        }
+    }
+
+    /// Takes the tithe from quest money and returns what the member keeps.
+    pub fn collect_tithe(&mut self, money: Money) -> Money {
+        let tithe = money * self.tithe.min(MAX_TITHE) / 100;
+        self.treasury += tithe;
+        money - tithe
+    }
+
+    pub fn upgrade_level(&self, upgrade: TribeUpgrade) -> u64 {
+        self.upgrades.get(&upgrade).copied().unwrap_or_default()
+    }
+
+    /// Bonus in percent for all members.
+    pub fn upgrade_bonus(&self, upgrade: TribeUpgrade) -> u64 {
+        self.upgrade_level(upgrade) * 5
+    }
+
+    pub fn upgrade_cost(&self, upgrade: TribeUpgrade) -> Option<Money> {
+        let level = self.upgrade_level(upgrade);
+        if level >= MAX_TRIBE_UPGRADE_LEVEL {
+            None
+        } else {
+            Some(upgrade.base_cost() * (level + 1))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq, Sequence, Display)]
+pub enum Banner {
+    Axe,
+    Hammer,
+    Crown,
+    Dragon,
+    Mountain,
+    Tree,
+    Fire,
+    Diamond,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq, Sequence, Display)]
+pub enum TribeUpgrade {
+    Hearth,
+    Guildhall,
+    WarCamp,
+}
+
+impl TribeUpgrade {
+    fn base_cost(self) -> Money {
+        match self {
+            TribeUpgrade::Hearth => 20000,
+            TribeUpgrade::Guildhall => 30000,
+            TribeUpgrade::WarCamp => 30000,
+        }
+    }
+
+    pub fn description(self) -> String {
+        match self {
+            TribeUpgrade::Hearth => "Increases the chance for new babies in the settlements of all members by 5% per level.".to_string(),
+            TribeUpgrade::Guildhall => "Increases the items collected by working dwarfs of all members by 5% per level.".to_string(),
+            TribeUpgrade::WarCamp => "Increases the quest score of all members by 5% per level.".to_string(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq, Sequence, Display)]
@@ -3887,6 +4847,23 @@ impl Territory {
     }
 }
 
+/// Whether the time lies from the first to the second hour of the day in
+/// the world. The range wraps around midnight if the first hour is later.
+pub fn in_hours(time: Time, from: u64, to: u64) -> bool {
+    let hour = time % ONE_DAY / ONE_HOUR;
+    if from <= to {
+        from <= hour && hour < to
+    } else {
+        from <= hour || hour < to
+    }
+}
+
+/// Whether it is night in the world, from `NIGHT_START_HOUR` to
+/// `NIGHT_END_HOUR`.
+pub fn is_night(time: Time) -> bool {
+    in_hours(time, NIGHT_START_HOUR, NIGHT_END_HOUR)
+}
+
 fn gen_ratio_valid(rng: &mut impl Rng, numerator: u32, denominator: u32) -> bool {
     if numerator == 0 {
         return false;
@@ -3975,7 +4952,7 @@ impl SpecialDwarf {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq, Sequence, Display)]
 pub enum Building {
     Headquarters, // Levelling up
     Huts, // Icrease Number of dwarfs
@@ -3990,4 +4967,377 @@ pub enum Building {
     Museum, // Rockhounding
     Shed, // Gathering
     Tavern, // Idling (More children)
-}
\ No newline at end of file
+}
+
+impl Building {
+    /// The occupation that gets more effective with each level of this building.
+    pub fn occupation(self) -> Option<Occupation> {
+        match self {
+            Building::Cabin => Some(Occupation::Logging),
+            Building::Dock => Some(Occupation::Fishing),
+            Building::Outpost => Some(Occupation::Exploring),
+            Building::Watchtower => Some(Occupation::Hunting),
+            Building::Mine => Some(Occupation::Mining),
+            Building::Farm => Some(Occupation::Farming),
+            Building::Barracks => Some(Occupation::Fighting),
+            Building::Museum => Some(Occupation::Rockhounding),
+            Building::Shed => Some(Occupation::Gathering),
+            Building::Headquarters | Building::Huts | Building::Workshop | Building::Tavern => None,
+        }
+    }
+
+    pub fn description(self) -> String {
+        match self {
+            Building::Headquarters => "The heart of your settlement. Upgrading it raises the settlement level.".to_string(),
+            Building::Huts => "Each level provides space for one more dwarf.".to_string(),
+            Building::Workshop => "Each level reduces the construction time of your settlement and its buildings by 5% and speeds up crafting by 10%.".to_string(),
+            Building::Tavern => "Each level increases the chance for new children by 10%.".to_string(),
+            _ => format!(
+                "Each level makes your dwarfs 10% more effective at {}.",
+                self.occupation().expect("building has an occupation").to_string().to_lowercase()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::world;
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    #[test]
+    fn tribe_points_are_kept_when_joining_and_lost_when_leaving() {
+        let (mut state, mut rng) = world(1);
+        let user_id = UserId(1);
+        state.players.get_mut(&user_id).unwrap().tribe_points = 3;
+
+        state.join_tribe(user_id, 0);
+        assert_eq!(state.players.get(&user_id).unwrap().tribe, Some(0));
+        assert_eq!(state.players.get(&user_id).unwrap().tribe_points, 3);
+
+        state.time += TRIBE_CHANGE_COOLDOWN;
+        state.client_event(&mut rng, ClientEvent::LeaveTribe, user_id, false).unwrap();
+        let player = state.players.get(&user_id).unwrap();
+        assert_eq!(player.tribe, None);
+        assert_eq!(player.tribe_points, 0);
+    }
+
+    #[test]
+    fn conflicting_traits_are_never_rolled_together() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let no_conflicts = |traits: &CustomSet<Trait>| {
+            traits.iter().all(|a| !traits.iter().any(|b| a.conflicts_with(*b)))
+        };
+
+        let mut father = Dwarf::new_with_added_stats(&mut rng, Stats::default());
+        father.traits = [Trait::Diligent, Trait::Tireless].into_iter().collect();
+        let mut mother = Dwarf::new_with_added_stats(&mut rng, Stats::default());
+        mother.traits = [Trait::Clumsy, Trait::Sickly].into_iter().collect();
+
+        for _ in 0..10000 {
+            let mut dwarf = Dwarf::new_with_added_stats(&mut rng, Stats::default());
+            assert!(no_conflicts(&dwarf.traits), "{:?}", dwarf.traits);
+            while dwarf.unlock_trait(&mut rng).is_some() {}
+            assert!(no_conflicts(&dwarf.traits), "{:?}", dwarf.traits);
+
+            let child = Dwarf::new_child(&mut rng, (0, &father), (1, &mother));
+            assert!(no_conflicts(&child.traits), "{:?}", child.traits);
+        }
+    }
+
+    #[test]
+    fn night_owls_only_work_better_at_night() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut dwarf = Dwarf::new_with_added_stats(&mut rng, Stats::default());
+        dwarf.traits = [Trait::NightOwl].into_iter().collect();
+        dwarf.occupation = Occupation::Hunting;
+
+        assert_eq!(dwarf.night_percent(ONE_HOUR * 12), 100);
+        assert_eq!(dwarf.night_percent(ONE_DAY + ONE_HOUR * 23), 125);
+        assert_eq!(dwarf.night_percent(ONE_HOUR * 2), 125);
+
+        dwarf.occupation = Occupation::Mining;
+        assert_eq!(dwarf.night_percent(ONE_HOUR * 2), 100);
+    }
+
+    #[test]
+    fn food_trigger_has_a_margin_and_keeps_manual_changes() {
+        let (mut state, _) = world(1);
+        let player = state.players.get_mut(&UserId(1)).unwrap();
+        player.manager_presets.push(ManagerPreset {
+            name: "Hunt".to_owned(),
+            manager: [(Occupation::Hunting, 5)].into_iter().collect(),
+            trigger: Some(PresetTrigger::FoodBelow(100)),
+        });
+        let hunting = |player: &Player| player.manager.get(&Occupation::Hunting).copied().unwrap_or_default();
+
+        player.base.food = 50;
+        player.update_manager_presets(None, 0);
+        assert!(player.triggered_preset.is_some());
+        assert!(hunting(player) > 0);
+
+        // Within the margin the preset stays.
+        player.base.food = 110;
+        player.update_manager_presets(None, 1);
+        assert!(player.triggered_preset.is_some());
+
+        player.base.food = 130;
+        player.update_manager_presets(None, 2);
+        assert!(player.triggered_preset.is_none());
+        assert_eq!(hunting(player), 0);
+
+        // A manager the player changed while the preset was applied is kept.
+        player.base.food = 50;
+        player.update_manager_presets(None, 3);
+        player.apply_manager(&[(Occupation::Mining, 5)].into_iter().collect()).unwrap();
+        player.base.food = 130;
+        player.update_manager_presets(None, 4);
+        assert!(player.triggered_preset.is_none());
+        assert!(player.manager.get(&Occupation::Mining).copied().unwrap_or_default() > 0);
+    }
+
+    #[test]
+    fn hours_of_triggers_are_checked() {
+        let (mut state, mut rng) = world(1);
+        let event = ClientEvent::SaveManagerPreset("Night".to_owned(), Some(PresetTrigger::Hours(22, 24)));
+        assert_eq!(state.client_event(&mut rng, event, UserId(1), true), Err(ActionError::InvalidHours));
+        let event = ClientEvent::AddRule(Rule::new(vec![Condition::Hours(24, 6)], Action::auto_store(Item::Apple)));
+        assert_eq!(state.client_event(&mut rng, event, UserId(1), true), Err(ActionError::InvalidHours));
+
+        assert!(in_hours(ONE_HOUR * 23, 22, 6));
+        assert!(in_hours(ONE_DAY + ONE_HOUR * 5, 22, 6));
+        assert!(!in_hours(ONE_HOUR * 6, 22, 6));
+        assert!(in_hours(ONE_HOUR * 6, 6, 22));
+        assert!(!in_hours(ONE_HOUR * 22, 6, 22));
+    }
+
+    #[test]
+    fn toggles_are_rules_and_exclude_each_other() {
+        let (mut state, mut rng) = world(1);
+        let user_id = UserId(1);
+
+        state.client_event(&mut rng, ClientEvent::ToggleAutoCraft(Item::Axe), user_id, true).unwrap();
+        state.client_event(&mut rng, ClientEvent::ToggleAutoStore(Item::Apple), user_id, true).unwrap();
+        let player = state.players.get(&user_id).unwrap();
+        assert!(player.toggled(&Action::auto_craft(Item::Axe)));
+        assert!(player.toggled(&Action::auto_store(Item::Apple)));
+        assert_eq!(player.rules.len(), 2);
+
+        state.client_event(&mut rng, ClientEvent::ToggleAutoDismantle(Item::Axe), user_id, true).unwrap();
+        let player = state.players.get(&user_id).unwrap();
+        assert!(!player.toggled(&Action::auto_craft(Item::Axe)));
+        assert!(player.toggled(&Action::auto_dismantle(Item::Axe)));
+
+        state.client_event(&mut rng, ClientEvent::ToggleAutoStore(Item::Apple), user_id, true).unwrap();
+        let player = state.players.get(&user_id).unwrap();
+        assert!(!player.toggled(&Action::auto_store(Item::Apple)));
+        assert_eq!(player.rules, vec![Rule::new(Vec::new(), Action::auto_dismantle(Item::Axe))]);
+    }
+
+    #[test]
+    fn auto_bids_outbid_and_end_with_the_trade() {
+        let (mut state, mut rng) = world(2);
+        let trade_id = state.next_trade_id;
+        state.trade_deals.insert(trade_id, TradeDeal::new(&mut rng, 1, trade_id));
+        state.next_trade_id += 1;
+        let first_bid = state.trade_deals.get(&trade_id).unwrap().next_bid;
+
+        state.client_event(&mut rng, ClientEvent::AutoBid(trade_id, first_bid * 10), UserId(2), true).unwrap();
+        state.client_event(&mut rng, ClientEvent::Bid(trade_id), UserId(1), false).unwrap();
+        let trade = state.trade_deals.get(&trade_id).unwrap();
+        assert_eq!(trade.highest_bidder.map(|(user_id, _)| user_id), Some(UserId(2)));
+        assert_eq!(state.players.get(&UserId(2)).unwrap().auto_bid(trade_id), Some(first_bid * 10));
+
+        let trade = state.trade_deals.get_mut(&trade_id).unwrap();
+        trade.time_left = 1;
+        let mut trade = trade.clone();
+        trade.update(&state.settings, &mut state.players, state.time, &state.ledger);
+        assert_eq!(state.players.get(&UserId(2)).unwrap().auto_bid(trade_id), None);
+        assert!(state.players.get(&UserId(2)).unwrap().rules.is_empty());
+    }
+
+    #[test]
+    fn huge_buy_orders_are_rejected() {
+        let (mut state, mut rng) = world(1);
+        let event = ClientEvent::BuyOrder(Item::Axe, u64::MAX, MIN_TRADE_VALUE);
+        state.players.get_mut(&UserId(1)).unwrap().money = MIN_TRADE_VALUE;
+        assert_eq!(state.client_event(&mut rng, event, UserId(1), false), Err(ActionError::AmountTooHigh));
+        assert_eq!(state.players.get(&UserId(1)).unwrap().money, MIN_TRADE_VALUE);
+        assert!(state.trade_deals.is_empty());
+    }
+
+    #[test]
+    fn cheap_merchant_auctions_run_until_they_end() {
+        let (mut state, mut rng) = world(1);
+        let mut trade = TradeDeal::new(&mut rng, 1, state.next_trade_id);
+        trade.next_bid = 1;
+        let time_left = trade.time_left;
+
+        trade.update(&state.settings, &mut state.players, state.time, &state.ledger);
+        assert!(!trade.done());
+        assert_eq!(trade.time_left, time_left - state.settings.world_speed);
+
+        // Players can still bid on it.
+        let trade_id = trade.trade_id;
+        state.trade_deals.insert(trade_id, trade);
+        state.client_event(&mut rng, ClientEvent::Bid(trade_id), UserId(1), false).unwrap();
+        let trade = state.trade_deals.get(&trade_id).unwrap();
+        assert_eq!(trade.highest_bidder.map(|(user_id, _)| user_id), Some(UserId(1)));
+    }
+
+    #[test]
+    fn auto_craft_only_queues_whole_plans() {
+        let (mut state, _) = world(1);
+        let player = state.players.get_mut(&UserId(1)).unwrap();
+        player.inventory.items = Bundle::new().add(Item::Iron, 100).add(Item::Coal, 100);
+        player.auto_functions.craft_targets.insert(
+            Item::ChainMail,
+            CraftTarget {
+                target: 1,
+                priority: 0,
+            },
+        );
+        for _ in 0..MAX_CRAFT_ORDERS - 1 {
+            player.queue_craft(Item::Nail, 1, false).unwrap();
+        }
+
+        player.auto_craft(state.time, true);
+        assert_eq!(player.queued(Item::Chain), 0);
+
+        player.craft_queue.pop_front();
+        player.auto_craft(state.time, true);
+        assert_eq!(player.queued(Item::Chain), 5);
+        assert_eq!(player.queued(Item::ChainMail), 1);
+    }
+
+    #[test]
+    fn stopped_crafts_name_the_missing_ingredients() {
+        let (mut state, _) = world(1);
+        let account = state.ledger.account(UserId(1));
+        let player = state.players.get_mut(&UserId(1)).unwrap();
+        player.inventory.items = Bundle::new().add(Item::Iron, 1).add(Item::Coal, 1);
+        player.queue_craft(Item::Nail, 3, false).unwrap();
+
+        for _ in 0..Item::Nail.crafting_time() * 2 {
+            player.work_on_crafts(&state.settings, state.time, false, account);
+        }
+
+        assert!(player.craft_queue.is_empty());
+        assert_eq!(player.inventory.items.get(&Item::Nail).copied(), Some(1));
+        let stopped = player.log.msgs.iter().find_map(|(_, msg)| match msg {
+            LogMsg::CraftingStopped(item, qty, missing) => Some((*item, *qty, missing.clone())),
+            _ => None,
+        });
+        let (item, qty, missing) = stopped.unwrap();
+        assert_eq!((item, qty), (Item::Nail, 2));
+        assert_eq!(missing.get(&Item::Iron).copied(), Some(1));
+        assert_eq!(missing.get(&Item::Coal).copied(), Some(1));
+    }
+
+    #[test]
+    fn base_upgrades_are_not_paid_twice_while_constructing() {
+        let (mut state, mut rng) = world(1);
+        let player = state.players.get_mut(&UserId(1)).unwrap();
+        let cost = player.base.upgrade_cost(&state.settings).unwrap();
+        player.inventory.items = cost.clone().mul(2);
+
+        state.client_event(&mut rng, ClientEvent::UpgradeBase, UserId(1), false).unwrap();
+        assert_eq!(
+            state.client_event(&mut rng, ClientEvent::UpgradeBase, UserId(1), false),
+            Err(ActionError::AlreadyConstructing)
+        );
+        let items = &state.players.get(&UserId(1)).unwrap().inventory.items;
+        assert_eq!(items.can_remove_x_times(&cost), Some(1));
+    }
+
+    #[test]
+    fn preset_rule_keeps_a_matching_manager() {
+        let (mut state, _) = world(1);
+        let account = state.ledger.account(UserId(1));
+        let player = state.players.get_mut(&UserId(1)).unwrap();
+        player.manager_presets.push(ManagerPreset {
+            name: "Mine".to_owned(),
+            manager: [(Occupation::Mining, 5)].into_iter().collect(),
+            trigger: None,
+        });
+        player.rules.push(Rule::new(Vec::new(), Action::ApplyPreset("Mine".to_owned())));
+        let applied = |player: &Player| {
+            player
+                .log
+                .msgs
+                .iter()
+                .filter(|(_, msg)| matches!(msg, LogMsg::ManagerPresetApplied(_)))
+                .count()
+        };
+
+        player.run_rules(None, 0, account);
+        assert_eq!(applied(player), 1);
+
+        // The preset fires again, but the manager is the same already.
+        player.rules[0].triggered = false;
+        player.run_rules(None, 1, account);
+        assert_eq!(applied(player), 1);
+    }
+
+    /// Checks that the recorded entries add up to nothing, and that every
+    /// player got exactly what the ledger says.
+    fn assert_ledger_balances(before: &CustomMap<UserId, Player>, state: &State) {
+        let entries = state.ledger.drain();
+        assert!(!entries.is_empty());
+        assert_eq!(entries.iter().map(|entry| entry.money).sum::<i64>(), 0);
+        for item in enum_iterator::all::<Item>() {
+            let qty = |entry: &LedgerEntry| entry.items.get(&item).copied().unwrap_or_default();
+            assert_eq!(entries.iter().map(qty).sum::<i64>(), 0);
+
+            for (user_id, player) in state.players.iter() {
+                let before = before.get(user_id).unwrap();
+                let own = entries.iter().filter(|entry| entry.user_id == *user_id);
+                assert_eq!(
+                    own.clone().map(qty).sum::<i64>(),
+                    player.inventory.items.get(&item).copied().unwrap_or_default() as i64
+                        - before.inventory.items.get(&item).copied().unwrap_or_default() as i64
+                );
+                assert_eq!(own.map(|entry| entry.money).sum::<i64>(), player.money as i64 - before.money as i64);
+            }
+        }
+    }
+
+    #[test]
+    fn ledger_balances_for_an_auction() {
+        let (mut state, mut rng) = world(3);
+        state.ledger = Ledger::recording();
+        for player in state.players.values_mut() {
+            player.money = 1_000_000;
+            player.inventory.items.add_checked(Bundle::new().add(Item::Axe, 100));
+        }
+        let before = state.players.clone();
+
+        let trade_id = state.next_trade_id;
+        state.client_event(&mut rng, ClientEvent::Sell(Item::Axe, 100), UserId(1), false).unwrap();
+        state.client_event(&mut rng, ClientEvent::Bid(trade_id), UserId(2), false).unwrap();
+        state.client_event(&mut rng, ClientEvent::Bid(trade_id), UserId(3), false).unwrap();
+
+        let mut trade = state.trade_deals.swap_remove(&trade_id).unwrap();
+        trade.time_left = 1;
+        trade.update(&state.settings, &mut state.players, state.time, &state.ledger);
+
+        assert!(state.players.get(&UserId(3)).unwrap().money < 1_000_000);
+        assert_ledger_balances(&before, &state);
+    }
+
+    #[test]
+    fn ledger_balances_for_a_direct_offer() {
+        let (mut state, mut rng) = world(2);
+        state.ledger = Ledger::recording();
+        let before = state.players.clone();
+
+        let offer_id = state.next_direct_offer_id;
+        let offer = ClientEvent::MakeOffer(UserId(2), Bundle::new().add(Item::Wood, 10), 0, Bundle::new().add(Item::Iron, 5), 0);
+        state.client_event(&mut rng, offer, UserId(1), false).unwrap();
+        state.client_event(&mut rng, ClientEvent::AcceptOffer(offer_id), UserId(2), false).unwrap();
+
+        assert!(state.direct_offers.is_empty());
+        assert_ledger_balances(&before, &state);
+    }
+}
@@ -0,0 +1,436 @@
+//! Assigns occupations and equipment to dwarfs for `ClientEvent::Optimize`.
+//!
+//! Occupations are handed out by solving an assignment problem between the
+//! managed dwarfs and the manager slots. Equipment is handed out the same
+//! way, one item type at a time, and both steps are repeated a few times
+//! because the best occupation of a dwarf depends on its equipment and the
+//! other way round.
+
+use crate::{ActionError, Bundle, Dwarf, DwarfId, Item, ItemType, Occupation, Player};
+use engine_shared::utils::custom_map::CustomMap;
+
+/// How often occupations and equipment are reassigned after the first guess.
+const REFINEMENT_ROUNDS: usize = 2;
+
+/// Solves the assignment problem for the given weight matrix and returns the
+/// column assigned to each row, maximizing the sum of the weights.
+///
+/// Every row gets a column if there are at least as many columns as rows,
+/// otherwise every column gets a row and the remaining rows are `None`.
+pub fn max_weight_assignment(weights: &[Vec<i64>]) -> Vec<Option<usize>> {
+    let rows = weights.len();
+    let cols = weights.first().map(Vec::len).unwrap_or_default();
+    if rows == 0 || cols == 0 {
+        return vec![None; rows];
+    }
+
+    if rows <= cols {
+        let cost = weights
+            .iter()
+            .map(|row| row.iter().map(|weight| -weight).collect())
+            .collect::<Vec<Vec<i64>>>();
+        hungarian(&cost).into_iter().map(Some).collect()
+    } else {
+        let cost = (0..cols)
+            .map(|col| (0..rows).map(|row| -weights[row][col]).collect())
+            .collect::<Vec<Vec<i64>>>();
+        let mut assignment = vec![None; rows];
+        for (col, row) in hungarian(&cost).into_iter().enumerate() {
+            assignment[row] = Some(col);
+        }
+        assignment
+    }
+}
+
+/// Minimum cost assignment for a matrix with at least as many columns as
+/// rows, using the Hungarian method with potentials in `O(rows² · cols)`.
+fn hungarian(cost: &[Vec<i64>]) -> Vec<usize> {
+    let n = cost.len();
+    let m = cost[0].len();
+    debug_assert!(n <= m);
+
+    // Row and column potentials, 1-indexed with a virtual column 0.
+    let mut u = vec![0i64; n + 1];
+    let mut v = vec![0i64; m + 1];
+    // The row matched to each column, 0 meaning none.
+    let mut matched = vec![0usize; m + 1];
+    let mut way = vec![0usize; m + 1];
+
+    for row in 1..=n {
+        matched[0] = row;
+        let mut col0 = 0;
+        let mut min_v = vec![i64::MAX; m + 1];
+        let mut used = vec![false; m + 1];
+        loop {
+            used[col0] = true;
+            let row0 = matched[col0];
+            let mut delta = i64::MAX;
+            let mut col1 = 0;
+            for col in 1..=m {
+                if !used[col] {
+                    let reduced = cost[row0 - 1][col - 1] - u[row0] - v[col];
+                    if reduced < min_v[col] {
+                        min_v[col] = reduced;
+                        way[col] = col0;
+                    }
+                    if min_v[col] < delta {
+                        delta = min_v[col];
+                        col1 = col;
+                    }
+                }
+            }
+            for col in 0..=m {
+                if used[col] {
+                    u[matched[col]] += delta;
+                    v[col] -= delta;
+                } else {
+                    min_v[col] -= delta;
+                }
+            }
+            col0 = col1;
+            if matched[col0] == 0 {
+                break;
+            }
+        }
+        loop {
+            let col1 = way[col0];
+            matched[col0] = matched[col1];
+            col0 = col1;
+            if col0 == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![0; n];
+    for col in 1..=m {
+        if matched[col] != 0 {
+            assignment[matched[col] - 1] = col - 1;
+        }
+    }
+    assignment
+}
+
+fn optimizable_item_types() -> impl Iterator<Item = ItemType> {
+    enum_iterator::all::<ItemType>()
+        .filter(|item_type| item_type.equippable() && *item_type != ItemType::Consumable)
+}
+
+fn effectiveness(dwarf: &Dwarf, occupation: Occupation) -> i64 {
+    dwarf.effectiveness_not_normalized(occupation) as i64
+}
+
+/// The effectiveness a dwarf would reach in an occupation if it could pick
+/// any of the available items, ignoring that other dwarfs might want the
+/// same ones.
+fn effectiveness_with_best_equipment(
+    dwarf: &Dwarf,
+    occupation: Occupation,
+    pool: &CustomMap<Item, u64>,
+) -> i64 {
+    let mut dwarf = dwarf.clone();
+    for item_type in optimizable_item_types() {
+        let mut best = (effectiveness(&dwarf, occupation), None);
+        for (item, num) in pool.iter() {
+            if *num > 0 && item.item_type() == Some(item_type) {
+                dwarf.equipment.insert(item_type, *item);
+                let effectiveness = effectiveness(&dwarf, occupation);
+                if effectiveness > best.0 {
+                    best = (effectiveness, Some(*item));
+                }
+                dwarf.equipment.swap_remove(&item_type);
+            }
+        }
+        if let Some(item) = best.1 {
+            dwarf.equipment.insert(item_type, item);
+        }
+    }
+    effectiveness(&dwarf, occupation)
+}
+
+/// Assigns the occupations of the given slots to the dwarfs, leaving the
+/// remaining dwarfs idle.
+fn assign_occupations(
+    dwarfs: &mut [(DwarfId, Dwarf, Occupation)],
+    slots: &[Occupation],
+    weight: impl Fn(&Dwarf, Occupation) -> i64,
+) {
+    // Slots of the same occupation share their weights.
+    let mut weights_by_occupation: CustomMap<Occupation, Vec<i64>> = CustomMap::new();
+    for occupation in slots {
+        if weights_by_occupation.get(occupation).is_none() {
+            weights_by_occupation.insert(
+                *occupation,
+                dwarfs
+                    .iter()
+                    .map(|(_, dwarf, _)| weight(dwarf, *occupation))
+                    .collect(),
+            );
+        }
+    }
+
+    let weights = (0..dwarfs.len())
+        .map(|idx| {
+            slots
+                .iter()
+                .map(|occupation| weights_by_occupation.get(occupation).unwrap()[idx])
+                .collect()
+        })
+        .collect::<Vec<Vec<i64>>>();
+
+    for ((_, _, occupation), slot) in dwarfs.iter_mut().zip(max_weight_assignment(&weights)) {
+        *occupation = slot.map(|slot| slots[slot]).unwrap_or(Occupation::Idling);
+    }
+}
+
+/// Hands out the items in the pool to the dwarfs for their occupations. Each
+/// item type is reassigned as a whole while the others stay fixed, so a pass
+/// never makes the total effectiveness worse.
+fn assign_equipment(dwarfs: &mut [(DwarfId, Dwarf, Occupation)], pool: &mut CustomMap<Item, u64>) {
+    for item_type in optimizable_item_types() {
+        for (_, dwarf, _) in dwarfs.iter_mut() {
+            if let Some(item) = dwarf.equipment.swap_remove(&item_type) {
+                *pool.entry(item).or_default() += 1;
+            }
+        }
+
+        // No dwarf can use more than one item of a kind, so the number of
+        // copies per item is capped by the number of dwarfs.
+        let units = pool
+            .iter()
+            .filter(|(item, num)| **num > 0 && item.item_type() == Some(item_type))
+            .flat_map(|(item, num)| std::iter::repeat_n(*item, (*num as usize).min(dwarfs.len())))
+            .collect::<Vec<Item>>();
+        if units.is_empty() {
+            continue;
+        }
+
+        let weights = dwarfs
+            .iter()
+            .map(|(_, dwarf, occupation)| {
+                let before = effectiveness(dwarf, *occupation);
+                let mut dwarf = dwarf.clone();
+                units
+                    .iter()
+                    .map(|item| {
+                        dwarf.equipment.insert(item_type, *item);
+                        (effectiveness(&dwarf, *occupation) - before).max(0)
+                    })
+                    .collect()
+            })
+            .collect::<Vec<Vec<i64>>>();
+
+        for (idx, unit) in max_weight_assignment(&weights).into_iter().enumerate() {
+            if let Some(unit) = unit {
+                if weights[idx][unit] > 0 {
+                    let item = units[unit];
+                    dwarfs[idx].1.equipment.insert(item_type, item);
+                    *pool.get_mut(&item).expect("units are taken from the pool") -= 1;
+                }
+            }
+        }
+    }
+}
+
+/// Reassigns occupations (only if no single dwarf is given) and equipment.
+pub fn optimize(player: &mut Player, to_optimize_dwarf_id: Option<DwarfId>) -> Result<(), ActionError> {
+    let dwarf_ids = if let Some(dwarf_id) = to_optimize_dwarf_id {
+        if player.dwarfs.get(&dwarf_id).is_none() {
+            return Err(ActionError::DwarfNotFound);
+        }
+        vec![dwarf_id]
+    } else {
+        player.set_manager();
+        player
+            .dwarfs
+            .iter()
+            .filter(|(_, dwarf)| dwarf.can_be_managed())
+            .map(|(dwarf_id, _)| *dwarf_id)
+            .collect()
+    };
+
+    // Take the equipment off, it is handed out again from the pool.
+    let mut dwarfs = Vec::new();
+    for dwarf_id in dwarf_ids {
+        let dwarf = player.dwarfs.get_mut(&dwarf_id).ok_or(ActionError::DwarfNotFound)?;
+        for item_type in optimizable_item_types() {
+            if let Some(item) = dwarf.equipment.swap_remove(&item_type) {
+                player.inventory.items.add_checked(Bundle::new().add(item, 1));
+            }
+        }
+        let occupation = if to_optimize_dwarf_id.is_some() {
+            dwarf.actual_occupation()
+        } else {
+            dwarf.occupation
+        };
+        dwarfs.push((dwarf_id, dwarf.clone(), occupation));
+    }
+
+    let mut pool: CustomMap<Item, u64> = CustomMap::new();
+    for (item, num) in player.inventory.items.iter() {
+        if *num > 0 && item.item_type().map(|item_type| optimizable_item_types().any(|t| t == item_type)).unwrap_or(false) {
+            pool.insert(*item, *num);
+        }
+    }
+
+    if to_optimize_dwarf_id.is_none() {
+        let slots = player
+            .manager
+            .iter()
+            .filter(|(occupation, _)| **occupation != Occupation::Idling)
+            .flat_map(|(occupation, num)| std::iter::repeat_n(*occupation, *num as usize))
+            .collect::<Vec<Occupation>>();
+
+        assign_occupations(&mut dwarfs, &slots, |dwarf, occupation| {
+            effectiveness_with_best_equipment(dwarf, occupation, &pool)
+        });
+        assign_equipment(&mut dwarfs, &mut pool);
+
+        for _ in 0..REFINEMENT_ROUNDS {
+            assign_occupations(&mut dwarfs, &slots, effectiveness);
+            assign_equipment(&mut dwarfs, &mut pool);
+        }
+    } else {
+        assign_equipment(&mut dwarfs, &mut pool);
+    }
+
+    for (dwarf_id, optimized, occupation) in dwarfs {
+        let dwarf = player.dwarfs.get_mut(&dwarf_id).ok_or(ActionError::DwarfNotFound)?;
+        if to_optimize_dwarf_id.is_none() {
+            dwarf.change_occupation(occupation);
+        }
+        for item_type in optimizable_item_types() {
+            if let Some(item) = optimized.equipment.get(&item_type) {
+                // The pool only hands out items from the inventory, so this
+                // can't fail unless the assignment is broken.
+                if !player.inventory.items.remove_checked(Bundle::new().add(*item, 1)) {
+                    return Err(ActionError::NotEnoughItems);
+                }
+                dwarf.equipment.insert(item_type, *item);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Player, Stats};
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    /// All orders of the items, for the brute force solutions.
+    fn permutations<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+        if items.is_empty() {
+            return vec![Vec::new()];
+        }
+        let mut result = Vec::new();
+        for idx in 0..items.len() {
+            let mut rest = items.to_vec();
+            let first = rest.remove(idx);
+            for mut permutation in permutations(&rest) {
+                permutation.insert(0, first.clone());
+                result.push(permutation);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn assignment_matches_brute_force() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for (rows, cols) in [(3, 3), (2, 4), (4, 2), (1, 3), (3, 1)] {
+            for _ in 0..100 {
+                let weights = (0..rows)
+                    .map(|_| (0..cols).map(|_| rng.gen_range(-50..100)).collect())
+                    .collect::<Vec<Vec<i64>>>();
+
+                let assignment = max_weight_assignment(&weights);
+                let mut used = Vec::new();
+                let mut total = 0;
+                for (row, col) in assignment.iter().enumerate() {
+                    if let Some(col) = col {
+                        assert!(!used.contains(col));
+                        used.push(*col);
+                        total += weights[row][*col];
+                    }
+                }
+                assert_eq!(used.len(), rows.min(cols));
+
+                let best = if rows <= cols {
+                    permutations(&(0..cols).collect::<Vec<_>>())
+                        .into_iter()
+                        .map(|cols| (0..rows).map(|row| weights[row][cols[row]]).sum::<i64>())
+                        .max()
+                } else {
+                    permutations(&(0..rows).collect::<Vec<_>>())
+                        .into_iter()
+                        .map(|rows| (0..cols).map(|col| weights[rows[col]][col]).sum::<i64>())
+                        .max()
+                };
+                assert_eq!(Some(total), best, "{:?}", weights);
+            }
+        }
+    }
+
+    /// The best total effectiveness of the dwarfs over every assignment of
+    /// the occupations and every way to hand out one tool each.
+    fn brute_force(dwarfs: &[Dwarf], occupations: &[Occupation], tools: &[Item]) -> i64 {
+        let mut best = 0;
+        for occupations in permutations(occupations) {
+            // `None` leaves the dwarf without a tool.
+            let choices = tools.iter().copied().map(Some).chain(std::iter::repeat_n(None, dwarfs.len()));
+            for tools in permutations(&choices.collect::<Vec<_>>()) {
+                let total = dwarfs
+                    .iter()
+                    .zip(&occupations)
+                    .zip(&tools)
+                    .map(|((dwarf, occupation), tool)| {
+                        let mut dwarf = dwarf.clone();
+                        if let Some(tool) = tool {
+                            dwarf.equipment.insert(ItemType::Tool, *tool);
+                        }
+                        effectiveness(&dwarf, *occupation)
+                    })
+                    .sum::<i64>();
+                best = best.max(total);
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn optimize_matches_brute_force() {
+        let occupations = [Occupation::Mining, Occupation::Logging, Occupation::Hunting];
+        let tools = [Item::Pickaxe, Item::Axe, Item::Bow];
+
+        for seed in 0..20 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let mut next_dwarf_id = 0;
+            let mut player = Player::new(0, &mut rng, &mut next_dwarf_id);
+            let dwarf_ids = player.dwarfs.keys().copied().take(3).collect::<Vec<_>>();
+            player.dwarfs.retain(|dwarf_id, _| dwarf_ids.contains(dwarf_id));
+            for dwarf in player.dwarfs.values_mut() {
+                dwarf.stats = Stats::random(&mut rng, 1, 10);
+                dwarf.equipment.clear();
+            }
+            player.inventory.items = Bundle::new();
+            for tool in tools {
+                player.inventory.items = player.inventory.items.clone().add(tool, 1);
+            }
+            player.manager = occupations.iter().map(|occupation| (*occupation, 1)).collect();
+
+            let dwarfs = player.dwarfs.values().cloned().collect::<Vec<_>>();
+            optimize(&mut player, None).unwrap();
+            let total = player
+                .dwarfs
+                .values()
+                .map(|dwarf| effectiveness(dwarf, dwarf.occupation))
+                .sum::<i64>();
+
+            assert!(total > 0);
+            assert_eq!(total, brute_force(&dwarfs, &occupations, &tools), "seed {}", seed);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use crate::{Bundle, Craftable, Item, Player};
+use engine_shared::utils::custom_map::CustomMap;
+use serde::{Deserialize, Serialize};
+
+/// Deeper recipe trees are not crafted automatically.
+const MAX_RECIPE_DEPTH: usize = 8;
+
+/// How many of an item auto-crafting should keep in stock. Targets with a
+/// higher priority get the ingredients first.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct CraftTarget {
+    pub target: u64,
+    pub priority: u64,
+}
+
+impl Player {
+    /// The crafting targets ordered by priority.
+    pub fn craft_targets(&self) -> Vec<(Item, CraftTarget)> {
+        let mut targets = self
+            .auto_functions
+            .craft_targets
+            .iter()
+            .map(|(item, target)| (*item, *target))
+            .collect::<Vec<_>>();
+        targets.sort_by_key(|(_, target)| std::cmp::Reverse(target.priority));
+        targets
+    }
+
+    /// Plans the crafts that bring the items up to their targets, one list
+    /// per target in the order in which they have to be carried out.
+    /// Intermediate items are only crafted if the stock does not cover a
+    /// recipe, and the reserves are never used.
+    pub fn plan_crafts(&self) -> Vec<Vec<(Item, u64)>> {
+        let mut available: CustomMap<Item, u64> = CustomMap::new();
+        for (item, qty) in self.inventory.items.iter() {
+            let reserve = self.auto_functions.reserves.get(item).copied().unwrap_or_default();
+            available.insert(*item, qty.saturating_sub(reserve));
+        }
+
+        let mut plans = Vec::new();
+        for (item, target) in self.craft_targets() {
+            let stock = self.inventory.items.get(&item).copied().unwrap_or_default()
+                + self.queued(item);
+            let missing = target.target.saturating_sub(stock);
+            if missing == 0 {
+                continue;
+            }
+
+            let qty = max_craftable(item, missing, &available);
+            if qty == 0 {
+                continue;
+            }
+            // Recipes the player can't craft yet leave the items untouched.
+            let mut planned_available = available.clone();
+            let mut planned_steps = Vec::new();
+            if self.plan_craft(item, qty, &mut planned_available, &mut planned_steps, 0) {
+                available = planned_available;
+                let mut steps: Vec<(Item, u64)> = Vec::new();
+                for (item, qty) in planned_steps {
+                    match steps.last_mut() {
+                        Some((last, last_qty)) if *last == item => *last_qty += qty,
+                        _ => steps.push((item, qty)),
+                    }
+                }
+                plans.push(steps);
+            }
+        }
+
+        plans
+    }
+
+    fn plan_craft(
+        &self,
+        item: Item,
+        qty: u64,
+        available: &mut CustomMap<Item, u64>,
+        steps: &mut Vec<(Item, u64)>,
+        depth: usize,
+    ) -> bool {
+        if depth > MAX_RECIPE_DEPTH {
+            return false;
+        }
+        let Some((level, requires)) = item.requires() else {
+            return false;
+        };
+        if self.base.curr_level < level {
+            return false;
+        }
+
+        for (ingredient, per_craft) in requires.iter() {
+            let needed = per_craft * qty;
+            let have = available.get(ingredient).copied().unwrap_or_default();
+            if have >= needed {
+                available.insert(*ingredient, have - needed);
+            } else {
+                available.insert(*ingredient, 0);
+                if !self.plan_craft(*ingredient, needed - have, available, steps, depth + 1) {
+                    return false;
+                }
+            }
+        }
+
+        steps.push((item, qty));
+        true
+    }
+}
+
+/// How many of the item, up to `max`, can be crafted from the available
+/// items. Every craft uses up the raw materials of the item, less those of
+/// the stock of intermediate items that it uses instead.
+fn max_craftable(item: Item, max: u64, available: &CustomMap<Item, u64>) -> u64 {
+    if item.requires().is_none() {
+        return 0;
+    }
+    let raw_materials = item.raw_materials();
+    let mut intermediates = CustomMap::new();
+    collect_intermediates(item, &mut intermediates);
+
+    let craftable = |used: &dyn Fn(Item) -> u64| {
+        raw_materials
+            .iter()
+            .map(|(raw, per_item)| {
+                let supply = available.get(raw).copied().unwrap_or_default()
+                    + intermediates
+                        .iter()
+                        .map(|(intermediate, raw_materials): (&Item, &Bundle<Item>)| {
+                            used(*intermediate) * raw_materials.get(raw).copied().unwrap_or_default()
+                        })
+                        .sum::<u64>();
+                supply / per_item
+            })
+            .min()
+            .unwrap_or_default()
+    };
+
+    // Using all of the stock gives an upper bound. The stock used for that
+    // many crafts lowers it until the crafts use no more than they count.
+    let mut qty = max.min(craftable(&|intermediate| {
+        available.get(&intermediate).copied().unwrap_or_default()
+    }));
+    loop {
+        let mut used = CustomMap::new();
+        use_stock(item, qty, available, &mut used);
+        let craftable = craftable(&|intermediate| used.get(&intermediate).copied().unwrap_or_default());
+        if craftable >= qty {
+            return qty;
+        }
+        qty = craftable;
+    }
+}
+
+/// The items in the recipe of the item that have a recipe themselves,
+/// together with their raw materials.
+fn collect_intermediates(item: Item, intermediates: &mut CustomMap<Item, Bundle<Item>>) {
+    if let Some((_level, requires)) = item.requires() {
+        for ingredient in requires.keys() {
+            if ingredient.requires().is_some() && intermediates.get(ingredient).is_none() {
+                intermediates.insert(*ingredient, ingredient.raw_materials());
+                collect_intermediates(*ingredient, intermediates);
+            }
+        }
+    }
+}
+
+/// The stock of intermediate items that crafting the item uses, taken in
+/// the same order as `Player::plan_craft` does.
+fn use_stock(item: Item, qty: u64, available: &CustomMap<Item, u64>, used: &mut CustomMap<Item, u64>) {
+    let Some((_level, requires)) = item.requires() else {
+        return;
+    };
+    for (ingredient, per_craft) in requires.iter() {
+        if ingredient.requires().is_none() {
+            continue;
+        }
+        let needed = per_craft * qty;
+        let used_ingredient = used.entry(*ingredient).or_default();
+        let left = available
+            .get(ingredient)
+            .copied()
+            .unwrap_or_default()
+            .saturating_sub(*used_ingredient);
+        let taken = needed.min(left);
+        *used_ingredient += taken;
+        if needed > taken {
+            use_stock(*ingredient, needed - taken, available, used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecipeTree;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    fn player(rng: &mut SmallRng) -> Player {
+        let mut player = Player::new(0, rng, &mut 0);
+        player.base.curr_level = 100;
+        player.inventory.items = Bundle::new();
+        player
+    }
+
+    #[test]
+    fn max_craftable_matches_planning_each_amount() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let player = player(&mut rng);
+        let items = [Item::Iron, Item::Axe, Item::Nail, Item::ChainMail, Item::Crossbow, Item::PoisonedBow];
+
+        fn stock(tree: &RecipeTree, rng: &mut SmallRng, available: &mut CustomMap<Item, u64>) {
+            let max = if tree.ingredients.is_empty() { 500 } else { 20 };
+            available.insert(tree.item, rng.gen_range(0..max));
+            for ingredient in &tree.ingredients {
+                stock(ingredient, rng, available);
+            }
+        }
+
+        for _ in 0..200 {
+            let item = items[rng.gen_range(0..items.len())];
+            let mut available = CustomMap::new();
+            stock(&item.recipe_tree(1), &mut rng, &mut available);
+
+            let mut expected = 0;
+            while player.plan_craft(item, expected + 1, &mut available.clone(), &mut Vec::new(), 0) {
+                expected += 1;
+            }
+
+            assert_eq!(max_craftable(item, u64::MAX, &available), expected, "{item} {available:?}");
+            assert_eq!(max_craftable(item, expected / 2, &available), expected / 2);
+        }
+    }
+
+    #[test]
+    fn plan_uses_the_stock_of_intermediates_first() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut player = player(&mut rng);
+        player.inventory.items = Bundle::new()
+            .add(Item::Iron, 2)
+            .add(Item::IronOre, 10)
+            .add(Item::Coal, 10)
+            .add(Item::Wood, 100);
+        player.auto_functions.craft_targets.insert(
+            Item::Iron,
+            CraftTarget {
+                target: 7,
+                priority: 0,
+            },
+        );
+
+        assert_eq!(player.plan_crafts(), vec![vec![(Item::Iron, 5)]]);
+    }
+}
@@ -0,0 +1,179 @@
+//! The summary of a finished world, kept next to the archived state once the
+//! world is closed.
+
+use crate::{GameMode, Money, State, Territory, Time, TribeId, UserId};
+use serde::{Deserialize, Serialize};
+
+/// How many players are listed as top producers.
+pub const TOP_PRODUCERS: usize = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WorldResults {
+    pub game_mode: GameMode,
+    pub duration: Time,
+    pub winner: Option<UserId>,
+    /// All players, the highest level first.
+    pub players: Vec<PlayerResult>,
+    /// All tribes, the most territories first.
+    pub tribes: Vec<TribeResult>,
+    pub top_producers: Vec<UserId>,
+    pub eldest_dwarf: Option<EldestDwarf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerResult {
+    pub user_id: UserId,
+    pub level: u64,
+    pub dwarfs: usize,
+    pub money: Money,
+    pub tribe: Option<TribeId>,
+    pub items_produced: u64,
+    pub quests_won: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TribeResult {
+    pub tribe_id: TribeId,
+    pub name: Option<String>,
+    pub members: usize,
+    pub territories: Vec<Territory>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EldestDwarf {
+    pub user_id: UserId,
+    pub name: String,
+    pub age_years: u64,
+}
+
+impl State {
+    pub fn results(&self) -> WorldResults {
+        let mut players = self
+            .players
+            .iter()
+            .map(|(user_id, player)| PlayerResult {
+                user_id: *user_id,
+                level: player.base.curr_level,
+                dwarfs: player.dwarfs.values().filter(|dwarf| !dwarf.dead()).count(),
+                money: player.money,
+                tribe: player.tribe,
+                items_produced: player.items_produced,
+                quests_won: player.quests_won,
+            })
+            .collect::<Vec<_>>();
+        players.sort_by_key(|player| (std::cmp::Reverse(player.level), player.user_id.0));
+
+        let member_counts = self.tribe_member_counts();
+        let mut tribes = self
+            .tribes
+            .iter()
+            .map(|(tribe_id, tribe)| TribeResult {
+                tribe_id: *tribe_id,
+                name: tribe.name.clone(),
+                members: member_counts.get(tribe_id).copied().unwrap_or_default(),
+                territories: self.controlled_territories(*tribe_id),
+            })
+            .collect::<Vec<_>>();
+        tribes.sort_by_key(|tribe| (std::cmp::Reverse(tribe.territories.len()), tribe.tribe_id));
+
+        let mut top_producers = players
+            .iter()
+            .filter(|player| player.items_produced > 0)
+            .collect::<Vec<_>>();
+        top_producers.sort_by_key(|player| std::cmp::Reverse(player.items_produced));
+        let top_producers = top_producers
+            .into_iter()
+            .take(TOP_PRODUCERS)
+            .map(|player| player.user_id)
+            .collect();
+
+        let eldest_dwarf = self
+            .players
+            .iter()
+            .flat_map(|(user_id, player)| {
+                player
+                    .dwarfs
+                    .values()
+                    .filter(|dwarf| !dwarf.dead())
+                    .map(move |dwarf| (user_id, dwarf))
+            })
+            .max_by_key(|(_, dwarf)| dwarf.age_seconds)
+            .map(|(user_id, dwarf)| EldestDwarf {
+                user_id: *user_id,
+                name: dwarf.actual_name().to_owned(),
+                age_years: dwarf.age_years(),
+            });
+
+        WorldResults {
+            game_mode: self.settings.game_mode,
+            duration: self.time,
+            winner: self.winner(),
+            players,
+            tribes,
+            top_producers,
+            eldest_dwarf,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::world;
+
+    #[test]
+    fn players_are_sorted_by_level_then_id() {
+        let (mut state, _) = world(3);
+        state.players.get_mut(&UserId(1)).unwrap().base.curr_level = 2;
+        state.players.get_mut(&UserId(2)).unwrap().base.curr_level = 5;
+        state.players.get_mut(&UserId(3)).unwrap().base.curr_level = 2;
+
+        let players = state
+            .results()
+            .players
+            .iter()
+            .map(|player| player.user_id)
+            .collect::<Vec<_>>();
+        assert_eq!(players, vec![UserId(2), UserId(1), UserId(3)]);
+    }
+
+    #[test]
+    fn top_producers_skip_idle_players_and_are_capped() {
+        let (mut state, _) = world(TOP_PRODUCERS as i64 + 2);
+        for (user_id, player) in state.players.iter_mut() {
+            player.items_produced = user_id.0 as u64 * 10;
+        }
+        state.players.get_mut(&UserId(3)).unwrap().items_produced = 0;
+
+        let top_producers = state.results().top_producers;
+        assert_eq!(
+            top_producers,
+            vec![UserId(7), UserId(6), UserId(5), UserId(4), UserId(2)]
+        );
+    }
+
+    #[test]
+    fn eldest_dwarf_is_the_oldest_living_one() {
+        let (mut state, _) = world(2);
+        for player in state.players.values_mut() {
+            for dwarf in player.dwarfs.values_mut() {
+                dwarf.age_seconds = 0;
+            }
+        }
+
+        let dead = state.players.get_mut(&UserId(1)).unwrap();
+        let dwarf = dead.dwarfs.values_mut().next().unwrap();
+        dwarf.age_seconds = 200 * 365 * 24 * 60 * 60;
+        dwarf.health = 0;
+
+        let alive = state.players.get_mut(&UserId(2)).unwrap();
+        let dwarf = alive.dwarfs.values_mut().last().unwrap();
+        dwarf.age_seconds = 90 * 365 * 24 * 60 * 60;
+        let name = dwarf.actual_name().to_owned();
+
+        let eldest = state.results().eldest_dwarf.unwrap();
+        assert_eq!(eldest.user_id, UserId(2));
+        assert_eq!(eldest.name, name);
+        assert_eq!(eldest.age_years, 90);
+    }
+}
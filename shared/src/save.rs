@@ -0,0 +1,315 @@
+//! The format in which the server stores a world in `games.data`.
+//!
+//! A save starts with `MAGIC` and the version of the format as a big endian
+//! `u32`, followed by the state encoded as MessagePack with field names, so
+//! that new fields with `#[serde(default)]` can be added anywhere in a
+//! struct. Saves from before the envelope existed have no header and count
+//! as version 0.
+//!
+//! Changes to `State` that `#[serde(default)]` can't cover, like renamed or
+//! retyped fields, need a new function at the end of `MIGRATIONS` that
+//! decodes the old layout into frozen copies of the changed structs, like
+//! `v0` does. Every version also gets golden saves in
+//! `shared/fixtures/saves`, written by the `saves` binary of the server and
+//! loaded by the tests below.
+
+use crate::{
+    automation::{Action, Rule},
+    AutoFunctions, ChatChannel, Player, State,
+};
+use engine_shared::utils::custom_map::CustomSet;
+use std::borrow::Cow;
+
+const MAGIC: &[u8; 4] = b"DWRF";
+
+/// Turns the payload of a save into the payload of the next version.
+type Migration = fn(&[u8]) -> Result<Vec<u8>, SaveError>;
+
+/// `MIGRATIONS[v]` upgrades a payload from version `v` to version `v + 1`.
+const MIGRATIONS: &[Migration] = &[migrate_v0];
+
+pub const SAVE_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Truncated,
+    UnsupportedVersion(u32),
+    Decode(u32, rmp_serde::decode::Error),
+    Encode(rmp_serde::encode::Error),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::Truncated => write!(f, "the save header is truncated"),
+            SaveError::UnsupportedVersion(version) => write!(
+                f,
+                "save version {version} is newer than the supported version {SAVE_VERSION}"
+            ),
+            SaveError::Decode(version, err) => write!(f, "could not decode save version {version}: {err}"),
+            SaveError::Encode(err) => write!(f, "could not encode save: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<rmp_serde::encode::Error> for SaveError {
+    fn from(err: rmp_serde::encode::Error) -> Self {
+        SaveError::Encode(err)
+    }
+}
+
+/// Version 0 is the bare state as written by `rmp_serde::to_vec`, which
+/// stores the fields of a struct by their position.
+fn migrate_v0(payload: &[u8]) -> Result<Vec<u8>, SaveError> {
+    let state: v0::State = rmp_serde::from_slice(payload).map_err(|err| SaveError::Decode(0, err))?;
+    Ok(rmp_serde::to_vec_named(&State::from(state))?)
+}
+
+/// The structs of version 0 whose layout changed since, as they were before
+/// the save envelope. All other types are decoded with their current
+/// definition, as fields were only appended to them.
+mod v0 {
+    use crate::{
+        Base, Chat, Dwarf, DwarfId, Inventory, Item, Log, Money, Occupation, Popup, Quest, QuestId,
+        Time, TradeDeal, TradeId, Tribe, TribeId, TutorialStep, UserId, WorldEvent, WorldSettings,
+    };
+    use engine_shared::utils::custom_map::{CustomMap, CustomSet};
+    use enum_iterator::Sequence;
+    use serde::Deserialize;
+    use std::collections::VecDeque;
+
+    #[derive(Deserialize)]
+    pub struct State {
+        pub players: CustomMap<UserId, Player>,
+        pub next_dwarf_id: DwarfId,
+        pub chat: Chat,
+        pub next_quest_id: QuestId,
+        pub next_trade_id: TradeId,
+        pub quests: CustomMap<QuestId, Quest>,
+        pub time: Time,
+        pub king: Option<UserId>,
+        #[serde(default)]
+        pub event: Option<WorldEvent>,
+        pub trade_deals: CustomMap<TradeId, TradeDeal>,
+        pub tribes: CustomMap<TribeId, Tribe>,
+        pub settings: WorldSettings,
+        #[serde(default)]
+        pub start_countdown: u64,
+        #[serde(default)]
+        pub eldest: Option<(UserId, DwarfId)>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Player {
+        pub base: Base,
+        pub dwarfs: CustomMap<DwarfId, Dwarf>,
+        pub inventory: Inventory,
+        pub log: Log,
+        pub money: Money,
+        pub last_online: Time,
+        pub auto_functions: AutoFunctions,
+        pub reward_time: Time,
+        #[serde(default = "TutorialStep::first")]
+        pub tutorial_step: Option<TutorialStep>,
+        #[serde(default)]
+        pub start_time: Time,
+        pub popups: VecDeque<Popup>,
+        pub manager: CustomMap<Occupation, u64>,
+        #[serde(default)]
+        pub chat_unread: bool,
+        pub tribe: Option<TribeId>,
+        pub tribe_points: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub struct AutoFunctions {
+        pub auto_idle: bool,
+        pub auto_craft: CustomSet<Item>,
+        pub auto_store: CustomSet<Item>,
+        pub auto_sell: CustomSet<Item>,
+        #[serde(default = "CustomSet::new")]
+        pub auto_dismantle: CustomSet<Item>,
+        #[serde(default = "CustomMap::new")]
+        pub auto_bid: CustomMap<TradeId, Money>,
+    }
+}
+
+impl From<v0::State> for State {
+    fn from(state: v0::State) -> Self {
+        State {
+            players: state
+                .players
+                .into_iter()
+                .map(|(user_id, player)| (user_id, Player::from(player)))
+                .collect(),
+            next_dwarf_id: state.next_dwarf_id,
+            chat: state.chat,
+            next_quest_id: state.next_quest_id,
+            next_trade_id: state.next_trade_id,
+            quests: state.quests,
+            time: state.time,
+            king: state.king,
+            event: state.event,
+            trade_deals: state.trade_deals,
+            tribes: state.tribes,
+            settings: state.settings,
+            start_countdown: state.start_countdown,
+            eldest: state.eldest,
+            direct_offers: Default::default(),
+            next_direct_offer_id: Default::default(),
+            journal: Default::default(),
+            ledger: Default::default(),
+        }
+    }
+}
+
+impl From<v0::Player> for Player {
+    fn from(player: v0::Player) -> Self {
+        let auto_functions = player.auto_functions;
+        // The toggles became rules without conditions.
+        let rules = auto_functions
+            .auto_craft
+            .iter()
+            .map(|item| Action::auto_craft(*item))
+            .chain(auto_functions.auto_store.iter().map(|item| Action::auto_store(*item)))
+            .chain(auto_functions.auto_dismantle.iter().map(|item| Action::auto_dismantle(*item)))
+            .chain(
+                auto_functions
+                    .auto_bid
+                    .iter()
+                    .map(|(trade_id, max_bid)| Action::BidOnTrade(*trade_id, *max_bid)),
+            )
+            .map(|action| Rule::new(Vec::new(), action))
+            .collect();
+        // There was only the global chat.
+        let mut unread_chats = CustomSet::new();
+        if player.chat_unread {
+            unread_chats.insert(ChatChannel::Global);
+        }
+
+        Player {
+            base: player.base,
+            dwarfs: player.dwarfs,
+            inventory: player.inventory,
+            log: player.log,
+            money: player.money,
+            last_online: player.last_online,
+            auto_functions: AutoFunctions {
+                auto_idle: auto_functions.auto_idle,
+                auto_sell: auto_functions.auto_sell,
+                craft_targets: Default::default(),
+                reserves: Default::default(),
+            },
+            reward_time: player.reward_time,
+            tutorial_step: player.tutorial_step,
+            start_time: player.start_time,
+            popups: player.popups,
+            manager: player.manager,
+            tribe: player.tribe,
+            tribe_points: player.tribe_points,
+            last_error: None,
+            tribe_choice_since: None,
+            tribe_joined: None,
+            pairing: None,
+            manager_presets: Vec::new(),
+            triggered_preset: None,
+            rules,
+            craft_queue: Default::default(),
+            items_produced: 0,
+            quests_won: 0,
+            unread_chats,
+        }
+    }
+}
+
+/// Encodes the state in the current version.
+pub fn encode(state: &State) -> Result<Vec<u8>, SaveError> {
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&SAVE_VERSION.to_be_bytes());
+    rmp_serde::encode::write_named(&mut data, state)?;
+    Ok(data)
+}
+
+/// Splits a save into its version and payload.
+pub fn version(data: &[u8]) -> Result<(u32, &[u8]), SaveError> {
+    // A MessagePack state starts with an array or map marker, so it can't
+    // be mistaken for the magic bytes.
+    let Some(rest) = data.strip_prefix(MAGIC) else {
+        return Ok((0, data));
+    };
+    if rest.len() < 4 {
+        return Err(SaveError::Truncated);
+    }
+    let (version, payload) = rest.split_at(4);
+    Ok((u32::from_be_bytes(version.try_into().unwrap()), payload))
+}
+
+/// Decodes a save of any version up to the current one, migrating it on the
+/// way.
+pub fn decode(data: &[u8]) -> Result<State, SaveError> {
+    let (version, payload) = version(data)?;
+    if version > SAVE_VERSION {
+        return Err(SaveError::UnsupportedVersion(version));
+    }
+
+    let mut payload = Cow::Borrowed(payload);
+    for migration in &MIGRATIONS[version as usize..] {
+        payload = Cow::Owned(migration(&payload)?);
+    }
+
+    rmp_serde::from_slice(&payload).map_err(|err| SaveError::Decode(SAVE_VERSION, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Item, UserId};
+    use std::path::PathBuf;
+
+    fn golden_save(name: &str) -> Vec<u8> {
+        let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/saves")).join(name);
+        std::fs::read(&path).unwrap_or_else(|err| panic!("could not read {}: {err}", path.display()))
+    }
+
+    #[test]
+    fn golden_saves_load() {
+        let dir = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/saves"));
+        let mut versions = CustomSet::new();
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let data = std::fs::read(&path).unwrap();
+            let (version, _) = version(&data).unwrap();
+            versions.insert(version);
+            if let Err(err) = decode(&data) {
+                panic!("could not load {}: {err}", path.display());
+            }
+        }
+        // Every version needs golden saves.
+        assert_eq!(versions.len() as u32, SAVE_VERSION + 1);
+    }
+
+    #[test]
+    fn version_0_toggles_become_rules() {
+        let state = decode(&golden_save("v0-played-speed.save")).unwrap();
+        assert_eq!(state.players.len(), 3);
+
+        let player = state.players.get(&UserId(1)).unwrap();
+        assert!(player.toggled(&Action::auto_craft(Item::Iron)));
+        assert!(player.toggled(&Action::auto_store(Item::Apple)));
+        assert!(player.toggled(&Action::auto_dismantle(Item::Pitchfork)));
+        assert!(player
+            .rules
+            .iter()
+            .any(|rule| matches!(rule.action, Action::BidOnTrade(..))));
+        assert!(player.unread_chats.contains(&ChatChannel::Global));
+    }
+
+    #[test]
+    fn saves_keep_the_state() {
+        let state = decode(&golden_save("v1-played-speed.save")).unwrap();
+        let decoded = decode(&encode(&state).unwrap()).unwrap();
+        assert_eq!(encode(&decoded).unwrap(), encode(&state).unwrap());
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{Bundle, ClientEvent, GameMode, Item, ServerEvent, State, Time, UserData, UserId};
+use engine_shared::{utils::custom_map::CustomMap, Event, State as _};
+use rand::{rngs::SmallRng, SeedableRng};
+
+/// Decides which client events a synthetic player sends before a tick.
+pub trait Strategy {
+    fn events(&mut self, state: &State, user_id: UserId) -> Vec<ClientEvent>;
+}
+
+/// A fixed stream of client events, either sent once at a given time or
+/// repeatedly in a given interval.
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    once: Vec<(Time, ClientEvent)>,
+    every: Vec<(Time, ClientEvent)>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script::default()
+    }
+
+    pub fn at(mut self, time: Time, event: ClientEvent) -> Self {
+        self.once.push((time, event));
+        self
+    }
+
+    pub fn every(mut self, interval: Time, event: ClientEvent) -> Self {
+        self.every.push((interval.max(1), event));
+        self
+    }
+}
+
+impl Strategy for Script {
+    fn events(&mut self, state: &State, _user_id: UserId) -> Vec<ClientEvent> {
+        self.once
+            .iter()
+            .filter(|(time, _)| *time == state.time)
+            .chain(
+                self.every
+                    .iter()
+                    .filter(|(interval, _)| state.time.is_multiple_of(*interval)),
+            )
+            .map(|(_, event)| event.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayerReport {
+    pub user_id: UserId,
+    pub level: u64,
+    pub money: u64,
+    pub dwarfs: usize,
+    pub adult_dwarfs: usize,
+    pub food: u64,
+    pub items: Bundle<Item>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub time: Time,
+    pub players: Vec<PlayerReport>,
+}
+
+/// Drives `State::update` without a server, using a seeded rng so that
+/// the same setup always produces the same world.
+///
+/// Note that `Player::new` and the quest and trade spawning behave
+/// differently in debug builds, so balancing questions should be answered
+/// with a release build.
+pub struct Simulation {
+    pub state: State,
+    rng: SmallRng,
+    user_data: CustomMap<UserId, UserData>,
+    strategies: Vec<(UserId, Box<dyn Strategy>)>,
+}
+
+impl Simulation {
+    pub fn new(game_mode: GameMode, seed: u64) -> Self {
+        let mut state = State::new(game_mode);
+        // There is no one to wait for, so the world starts right away.
+        state.start_countdown = 0;
+
+        Simulation {
+            state,
+            rng: SmallRng::seed_from_u64(seed),
+            user_data: CustomMap::new(),
+            strategies: Vec::new(),
+        }
+    }
+
+    pub fn add_player(
+        &mut self,
+        user_id: UserId,
+        premium: bool,
+        strategy: impl Strategy + 'static,
+    ) {
+        self.user_data.insert(
+            user_id,
+            UserData {
+                username: format!("sim-{}", user_id.0),
+                premium: if premium { u64::MAX } else { 0 },
+                games_won: 0,
+                admin: false,
+                guest: false,
+                joined: time::PrimitiveDateTime::MIN,
+                referrer: None,
+                dwarf_skins: Vec::new(),
+            },
+        );
+        self.strategies.push((user_id, Box::new(strategy)));
+        self.state.update(
+            &mut self.rng,
+            Event::ClientEvent(ClientEvent::Init, user_id),
+            &self.user_data,
+        );
+    }
+
+    pub fn step(&mut self) {
+        for (user_id, strategy) in self.strategies.iter_mut() {
+            for event in strategy.events(&self.state, *user_id) {
+                self.state.update(
+                    &mut self.rng,
+                    Event::ClientEvent(event, *user_id),
+                    &self.user_data,
+                );
+            }
+        }
+
+        self.state.update(
+            &mut self.rng,
+            Event::ServerEvent(ServerEvent::Tick),
+            &self.user_data,
+        );
+    }
+
+    /// Runs the given number of ticks and takes a report every `report_every` ticks
+    /// as well as after the last tick.
+    pub fn run(&mut self, ticks: u64, report_every: u64) -> Vec<Report> {
+        let mut reports = Vec::new();
+        for tick in 1..=ticks {
+            self.step();
+            if tick.is_multiple_of(report_every.max(1)) || tick == ticks {
+                reports.push(self.report());
+            }
+        }
+        reports
+    }
+
+    /// Runs until the condition holds and returns the game time at that point,
+    /// or `None` if it did not hold within `max_ticks`.
+    pub fn run_until(&mut self, max_ticks: u64, condition: impl Fn(&State) -> bool) -> Option<Time> {
+        for _ in 0..max_ticks {
+            self.step();
+            if condition(&self.state) {
+                return Some(self.state.time);
+            }
+        }
+        None
+    }
+
+    pub fn report(&self) -> Report {
+        Report {
+            time: self.state.time,
+            players: self
+                .strategies
+                .iter()
+                .filter_map(|(user_id, _)| {
+                    let player = self.state.players.get(user_id)?;
+                    Some(PlayerReport {
+                        user_id: *user_id,
+                        level: player.base.curr_level,
+                        money: player.money,
+                        dwarfs: player.dwarfs.len(),
+                        adult_dwarfs: player
+                            .dwarfs
+                            .values()
+                            .filter(|dwarf| dwarf.is_adult())
+                            .count(),
+                        food: player.base.food,
+                        items: player.inventory.items.clone(),
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Occupation, ONE_MINUTE};
+
+    fn run(seed: u64) -> Vec<Report> {
+        let mut simulation = Simulation::new(GameMode::Ranked, seed);
+        for user_id in 1..=3 {
+            simulation.add_player(
+                UserId(user_id),
+                user_id == 1,
+                Script::new().every(ONE_MINUTE, ClientEvent::SetManagerOccupation(Occupation::Hunting, 1)),
+            );
+        }
+        simulation.run(ONE_MINUTE * 30, ONE_MINUTE * 5)
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_reports() {
+        let first = run(42);
+        assert_eq!(first.len(), 6);
+        assert_eq!(format!("{:?}", first), format!("{:?}", run(42)));
+    }
+}
@@ -0,0 +1,1338 @@
+use crate::{
+    gen_ratio_valid, Bundle, Dwarf, DwarfId, Item, ItemProbability, ItemType, LedgerEntry,
+    LedgerReason, LogMsg, Occupation,
+    Money, Quest, QuestType, RewardMode, State, Stats, TradeDeal, Tribe, TribeId, TribeUpgrade,
+    UserData, UserId, WorldEvent,
+    AGE_SECONDS_PER_TICK, APPRENTICE_EFFECTIVENESS_DIVIDER, IMPROVEMENT_DURATION,
+    JOIN_TRIBE_LEVEL, MAX_HEALTH, MAX_TRIBE_SIZE_DIFFERENCE, NEW_PLAYER_DIVIDER, ONE_DAY, ONE_HOUR,
+    TRAIT_PROBABILITY, TRIBE_CHOICE_TIME,
+};
+use engine_shared::utils::custom_map::{CustomMap, CustomSet};
+use enum_iterator::Sequence;
+use rand::{
+    seq::{IteratorRandom, SliceRandom},
+    Rng,
+};
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+/// One phase of `ServerEvent::Tick`.
+pub trait TickSystem {
+    fn tick(
+        &self,
+        state: &mut State,
+        rng: &mut impl Rng,
+        user_data: &CustomMap<UserId, UserData>,
+    ) -> Option<()>;
+}
+
+/// The tick pipeline. Phases run in the order they are declared here and
+/// can be disabled per world through `WorldSettings::disabled_phases`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq, Sequence, Display)]
+pub enum TickPhase {
+    Event,
+    Territory,
+    Population,
+    Production,
+    Automation,
+    Quest,
+    Trade,
+}
+
+impl TickPhase {
+    pub fn run(
+        self,
+        state: &mut State,
+        rng: &mut impl Rng,
+        user_data: &CustomMap<UserId, UserData>,
+    ) -> Option<()> {
+        match self {
+            TickPhase::Event => EventSystem.tick(state, rng, user_data),
+            TickPhase::Territory => TerritorySystem.tick(state, rng, user_data),
+            TickPhase::Population => PopulationSystem.tick(state, rng, user_data),
+            TickPhase::Production => ProductionSystem.tick(state, rng, user_data),
+            TickPhase::Automation => AutomationSystem.tick(state, rng, user_data),
+            TickPhase::Quest => QuestSystem.tick(state, rng, user_data),
+            TickPhase::Trade => TradeSystem.tick(state, rng, user_data),
+        }
+    }
+}
+
+fn is_premium(user_data: &CustomMap<UserId, UserData>, user_id: &UserId) -> bool {
+    user_data
+        .get(user_id)
+        .map(|user_data| user_data.premium > 0)
+        .unwrap_or(false)
+}
+
+/// The dwarfen eldest, world events and the revolution against the king.
+pub struct EventSystem;
+
+impl TickSystem for EventSystem {
+    fn tick(
+        &self,
+        state: &mut State,
+        rng: &mut impl Rng,
+        _user_data: &CustomMap<UserId, UserData>,
+    ) -> Option<()> {
+        // Find the dwarfen eldest.
+        let mut eldest = None;
+        let mut age = 0;
+        for (user_id, player) in &state.players {
+            for (dwarf_id, dwarf) in &player.dwarfs {
+                if dwarf.age_seconds >= age {
+                    age = dwarf.age_seconds;
+                    eldest = Some((*user_id, *dwarf_id));
+                }
+            }
+        }
+        state.eldest = eldest;
+        if let Some((user_id, dwarf_id)) = state.eldest {
+            if let Some(player) = state.players.get_mut(&user_id) {
+                if let Some(_dwarf) = player.dwarfs.get_mut(&dwarf_id) {
+                    if gen_ratio_valid(rng, state.settings.world_speed as u32, ONE_DAY as u32) {
+                        player.inventory.add(Bundle::new().add(Item::KnowledgeOfTheEldest, 1), state.time);
+                    }
+                }
+            }
+        }
+
+        if matches!(state.event, Some(WorldEvent::Revolution)) {
+            state.king = None;
+        };
+
+        if state.event.is_some() {
+            if gen_ratio_valid(rng, state.settings.world_speed as u32, ONE_DAY as u32 / 4) {
+                state.event = None;
+            }
+        } else if gen_ratio_valid(
+            rng,
+            (state.settings.world_speed * state.settings.event_frequency) as u32,
+            ONE_DAY as u32 / 2 * 100,
+        ) {
+            state.event = Some(enum_iterator::all().choose(rng).unwrap());
+        }
+
+        for (user_id, player) in state.players.iter_mut() {
+            // Revolutionary spirit drops.
+            if Some(*user_id) != state.king
+                && gen_ratio_valid(rng, state.settings.world_speed as u32, ONE_DAY as u32 * 3)
+            {
+                player.inventory.add(Bundle::new().add(Item::RevolutionarySpirit, 1), state.time);
+            }
+        }
+
+        Some(())
+    }
+}
+
+/// Settlement construction, tribe membership and the item drops of
+/// controlled territories.
+pub struct TerritorySystem;
+
+impl TickSystem for TerritorySystem {
+    fn tick(
+        &self,
+        state: &mut State,
+        rng: &mut impl Rng,
+        user_data: &CustomMap<UserId, UserData>,
+    ) -> Option<()> {
+        // Players that did not choose a tribe in time are assigned to the
+        // tribe of their referrer, or the one with the fewest members.
+        let mut member_counts = state.tribe_member_counts();
+
+        let tribes_map = state.players.iter()
+            .filter_map(|(user_id, player)| player.tribe.map(|tribe_id| (*user_id, tribe_id)))
+            .collect::<CustomMap<_, _>>();
+
+        let controlled_territories = state
+            .tribes
+            .keys()
+            .map(|tribe_id| (*tribe_id, state.controlled_territories(*tribe_id)))
+            .collect::<CustomMap<_, _>>();
+
+        let mut joined = Vec::new();
+        for (user_id, player) in state.players.iter_mut() {
+            // Build the base first, the new level decides whether the
+            // player can join a tribe.
+            player.base.build(&state.settings);
+            if let Some((building, level)) = player.base.construct(&state.settings) {
+                player.log.add(state.time, LogMsg::BuildingUpgraded(building, level));
+            }
+
+            // Join a tribe.
+            if player.tribe.is_none() && player.base.curr_level >= JOIN_TRIBE_LEVEL {
+                match player.tribe_choice_since {
+                    None => {
+                        player.tribe_choice_since = Some(state.time);
+                        player.log.add(state.time, LogMsg::ChooseTribe);
+                    }
+                    Some(since) if state.time >= since + TRIBE_CHOICE_TIME => {
+                        let smallest = member_counts.values().min().copied().unwrap_or_default();
+                        let referrer_tribe = user_data.get(user_id).and_then(|user_data| {
+                            user_data.referrer
+                        }).and_then(|referrer_id| {
+                            tribes_map.get(&referrer_id)
+                        }).copied().filter(|tribe_id| {
+                            member_counts.get(tribe_id).copied().unwrap_or_default() < smallest + MAX_TRIBE_SIZE_DIFFERENCE
+                        });
+                        let fewest_members_tribe_id = member_counts
+                            .iter()
+                            .min_by_key(|(_, count)| **count)
+                            .map(|(tribe_id, _)| *tribe_id);
+
+                        if let Some(tribe_id) = referrer_tribe.or(fewest_members_tribe_id) {
+                            *member_counts.entry(tribe_id).or_default() += 1;
+                            joined.push((*user_id, tribe_id));
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            let controlled_territories = player
+                .tribe
+                .and_then(|tribe_id| controlled_territories.get(&tribe_id))
+                .cloned()
+                .unwrap_or_default();
+
+            if !controlled_territories.is_empty()
+                && gen_ratio_valid(
+                    rng,
+                    state.settings.world_speed as u32,
+                    ONE_DAY as u32 * 3 / (controlled_territories.len() as u32 + 1),
+                )
+            {
+                let t = controlled_territories.choose(rng).unwrap();
+                let item = t.drop();
+
+                player.inventory.add(Bundle::new().add(item, 1), state.time);
+            }
+        }
+
+        for (user_id, tribe_id) in joined {
+            state.join_tribe(user_id, tribe_id);
+        }
+
+        Some(())
+    }
+}
+
+/// New dwarfs, births, eating, aging, training and deaths.
+pub struct PopulationSystem;
+
+impl TickSystem for PopulationSystem {
+    fn tick(
+        &self,
+        state: &mut State,
+        rng: &mut impl Rng,
+        _user_data: &CustomMap<UserId, UserData>,
+    ) -> Option<()> {
+        let controlled_territories = state
+            .tribes
+            .keys()
+            .map(|tribe_id| (*tribe_id, state.controlled_territories(*tribe_id)))
+            .collect::<CustomMap<_, _>>();
+
+        for (user_id, player) in state.players.iter_mut() {
+            // Chance for a new dwarf!
+            let controlled_territories = player
+                .tribe
+                .and_then(|tribe_id| controlled_territories.get(&tribe_id))
+                .cloned()
+                .unwrap_or_default();
+
+            if gen_ratio_valid(rng, 
+                state.event
+                    .map(|event| event.new_dwarfs_multiplier())
+                    .unwrap_or(1) * state.settings.world_speed as u32,
+                    ONE_DAY as u32 * 5 / (controlled_territories.len() as u32 + 1)
+            ) {
+                let added_stats = controlled_territories.iter()
+                    .flat_map(|t| t.best_for_occupation())
+                    .collect::<Vec<_>>()
+                    .choose(rng)
+                    .map(|o| o.requires_stats())
+                    .unwrap_or(Stats::default());
+
+                player.new_dwarf(
+                    rng,
+                    &mut state.next_dwarf_id,
+                    state.time,
+                    Some(added_stats),
+                );
+            }
+
+            let male_idle_dwarfs = player
+                .dwarfs
+                .values()
+                .filter(|dwarf| {
+                    dwarf.occupation == Occupation::Idling
+                        && dwarf.is_adult()
+                        && !dwarf.is_female
+                })
+                .count();
+
+            let female_idle_dwarfs = player
+                .dwarfs
+                .values()
+                .filter(|dwarf| {
+                    dwarf.occupation == Occupation::Idling
+                        && dwarf.is_adult()
+                        && dwarf.is_female
+                })
+                .count();
+
+            let pairs = male_idle_dwarfs.min(female_idle_dwarfs);
+
+            // Chance for a new baby dwarf!
+            let baby_dwarf_multiplier_event =
+                if matches!(state.event, Some(WorldEvent::FullMoon)) {
+                    3
+                } else {
+                    1
+                };
+
+            let baby_dwarf_multiplier_consumable = player
+                .dwarfs
+                .values()
+                .filter(|dwarf| {
+                    dwarf.occupation == Occupation::Idling
+                        && dwarf.is_adult()
+                })
+                .fold(1, |acc, d| acc * match d.equipment.get(&ItemType::Consumable) {
+                    Some(&Item::RhinoHornPowder) => 2,
+                    _ => 1,
+                });
+
+            if gen_ratio_valid(rng, 
+                pairs as u32
+                    * baby_dwarf_multiplier_event * baby_dwarf_multiplier_consumable * state.settings.world_speed as u32
+                    * (100
+                        + player.base.birth_bonus() as u32
+                        + player.tribe_bonus(&state.tribes, TribeUpgrade::Hearth) as u32),
+                ONE_HOUR as u32 * 4 * 100,
+            ) {
+                if let Some((father_id, mother_id)) = player.parents_for_birth(rng) {
+                    player.new_child(rng, &mut state.next_dwarf_id, state.time, father_id, mother_id);
+                }
+            }
+
+            let mut became_adult = CustomSet::new();
+
+            // Let the dwarfs eat!
+            let health_cost_multiplier = match state.event {
+                Some(WorldEvent::Plague) => {
+                    (1 + player.dwarfs.len() as u64 / 15).min(3)
+                }
+                _ => 1,
+            };
+            let mut sorted_by_health =
+                player.dwarfs.iter_mut().collect::<Vec<_>>();
+            sorted_by_health.sort_by_key(|(_, dwarf)| dwarf.health);
+            for (dwarf_id, dwarf) in sorted_by_health {
+                if dwarf.consumable_timer > 0 {
+                    dwarf.consumable_timer = dwarf.consumable_timer.saturating_sub(state.settings.world_speed);
+                } else {
+                    dwarf.equipment.swap_remove(&ItemType::Consumable);
+                }
+
+                match dwarf.equipment.get(&ItemType::Consumable) {
+                    Some(&Item::BearClawPowder) => {},
+                    _ => {
+                        dwarf.decr_health(
+                            dwarf.actual_occupation().health_cost_per_tick()
+                                * dwarf.health_cost_percent() / 100
+                                * health_cost_multiplier * state.settings.world_speed,
+                        );
+                    },
+                }
+                
+                if dwarf.actual_occupation() == Occupation::Idling {
+                    let substract_food = state.settings.world_speed.min(player.base.food);
+                    if substract_food > 0 {
+                        if dwarf.health <= MAX_HEALTH - (MAX_HEALTH / 1000) * substract_food {
+                            player.base.food = player.base.food.saturating_sub(substract_food * dwarf.food_multiplier());
+                            dwarf.incr_health((MAX_HEALTH / 1000) * substract_food);
+                        } else if player.auto_functions.auto_idle {
+                            dwarf.auto_idle = false;
+                        }
+                    } else if dwarf.auto_idle {
+                        dwarf.auto_idle = false;
+                    }
+                } else if player.auto_functions.auto_idle
+                    && dwarf.health <= MAX_HEALTH / 5
+                    && dwarf.occupation != Occupation::Idling
+                    && player.base.food > 0
+                {
+                    dwarf.auto_idle = true;
+                }
+
+                if !dwarf.dead() {
+                    let is_adult_before = dwarf.is_adult();
+
+                    match dwarf.equipment.get(&ItemType::Consumable) {
+                        Some(&Item::TigerFangPowder) => {},
+                        _ => {
+                            dwarf.age_seconds += AGE_SECONDS_PER_TICK * state.settings.world_speed;
+                        },
+                    }
+
+                    if dwarf.age_years() > 200 && gen_ratio_valid(rng, state.settings.world_speed as u32, ONE_DAY as u32 * 5) {
+                        dwarf.health = 0;
+                    }
+                    if !is_adult_before && dwarf.is_adult() {
+                        became_adult.insert(*dwarf_id);
+                    }
+                }
+            }
+
+            // Let the dwarfs improve and handle deaths.
+            let ids = player.dwarfs.keys().cloned().collect::<Vec<_>>();
+            for dwarf_id in ids {
+                let dwarf = player.dwarfs.get(&dwarf_id)?;
+                let (improvement_occupation, improvement_multiplier) = if dwarf
+                    .is_adult()
+                {
+                    (dwarf.actual_occupation(), 1)
+                } else if let Some(mentor_id) = dwarf.mentor {
+                    if let Some(mentor) = player.dwarfs.get(&mentor_id) {
+                        if mentor.is_adult() {
+                            (
+                                mentor.actual_occupation(),
+                                APPRENTICE_EFFECTIVENESS_DIVIDER,
+                            )
+                        } else {
+                            (
+                                Occupation::Idling,
+                                APPRENTICE_EFFECTIVENESS_DIVIDER,
+                            )
+                        }
+                    } else {
+                        (
+                            Occupation::Idling,
+                            APPRENTICE_EFFECTIVENESS_DIVIDER,
+                        )
+                    }
+                } else {
+                    (Occupation::Idling, APPRENTICE_EFFECTIVENESS_DIVIDER)
+                };
+
+                let dwarf = player.dwarfs.get_mut(&dwarf_id)?;
+
+                if !dwarf.dead() {
+                    // Quests teach twice as much as the daily work.
+                    let experience = if dwarf.participates_in_quest.is_some() {
+                        state.settings.world_speed * 2
+                    } else {
+                        state.settings.world_speed
+                    };
+                    if let Some(mastery) = dwarf.gain_experience(improvement_occupation, experience) {
+                        player.log.add(
+                            state.time,
+                            LogMsg::DwarfMastery(
+                                dwarf.actual_name().to_owned(),
+                                improvement_occupation,
+                                mastery,
+                            ),
+                        );
+                    }
+
+                    if gen_ratio_valid(rng, 
+                        improvement_occupation.requires_stats().agility as u32
+                            * improvement_multiplier as u32
+                            * state.settings.world_speed as u32,
+                        IMPROVEMENT_DURATION,
+                    ) && dwarf.stats.agility < 10
+                    {
+                        dwarf.stats.agility += 1;
+                        player.log.add(
+                            state.time,
+                            LogMsg::DwarfUpgrade(
+                                dwarf.actual_name().to_owned(),
+                                "agility".to_string(),
+                            ),
+                        );
+                    }
+                    if gen_ratio_valid(rng, 
+                        improvement_occupation.requires_stats().endurance
+                            as u32 * state.settings.world_speed as u32
+                            * improvement_multiplier as u32,
+                        IMPROVEMENT_DURATION,
+                    ) && dwarf.stats.endurance < 10
+                    {
+                        dwarf.stats.endurance += 1;
+                        player.log.add(
+                            state.time,
+                            LogMsg::DwarfUpgrade(
+                                dwarf.actual_name().to_owned(),
+                                "endurance".to_string(),
+                            ),
+                        );
+                    }
+                    if gen_ratio_valid(rng, 
+                        improvement_occupation.requires_stats().strength as u32
+                            * improvement_multiplier as u32 * state.settings.world_speed as u32,
+                        IMPROVEMENT_DURATION,
+                    ) && dwarf.stats.strength < 10
+                    {
+                        dwarf.stats.strength += 1;
+                        player.log.add(
+                            state.time,
+                            LogMsg::DwarfUpgrade(
+                                dwarf.actual_name().to_owned(),
+                                "strength".to_string(),
+                            ),
+                        );
+                    }
+                    if gen_ratio_valid(rng, 
+                        improvement_occupation.requires_stats().intelligence
+                            as u32
+                            * improvement_multiplier as u32,
+                        IMPROVEMENT_DURATION,
+                    ) && dwarf.stats.intelligence < 10
+                    {
+                        dwarf.stats.intelligence += 1;
+                        player.log.add(
+                            state.time,
+                            LogMsg::DwarfUpgrade(
+                                dwarf.actual_name().to_owned(),
+                                "intelligence".to_string(),
+                            ),
+                        );
+                    }
+                    if gen_ratio_valid(rng, 
+                        improvement_occupation.requires_stats().perception
+                            as u32
+                            * improvement_multiplier as u32,
+                        IMPROVEMENT_DURATION,
+                    ) && dwarf.stats.perception < 10
+                    {
+                        dwarf.stats.perception += 1;
+                        player.log.add(
+                            state.time,
+                            LogMsg::DwarfUpgrade(
+                                dwarf.actual_name().to_owned(),
+                                "perception".to_string(),
+                            ),
+                        );
+                    }
+                }
+            }
+
+            // Handle dwarfs that became adult
+            for dwarf_id in became_adult {
+                player.set_mentor(dwarf_id, None);
+
+                let dwarf: &mut Dwarf = player.dwarfs.get_mut(&dwarf_id)?;
+                let name = dwarf.actual_name().to_owned();
+                // Growing up can reveal a new trait.
+                let new_trait = if rng.gen_bool(TRAIT_PROBABILITY) {
+                    dwarf.unlock_trait(rng)
+                } else {
+                    None
+                };
+                player.log.add(
+                    state.time,
+                    LogMsg::DwarfIsAdult(name.clone()),
+                );
+                if let Some(new_trait) = new_trait {
+                    player.log.add(state.time, LogMsg::DwarfTrait(name, new_trait));
+                }
+            }
+
+            // Handle removed dwarfs
+            let removed_dwarfs = player
+                .dwarfs
+                .iter()
+                .filter(|(_, dwarf)| dwarf.dead() || dwarf.released)
+                .map(|(id, _)| id)
+                .copied()
+                .collect::<CustomSet<DwarfId>>();
+            for dwarf_id in removed_dwarfs {
+                let apprentice_id: Option<DwarfId> =
+                    player.dwarfs.get(&dwarf_id)?.apprentice;
+
+                if let Some(apprentice_id) = apprentice_id {
+                    player.set_mentor(apprentice_id, None);
+                }
+                player.set_mentor(dwarf_id, None);
+
+                let dwarf: &Dwarf = player.dwarfs.get(&dwarf_id)?;
+                // Send log message that dwarf died.
+                player.log.add(
+                    state.time,
+                    LogMsg::DwarfDied(dwarf.actual_name().to_owned()),
+                );
+                // Add the equipment to the inventory.
+                for (_, item) in &dwarf.equipment {
+                    player
+                        .inventory
+                        .items
+                        .add_checked(Bundle::new().add(*item, 1));
+                }
+            }
+            // Remove dead dwarfs from quests.
+            for quest in state.quests.values_mut() {
+                if let Some(contestant) = quest.contestants.get_mut(user_id) {
+                    contestant.dwarfs.retain(|_, dwarf_id| {
+                        !player
+                            .dwarfs
+                            .get(&*dwarf_id)
+                            .map(|d| d.dead() || d.released)
+                            .unwrap_or(true)
+                    });
+                }
+            }
+
+            // Remove dead dwarfs from the base.
+            player
+                .dwarfs
+                .retain(|_, dwarf| !(dwarf.dead() || dwarf.released));
+        }
+
+        Some(())
+    }
+}
+
+/// The items collected and crafted by working dwarfs.
+pub struct ProductionSystem;
+
+impl TickSystem for ProductionSystem {
+    fn tick(
+        &self,
+        state: &mut State,
+        rng: &mut impl Rng,
+        user_data: &CustomMap<UserId, UserData>,
+    ) -> Option<()> {
+        for (user_id, player) in state.players.iter_mut() {
+            let is_premium = is_premium(user_data, user_id);
+
+            // Switch the manager presets.
+            if is_premium {
+                player.update_manager_presets(state.event, state.time);
+            }
+
+            // Let the dwarfs work!
+            let mut added_items = Bundle::new();
+            for dwarf in player.dwarfs.values() {
+                if !dwarf.dead() && dwarf.is_adult() {
+                    for item in enum_iterator::all::<Item>() {
+                        if let Some(ItemProbability {
+                            expected_ticks_per_drop,
+                        }) =
+                            item.item_probability(dwarf.actual_occupation())
+                        {
+                            if dwarf.gen_ratio_effectiveness(
+                                &state.settings,
+                                &player.dwarfs,
+                                rng,
+                                player.drop_denominator_mul(
+                                    dwarf.actual_occupation(),
+                                    expected_ticks_per_drop,
+                                    state.event,
+                                    &state.tribes,
+                                ),
+                                state.time,
+                            ) {
+                                added_items = added_items.add(item, 1);
+                            }
+                        }
+                    }
+                }
+            }
+            player.items_produced += added_items.values().sum::<u64>();
+            player.add_items(added_items, state.time, is_premium, state.ledger.account(*user_id));
+
+            // Let the dwarfs craft!
+            player.work_on_crafts(&state.settings, state.time, is_premium, state.ledger.account(*user_id));
+        }
+
+        Some(())
+    }
+}
+
+/// The automation rules of premium players.
+pub struct AutomationSystem;
+
+impl TickSystem for AutomationSystem {
+    fn tick(
+        &self,
+        state: &mut State,
+        _rng: &mut impl Rng,
+        user_data: &CustomMap<UserId, UserData>,
+    ) -> Option<()> {
+        let mut bidders = Vec::new();
+        for (user_id, player) in state.players.iter_mut() {
+            if is_premium(user_data, user_id) {
+                player.run_rules(state.event, state.time, state.ledger.account(*user_id));
+                bidders.push(*user_id);
+            }
+        }
+
+        // Bids need the trade deals, so they are placed once all rules ran.
+        // Between two ticks, the same rules outbid other players right away.
+        for user_id in bidders {
+            let trade_ids = state.trade_deals.keys().copied().collect::<Vec<_>>();
+            for trade_id in trade_ids {
+                let player = state.players.get(&user_id)?;
+                let trade = state.trade_deals.get_mut(&trade_id)?;
+                let wants_bid = player
+                    .max_auto_bid(trade)
+                    .map(|max_bid| max_bid >= trade.next_bid)
+                    .unwrap_or(false);
+                if wants_bid && trade.check_bid(player, user_id).is_ok() {
+                    trade.bid(&mut state.players, user_id, state.time, &state.ledger);
+                }
+            }
+        }
+
+        Some(())
+    }
+}
+
+/// Moves the tithe of quest money to the tribe treasury and returns the
+/// money the player keeps.
+fn pay_tithe(tribes: &mut CustomMap<TribeId, Tribe>, tribe_id: Option<TribeId>, money: Money) -> Money {
+    match tribe_id.and_then(|tribe_id| tribes.get_mut(&tribe_id)) {
+        Some(tribe) => tribe.collect_tithe(money),
+        None => money,
+    }
+}
+
+/// Running quests, their rewards and new quests.
+pub struct QuestSystem;
+
+impl TickSystem for QuestSystem {
+    fn tick(
+        &self,
+        state: &mut State,
+        rng: &mut impl Rng,
+        user_data: &CustomMap<UserId, UserData>,
+    ) -> Option<()> {
+        // Continue the active quests.
+        for quest in state.quests.values_mut() {
+            quest.run(&state.settings, &state.players, &state.tribes)?;
+
+            if quest.done() {
+                match quest.quest_type.reward_mode() {
+                    RewardMode::BestGetsAll(money) => {
+                        if let Some(user_id) = quest.best() {
+                            if let Some(player) = state.players.get_mut(&user_id)
+                            {
+                                player.tribe_points += 1;
+                                player.quests_won += 1;
+
+                                let reward = pay_tithe(
+                                    &mut state.tribes,
+                                    player.tribe,
+                                    if state.king.is_some() {
+                                        money * 9 / 10
+                                    } else {
+                                        money
+                                    },
+                                );
+                                player.money += reward;
+                                state.ledger.record(|| {
+                                    LedgerEntry::new(state.time, user_id, LedgerReason::QuestReward)
+                                        .received_money(reward)
+                                });
+                                player.log.add(
+                                    state.time,
+                                    LogMsg::QuestCompletedMoney(
+                                        quest.quest_type,
+                                        money,
+                                    ),
+                                );
+                            }
+                            if let Some(king) = state.king {
+                                if let Some(player) =
+                                    state.players.get_mut(&king)
+                                {
+                                    player.money += money / 10;
+                                    state.ledger.record(|| {
+                                        LedgerEntry::new(state.time, king, LedgerReason::KingsCut)
+                                            .received_money(money / 10)
+                                    });
+                                    player.log.add(
+                                        state.time,
+                                        LogMsg::MoneyForKing(money / 10),
+                                    );
+                                }
+                            }
+                            for contestant_id in quest.contestants.keys() {
+                                if *contestant_id != user_id {
+                                    let Some(player) = state.players.get_mut(contestant_id) else {
+                                        continue;
+                                    };
+                                    player.log.add(
+                                        state.time,
+                                        LogMsg::QuestCompletedMoney(
+                                            quest.quest_type,
+                                            0,
+                                        ),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    RewardMode::BecomeKing => {
+                        if let Some(user_id) = quest.best() {
+                            if let Some(player) = state.players.get_mut(&user_id)
+                            {
+                                player.tribe_points += 1;
+                                player.quests_won += 1;
+
+                                if !matches!(
+                                    state.event,
+                                    Some(WorldEvent::Revolution)
+                                ) {
+                                    state.king = Some(user_id);
+                                    player.log.add(
+                                        state.time,
+                                        LogMsg::QuestCompletedKing(
+                                            quest.quest_type,
+                                            true,
+                                        ),
+                                    );
+                                }
+                            }
+                            for contestant_id in quest.contestants.keys() {
+                                if Some(*contestant_id) != state.king {
+                                    let Some(player) = state.players.get_mut(contestant_id) else {
+                                        continue;
+                                    };
+                                    player.log.add(
+                                        state.time,
+                                        LogMsg::QuestCompletedKing(
+                                            quest.quest_type,
+                                            false,
+                                        ),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    RewardMode::SplitFairly(money) => {
+                        for (user_id, money) in
+                            quest.split_by_score(if state.king.is_some() {
+                                money * 9 / 10
+                            } else {
+                                money
+                            })
+                        {
+                            if let Some(player) = state.players.get_mut(&user_id)
+                            {
+                                let reward = pay_tithe(&mut state.tribes, player.tribe, money);
+                                player.money += reward;
+                                state.ledger.record(|| {
+                                    LedgerEntry::new(state.time, user_id, LedgerReason::QuestReward)
+                                        .received_money(reward)
+                                });
+                                player.log.add(
+                                    state.time,
+                                    LogMsg::QuestCompletedMoney(
+                                        quest.quest_type,
+                                        money,
+                                    ),
+                                );
+                            }
+                        }
+                        if let Some(king) = state.king {
+                            if let Some(player) = state.players.get_mut(&king) {
+                                player.money += money / 10;
+                                state.ledger.record(|| {
+                                    LedgerEntry::new(state.time, king, LedgerReason::KingsCut)
+                                        .received_money(money / 10)
+                                });
+                                player.log.add(
+                                    state.time,
+                                    LogMsg::MoneyForKing(money / 10),
+                                );
+                            }
+                        }
+                    }
+                    RewardMode::BestGetsItems(items) => {
+                        if let Some(user_id) = quest.best() {
+                            if let Some(player) = state.players.get_mut(&user_id)
+                            {
+                                player.tribe_points += 1;
+                                player.quests_won += 1;
+
+                                let is_premium = is_premium(user_data, &user_id);
+
+                                if player.add_items(
+                                    items.clone(),
+                                    state.time,
+                                    is_premium,
+                                    state.ledger.account(user_id),
+                                ) {
+                                    state.ledger.record(|| {
+                                        LedgerEntry::new(state.time, user_id, LedgerReason::QuestReward)
+                                            .received(&items)
+                                    });
+                                }
+                                player.log.add(
+                                    state.time,
+                                    LogMsg::QuestCompletedItems(
+                                        quest.quest_type,
+                                        Some(items),
+                                    ),
+                                );
+                            }
+                            for contestant_id in quest.contestants.keys() {
+                                if *contestant_id != user_id {
+                                    let Some(player) = state.players.get_mut(contestant_id) else {
+                                        continue;
+                                    };
+                                    player.log.add(
+                                        state.time,
+                                        LogMsg::QuestCompletedItems(
+                                            quest.quest_type,
+                                            None,
+                                        ),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    RewardMode::ItemsByChance(items) => {
+                        if let Some(user_id) = quest.chance_by_score(rng) {
+                            if let Some(player) = state.players.get_mut(&user_id)
+                            {
+                                let is_premium = is_premium(user_data, &user_id);
+
+                                if player.add_items(
+                                    items.clone(),
+                                    state.time,
+                                    is_premium,
+                                    state.ledger.account(user_id),
+                                ) {
+                                    state.ledger.record(|| {
+                                        LedgerEntry::new(state.time, user_id, LedgerReason::QuestReward)
+                                            .received(&items)
+                                    });
+                                }
+                                player.log.add(
+                                    state.time,
+                                    LogMsg::QuestCompletedItems(
+                                        quest.quest_type,
+                                        Some(items),
+                                    ),
+                                );
+                            }
+                            for contestant_id in quest.contestants.keys() {
+                                if *contestant_id != user_id {
+                                    let Some(player) = state.players.get_mut(contestant_id) else {
+                                        continue;
+                                    };
+                                    player.log.add(
+                                        state.time,
+                                        LogMsg::QuestCompletedItems(
+                                            quest.quest_type,
+                                            None,
+                                        ),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    RewardMode::NewDwarfByChance(num_dwarfs) => {
+                        let mut reward = CustomMap::new();
+                        for _ in 0..num_dwarfs {
+                            if let Some(user_id) = quest.chance_by_score(rng) {
+                                *reward.entry(user_id).or_default() += 1;
+                            }
+                        }
+                        for (user_id, num_dwarfs) in reward.iter() {
+                            if let Some(player) =
+                                state.players.get_mut(user_id)
+                            {
+                                player.log.add(
+                                    state.time,
+                                    LogMsg::QuestCompletedDwarfs(
+                                        quest.quest_type,
+                                        Some(*num_dwarfs),
+                                    ),
+                                );
+                                for _ in 0..*num_dwarfs {
+                                    player.new_dwarf(
+                                        rng,
+                                        &mut state.next_dwarf_id,
+                                        state.time,
+                                        Some(Stats::default()),
+                                    );
+                                }
+       
+                            }
+                        }
+                        for contestant_id in quest.contestants.keys() {
+                            if !reward.contains_key(contestant_id) {
+                                let Some(player) = state.players.get_mut(contestant_id) else {
+                                    continue;
+                                };
+                                player.log.add(
+                                    state.time,
+                                    LogMsg::QuestCompletedDwarfs(
+                                        quest.quest_type,
+                                        None,
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                    RewardMode::NewDwarf(num_dwarfs) => {
+                        if let Some(user_id) = quest.best() {
+                            if let Some(player) = state.players.get_mut(&user_id)
+                            {
+                                player.tribe_points += 1;
+                                player.quests_won += 1;
+                                player.log.add(
+                                    state.time,
+                                    LogMsg::QuestCompletedDwarfs(
+                                        quest.quest_type,
+                                        Some(num_dwarfs),
+                                    ),
+                                );
+                                for _ in 0..num_dwarfs {
+                                    player.new_dwarf(
+                                        rng,
+                                        &mut state.next_dwarf_id,
+                                        state.time,
+                                        Some(Stats::default()),
+                                    );
+                                }
+                            }
+                            for contestant_id in quest.contestants.keys() {
+                                if *contestant_id != user_id {
+                                    let Some(player) = state.players.get_mut(contestant_id) else {
+                                        continue;
+                                    };
+                                    player.log.add(
+                                        state.time,
+                                        LogMsg::QuestCompletedDwarfs(
+                                            quest.quest_type,
+                                            None,
+                                        ),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Players and dwarfs that are gone since are skipped, a
+                // finished quest must not stay around to pay out again.
+                for (contestant_id, contestant) in quest.contestants.iter() {
+                    let Some(player) = state.players.get_mut(contestant_id) else {
+                        continue;
+                    };
+                    for dwarf_id in contestant.dwarfs.values() {
+                        if let Some(dwarf) = player.dwarfs.get_mut(dwarf_id) {
+                            dwarf.participates_in_quest = None;
+                        }
+                    }
+                }
+            }
+        }
+
+        state.quests.retain(|_, quest| !quest.done());
+
+        // Add quests.
+        let active_players = state.active_players();
+        let active_not_new_players = state.active_not_new_players();
+
+        let num_quests = if cfg!(debug_assertions) {
+            30
+        } else {
+            (active_players / (2 * NEW_PLAYER_DIVIDER as usize) + active_not_new_players * 2)
+                .max(10)
+        } * state.settings.quest_density as usize / 100;
+
+        let max_player_level = state.max_player_level();
+
+        let mut potential_quests = enum_iterator::all::<QuestType>()
+            .filter(|quest_type| {
+                (if let Some(level) = quest_type.max_level() {
+                    max_player_level > level - 10 && max_player_level <= level
+                } else {
+                    true
+                }) && (!quest_type.one_at_a_time()
+                    || (quest_type.one_at_a_time()
+                        && !state
+                            .quests
+                            .values()
+                            .any(|quest| quest.quest_type == *quest_type)))
+            })
+            .collect::<CustomSet<_>>();
+
+        while state.quests.len() < num_quests {
+            if potential_quests.is_empty() {
+                break;
+            }
+
+            let selected_quest = *potential_quests
+                .iter()
+                .copied()
+                .collect::<Vec<_>>()
+                .choose(rng)
+                .expect("potential quests is empty");
+
+            if selected_quest.one_at_a_time() {
+                potential_quests.swap_remove(&selected_quest);
+            }
+
+            let (min_level, max_level) =
+                if let Some(level) = selected_quest.max_level() {
+                    (1, level)
+                } else if selected_quest.one_at_a_time() {
+                    (1, u64::MAX)
+                } else {
+                    let selected_level = state
+                        .players
+                        .iter()
+                        .collect::<Vec<_>>()
+                        .choose_weighted(rng, |(_, player)| {
+                            if player.is_active(state.time) {
+                                if player.is_new(state.time) {
+                                    1
+                                } else {
+                                    NEW_PLAYER_DIVIDER
+                                }
+                            } else {
+                                0
+                            }
+                        })
+                        .map(|(_, player)| player.base.curr_level)
+                        .unwrap_or(1);
+                    
+                    let bracket_low = selected_level / 10 + 5;
+                    let bracket_high = selected_level / 5 + 10;
+                    let mut min_level = (selected_level.saturating_sub(rng.gen_range(bracket_low..=bracket_high))).max(1);
+                    let mut max_level = selected_level + rng.gen_range(bracket_low..=bracket_high);
+                    
+                    if min_level < selected_quest.occupation().unlocked_at_level() {
+                        let diff = selected_quest.occupation().unlocked_at_level() - min_level;
+                        min_level += diff;
+                        max_level += diff;
+                    }
+
+                    (min_level, max_level)
+                };
+
+            if max_level < selected_quest.occupation().unlocked_at_level() {
+                continue;
+            }
+
+            let quest = Quest::new(selected_quest, min_level, max_level);
+
+            state.quests.insert(state.next_quest_id, quest);
+
+            state.next_quest_id += 1;
+        }
+
+        Some(())
+    }
+}
+
+/// Running trades and new trades from the merchants.
+pub struct TradeSystem;
+
+impl TickSystem for TradeSystem {
+    fn tick(
+        &self,
+        state: &mut State,
+        rng: &mut impl Rng,
+        _user_data: &CustomMap<UserId, UserData>,
+    ) -> Option<()> {
+        let active_players = state.active_players();
+        let active_not_new_players = state.active_not_new_players();
+        let max_player_level = state.max_player_level();
+
+        for trade in state.trade_deals.values_mut() {
+            trade.update(&state.settings, &mut state.players, state.time, &state.ledger)?;
+        }
+
+        state.trade_deals.retain(|_, trade| !trade.done());
+
+        for offer in state.direct_offers.values_mut() {
+            offer.time_left = offer.time_left.saturating_sub(state.settings.world_speed);
+            if offer.time_left == 0 {
+                if let Some(sender) = offer.refund(&mut state.players, state.time, &state.ledger) {
+                    sender.log.add(state.time, LogMsg::OfferExpired(offer.to));
+                }
+            }
+        }
+
+        state.direct_offers.retain(|_, offer| offer.time_left > 0);
+
+        let num_trades = if cfg!(debug_assertions) {
+            15
+        } else {
+            (active_players / (5 * NEW_PLAYER_DIVIDER as usize) + active_not_new_players / 5)
+                .max(3)
+                .min(15)
+        };
+
+        while state
+            .trade_deals
+            .values()
+            .filter(|trade_deal| trade_deal.creator.is_none())
+            .count()
+            < num_trades
+        {
+            state.trade_deals.insert(state.next_trade_id, TradeDeal::new(rng, max_player_level, state.next_trade_id));
+            state.next_trade_id += 1;
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        automation::{Action, Rule},
+        testing::{premium, world},
+        Contestant, Player, MAX_LEVEL,
+    };
+    use engine_shared::{Event, State as _};
+
+    #[test]
+    fn finished_quests_pay_out_once_when_a_contestant_is_gone() {
+        let (mut state, mut rng) = world(1);
+        let mut quest = Quest::new(QuestType::ArenaFight, 0, MAX_LEVEL);
+        quest.preparation_time = 0;
+        quest.time_left = 1;
+        for (user_id, score) in [(UserId(1), 10), (UserId(2), 0)] {
+            quest.contestants.insert(
+                user_id,
+                Contestant {
+                    achieved_score: score,
+                    ..Default::default()
+                },
+            );
+        }
+        let quest_id = state.next_quest_id;
+        state.quests.insert(quest_id, quest);
+        state.next_quest_id += 1;
+        let money = state.players.get(&UserId(1)).unwrap().money;
+
+        QuestSystem.tick(&mut state, &mut rng, &CustomMap::new()).unwrap();
+        let paid = state.players.get(&UserId(1)).unwrap().money;
+        assert!(paid > money);
+        assert!(!state.quests.contains_key(&quest_id));
+
+        QuestSystem.tick(&mut state, &mut rng, &CustomMap::new()).unwrap();
+        assert_eq!(state.players.get(&UserId(1)).unwrap().money, paid);
+    }
+
+    #[test]
+    fn event_finds_the_eldest() {
+        let (mut state, mut rng) = world(2);
+        let player = state.players.get_mut(&UserId(2)).unwrap();
+        let (dwarf_id, dwarf) = player.dwarfs.iter_mut().next().unwrap();
+        dwarf.age_seconds = u64::MAX / 2;
+        let dwarf_id = *dwarf_id;
+
+        EventSystem.tick(&mut state, &mut rng, &CustomMap::new()).unwrap();
+
+        assert_eq!(state.eldest, Some((UserId(2), dwarf_id)));
+    }
+
+    #[test]
+    fn territory_builds_before_joining() {
+        let (mut state, mut rng) = world(1);
+        let player = state.players.get_mut(&UserId(1)).unwrap();
+        player.base.curr_level = JOIN_TRIBE_LEVEL - 1;
+        player.base.build_time = 1;
+
+        TerritorySystem.tick(&mut state, &mut rng, &CustomMap::new()).unwrap();
+
+        let player = state.players.get(&UserId(1)).unwrap();
+        assert_eq!(player.base.curr_level, JOIN_TRIBE_LEVEL);
+        assert_eq!(player.tribe_choice_since, Some(state.time));
+    }
+
+    #[test]
+    fn population_removes_dead_dwarfs() {
+        let (mut state, mut rng) = world(1);
+        let player = state.players.get_mut(&UserId(1)).unwrap();
+        let dwarfs = player.dwarfs.len();
+        player.dwarfs.values_mut().next().unwrap().health = 0;
+
+        PopulationSystem.tick(&mut state, &mut rng, &CustomMap::new()).unwrap();
+
+        let player = state.players.get(&UserId(1)).unwrap();
+        assert!(player.dwarfs.len() < dwarfs);
+        assert!(player.dwarfs.values().all(|dwarf| !dwarf.dead()));
+    }
+
+    #[test]
+    fn production_collects_items() {
+        let (mut state, mut rng) = world(1);
+        for dwarf in state.players.get_mut(&UserId(1)).unwrap().dwarfs.values_mut() {
+            dwarf.occupation = Occupation::Logging;
+        }
+
+        for _ in 0..ONE_DAY {
+            ProductionSystem.tick(&mut state, &mut rng, &CustomMap::new()).unwrap();
+        }
+
+        assert!(state.players.get(&UserId(1)).unwrap().items_produced > 0);
+    }
+
+    #[test]
+    fn automation_runs_rules_of_premium_players() {
+        let (mut state, mut rng) = world(2);
+        for player in state.players.values_mut() {
+            player.inventory.items.add_checked(Bundle::new().add(Item::Apple, 10));
+            player.rules.push(Rule::new(Vec::new(), Action::StoreAbove(Item::Apple, 0)));
+        }
+
+        AutomationSystem.tick(&mut state, &mut rng, &premium(UserId(1))).unwrap();
+
+        let apples = |user_id| {
+            let player: &Player = state.players.get(&user_id).unwrap();
+            player.inventory.items.get(&Item::Apple).copied().unwrap_or_default()
+        };
+        assert_eq!(apples(UserId(1)), 0);
+        assert_eq!(apples(UserId(2)), 10);
+    }
+
+    #[test]
+    fn quest_spawns_quests() {
+        let (mut state, mut rng) = world(1);
+
+        QuestSystem.tick(&mut state, &mut rng, &CustomMap::new()).unwrap();
+
+        assert!(!state.quests.is_empty());
+    }
+
+    #[test]
+    fn trade_spawns_merchant_deals() {
+        let (mut state, mut rng) = world(1);
+
+        TradeSystem.tick(&mut state, &mut rng, &CustomMap::new()).unwrap();
+
+        assert!(state.trade_deals.values().any(|deal| deal.creator.is_none()));
+    }
+
+    #[test]
+    fn failing_phase_does_not_skip_the_others() {
+        let (mut state, mut rng) = world(1);
+        // A trade won by a player that is gone makes the trade phase fail.
+        let mut trade = TradeDeal::new(&mut rng, 1, state.next_trade_id);
+        trade.time_left = 1;
+        trade.highest_bidder = Some((UserId(99), trade.next_bid));
+        state.trade_deals.insert(state.next_trade_id, trade);
+        state.next_trade_id += 1;
+        assert!(TradeSystem.tick(&mut state.clone(), &mut rng, &CustomMap::new()).is_none());
+        assert!(state.quests.is_empty());
+
+        state.update(&mut rng, Event::ServerEvent(crate::ServerEvent::Tick), &CustomMap::new());
+
+        assert!(!state.quests.is_empty());
+    }
+}
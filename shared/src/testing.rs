@@ -0,0 +1,36 @@
+//! Fixtures shared by the unit tests of the modules.
+
+use crate::{GameMode, Player, State, UserData, UserId};
+use engine_shared::utils::custom_map::CustomMap;
+use rand::{rngs::SmallRng, SeedableRng};
+
+/// A started world with the given number of fresh players, whose ids start at one.
+pub fn world(num_players: i64) -> (State, SmallRng) {
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut state = State::new(GameMode::Ranked);
+    state.start_countdown = 0;
+    for user_id in 1..=num_players {
+        let player = Player::new(state.time, &mut rng, &mut state.next_dwarf_id);
+        state.players.insert(UserId(user_id), player);
+    }
+    (state, rng)
+}
+
+/// User data in which only the given player has a premium account.
+pub fn premium(user_id: UserId) -> CustomMap<UserId, UserData> {
+    let mut user_data = CustomMap::new();
+    user_data.insert(
+        user_id,
+        UserData {
+            username: String::new(),
+            premium: u64::MAX,
+            games_won: 0,
+            admin: false,
+            guest: false,
+            joined: time::PrimitiveDateTime::MIN,
+            referrer: None,
+            dwarf_skins: Vec::new(),
+        },
+    );
+    user_data
+}